@@ -1,9 +1,24 @@
+use crate::config::{MigrationSettings, override_pairs};
+use crate::foreign_key::ForeignKeyOptions;
+use crate::schema_config::SchemaConfig;
 use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Strategy {
     Triggers,
     Logical,
+    /// Logical decoding over a replication connection instead of polling the slot
+    Streaming,
+}
+
+/// How `--plan` prints the plan
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
 }
 
 #[derive(Parser, Debug)]
@@ -12,55 +27,380 @@ pub struct Args {
     #[command(subcommand)]
     pub command: Command,
 
-    /// Use logical replication (wal2json) instead of log table triggers
-    #[clap(long)]
-    pub logical: bool,
+    /// TOML or YAML config file; command line flags override its values
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+}
+
+/// Where the tool keeps its shadow, log and archived tables
+#[derive(clap::Args, Debug, Clone)]
+pub struct SchemaArgs {
+    /// Schema for shadow tables [default: post_migrations]
+    #[arg(long)]
+    pub shadow_schema: Option<String>,
+
+    /// Schema for log tables and trigger functions; must differ from the other tool schemas [default: post_migrations_log]
+    #[arg(long)]
+    pub log_schema: Option<String>,
+
+    /// Schema the original table is archived to after the swap; must differ from the other tool schemas [default: post_migrations_old]
+    #[arg(long)]
+    pub archive_schema: Option<String>,
+
+    /// Schema for the tool's bookkeeping tables; must differ from the other tool schemas [default: post_migrations_state]
+    #[arg(long)]
+    pub state_schema: Option<String>,
+
+    /// Name template for tool tables; supports {schema}, {table} and {namespaced}, and must keep tables migrated together apart [default: {namespaced}]
+    #[arg(long)]
+    pub table_name_template: Option<String>,
+
+    /// Role to own the tool schemas
+    #[arg(long)]
+    pub schema_owner: Option<String>,
+
+    /// Role to grant USAGE on the tool schemas (repeatable); revokes PUBLIC access
+    #[arg(long)]
+    pub schema_grant: Vec<String>,
+}
+
+impl SchemaArgs {
+    /// Overrides `config` with the flags that were given.
+    pub fn apply_to(self, config: &mut SchemaConfig) {
+        if let Some(shadow_schema) = self.shadow_schema {
+            config.shadow_schema = shadow_schema;
+        }
+        if let Some(log_schema) = self.log_schema {
+            config.log_schema = log_schema;
+        }
+        if let Some(archive_schema) = self.archive_schema {
+            config.archive_schema = archive_schema;
+        }
+        if let Some(state_schema) = self.state_schema {
+            config.state_schema = state_schema;
+        }
+        if let Some(table_name_template) = self.table_name_template {
+            config.table_name_template = table_name_template;
+        }
+        if let Some(owner) = self.schema_owner {
+            config.owner = Some(owner);
+        }
+        if !self.schema_grant.is_empty() {
+            config.usage_grants = self.schema_grant;
+        }
+    }
+}
+
+/// What to do with foreign keys on other tables that reference the migrated table
+#[derive(clap::Args, Debug, Clone)]
+pub struct ForeignKeyArgs {
+    /// Leave inbound foreign keys pointing at the archived table instead of recreating them
+    #[arg(long)]
+    pub no_recreate_foreign_keys: bool,
+
+    /// Leave recreated foreign keys NOT VALID instead of validating them after the cutover
+    #[arg(long)]
+    pub no_validate_foreign_keys: bool,
+
+    /// Keep the foreign keys pointing at the archived table, renamed with an _old suffix. They
+    /// are always kept when they aren't recreated
+    #[arg(long)]
+    pub keep_old_foreign_keys: bool,
+}
+
+impl ForeignKeyArgs {
+    /// Turns off the steps whose flags were given.
+    pub fn apply_to(self, options: &mut ForeignKeyOptions) {
+        if self.no_recreate_foreign_keys {
+            options.recreate = false;
+        }
+        if self.no_validate_foreign_keys {
+            options.validate = false;
+        }
+        if self.keep_old_foreign_keys {
+            options.drop_old = false;
+        }
+    }
+}
+
+/// Batch sizes, throttles, cutover timeouts and hooks
+#[derive(clap::Args, Debug, Clone)]
+pub struct TuningArgs {
+    /// Rows copied per backfill batch [default: 1000]
+    #[arg(long, value_parser = batch_size_parser())]
+    pub backfill_batch_size: Option<usize>,
+
+    /// Pause between backfill batches, in milliseconds [default: 0]
+    #[arg(long)]
+    pub backfill_throttle_ms: Option<u64>,
+
+    /// Changes applied per replay batch [default: 100]
+    #[arg(long, value_parser = batch_size_parser())]
+    pub replay_batch_size: Option<usize>,
+
+    /// Pause between replay batches while the backfill runs, in milliseconds [default: 200]
+    #[arg(long)]
+    pub replay_interval_ms: Option<u64>,
+
+    /// Switch tables to REPLICA IDENTITY FULL while they are published, when their identity
+    /// doesn't carry the primary key; restored at teardown
+    #[arg(long)]
+    pub replica_identity_full: bool,
+
+    /// Give up on the cutover if the table lock isn't granted within this many milliseconds
+    #[arg(long)]
+    pub lock_timeout_ms: Option<u64>,
+
+    /// Limit each statement in the cutover transaction to this many milliseconds
+    #[arg(long)]
+    pub statement_timeout_ms: Option<u64>,
+
+    /// SQL to run under the cutover lock just before the swap (repeatable)
+    #[arg(long)]
+    pub before_swap: Vec<String>,
+
+    /// SQL to run once the cutover has committed (repeatable)
+    #[arg(long)]
+    pub after_cutover: Vec<String>,
+}
+
+/// Batch sizes start at 1: a batch of 0 rows reads as the end of the table or the log.
+fn batch_size_parser() -> clap::builder::RangedU64ValueParser<usize> {
+    clap::builder::RangedU64ValueParser::new().range(1..)
+}
+
+impl TuningArgs {
+    /// Overrides `settings` with the flags that were given.
+    pub fn apply_to(self, settings: &mut MigrationSettings) {
+        if let Some(batch_size) = self.backfill_batch_size {
+            settings.backfill_batch_size = batch_size;
+        }
+        if let Some(throttle_ms) = self.backfill_throttle_ms {
+            settings.backfill_throttle = Duration::from_millis(throttle_ms);
+        }
+        if let Some(batch_size) = self.replay_batch_size {
+            settings.replay_batch_size = batch_size;
+        }
+        if let Some(interval_ms) = self.replay_interval_ms {
+            settings.replay_interval = Duration::from_millis(interval_ms);
+        }
+        if self.replica_identity_full {
+            settings.replica_identity_full = true;
+        }
+        if let Some(timeout_ms) = self.lock_timeout_ms {
+            settings.cutover.lock_timeout = Some(Duration::from_millis(timeout_ms));
+        }
+        if let Some(timeout_ms) = self.statement_timeout_ms {
+            settings.cutover.statement_timeout = Some(Duration::from_millis(timeout_ms));
+        }
+        if !self.before_swap.is_empty() {
+            settings.cutover.before_swap = self.before_swap;
+        }
+        if !self.after_cutover.is_empty() {
+            settings.cutover.after_cutover = self.after_cutover;
+        }
+    }
+}
+
+/// Which rows the migration keeps and how shadow columns are filled
+#[derive(clap::Args, Debug, Clone)]
+pub struct CopyArgs {
+    /// Keep only the rows matching this SQL condition; the rest are purged by the migration
+    #[arg(long = "where", value_name = "CONDITION")]
+    pub row_filter: Option<String>,
+
+    /// Fill a shadow column from an SQL expression over the table's columns (repeatable)
+    #[arg(long, value_name = "COLUMN=EXPR", value_parser = parse_transform)]
+    pub transform: Vec<(String, String)>,
+
+    /// Map a column to its new name, or drop it with OLD= (repeatable); overrides the
+    /// renames and drops in the SQL
+    #[arg(long, value_name = "OLD=NEW", value_parser = parse_mapping)]
+    pub map: Vec<(String, Option<String>)>,
+}
+
+impl CopyArgs {
+    /// Overrides `settings` with the flags that were given.
+    pub fn apply_to(self, settings: &mut MigrationSettings) {
+        if self.row_filter.is_some() {
+            settings.row_filter = self.row_filter;
+        }
+        override_pairs(&mut settings.transforms, self.transform);
+        override_pairs(&mut settings.column_mappings, self.map);
+    }
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Run the full migration (default)
     Migrate {
-        /// PostgreSQL connection URI
+        /// PostgreSQL connection URI; overrides the config file's [connection]
         #[arg(short, long)]
-        uri: String,
+        uri: Option<String>,
 
-        /// ALTER TABLE statement
-        #[arg(short, long)]
-        sql: String,
+        /// ALTER TABLE statement; repeat to migrate several tables together (not with --strategy streaming)
+        #[arg(short, long, required = true)]
+        sql: Vec<String>,
 
         /// Execute the migration (swap tables and drop old table)
         #[arg(short, long, default_value = "false")]
         execute: bool,
 
-        /// Change capture strategy: triggers (default) or logical
-        #[arg(long, value_enum, default_value_t = Strategy::Triggers)]
-        strategy: Strategy,
+        /// Change capture strategy: triggers (default), logical or streaming
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+
+        /// With several --sql, cut every table over in one transaction so they switch together
+        #[arg(long)]
+        atomic_cutover: bool,
+
+        /// Print the ordered SQL the migration would run, without changing anything
+        #[arg(long)]
+        plan: bool,
+
+        /// Output format for --plan
+        #[arg(long, value_enum, default_value_t = PlanFormat::Text)]
+        plan_format: PlanFormat,
+
+        #[command(flatten)]
+        copy: CopyArgs,
+
+        #[command(flatten)]
+        schemas: SchemaArgs,
+
+        #[command(flatten)]
+        foreign_keys: ForeignKeyArgs,
+
+        #[command(flatten)]
+        tuning: TuningArgs,
+    },
+    /// Apply the pending SQL files in a directory, in order, each as its own migration. Row
+    /// filters, transforms and column maps are set per table in the config file
+    RunDir {
+        /// Directory of numbered .sql files
+        dir: PathBuf,
+
+        /// PostgreSQL connection URI; overrides the config file's [connection]
+        #[arg(short, long)]
+        uri: Option<String>,
+
+        /// Run the pending migrations; without this the pending files are only listed
+        #[arg(short, long, default_value = "false")]
+        execute: bool,
+
+        /// Change capture strategy: triggers (default), logical or streaming
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+
+        #[command(flatten)]
+        schemas: SchemaArgs,
 
-        /// Use logical replication (wal2json) instead of log table triggers
-        #[clap(long)]
-        logical: bool,
+        #[command(flatten)]
+        foreign_keys: ForeignKeyArgs,
+
+        #[command(flatten)]
+        tuning: TuningArgs,
+    },
+    /// Check that a migration can run safely, without changing anything
+    Check {
+        /// PostgreSQL connection URI; overrides the config file's [connection]
+        #[arg(short, long)]
+        uri: Option<String>,
+
+        /// ALTER TABLE statement
+        #[arg(short, long)]
+        sql: String,
+
+        /// Change capture strategy: triggers (default), logical or streaming
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+
+        /// Free space on the database volume in bytes, to compare against the table size
+        #[arg(long)]
+        available_disk_bytes: Option<i64>,
+
+        /// Check as if the migration will switch the table to REPLICA IDENTITY FULL
+        #[arg(long)]
+        replica_identity_full: bool,
+
+        #[command(flatten)]
+        copy: CopyArgs,
+
+        #[command(flatten)]
+        schemas: SchemaArgs,
+
+        #[command(flatten)]
+        foreign_keys: ForeignKeyArgs,
     },
     /// Run only migration setup and log replay (no backfill)
     ReplayOnly {
-        /// PostgreSQL connection URI
+        /// PostgreSQL connection URI; overrides the config file's [connection]
         #[arg(short, long)]
-        uri: String,
+        uri: Option<String>,
 
         /// ALTER TABLE statement
         #[arg(short, long)]
         sql: String,
 
-        /// Change capture strategy: triggers (default) or logical
-        #[arg(long, value_enum, default_value_t = Strategy::Triggers)]
-        strategy: Strategy,
+        /// Change capture strategy: triggers (default), logical or streaming
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+
+        /// Resume an interrupted streaming run from the slot it printed, keeping its shadow table
+        #[arg(long)]
+        resume_slot: Option<String>,
 
-        /// Use logical replication (wal2json) instead of log table triggers
-        #[clap(long)]
-        logical: bool,
+        #[command(flatten)]
+        schemas: SchemaArgs,
     },
 }
 
+/// Splits `COLUMN=EXPR` on the first `=`, so the expression may contain comparisons.
+fn parse_transform(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((column, expression))
+            if !column.trim().is_empty() && !expression.trim().is_empty() =>
+        {
+            Ok((column.trim().to_string(), expression.trim().to_string()))
+        }
+        _ => Err(format!("expected COLUMN=EXPR, got {:?}", value)),
+    }
+}
+
+/// Splits `OLD=NEW` on the first `=`; an empty `NEW` drops the column.
+fn parse_mapping(value: &str) -> Result<(String, Option<String>), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.trim().is_empty() => Ok((
+            from.trim().to_string(),
+            Some(to.trim().to_string()).filter(|to| !to.is_empty()),
+        )),
+        _ => Err(format!("expected OLD=NEW, got {:?}", value)),
+    }
+}
+
 pub fn get_args() -> Result<Args, clap::Error> {
     Args::try_parse()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_sizes_must_be_positive() {
+        for flag in ["--backfill-batch-size", "--replay-batch-size"] {
+            let parse = |value: &str| {
+                Args::try_parse_from([
+                    "postgres-ost",
+                    "migrate",
+                    "--sql",
+                    "ALTER TABLE t",
+                    flag,
+                    value,
+                ])
+            };
+            assert!(parse("0").is_err(), "{} accepted 0", flag);
+            assert!(parse("1").is_ok(), "{} refused 1", flag);
+        }
+    }
+}
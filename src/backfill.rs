@@ -1,3 +1,5 @@
+use crate::PrimaryKey;
+use crate::identifier::{quote_ident, quote_ident_list};
 use crate::table::Table;
 
 pub trait Backfill {
@@ -10,7 +12,12 @@ pub trait Backfill {
     ) -> anyhow::Result<()>;
 }
 
-pub struct SimpleBackfill;
+/// Copies the whole table in one statement.
+#[derive(Default)]
+pub struct SimpleBackfill {
+    /// Only copy rows matching this condition
+    pub row_filter: Option<String>,
+}
 
 impl Backfill for SimpleBackfill {
     fn backfill(
@@ -20,21 +27,71 @@ impl Backfill for SimpleBackfill {
         column_map: &crate::ColumnMap,
         client: &mut postgres::Client,
     ) -> anyhow::Result<()> {
-        let main_cols = column_map.main_cols();
-        let shadow_cols = column_map.shadow_cols();
-        let insert_cols_csv = shadow_cols.join(", ");
-        let select_cols_csv = main_cols.join(", ");
+        let insert_cols_csv = quote_ident_list(&column_map.insert_cols());
+        let select_cols_csv = column_map.select_list();
+        let filter = match &self.row_filter {
+            Some(row_filter) => format!(" WHERE ({})", row_filter),
+            None => String::new(),
+        };
         let backfill_statement = format!(
-            "INSERT INTO {} ({}) SELECT {} FROM {}",
-            shadow_table, insert_cols_csv, select_cols_csv, table
+            "INSERT INTO {} ({}) OVERRIDING SYSTEM VALUE SELECT {} FROM {}{}",
+            shadow_table, insert_cols_csv, select_cols_csv, table, filter
         );
         client.simple_query(&backfill_statement)?;
         Ok(())
     }
 }
 
+/// Rows copied per batch unless configured otherwise.
+pub const DEFAULT_BACKFILL_BATCH_SIZE: usize = 1000;
+
 pub struct BatchedBackfill {
     pub batch_size: usize,
+    /// Pause between batches, to limit the load on the server
+    pub throttle: std::time::Duration,
+    /// Only copy rows matching this condition
+    pub row_filter: Option<String>,
+}
+
+impl BatchedBackfill {
+    /// The statement copying one batch, returning the batch's keys under the main table's name
+    /// and type for `primary_key`. After the first batch it resumes past the last key, bound as
+    /// `$1`. Rows the replay already copied ahead of the backfill, such as rows it inserted or
+    /// updated into matching the row filter, are left as they are, and the batch's keys are
+    /// returned whether or not its rows were copied, so the backfill still moves past them.
+    pub fn batch_statement(
+        &self,
+        table: &Table,
+        shadow_table: &Table,
+        column_map: &crate::ColumnMap,
+        primary_key: &crate::PrimaryKeyInfo,
+        resume: bool,
+    ) -> anyhow::Result<String> {
+        let shadow_pk_col = quote_ident(&column_map.shadow_key(&primary_key.name)?);
+        let pk_col = quote_ident(&primary_key.name);
+        let mut conditions = Vec::new();
+        if resume {
+            conditions.push(format!("{} > $1", pk_col));
+        }
+        if let Some(row_filter) = &self.row_filter {
+            conditions.push(format!("({})", row_filter));
+        }
+        Ok(format!(
+            "WITH batch AS (SELECT * FROM {}{} ORDER BY {pk} ASC LIMIT {}), copied AS (INSERT INTO {} ({}) OVERRIDING SYSTEM VALUE SELECT {} FROM batch ON CONFLICT ({shadow_pk}) DO NOTHING) SELECT {pk} FROM batch ORDER BY {pk} ASC",
+            table,
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            },
+            self.batch_size,
+            shadow_table,
+            quote_ident_list(&column_map.insert_cols()),
+            column_map.select_list(),
+            pk = pk_col,
+            shadow_pk = shadow_pk_col,
+        ))
+    }
 }
 
 impl Backfill for BatchedBackfill {
@@ -45,30 +102,28 @@ impl Backfill for BatchedBackfill {
         column_map: &crate::ColumnMap,
         client: &mut postgres::Client,
     ) -> anyhow::Result<()> {
-        let batch_size = self.batch_size;
-        let main_cols = column_map.main_cols();
-        let shadow_cols = column_map.shadow_cols();
-        let insert_cols_csv = shadow_cols.join(", ");
-        let select_cols_csv = main_cols.join(", ");
-        let mut last_seen_id: Option<i64> = None;
+        let primary_key = table.get_primary_key_info(client)?;
+        let mut last_seen_id: Option<PrimaryKey> = None;
         loop {
-            let rows = if let Some(last_id) = last_seen_id {
-                let backfill_statement = format!(
-                    "INSERT INTO {} ({}) SELECT {} FROM {} WHERE id > $1 ORDER BY id ASC LIMIT {} RETURNING id",
-                    shadow_table, insert_cols_csv, select_cols_csv, table, batch_size
-                );
-                client.query(&backfill_statement, &[&last_id])?
+            let rows = if let Some(last_id) = &last_seen_id {
+                let backfill_statement =
+                    self.batch_statement(table, shadow_table, column_map, &primary_key, true)?;
+                client.query(&backfill_statement, &[last_id.as_param()])?
             } else {
-                let backfill_statement = format!(
-                    "INSERT INTO {} ({}) SELECT {} FROM {} ORDER BY id ASC LIMIT {} RETURNING id",
-                    shadow_table, insert_cols_csv, select_cols_csv, table, batch_size
-                );
+                let backfill_statement =
+                    self.batch_statement(table, shadow_table, column_map, &primary_key, false)?;
                 client.query(&backfill_statement, &[])?
             };
             if rows.is_empty() {
                 break;
             }
-            last_seen_id = rows.last().map(|row| row.get::<_, i64>(0));
+            last_seen_id = rows
+                .last()
+                .map(|row| PrimaryKey::from_row(row, &primary_key.name, &primary_key.ty))
+                .transpose()?;
+            if !self.throttle.is_zero() {
+                std::thread::sleep(self.throttle);
+            }
         }
         Ok(())
     }
@@ -0,0 +1,553 @@
+// src/check.rs
+// Preflight checks run by `postgres-ost check` before anything is created.
+
+use crate::backfill::BatchedBackfill;
+use crate::config::MigrationSettings;
+use crate::foreign_key::{ForeignKey, ForeignKeyOptions};
+use crate::logical_replication::ReplicaIdentity;
+use crate::migration::Migration;
+use crate::migration_runner::ReplayMode;
+use crate::pg_query_parser::PgQueryParser;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::Result;
+use postgres::{Client, GenericClient};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+/// The outcome of every preflight check, in the order they ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport(pub Vec<CheckResult>);
+
+impl CheckReport {
+    fn push(&mut self, name: &str, status: CheckStatus, message: impl Into<String>) {
+        self.0.push(CheckResult {
+            name: name.to_string(),
+            status,
+            message: message.into(),
+        });
+    }
+
+    /// True unless a check failed; warnings don't stop a migration.
+    pub fn passed(&self) -> bool {
+        self.0
+            .iter()
+            .all(|result| result.status != CheckStatus::Fail)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CheckResult> {
+        self.0.iter().find(|result| result.name == name)
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.0 {
+            writeln!(f, "[{}] {}: {}", result.status, result.name, result.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings the checks depend on.
+pub struct CheckOptions<'a> {
+    pub mode: &'a ReplayMode,
+    pub schema_config: &'a SchemaConfig,
+    /// Free space on the database volume, which can't be read from SQL
+    pub available_disk_bytes: Option<i64>,
+    /// The column mappings, transforms and row filter the migration will run with, and
+    /// whether it may switch the table to REPLICA IDENTITY FULL
+    pub settings: &'a MigrationSettings,
+    /// What the cutover will do with foreign keys referencing the table
+    pub foreign_key_options: &'a ForeignKeyOptions,
+}
+
+/// Runs every preflight check for `sql`. Checks that need the migrated table are skipped when
+/// it can't be found. Nothing is left behind: the migration is rehearsed in a transaction that
+/// is rolled back.
+pub fn run_checks(sql: &str, options: &CheckOptions, client: &mut Client) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let logical = !matches!(options.mode, ReplayMode::Log);
+
+    let version = crate::version::detect_and_set_pg_version(client)?;
+    if version.version_num < 100000 {
+        report.push(
+            "version",
+            CheckStatus::Fail,
+            format!("PostgreSQL {} is older than 10", version.version_num),
+        );
+    } else {
+        report.push(
+            "version",
+            CheckStatus::Pass,
+            format!("server_version_num {}", version.version_num),
+        );
+    }
+
+    let main = match PgQueryParser.check_single_table(sql) {
+        Ok(main) => {
+            report.push("sql", CheckStatus::Pass, format!("only changes {}", main));
+            main
+        }
+        Err(e) => {
+            report.push("sql", CheckStatus::Fail, e.to_string());
+            return Ok(report);
+        }
+    };
+    let table = match Table::new(&main).resolve_schema(client) {
+        Ok(table) => {
+            report.push("table", CheckStatus::Pass, format!("{} exists", table));
+            table
+        }
+        Err(e) => {
+            report.push("table", CheckStatus::Fail, e.to_string());
+            return Ok(report);
+        }
+    };
+
+    let primary_key_ok = check_primary_key(&table, client, &mut report)?;
+    if logical {
+        check_logical_replication(
+            &table,
+            options.settings.replica_identity_full,
+            client,
+            &mut report,
+        )?;
+    }
+    check_disk_space(&table, options.available_disk_bytes, client, &mut report)?;
+    check_privileges(&table, logical, options.schema_config, client, &mut report)?;
+    check_triggers(&table, client, &mut report)?;
+
+    check_foreign_keys(&table, options.foreign_key_options, client, &mut report)?;
+
+    if primary_key_ok {
+        rehearse_migration(sql, options, client, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Reports the foreign keys referencing the table and what the cutover will do with them.
+fn check_foreign_keys<C: GenericClient>(
+    table: &Table,
+    options: &ForeignKeyOptions,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let foreign_keys = ForeignKey::inbound(table, client)?;
+    if foreign_keys.is_empty() {
+        report.push(
+            "foreign keys",
+            CheckStatus::Pass,
+            "none reference the table",
+        );
+        return Ok(());
+    }
+    let names: Vec<String> = foreign_keys
+        .iter()
+        .map(|fk| format!("{} on {}", fk.name, fk.table))
+        .collect();
+    let handling = if !options.recreate {
+        "not recreated: they keep pointing at the archived table, which stops receiving writes \
+         at cutover"
+            .to_string()
+    } else {
+        format!(
+            "recreated NOT VALID at cutover and {}; the old constraints are {}",
+            if options.validate {
+                "validated afterwards"
+            } else {
+                "left unvalidated"
+            },
+            if options.drops_old() {
+                "dropped"
+            } else {
+                "kept with an _old suffix"
+            }
+        )
+    };
+    report.push(
+        "foreign keys",
+        CheckStatus::Warn,
+        format!("{}: {}", handling, names.join(", ")),
+    );
+    Ok(())
+}
+
+fn check_primary_key<C: GenericClient>(
+    table: &Table,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<bool> {
+    let rows = client.query(
+        "SELECT a.attname::text, format_type(a.atttypid, a.atttypmod)
+         FROM pg_index i
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+         WHERE i.indrelid = ($1)::text::regclass AND i.indisprimary",
+        &[&table.to_string()],
+    )?;
+    let (status, message) = match rows.as_slice() {
+        [] => (
+            CheckStatus::Fail,
+            "the table has no primary key".to_string(),
+        ),
+        [row] => {
+            let name: String = row.get(0);
+            let ty: String = row.get(1);
+            if ty == "integer" || ty == "bigint" {
+                (CheckStatus::Pass, format!("{} {}", name, ty))
+            } else {
+                (
+                    CheckStatus::Fail,
+                    format!(
+                        "{} is {}; only integer and bigint keys are supported",
+                        name, ty
+                    ),
+                )
+            }
+        }
+        _ => (
+            CheckStatus::Fail,
+            format!("composite keys aren't supported ({} columns)", rows.len()),
+        ),
+    };
+    report.push("primary key", status, message);
+    Ok(status == CheckStatus::Pass)
+}
+
+fn check_logical_replication<C: GenericClient>(
+    table: &Table,
+    replica_identity_full: bool,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let wal_level: String = client.query_one("SHOW wal_level", &[])?.get(0);
+    if wal_level == "logical" {
+        report.push("wal_level", CheckStatus::Pass, "logical");
+    } else {
+        report.push(
+            "wal_level",
+            CheckStatus::Fail,
+            format!("is {}, logical replication needs logical", wal_level),
+        );
+    }
+
+    let identity = ReplicaIdentity::load(table, client)?;
+    let (status, message) = if ReplicaIdentity::carries_primary_key(table, client)? {
+        (
+            CheckStatus::Pass,
+            format!("{} carries the primary key", identity),
+        )
+    } else if replica_identity_full {
+        (
+            CheckStatus::Warn,
+            format!(
+                "{} doesn't carry the primary key; FULL is used until the teardown, \
+                 writing whole old rows to the WAL",
+                identity
+            ),
+        )
+    } else {
+        (
+            CheckStatus::Fail,
+            format!(
+                "{} doesn't carry the primary key; change it or pass --replica-identity-full",
+                identity
+            ),
+        )
+    };
+    report.push("replica identity", status, message);
+
+    // A temporary slot is dropped when the session ends, even if the drop below doesn't run
+    let slot = format!("post_check_{}", uuid::Uuid::new_v4().simple());
+    match client.query_one(
+        "SELECT pg_create_logical_replication_slot($1, 'wal2json', true)",
+        &[&slot],
+    ) {
+        Ok(_) => {
+            client.execute("SELECT pg_drop_replication_slot($1)", &[&slot])?;
+            report.push("wal2json", CheckStatus::Pass, "output plugin is available");
+        }
+        Err(e) => report.push(
+            "wal2json",
+            CheckStatus::Fail,
+            format!("couldn't create a wal2json slot: {}", e),
+        ),
+    }
+    Ok(())
+}
+
+fn check_disk_space<C: GenericClient>(
+    table: &Table,
+    available_disk_bytes: Option<i64>,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let row = client.query_one(
+        "SELECT pg_total_relation_size(($1)::text::regclass),
+                pg_size_pretty(pg_total_relation_size(($1)::text::regclass))",
+        &[&table.to_string()],
+    )?;
+    let size: i64 = row.get(0);
+    let pretty: String = row.get(1);
+    // The shadow table is a full copy with its indexes, plus WAL and log table churn on top
+    let (status, message) = match available_disk_bytes {
+        None => (
+            CheckStatus::Warn,
+            format!(
+                "free space can't be read from SQL; the copy needs at least {}, pass --available-disk-bytes to check",
+                pretty
+            ),
+        ),
+        Some(available) if available < size => (
+            CheckStatus::Fail,
+            format!(
+                "{} bytes free, the copy needs at least {}",
+                available, pretty
+            ),
+        ),
+        Some(available) if available < size * 2 => (
+            CheckStatus::Warn,
+            format!(
+                "{} bytes free, the copy needs {} plus WAL and log table growth",
+                available, pretty
+            ),
+        ),
+        Some(available) => (
+            CheckStatus::Pass,
+            format!("{} bytes free, the copy needs {}", available, pretty),
+        ),
+    };
+    report.push("disk space", status, message);
+    Ok(())
+}
+
+fn check_privileges<C: GenericClient>(
+    table: &Table,
+    logical: bool,
+    schema_config: &SchemaConfig,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let row = client.query_one(
+        "SELECT r.rolsuper, r.rolreplication, pg_has_role(c.relowner, 'USAGE'),
+                has_schema_privilege(c.relnamespace, 'CREATE'),
+                has_database_privilege(current_database(), 'CREATE')
+         FROM pg_roles r, pg_class c
+         WHERE r.rolname = current_user AND c.oid = ($1)::text::regclass",
+        &[&table.to_string()],
+    )?;
+    let superuser: bool = row.get(0);
+    if superuser {
+        report.push("privileges", CheckStatus::Pass, "superuser");
+        return Ok(());
+    }
+    let replication: bool = row.get(1);
+    let owner: bool = row.get(2);
+    let create_in_schema: bool = row.get(3);
+    let create_in_database: bool = row.get(4);
+    let mut missing = Vec::new();
+    if !owner {
+        missing.push(format!("membership in the owner role of {}", table));
+    }
+    if !create_in_schema {
+        missing.push(format!(
+            "CREATE on schema {}",
+            table.schema.as_deref().unwrap_or("public")
+        ));
+    }
+    if !create_in_database {
+        for schema in schema_config.schemas() {
+            let row = client.query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)",
+                &[&schema],
+            )?;
+            let exists: bool = row.get(0);
+            if !exists {
+                missing.push(format!(
+                    "CREATE on the database to create schema {}",
+                    schema
+                ));
+            }
+        }
+    }
+    if logical && !replication {
+        missing.push("the REPLICATION attribute".to_string());
+    }
+    if missing.is_empty() {
+        report.push("privileges", CheckStatus::Pass, "sufficient");
+    } else {
+        report.push(
+            "privileges",
+            CheckStatus::Fail,
+            format!("missing {}", missing.join(", ")),
+        );
+    }
+    Ok(())
+}
+
+fn check_triggers<C: GenericClient>(
+    table: &Table,
+    client: &mut C,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let triggers: Vec<String> = client
+        .query(
+            "SELECT tgname::text FROM pg_trigger
+             WHERE tgrelid = ($1)::text::regclass AND NOT tgisinternal
+             ORDER BY tgname",
+            &[&table.to_string()],
+        )?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    if triggers.is_empty() {
+        report.push("triggers", CheckStatus::Pass, "none on the table");
+    } else {
+        report.push(
+            "triggers",
+            CheckStatus::Warn,
+            format!("not copied to the new table: {}", triggers.join(", ")),
+        );
+    }
+    Ok(())
+}
+
+/// Builds a throwaway shadow table, applies the migration to it and checks the dependent views,
+/// all in a transaction that is rolled back.
+fn rehearse_migration(
+    sql: &str,
+    options: &CheckOptions,
+    client: &mut Client,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let mut transaction = client.transaction()?;
+    let migration = match Migration::new_with_schema_config(
+        sql,
+        options.schema_config.clone(),
+        &mut transaction,
+    ) {
+        Ok(migration) => migration,
+        Err(e) => {
+            report.push(
+                "migration",
+                CheckStatus::Fail,
+                format!("couldn't be prepared: {:#}", e),
+            );
+            return Ok(());
+        }
+    };
+    // A throwaway shadow table, so a run already building the real one isn't locked out
+    let rehearsal_table = migration.shadow_table.with_suffix(&format!(
+        "_check_{}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    ));
+    let migration = migration.with_shadow_table(rehearsal_table);
+    let setup = migration
+        .create_post_migrations_schema(&mut transaction)
+        .and_then(|_| migration.create_shadow_table(&mut transaction))
+        .and_then(|_| migration.migrate_shadow_table(&mut transaction));
+    if let Err(e) = setup {
+        report.push(
+            "migration",
+            CheckStatus::Fail,
+            format!("couldn't be applied to a copy of the table: {}", e),
+        );
+        return Ok(());
+    }
+    let column_map = match migration.column_map(options.settings, &mut transaction) {
+        Ok(column_map) => column_map,
+        Err(e) => {
+            report.push("migration", CheckStatus::Fail, e.to_string());
+            return Ok(());
+        }
+    };
+    let dropped: Vec<String> = match migration.table.get_columns(&mut transaction) {
+        Ok(columns) => columns
+            .into_iter()
+            .filter(|column| column_map.shadow_col(column).is_none())
+            .collect(),
+        Err(e) => {
+            report.push(
+                "migration",
+                CheckStatus::Fail,
+                format!("couldn't read the table's columns: {}", e),
+            );
+            return Ok(());
+        }
+    };
+    if dropped.is_empty() {
+        report.push(
+            "migration",
+            CheckStatus::Pass,
+            "applies to a copy of the table",
+        );
+    } else {
+        report.push(
+            "migration",
+            CheckStatus::Warn,
+            format!("applies; data in {} won't be copied", dropped.join(", ")),
+        );
+    }
+    if options.settings.row_filter.is_some() || !options.settings.transforms.is_empty() {
+        // Copies no rows, but plans the backfill's statement against the real columns
+        let backfill = BatchedBackfill {
+            batch_size: 0,
+            throttle: Duration::ZERO,
+            row_filter: options.settings.row_filter.clone(),
+        };
+        let statement = backfill.batch_statement(
+            &migration.table,
+            &migration.shadow_table,
+            &column_map,
+            &migration.primary_key,
+            false,
+        )?;
+        let mut savepoint = transaction.savepoint("rehearse_copy")?;
+        match savepoint.batch_execute(&statement) {
+            Ok(()) => report.push(
+                "copy",
+                CheckStatus::Pass,
+                "the row filter and transforms apply to the table",
+            ),
+            Err(e) => report.push(
+                "copy",
+                CheckStatus::Fail,
+                format!("the row filter or transforms don't apply: {}", e),
+            ),
+        }
+    }
+    match migration.check_dependent_views(&mut transaction) {
+        Ok(()) => report.push(
+            "dependent views",
+            CheckStatus::Pass,
+            "can be recreated against the new table",
+        ),
+        Err(e) => report.push("dependent views", CheckStatus::Fail, e.to_string()),
+    }
+    transaction.rollback()?;
+    Ok(())
+}
@@ -1,54 +1,198 @@
+use crate::identifier::quote_ident;
 use crate::table::Table;
+use anyhow::{Result, anyhow, bail};
 use postgres::GenericClient;
 
-/// Maps columns from the main table to the shadow table, handling renames and drops.
+/// Maps columns from the main table to the shadow table, handling renames and drops. A shadow
+/// column can instead be filled from an SQL expression over the main table's columns.
 #[derive(Clone)]
-pub struct ColumnMap(Vec<(String, Option<String>)>);
+pub struct ColumnMap {
+    columns: Vec<(String, Option<String>)>,
+    /// Shadow columns and the expressions filling them
+    expressions: Vec<(String, String)>,
+}
 
 impl ColumnMap {
-    /// Constructs a new `ColumnMap` from the main and shadow Table objects, fetching columns from the database.
-    pub fn new<C: GenericClient>(main: &Table, shadow: &Table, client: &mut C) -> Self {
-        let main_cols = main.get_columns(client);
-        let shadow_cols = shadow.get_columns(client);
+    /// Constructs a new `ColumnMap` from the main and shadow Table objects, fetching columns
+    /// from the database. `mappings` sends main columns to a shadow column, or to `None` when
+    /// they are dropped; every other column keeps its name. A column that disappears without a
+    /// mapping while new columns appear could be a rename, so it is refused rather than guessed.
+    pub fn new<C: GenericClient>(
+        main: &Table,
+        shadow: &Table,
+        mappings: &[(String, Option<String>)],
+        client: &mut C,
+    ) -> Result<Self> {
+        let main_cols = main.get_columns(client)?;
+        let shadow_cols = shadow.get_columns(client)?;
         let mut map = Vec::new();
-        let unmatched_main: Vec<String> = main_cols
+        let mut vanished = Vec::new();
+        for main_col in &main_cols {
+            let shadow_col = match mappings.iter().find(|(from, _)| from == main_col) {
+                Some((_, Some(to))) if !shadow_cols.contains(to) => {
+                    bail!(
+                        "{} is mapped to {}, which {} doesn't have",
+                        main_col,
+                        to,
+                        shadow
+                    )
+                }
+                Some((_, to)) => to.clone(),
+                None if shadow_cols.contains(main_col) => Some(main_col.clone()),
+                None => {
+                    vanished.push(main_col.clone());
+                    None
+                }
+            };
+            map.push((main_col.clone(), shadow_col));
+        }
+        let appeared: Vec<&String> = shadow_cols
             .iter()
-            .filter(|c| !shadow_cols.contains(c))
-            .cloned()
+            .filter(|column| !main_cols.contains(column))
+            .filter(|column| !map.iter().any(|(_, to)| to.as_ref() == Some(*column)))
             .collect();
-        let unmatched_shadow: Vec<String> = shadow_cols
+        if !vanished.is_empty() && !appeared.is_empty() {
+            bail!(
+                "Can't tell whether {} became {}; pass --map old=new for renames, or --map old= \
+                 to drop a column",
+                vanished.join(", "),
+                appeared
+                    .iter()
+                    .map(|column| column.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for (index, (from, to)) in map.iter().enumerate() {
+            if let Some(to) = to
+                && let Some((other, _)) = map[index + 1..]
+                    .iter()
+                    .find(|(_, other_to)| other_to.as_ref() == Some(to))
+            {
+                bail!("Both {} and {} are mapped to {}", from, other, to);
+            }
+        }
+        Ok(ColumnMap {
+            columns: map,
+            expressions: Vec::new(),
+        })
+    }
+
+    /// Maps every column to itself, for when the shadow table doesn't exist yet.
+    pub fn identity(columns: Vec<String>) -> Self {
+        ColumnMap {
+            columns: columns
+                .into_iter()
+                .map(|column| (column.clone(), Some(column)))
+                .collect(),
+            expressions: Vec::new(),
+        }
+    }
+
+    /// Sends each main column in `mappings` to its shadow column, or drops it when mapped to
+    /// `None`, without checking the shadow table. For planning before the shadow table exists.
+    pub fn with_mappings(mut self, mappings: &[(String, Option<String>)]) -> Self {
+        for (main, shadow) in &mut self.columns {
+            if let Some((_, to)) = mappings.iter().find(|(from, _)| from == main) {
+                *shadow = to.clone();
+            }
+        }
+        self
+    }
+
+    /// Fills `shadow_col` from `expression`, an SQL expression over the main table's columns,
+    /// instead of copying a column. The expression is evaluated against the main table row by
+    /// the backfill and by every replay.
+    pub fn with_expression(mut self, shadow_col: &str, expression: &str) -> Self {
+        crate::config::override_pairs(
+            &mut self.expressions,
+            [(shadow_col.to_string(), expression.to_string())],
+        );
+        self
+    }
+
+    /// Applies `with_expression` for each shadow column and expression in `expressions`.
+    pub fn with_expressions(self, expressions: &[(String, String)]) -> Self {
+        expressions
+            .iter()
+            .fold(self, |map, (shadow_col, expression)| {
+                map.with_expression(shadow_col, expression)
+            })
+    }
+
+    /// The shadow columns that are written, each with the SQL reading its value from the main
+    /// table row: the mapped columns in order, then the ones only filled by an expression.
+    pub fn assignments(&self) -> Vec<(String, String)> {
+        let expression = |shadow: &str| {
+            self.expressions
+                .iter()
+                .find(|(column, _)| column == shadow)
+                .map(|(_, expression)| format!("({})", expression))
+        };
+        let mut assignments: Vec<(String, String)> = self
+            .columns
             .iter()
-            .filter(|c| !main_cols.contains(c))
-            .cloned()
+            .filter_map(|(main, shadow)| {
+                let shadow = shadow.as_ref()?;
+                let value = expression(shadow).unwrap_or_else(|| quote_ident(main));
+                Some((shadow.clone(), value))
+            })
             .collect();
-        for main_col in &main_cols {
-            if let Some(shadow_col) = shadow_cols.iter().find(|c| *c == main_col) {
-                map.push((main_col.clone(), Some(shadow_col.clone())));
-            } else if unmatched_main.len() == 1
-                && unmatched_shadow.len() == 1
-                && unmatched_main[0] == *main_col
-            {
-                // Assume rename
-                map.push((main_col.clone(), Some(unmatched_shadow[0].clone())));
-            } else {
-                map.push((main_col.clone(), None));
+        for (column, expression) in &self.expressions {
+            if !assignments.iter().any(|(shadow, _)| shadow == column) {
+                assignments.push((column.clone(), format!("({})", expression)));
             }
         }
-        ColumnMap(map)
+        assignments
+    }
+
+    /// The shadow columns that are written, for an INSERT column list.
+    pub fn insert_cols(&self) -> Vec<String> {
+        self.assignments()
+            .into_iter()
+            .map(|(shadow, _)| shadow)
+            .collect()
+    }
+
+    /// The values of `insert_cols` as an SQL select list over the main table.
+    pub fn select_list(&self) -> String {
+        self.assignments()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>()
+            .join(", ")
     }
 
     /// Returns the shadow table columns that correspond to main table columns.
     pub fn shadow_cols(&self) -> Vec<String> {
-        self.0
+        self.columns
             .iter()
             .filter_map(|(_main, shadow)| shadow.clone())
             .collect()
     }
     /// Returns the main table columns that have a corresponding shadow column.
     pub fn main_cols(&self) -> Vec<String> {
-        self.0
+        self.columns
             .iter()
             .filter_map(|(main, shadow)| shadow.as_ref().map(|_| main.clone()))
             .collect()
     }
+    /// Returns the shadow column a main table column maps to, if it wasn't dropped.
+    pub fn shadow_col(&self, main_col: &str) -> Option<String> {
+        self.columns
+            .iter()
+            .find(|(main, _shadow)| main == main_col)
+            .and_then(|(_main, shadow)| shadow.clone())
+    }
+
+    /// The shadow column the main table's primary key `main_pk` maps to. Shadow rows are
+    /// matched to main rows by it, so it can't be dropped.
+    pub fn shadow_key(&self, main_pk: &str) -> Result<String> {
+        self.shadow_col(main_pk).ok_or_else(|| {
+            anyhow!(
+                "The primary key {} is dropped; it has to be kept, or renamed with --map",
+                main_pk
+            )
+        })
+    }
 }
@@ -0,0 +1,495 @@
+// src/config.rs
+// Migration config files and the tunables they set. Files are TOML, or YAML when the name ends
+// in .yaml or .yml; every section is optional and falls back to the built-in defaults.
+
+use crate::args::Strategy;
+use crate::backfill::DEFAULT_BACKFILL_BATCH_SIZE;
+use crate::foreign_key::ForeignKeyOptions;
+use crate::replay::{DEFAULT_DRAIN_TIMEOUT, DEFAULT_REPLAY_BATCH_SIZE, DEFAULT_REPLAY_INTERVAL};
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Batch sizes, throttles and cutover behaviour for one migration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationSettings {
+    /// Rows copied per backfill batch
+    pub backfill_batch_size: usize,
+    /// Pause between backfill batches
+    pub backfill_throttle: Duration,
+    /// Changes applied per replay batch
+    pub replay_batch_size: usize,
+    /// Pause between replay batches while the backfill runs
+    pub replay_interval: Duration,
+    /// Switch the table to REPLICA IDENTITY FULL for the logical strategies, when its own
+    /// identity doesn't carry the primary key
+    pub replica_identity_full: bool,
+    /// SQL condition on the main table's columns. Only matching rows are copied and kept in
+    /// the shadow table, so the rest are purged by the migration.
+    pub row_filter: Option<String>,
+    /// Shadow columns filled from SQL expressions over the main table's columns, applied after
+    /// any `USING` expressions in the migration
+    pub transforms: Vec<(String, String)>,
+    /// Main table columns mapped to a shadow column, or to `None` to drop them, overriding the
+    /// renames and drops in the migration SQL
+    pub column_mappings: Vec<(String, Option<String>)>,
+    pub cutover: CutoverSettings,
+}
+
+impl Default for MigrationSettings {
+    fn default() -> Self {
+        MigrationSettings {
+            backfill_batch_size: DEFAULT_BACKFILL_BATCH_SIZE,
+            backfill_throttle: Duration::ZERO,
+            replay_batch_size: DEFAULT_REPLAY_BATCH_SIZE,
+            replay_interval: DEFAULT_REPLAY_INTERVAL,
+            replica_identity_full: false,
+            row_filter: None,
+            transforms: Vec::new(),
+            column_mappings: Vec::new(),
+            cutover: CutoverSettings::default(),
+        }
+    }
+}
+
+/// Appends `overrides` to `pairs`, replacing the pairs whose keys they name.
+pub fn override_pairs<K: PartialEq, V>(
+    pairs: &mut Vec<(K, V)>,
+    overrides: impl IntoIterator<Item = (K, V)>,
+) {
+    for (key, value) in overrides {
+        pairs.retain(|(existing, _)| *existing != key);
+        pairs.push((key, value));
+    }
+}
+
+/// Timeouts and hooks for the cutover transaction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CutoverSettings {
+    /// How long to wait for the table lock before giving up on the cutover
+    pub lock_timeout: Option<Duration>,
+    /// Limit on each statement in the cutover transaction
+    pub statement_timeout: Option<Duration>,
+    /// SQL run in the cutover transaction, under the lock, just before the swap
+    pub before_swap: Vec<String>,
+    /// SQL run once the cutover has committed
+    pub after_cutover: Vec<String>,
+}
+
+impl CutoverSettings {
+    /// `SET LOCAL` statements applying the timeouts, run first in the cutover transaction.
+    pub fn timeout_statements(&self) -> Vec<String> {
+        let mut statements = Vec::new();
+        if let Some(timeout) = self.lock_timeout {
+            statements.push(format!(
+                "SET LOCAL lock_timeout = '{}ms'",
+                timeout.as_millis()
+            ));
+        }
+        if let Some(timeout) = self.statement_timeout {
+            statements.push(format!(
+                "SET LOCAL statement_timeout = '{}ms'",
+                timeout.as_millis()
+            ));
+        }
+        statements
+    }
+
+    /// How long a streaming cutover may read the stream under the lock before giving up: the
+    /// statement timeout, or the lock timeout, or `DEFAULT_DRAIN_TIMEOUT`.
+    pub fn drain_timeout(&self) -> Duration {
+        self.statement_timeout
+            .or(self.lock_timeout)
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT)
+    }
+}
+
+/// The contents of a config file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub connection: ConnectionConfig,
+    pub strategy: Option<Strategy>,
+    pub schemas: SchemaConfig,
+    pub foreign_keys: ForeignKeyOptions,
+    pub backfill: BackfillConfig,
+    pub replay: ReplayConfig,
+    pub cutover: CutoverConfig,
+    /// Overrides for single tables, keyed by `table` or `schema.table`
+    pub tables: BTreeMap<String, TableConfig>,
+}
+
+/// Either a URI (or libpq key/value string), or its parts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub uri: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub dbname: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackfillConfig {
+    pub batch_size: Option<usize>,
+    pub throttle_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub batch_size: Option<usize>,
+    pub interval_ms: Option<u64>,
+    pub replica_identity_full: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CutoverConfig {
+    pub lock_timeout_ms: Option<u64>,
+    pub statement_timeout_ms: Option<u64>,
+    pub before_swap: Option<Vec<String>>,
+    pub after_cutover: Option<Vec<String>>,
+}
+
+/// Settings for one table, overriding the top-level sections.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableConfig {
+    /// Keep only the rows matching this condition
+    #[serde(rename = "where")]
+    pub row_filter: Option<String>,
+    /// Shadow columns filled from SQL expressions over the table's columns
+    pub transform: BTreeMap<String, String>,
+    /// Main table columns mapped to their shadow column; an empty name drops the column
+    pub map: BTreeMap<String, String>,
+    pub backfill: BackfillConfig,
+    pub replay: ReplayConfig,
+    pub cutover: CutoverConfig,
+}
+
+impl Config {
+    /// Reads a config file, choosing YAML or TOML by its extension.
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let yaml = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml" | "yml")
+        );
+        if yaml {
+            Config::from_yaml(&contents)
+        } else {
+            Config::from_toml(&contents)
+        }
+        .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Config> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Config> {
+        let config: Config = serde_yaml_ng::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Refuses batch sizes of 0: an empty batch reads as the end of the table or the log, so
+    /// nothing would be copied or replayed before the cutover.
+    fn validate(&self) -> Result<()> {
+        let sections = std::iter::once(("", &self.backfill, &self.replay)).chain(
+            self.tables
+                .iter()
+                .map(|(key, table)| (key.as_str(), &table.backfill, &table.replay)),
+        );
+        for (key, backfill, replay) in sections {
+            let section = |name: &str| match key {
+                "" => format!("[{}]", name),
+                key => format!("[tables.\"{}\".{}]", key, name),
+            };
+            if backfill.batch_size == Some(0) {
+                bail!("batch_size in {} must be at least 1", section("backfill"));
+            }
+            if replay.batch_size == Some(0) {
+                bail!("batch_size in {} must be at least 1", section("replay"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The defaults overridden by the top-level sections only, for what several tables share,
+    /// such as a replay capturing them all through one slot.
+    pub fn settings(&self) -> MigrationSettings {
+        let mut settings = MigrationSettings::default();
+        self.backfill.apply_to(&mut settings);
+        self.replay.apply_to(&mut settings);
+        self.cutover.apply_to(&mut settings);
+        settings
+    }
+
+    /// The settings for migrating `table`: defaults, then the top-level sections, then the
+    /// table's own section. A key without a schema matches the table in any schema; a key with
+    /// one only matches a table in that schema, so `table` should have its schema resolved.
+    pub fn settings_for(&self, table: &Table) -> MigrationSettings {
+        let mut settings = self.settings();
+        // Schema-less keys first, so a schema-qualified key wins. A schema-qualified key only
+        // matches a table in that schema, never one whose schema isn't known.
+        let mut matching: Vec<(Table, &TableConfig)> = self
+            .tables
+            .iter()
+            .filter_map(|(key, config)| Some((key.parse::<Table>().ok()?, config)))
+            .filter(|(key, _config)| {
+                key.name == table.name && (key.schema.is_none() || key.schema == table.schema)
+            })
+            .collect();
+        matching.sort_by_key(|(key, _config)| key.schema.is_some());
+        for (_key, config) in matching {
+            if let Some(row_filter) = &config.row_filter {
+                settings.row_filter = Some(row_filter.clone());
+            }
+            override_pairs(&mut settings.transforms, config.transform.clone());
+            override_pairs(
+                &mut settings.column_mappings,
+                config
+                    .map
+                    .iter()
+                    .map(|(from, to)| (from.clone(), Some(to.clone()).filter(|to| !to.is_empty()))),
+            );
+            config.backfill.apply_to(&mut settings);
+            config.replay.apply_to(&mut settings);
+            config.cutover.apply_to(&mut settings);
+        }
+        settings
+    }
+}
+
+impl ConnectionConfig {
+    /// The connection string to use. A URI wins over the separate parts.
+    pub fn conninfo(&self) -> Result<String> {
+        if let Some(uri) = &self.uri {
+            return Ok(uri.clone());
+        }
+        let parts: Vec<String> = [
+            ("host", self.host.clone()),
+            ("port", self.port.map(|port| port.to_string())),
+            ("user", self.user.clone()),
+            ("password", self.password.clone()),
+            ("dbname", self.dbname.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| {
+            value.map(|value| {
+                format!(
+                    "{}='{}'",
+                    key,
+                    value.replace('\\', "\\\\").replace('\'', "\\'")
+                )
+            })
+        })
+        .collect();
+        if parts.is_empty() {
+            bail!("No connection given: pass --uri or set [connection] in the config file");
+        }
+        Ok(parts.join(" "))
+    }
+}
+
+impl BackfillConfig {
+    fn apply_to(&self, settings: &mut MigrationSettings) {
+        if let Some(batch_size) = self.batch_size {
+            settings.backfill_batch_size = batch_size;
+        }
+        if let Some(throttle_ms) = self.throttle_ms {
+            settings.backfill_throttle = Duration::from_millis(throttle_ms);
+        }
+    }
+}
+
+impl ReplayConfig {
+    fn apply_to(&self, settings: &mut MigrationSettings) {
+        if let Some(batch_size) = self.batch_size {
+            settings.replay_batch_size = batch_size;
+        }
+        if let Some(interval_ms) = self.interval_ms {
+            settings.replay_interval = Duration::from_millis(interval_ms);
+        }
+        if let Some(replica_identity_full) = self.replica_identity_full {
+            settings.replica_identity_full = replica_identity_full;
+        }
+    }
+}
+
+impl CutoverConfig {
+    fn apply_to(&self, settings: &mut MigrationSettings) {
+        if let Some(timeout_ms) = self.lock_timeout_ms {
+            settings.cutover.lock_timeout = Some(Duration::from_millis(timeout_ms));
+        }
+        if let Some(timeout_ms) = self.statement_timeout_ms {
+            settings.cutover.statement_timeout = Some(Duration::from_millis(timeout_ms));
+        }
+        if let Some(before_swap) = &self.before_swap {
+            settings.cutover.before_swap = before_swap.clone();
+        }
+        if let Some(after_cutover) = &self.after_cutover {
+            settings.cutover.after_cutover = after_cutover.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+strategy = "logical"
+
+[connection]
+host = "db.internal"
+port = 5433
+dbname = "app"
+
+[schemas]
+shadow_schema = "ost"
+
+[foreign_keys]
+validate = false
+
+[backfill]
+batch_size = 5000
+throttle_ms = 10
+
+[cutover]
+lock_timeout_ms = 2000
+before_swap = ["SELECT 1"]
+
+[tables.orders]
+where = "created_at > now() - interval '90 days'"
+
+[tables.orders.transform]
+total = "cents / 100.0"
+
+[tables.orders.map]
+amount = "cents"
+note = ""
+
+[tables.orders.backfill]
+batch_size = 500
+
+[tables."billing.orders".replay]
+interval_ms = 50
+replica_identity_full = true
+
+[tables."billing.orders".backfill]
+batch_size = 250
+"#;
+
+    #[test]
+    fn test_toml_sections_and_table_overrides() {
+        let config = Config::from_toml(TOML).unwrap();
+        assert_eq!(config.strategy, Some(Strategy::Logical));
+        assert_eq!(config.schemas.shadow_schema, "ost");
+        assert_eq!(
+            config.schemas.archive_schema,
+            SchemaConfig::default().archive_schema
+        );
+        assert!(config.foreign_keys.recreate);
+        assert!(!config.foreign_keys.validate);
+        assert_eq!(
+            config.connection.conninfo().unwrap(),
+            "host='db.internal' port='5433' dbname='app'"
+        );
+
+        let other = config.settings_for(&Table::new("public.users"));
+        assert_eq!(other.backfill_batch_size, 5000);
+        assert_eq!(other.backfill_throttle, Duration::from_millis(10));
+        assert_eq!(other.replay_batch_size, DEFAULT_REPLAY_BATCH_SIZE);
+        assert_eq!(
+            other.cutover.timeout_statements(),
+            vec!["SET LOCAL lock_timeout = '2000ms'"]
+        );
+        assert_eq!(other.cutover.before_swap, vec!["SELECT 1"]);
+        assert_eq!(other.cutover.drain_timeout(), Duration::from_millis(2000));
+        assert_eq!(
+            MigrationSettings::default().cutover.drain_timeout(),
+            DEFAULT_DRAIN_TIMEOUT
+        );
+
+        let orders = config.settings_for(&Table::new("public.orders"));
+        assert_eq!(orders.backfill_batch_size, 500);
+        assert_eq!(orders.replay_interval, DEFAULT_REPLAY_INTERVAL);
+        assert_eq!(
+            orders.row_filter.as_deref(),
+            Some("created_at > now() - interval '90 days'")
+        );
+        assert_eq!(other.row_filter, None);
+        assert_eq!(
+            orders.transforms,
+            vec![("total".to_string(), "cents / 100.0".to_string())]
+        );
+        assert_eq!(
+            orders.column_mappings,
+            vec![
+                ("amount".to_string(), Some("cents".to_string())),
+                ("note".to_string(), None),
+            ]
+        );
+
+        let billing = config.settings_for(&Table::new("billing.orders"));
+        assert_eq!(billing.backfill_batch_size, 250);
+        assert_eq!(billing.replay_interval, Duration::from_millis(50));
+        // Table sections don't reach the shared settings
+        assert_eq!(config.settings().replay_interval, DEFAULT_REPLAY_INTERVAL);
+        assert!(billing.replica_identity_full);
+        assert!(!orders.replica_identity_full);
+
+        // Without its schema the table only gets the schema-less key's section
+        let unqualified = config.settings_for(&Table::new("orders"));
+        assert_eq!(unqualified.backfill_batch_size, 500);
+        assert!(!unqualified.replica_identity_full);
+    }
+
+    #[test]
+    fn test_zero_batch_sizes_are_refused() {
+        let err = Config::from_toml("[backfill]\nbatch_size = 0\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "batch_size in [backfill] must be at least 1"
+        );
+        let err = Config::from_yaml("tables:\n  orders:\n    replay:\n      batch_size: 0\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "batch_size in [tables.\"orders\".replay] must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_yaml_and_unknown_fields() {
+        let config = Config::from_yaml(
+            "connection:\n  uri: postgresql://localhost/app\nreplay:\n  batch_size: 20\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.connection.conninfo().unwrap(),
+            "postgresql://localhost/app"
+        );
+        assert_eq!(
+            config
+                .settings_for(&Table::new("test_table"))
+                .replay_batch_size,
+            20
+        );
+        assert!(Config::from_toml("[backfill]\nbatch = 1").is_err());
+        assert!(Config::default().connection.conninfo().is_err());
+    }
+}
@@ -0,0 +1,198 @@
+// src/dependent_view.rs
+// Views and materialized views selecting from the migrated table. They bind to the table's
+// OID, so after the swap they'd still read from the archived table unless recreated.
+
+use crate::identifier::quote_ident;
+use crate::table::Table;
+use crate::table_metadata::TableMetadata;
+use anyhow::{Result, anyhow, bail};
+use postgres::GenericClient;
+
+/// A view or materialized view that depends on the migrated table, with what's needed to recreate it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DependentView {
+    pub view: Table,
+    pub materialized: bool,
+    /// The view's query, as returned by `pg_get_viewdef`
+    pub definition: String,
+    /// Storage parameters such as `security_barrier=true` or `check_option=local`
+    pub options: Vec<String>,
+    /// Whether a materialized view holds data
+    pub populated: bool,
+    /// Whether other views depend on this one
+    pub has_dependents: bool,
+    /// `CREATE INDEX` statements for a materialized view's indexes
+    pub indexes: Vec<String>,
+    /// Owner, grants and comment of a materialized view, which are lost when it's dropped
+    pub metadata: Option<TableMetadata>,
+}
+
+impl DependentView {
+    /// Finds the views and materialized views reading from `table`. Definitions are rendered
+    /// against the session's `search_path`.
+    pub fn find<C: GenericClient>(table: &Table, client: &mut C) -> Result<Vec<DependentView>> {
+        let rows = client.query(
+            "SELECT DISTINCT n.nspname::text, v.relname::text, v.relkind = 'm', pg_get_viewdef(v.oid),
+                    COALESCE(v.reloptions, '{}')::text[], v.relispopulated,
+                    EXISTS (SELECT 1 FROM pg_depend d2 JOIN pg_rewrite r2 ON r2.oid = d2.objid
+                            WHERE d2.classid = 'pg_rewrite'::regclass AND d2.refclassid = 'pg_class'::regclass
+                              AND d2.refobjid = v.oid AND r2.ev_class <> v.oid)
+             FROM pg_depend d
+             JOIN pg_rewrite r ON r.oid = d.objid
+             JOIN pg_class v ON v.oid = r.ev_class
+             JOIN pg_namespace n ON n.oid = v.relnamespace
+             WHERE d.classid = 'pg_rewrite'::regclass AND d.refclassid = 'pg_class'::regclass
+               AND d.refobjid = ($1)::text::regclass AND v.oid <> d.refobjid
+             ORDER BY 1, 2",
+            &[&table.to_string()],
+        )?;
+        let mut views = Vec::new();
+        for row in rows {
+            let schema: String = row.get(0);
+            let name: String = row.get(1);
+            let view = Table::from_parts(Some(&schema), &name);
+            let materialized: bool = row.get(2);
+            let definition: String = row.get(3);
+            let (indexes, metadata) = if materialized {
+                let indexes = client
+                    .query(
+                        "SELECT pg_get_indexdef(indexrelid) FROM pg_index
+                         WHERE indrelid = ($1)::text::regclass ORDER BY indexrelid",
+                        &[&view.to_string()],
+                    )?
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+                (indexes, Some(TableMetadata::fetch(&view, client)?))
+            } else {
+                (Vec::new(), None)
+            };
+            views.push(DependentView {
+                view,
+                materialized,
+                definition: definition.trim().trim_end_matches(';').to_string(),
+                options: row.get(4),
+                populated: row.get(5),
+                has_dependents: row.get(6),
+                indexes,
+                metadata,
+            });
+        }
+        Ok(views)
+    }
+
+    /// Checks that every view depending on `table` can be recreated once `shadow` replaces it.
+    /// Definitions are rendered with the table's schema first in the `search_path`, so the
+    /// table is referenced unqualified, and then created as temporary views with a temporary
+    /// view standing in for the table. Nothing outside the temporary schema is changed.
+    pub fn check<C: GenericClient>(table: &Table, shadow: &Table, client: &mut C) -> Result<()> {
+        let mut transaction = client.transaction()?;
+        let search_path: String = transaction
+            .query_one("SELECT current_setting('search_path')", &[])?
+            .get(0);
+        transaction.batch_execute(&format!(
+            "SET LOCAL search_path = {}, {}",
+            quote_ident(table.schema.as_deref().unwrap_or("public")),
+            search_path
+        ))?;
+        let views = DependentView::find(table, &mut transaction)?;
+        if views.is_empty() {
+            return Ok(());
+        }
+        transaction.batch_execute(&format!(
+            "CREATE TEMP VIEW {} AS SELECT * FROM {}",
+            quote_ident(&table.name),
+            shadow
+        ))?;
+        for (i, view) in views.iter().enumerate() {
+            if view.materialized && view.has_dependents {
+                bail!(
+                    "Materialized view {} has dependent views and can't be recreated",
+                    view.view
+                );
+            }
+            let preflight = format!("ost_preflight_{}", i);
+            transaction
+                .batch_execute(&format!(
+                    "CREATE TEMP VIEW {} AS {}",
+                    preflight, view.definition
+                ))
+                .map_err(|e| {
+                    anyhow!(
+                        "View {} can't be recreated against the migrated table: {}",
+                        view.view,
+                        e
+                    )
+                })?;
+            if view.materialized {
+                continue;
+            }
+            // CREATE OR REPLACE VIEW requires the existing columns to keep their names and
+            // types; new ones may only be added at the end.
+            let existing = view_columns(&view.view.to_string(), &mut transaction)?;
+            let replacement = view_columns(&format!("pg_temp.{}", preflight), &mut transaction)?;
+            if !replacement.starts_with(&existing) {
+                bail!(
+                    "View {} can't be recreated against the migrated table: its columns would change from {:?} to {:?}",
+                    view.view,
+                    existing,
+                    replacement
+                );
+            }
+        }
+        // Dropping the transaction rolls back the temporary views
+        Ok(())
+    }
+
+    /// Statements recreating the view against whatever its definition now resolves to.
+    /// Materialized views are dropped and created again, with their indexes.
+    pub fn recreate_statements(&self) -> Vec<String> {
+        let with = if self.options.is_empty() {
+            String::new()
+        } else {
+            format!(" WITH ({})", self.options.join(", "))
+        };
+        if !self.materialized {
+            return vec![format!(
+                "CREATE OR REPLACE VIEW {}{} AS {}",
+                self.view, with, self.definition
+            )];
+        }
+        let mut statements = vec![
+            format!("DROP MATERIALIZED VIEW {}", self.view),
+            format!(
+                "CREATE MATERIALIZED VIEW {}{} AS {} WITH {}",
+                self.view,
+                with,
+                self.definition,
+                if self.populated { "DATA" } else { "NO DATA" }
+            ),
+        ];
+        statements.extend(self.indexes.iter().cloned());
+        statements
+    }
+
+    /// Recreates the view, restoring a materialized view's owner, grants and comment.
+    pub fn recreate<C: GenericClient>(&self, client: &mut C) -> Result<()> {
+        for statement in self.recreate_statements() {
+            client.batch_execute(&statement)?;
+        }
+        if let Some(metadata) = &self.metadata {
+            metadata.apply(&self.view, None, client)?;
+        }
+        Ok(())
+    }
+}
+
+fn view_columns<C: GenericClient>(view: &str, client: &mut C) -> Result<Vec<(String, String)>> {
+    Ok(client
+        .query(
+            "SELECT attname::text, format_type(atttypid, atttypmod) FROM pg_attribute
+             WHERE attrelid = ($1)::text::regclass AND attnum > 0 AND NOT attisdropped
+             ORDER BY attnum",
+            &[&view],
+        )?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
@@ -0,0 +1,190 @@
+// src/foreign_key.rs
+// Foreign keys on other tables that reference the migrated table. Constraints bind to the
+// referenced table's OID, so after the swap they still point at the archived table.
+
+use crate::ColumnMap;
+use crate::identifier::{quote_ident, quote_ident_list};
+use crate::table::Table;
+use anyhow::{Result, bail};
+use postgres::GenericClient;
+
+/// What to do with inbound foreign keys at cutover.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForeignKeyOptions {
+    /// Recreate the constraints against the new table as `NOT VALID` inside the cutover transaction.
+    pub recreate: bool,
+    /// Validate the recreated constraints once the cutover has committed.
+    pub validate: bool,
+    /// Drop the constraints pointing at the archived table. When kept they are renamed with an
+    /// `_old` suffix so the recreated constraints can keep the original names. Only applies
+    /// with `recreate`: constraints that aren't recreated are left in place.
+    pub drop_old: bool,
+}
+
+impl ForeignKeyOptions {
+    /// Whether the constraints pointing at the archived table are dropped. Without `recreate`
+    /// that would leave the referencing tables unchecked, so they are kept.
+    pub fn drops_old(&self) -> bool {
+        self.recreate && self.drop_old
+    }
+}
+
+impl Default for ForeignKeyOptions {
+    fn default() -> Self {
+        ForeignKeyOptions {
+            recreate: true,
+            validate: true,
+            drop_old: true,
+        }
+    }
+}
+
+/// A foreign key constraint on another table referencing the migrated table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub name: String,
+    /// The table the constraint is defined on
+    pub table: Table,
+    pub columns: Vec<String>,
+    pub referenced_columns: Vec<String>,
+    /// Everything after the referenced columns: `MATCH`, `ON UPDATE`, `ON DELETE` and deferrability
+    pub options: String,
+}
+
+impl ForeignKey {
+    /// Finds the foreign keys on other tables that reference `table`.
+    pub fn inbound<C: GenericClient>(table: &Table, client: &mut C) -> Result<Vec<ForeignKey>> {
+        let rows = client.query(
+            "SELECT c.conname::text, n.nspname::text, r.relname::text,
+                    ARRAY(SELECT a.attname::text FROM unnest(c.conkey) WITH ORDINALITY k(attnum, i)
+                          JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum
+                          ORDER BY k.i),
+                    ARRAY(SELECT a.attname::text FROM unnest(c.confkey) WITH ORDINALITY k(attnum, i)
+                          JOIN pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum
+                          ORDER BY k.i),
+                    c.confmatchtype::text, c.confupdtype::text, c.confdeltype::text,
+                    c.condeferrable, c.condeferred
+             FROM pg_constraint c
+             JOIN pg_class r ON r.oid = c.conrelid
+             JOIN pg_namespace n ON n.oid = r.relnamespace
+             WHERE c.contype = 'f' AND c.confrelid = ($1)::text::regclass
+               AND c.conrelid <> c.confrelid AND c.conparentid = 0
+             ORDER BY n.nspname, r.relname, c.conname",
+            &[&table.to_string()],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let schema: String = row.get(1);
+                let name: String = row.get(2);
+                let match_type: String = row.get(5);
+                let update_action: String = row.get(6);
+                let delete_action: String = row.get(7);
+                let deferrable: bool = row.get(8);
+                let deferred: bool = row.get(9);
+                let mut options = Vec::new();
+                if match_type == "f" {
+                    options.push("MATCH FULL".to_string());
+                }
+                options.push(format!("ON UPDATE {}", referential_action(&update_action)));
+                options.push(format!("ON DELETE {}", referential_action(&delete_action)));
+                if deferrable {
+                    options.push("DEFERRABLE".to_string());
+                    if deferred {
+                        options.push("INITIALLY DEFERRED".to_string());
+                    }
+                }
+                ForeignKey {
+                    name: row.get(0),
+                    table: Table::from_parts(Some(&schema), &name),
+                    columns: row.get(3),
+                    referenced_columns: row.get(4),
+                    options: options.join(" "),
+                }
+            })
+            .collect())
+    }
+
+    /// Drops the constraint, which after the swap points at the archived table.
+    pub fn drop_statement(&self) -> String {
+        format!(
+            "ALTER TABLE {} DROP CONSTRAINT {}",
+            self.table,
+            quote_ident(&self.name)
+        )
+    }
+
+    /// Renames the constraint pointing at the archived table out of the way.
+    pub fn rename_old_statement(&self) -> String {
+        format!(
+            "ALTER TABLE {} RENAME CONSTRAINT {} TO {}",
+            self.table,
+            quote_ident(&self.name),
+            quote_ident(&format!("{}_old", self.name))
+        )
+    }
+
+    /// Adds the constraint as `NOT VALID` against `target`, following renamed referenced columns.
+    pub fn add_statement(&self, target: &Table, column_map: &ColumnMap) -> Result<String> {
+        let mut referenced_columns = Vec::new();
+        for column in &self.referenced_columns {
+            match column_map.shadow_col(column) {
+                Some(shadow_col) => referenced_columns.push(shadow_col),
+                None => bail!(
+                    "Foreign key {} on {} references column {} which the migration drops",
+                    self.name,
+                    self.table,
+                    column
+                ),
+            }
+        }
+        Ok(format!(
+            "ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY ({}) REFERENCES {} ({}) {} NOT VALID",
+            self.table,
+            quote_ident(&self.name),
+            quote_ident_list(&self.columns),
+            target,
+            quote_ident_list(&referenced_columns),
+            self.options
+        ))
+    }
+
+    /// The statements moving the constraint to `target` at cutover, following `options`.
+    pub fn cutover_statements(
+        &self,
+        options: &ForeignKeyOptions,
+        target: &Table,
+        column_map: &ColumnMap,
+    ) -> Result<Vec<String>> {
+        if !options.recreate {
+            return Ok(Vec::new());
+        }
+        Ok(vec![
+            if options.drops_old() {
+                self.drop_statement()
+            } else {
+                self.rename_old_statement()
+            },
+            self.add_statement(target, column_map)?,
+        ])
+    }
+
+    pub fn validate_statement(&self) -> String {
+        format!(
+            "ALTER TABLE {} VALIDATE CONSTRAINT {}",
+            self.table,
+            quote_ident(&self.name)
+        )
+    }
+}
+
+fn referential_action(code: &str) -> &'static str {
+    match code {
+        "r" => "RESTRICT",
+        "c" => "CASCADE",
+        "n" => "SET NULL",
+        "d" => "SET DEFAULT",
+        _ => "NO ACTION",
+    }
+}
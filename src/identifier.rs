@@ -0,0 +1,282 @@
+// src/identifier.rs
+// Quoting helpers for identifiers and literals spliced into generated SQL.
+
+/// Keywords that Postgres won't accept as a bare identifier (everything except
+/// `UNRESERVED_KEYWORD` in the parser's kwlist.h). Must stay sorted.
+const NON_UNRESERVED_KEYWORDS: &[&str] = &[
+    "all",
+    "analyse",
+    "analyze",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "asymmetric",
+    "authorization",
+    "between",
+    "bigint",
+    "binary",
+    "bit",
+    "boolean",
+    "both",
+    "case",
+    "cast",
+    "char",
+    "character",
+    "check",
+    "coalesce",
+    "collate",
+    "collation",
+    "column",
+    "concurrently",
+    "constraint",
+    "create",
+    "cross",
+    "current_catalog",
+    "current_date",
+    "current_role",
+    "current_schema",
+    "current_time",
+    "current_timestamp",
+    "current_user",
+    "dec",
+    "decimal",
+    "default",
+    "deferrable",
+    "desc",
+    "distinct",
+    "do",
+    "else",
+    "end",
+    "except",
+    "exists",
+    "extract",
+    "false",
+    "fetch",
+    "float",
+    "for",
+    "foreign",
+    "freeze",
+    "from",
+    "full",
+    "grant",
+    "greatest",
+    "group",
+    "grouping",
+    "having",
+    "ilike",
+    "in",
+    "initially",
+    "inner",
+    "inout",
+    "int",
+    "integer",
+    "intersect",
+    "interval",
+    "into",
+    "is",
+    "isnull",
+    "join",
+    "json",
+    "json_array",
+    "json_arrayagg",
+    "json_exists",
+    "json_object",
+    "json_objectagg",
+    "json_query",
+    "json_scalar",
+    "json_serialize",
+    "json_table",
+    "json_value",
+    "lateral",
+    "leading",
+    "least",
+    "left",
+    "like",
+    "limit",
+    "localtime",
+    "localtimestamp",
+    "merge_action",
+    "national",
+    "natural",
+    "nchar",
+    "none",
+    "normalize",
+    "not",
+    "notnull",
+    "null",
+    "nullif",
+    "numeric",
+    "offset",
+    "on",
+    "only",
+    "or",
+    "order",
+    "out",
+    "outer",
+    "overlaps",
+    "overlay",
+    "placing",
+    "position",
+    "precision",
+    "primary",
+    "real",
+    "references",
+    "returning",
+    "right",
+    "row",
+    "select",
+    "session_user",
+    "setof",
+    "similar",
+    "smallint",
+    "some",
+    "substring",
+    "symmetric",
+    "system_user",
+    "table",
+    "tablesample",
+    "then",
+    "time",
+    "timestamp",
+    "to",
+    "trailing",
+    "treat",
+    "trim",
+    "true",
+    "union",
+    "unique",
+    "user",
+    "using",
+    "values",
+    "varchar",
+    "variadic",
+    "verbose",
+    "when",
+    "where",
+    "window",
+    "with",
+    "xmlattributes",
+    "xmlconcat",
+    "xmlelement",
+    "xmlexists",
+    "xmlforest",
+    "xmlnamespaces",
+    "xmlparse",
+    "xmlpi",
+    "xmlroot",
+    "xmlserialize",
+    "xmltable",
+];
+
+/// Quotes an identifier for use in SQL, following the same rules as Postgres' `quote_ident`:
+/// the name is left bare only if it is lower case, starts with a letter or underscore and is
+/// not a reserved word.
+pub fn quote_ident(ident: &str) -> String {
+    let safe = ident
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && ident
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && NON_UNRESERVED_KEYWORDS.binary_search(&ident).is_err();
+    if safe {
+        ident.to_string()
+    } else {
+        format!("\"{}\"", ident.replace('"', "\"\""))
+    }
+}
+
+/// Quotes a string as a SQL literal, like Postgres' `quote_literal`.
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quotes each column name and joins them into a comma separated list.
+pub fn quote_ident_list<S: AsRef<str>>(idents: &[S]) -> String {
+    idents
+        .iter()
+        .map(|ident| quote_ident(ident.as_ref()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Splits a possibly qualified SQL name (e.g. `my_schema."Order"`) into its parts, unquoting
+/// double quoted parts and folding unquoted parts to lower case like Postgres does.
+pub fn parse_qualified_name(name: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut chars = name.trim().chars().peekable();
+    let mut in_quotes = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                current.push('"');
+            }
+            '"' => in_quotes = !in_quotes,
+            '.' if !in_quotes => parts.push(std::mem::take(&mut current)),
+            c if in_quotes => current.push(c),
+            c => current.extend(c.to_lowercase()),
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keywords_sorted() {
+        assert!(NON_UNRESERVED_KEYWORDS.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!(quote_ident("test_table"), "test_table");
+        assert_eq!(quote_ident("_t1"), "_t1");
+        assert_eq!(quote_ident("MixedCase"), "\"MixedCase\"");
+        assert_eq!(quote_ident("order"), "\"order\"");
+        assert_eq!(quote_ident("1abc"), "\"1abc\"");
+        assert_eq!(quote_ident("has.dot"), "\"has.dot\"");
+        assert_eq!(quote_ident("has space"), "\"has space\"");
+        assert_eq!(quote_ident("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_ident(""), "\"\"");
+    }
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("slot"), "'slot'");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn test_quote_ident_list() {
+        let cols = vec!["id".to_string(), "order".to_string(), "Name".to_string()];
+        assert_eq!(quote_ident_list(&cols), "id, \"order\", \"Name\"");
+    }
+
+    #[test]
+    fn test_parse_qualified_name() {
+        assert_eq!(parse_qualified_name("test_table"), vec!["test_table"]);
+        assert_eq!(
+            parse_qualified_name("My_Schema.Test"),
+            vec!["my_schema", "test"]
+        );
+        assert_eq!(
+            parse_qualified_name("\"My.Schema\".\"Order \"\"Items\"\"\""),
+            vec!["My.Schema", "Order \"Items\""]
+        );
+    }
+
+    #[test]
+    fn test_quote_roundtrip() {
+        for name in ["plain", "MixedCase", "order", "with.dot", "quo\"te"] {
+            assert_eq!(parse_qualified_name(&quote_ident(name)), vec![name]);
+        }
+    }
+}
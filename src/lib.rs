@@ -2,15 +2,24 @@
 
 pub mod args;
 pub mod backfill;
+pub mod check;
 pub mod column_map;
+pub mod config;
+pub mod dependent_view;
+pub mod foreign_key;
+pub mod identifier;
 pub mod logical_replication;
 pub mod migration;
+pub mod migration_queue;
 pub mod migration_runner;
 mod orchestrator;
 pub mod parse;
 pub mod pg_query_parser;
+pub mod plan;
 pub mod replay;
+pub mod schema_config;
 pub mod table;
+pub mod table_metadata;
 pub mod version;
 
 // Re-export key types for ergonomic access
@@ -22,6 +31,7 @@ pub use crate::replay::log_table_replay::PrimaryKey;
 pub use crate::replay::logical_replay::LogicalReplay;
 pub use crate::replay::logical_replay::wal2json2sql;
 pub use crate::replay::streaming_logical_replay::StreamingLogicalReplay;
+pub use crate::schema_config::SchemaConfig;
 pub use crate::table::Table;
 pub use backfill::*;
 pub use migration::*;
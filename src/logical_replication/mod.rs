@@ -1,9 +1,17 @@
 pub mod message;
+pub mod progress;
 pub mod publication;
+pub mod reconnect;
+pub mod replica_identity;
 pub mod slot;
+pub mod standby_status;
 pub mod stream;
 
 pub use message::{PrimaryKeepAlive, ReplicationMessage, XLogData};
+pub use progress::ReplayProgress;
 pub use publication::Publication;
-pub use slot::Slot;
+pub use reconnect::{OutageHandler, ReconnectPolicy, ReplicationOutage};
+pub use replica_identity::{ReplicaIdentity, SavedIdentities};
+pub use slot::{Slot, Wal2JsonFormat};
+pub use standby_status::StandbyStatus;
 pub use stream::LogicalReplicationStream;
@@ -0,0 +1,101 @@
+// Durable replay positions, so a restarted stream resumes where the applied changes end
+
+use crate::identifier::quote_ident;
+use crate::logical_replication::message::Lsn;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::Result;
+use postgres::GenericClient;
+use postgres::types::PgLsn;
+
+/// Name of the table recording the last applied LSN per slot, in the state schema.
+pub const PROGRESS_TABLE_NAME: &str = "replay_progress";
+
+/// The table recording how far each slot's changes have been applied to the shadow tables.
+/// Positions are saved in the transaction that applies the changes, so they are never ahead of
+/// or behind the shadow tables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayProgress {
+    pub table: Table,
+}
+
+impl ReplayProgress {
+    pub fn new(schema_config: &SchemaConfig) -> Self {
+        ReplayProgress {
+            table: Table::from_parts(Some(&schema_config.state_schema), PROGRESS_TABLE_NAME),
+        }
+    }
+
+    pub fn create_statement(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (slot_name TEXT PRIMARY KEY, lsn PG_LSN NOT NULL, updated_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+            self.table
+        )
+    }
+
+    /// Creates the table, and its schema if it doesn't exist yet.
+    pub fn create<C: GenericClient>(&self, client: &mut C) -> Result<()> {
+        if let Some(schema) = &self.table.schema {
+            client.batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {}",
+                quote_ident(schema)
+            ))?;
+        }
+        client.batch_execute(&self.create_statement())?;
+        Ok(())
+    }
+
+    /// The last LSN applied for `slot_name`, if any was saved.
+    pub fn load<C: GenericClient>(&self, slot_name: &str, client: &mut C) -> Result<Option<Lsn>> {
+        if !self.table.exists(client)? {
+            return Ok(None);
+        }
+        let row = client.query_opt(
+            &format!("SELECT lsn FROM {} WHERE slot_name = $1", self.table),
+            &[&slot_name],
+        )?;
+        Ok(row.map(|row| Lsn(u64::from(row.get::<_, PgLsn>(0)))))
+    }
+
+    /// Records `lsn` as applied for `slot_name`. Call it in the transaction applying the changes.
+    pub fn save<C: GenericClient>(&self, slot_name: &str, lsn: Lsn, client: &mut C) -> Result<()> {
+        client.execute(
+            &format!(
+                "INSERT INTO {} (slot_name, lsn) VALUES ($1, $2)
+                 ON CONFLICT (slot_name) DO UPDATE SET lsn = EXCLUDED.lsn, updated_at = now()",
+                self.table
+            ),
+            &[&slot_name, &PgLsn::from(lsn.0)],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets `slot_name`'s position, once its slot is dropped.
+    pub fn clear<C: GenericClient>(&self, slot_name: &str, client: &mut C) -> Result<()> {
+        client.execute(
+            &format!("DELETE FROM {} WHERE slot_name = $1", self.table),
+            &[&slot_name],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_table_in_state_schema() {
+        let schema_config = SchemaConfig {
+            state_schema: "tool".to_string(),
+            ..SchemaConfig::default()
+        };
+        let progress = ReplayProgress::new(&schema_config);
+        assert_eq!(progress.table.to_string(), "tool.replay_progress");
+        assert!(
+            progress
+                .create_statement()
+                .starts_with("CREATE TABLE IF NOT EXISTS tool.replay_progress (slot_name TEXT PRIMARY KEY, lsn PG_LSN")
+        );
+    }
+}
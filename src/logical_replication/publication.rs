@@ -1,32 +1,194 @@
 // Publication management for logical replication
 
+use crate::identifier::quote_ident;
+use crate::logical_replication::replica_identity::{ReplicaIdentity, SavedIdentities};
 use crate::logical_replication::slot::Slot;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::bail;
 
 #[derive(Clone)]
 pub struct Publication {
     pub name: String,
-    pub table: crate::table::Table,
+    /// Tables published together, sharing one slot
+    pub tables: Vec<Table>,
     pub slot: Slot,
+    /// Tables switched to REPLICA IDENTITY FULL instead of requiring an identity that carries
+    /// the primary key
+    pub full_identity: Vec<Table>,
+    /// Where `create` records the identities it replaces, to put them back when the
+    /// publication is dropped
+    pub saved_identities: SavedIdentities,
 }
 
 impl Publication {
-    pub fn new(name: String, table: crate::table::Table, slot: Slot) -> Self {
-        Publication { name, table, slot }
+    pub fn new(name: String, table: Table, slot: Slot) -> Self {
+        Self::for_tables(name, vec![table], slot)
     }
 
+    /// A publication covering several tables, for migrating them over one slot.
+    pub fn for_tables(name: String, tables: Vec<Table>, slot: Slot) -> Self {
+        Publication {
+            name,
+            tables,
+            slot,
+            full_identity: Vec::new(),
+            saved_identities: SavedIdentities::new(&SchemaConfig::default()),
+        }
+    }
+
+    /// Record replaced identities in the state schema of `schema_config`.
+    pub fn with_schema_config(mut self, schema_config: &SchemaConfig) -> Self {
+        self.saved_identities = SavedIdentities::new(schema_config);
+        self
+    }
+
+    /// Switch the tables to REPLICA IDENTITY FULL while they are published. This writes the
+    /// whole old row to the WAL for every update and delete.
+    pub fn with_full_identity(mut self, full_identity: bool) -> Self {
+        self.full_identity = if full_identity {
+            self.tables.clone()
+        } else {
+            Vec::new()
+        };
+        self
+    }
+
+    /// Like `with_full_identity`, for `table` alone, so tables migrated together can differ.
+    pub fn with_full_identity_for(mut self, table: &Table, full_identity: bool) -> Self {
+        self.full_identity.retain(|full| full != table);
+        if full_identity {
+            self.full_identity.push(table.clone());
+        }
+        self
+    }
+
+    /// Statements publishing the tables, preceded by the switch to full row images for the
+    /// `full_identity` tables. Tables that are already FULL are left alone by `create`.
+    pub fn create_statements(&self) -> Vec<String> {
+        let mut statements: Vec<String> = self
+            .tables
+            .iter()
+            .filter(|table| self.full_identity.contains(table))
+            .map(|table| ReplicaIdentity::Full.alter_statement(table))
+            .collect();
+        statements.push(self.create_publication_statement());
+        statements
+    }
+
+    fn create_publication_statement(&self) -> String {
+        format!(
+            "CREATE PUBLICATION {} FOR TABLE {}",
+            quote_ident(&self.name),
+            self.tables
+                .iter()
+                .map(|table| table.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
+    pub fn drop_statement(&self) -> String {
+        format!("DROP PUBLICATION IF EXISTS {}", quote_ident(&self.name))
+    }
+
+    /// Stops publishing one table while the others carry on.
+    pub fn drop_table_statement(&self, table: &Table) -> String {
+        format!(
+            "ALTER PUBLICATION {} DROP TABLE {}",
+            quote_ident(&self.name),
+            table
+        )
+    }
+
+    /// Publishes the tables. Each table keeps its replica identity when it carries the primary
+    /// key; the `full_identity` tables are switched to FULL and their identity saved.
     pub fn create<C: postgres::GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
-        // Set REPLICA IDENTITY FULL for the table
-        let identity_sql = format!("ALTER TABLE {} REPLICA IDENTITY FULL", self.table);
-        client.simple_query(&identity_sql)?;
-        // Create the publication
-        let create_pub_sql = format!("CREATE PUBLICATION {} FOR TABLE {}", self.name, self.table);
-        client.simple_query(&create_pub_sql)?;
+        for table in &self.tables {
+            let identity = ReplicaIdentity::load(table, client)?;
+            if self.full_identity.contains(table) {
+                if identity != ReplicaIdentity::Full {
+                    self.saved_identities.create(client)?;
+                    self.saved_identities.save(table, &identity, client)?;
+                    client.simple_query(&ReplicaIdentity::Full.alter_statement(table))?;
+                }
+            } else if !ReplicaIdentity::carries_primary_key(table, client)? {
+                bail!(
+                    "{} has REPLICA IDENTITY {}, which doesn't carry its primary key; \
+                     change it or pass --replica-identity-full",
+                    table,
+                    identity
+                );
+            }
+        }
+        client.simple_query(&self.create_publication_statement())?;
         Ok(())
     }
 
+    /// Drops the publication and restores the identities `create` replaced.
     pub fn drop<C: postgres::GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
-        let drop_pub_sql = format!("DROP PUBLICATION IF EXISTS {}", self.name);
-        client.simple_query(&drop_pub_sql)?;
+        client.simple_query(&self.drop_statement())?;
+        for table in &self.tables {
+            self.saved_identities.restore(table, client)?;
+        }
         Ok(())
     }
+
+    /// Removes `table` from the publication.
+    pub fn drop_table<C: postgres::GenericClient>(
+        &mut self,
+        table: &Table,
+        client: &mut C,
+    ) -> anyhow::Result<()> {
+        client.simple_query(&self.drop_table_statement(table))?;
+        self.tables.retain(|published| published != table);
+        self.saved_identities.restore(table, client)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statements_cover_every_table() {
+        let publication = Publication::for_tables(
+            "ost_pub".to_string(),
+            vec![
+                Table::new("public.orders"),
+                Table::new("billing.\"Invoices\""),
+            ],
+            Slot::new("ost_slot".to_string()),
+        );
+        assert_eq!(
+            publication.create_statements(),
+            vec!["CREATE PUBLICATION ost_pub FOR TABLE public.orders, billing.\"Invoices\""]
+        );
+        assert_eq!(
+            publication
+                .clone()
+                .with_full_identity(true)
+                .create_statements(),
+            vec![
+                "ALTER TABLE public.orders REPLICA IDENTITY FULL",
+                "ALTER TABLE billing.\"Invoices\" REPLICA IDENTITY FULL",
+                "CREATE PUBLICATION ost_pub FOR TABLE public.orders, billing.\"Invoices\"",
+            ]
+        );
+        assert_eq!(
+            publication
+                .clone()
+                .with_full_identity_for(&Table::new("billing.\"Invoices\""), true)
+                .create_statements(),
+            vec![
+                "ALTER TABLE billing.\"Invoices\" REPLICA IDENTITY FULL",
+                "CREATE PUBLICATION ost_pub FOR TABLE public.orders, billing.\"Invoices\"",
+            ]
+        );
+        assert_eq!(
+            publication.drop_table_statement(&Table::new("public.orders")),
+            "ALTER PUBLICATION ost_pub DROP TABLE public.orders"
+        );
+    }
 }
@@ -0,0 +1,83 @@
+// Reconnecting a replication stream after its connection is lost
+
+use crate::logical_replication::message::Lsn;
+use std::time::Duration;
+
+/// How a stream retries after losing its replication connection. The wait between attempts
+/// doubles from `initial_backoff` up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Attempts before the stream gives up and returns the error
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The wait before attempt `attempt`, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+/// Called with the slot name each time a stream recovers from an outage. A stream that runs
+/// out of attempts returns the error instead, which stops the replay reading it.
+pub type OutageHandler = std::sync::Arc<dyn Fn(&str, &ReplicationOutage) + Send + Sync>;
+
+/// A lost replication connection and how the stream got it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationOutage {
+    /// Why the connection ended, as reported by libpq
+    pub cause: String,
+    /// Connection attempts until replication restarted
+    pub attempts: u32,
+    /// Time from noticing the loss to restarting replication
+    pub duration: Duration,
+    /// The confirmed position replication restarted from
+    pub resumed_from: Lsn,
+}
+
+impl std::fmt::Display for ReplicationOutage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "replication connection lost ({}); resumed from {} after {} attempt(s) in {:.1}s",
+            self.cause,
+            self.resumed_from,
+            self.attempts,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: 10,
+        };
+        let waits: Vec<u64> = (1..=6)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(waits, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(1));
+    }
+}
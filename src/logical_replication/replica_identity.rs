@@ -0,0 +1,289 @@
+// Replica identity: which old-row columns a table writes to the WAL for updates and deletes
+
+use crate::ColumnMap;
+use crate::identifier::quote_ident;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::{Result, bail};
+use postgres::GenericClient;
+use std::fmt;
+
+/// Name of the table recording the identities replaced while tables are published, in the
+/// state schema.
+pub const SAVED_IDENTITIES_TABLE_NAME: &str = "replica_identities";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaIdentity {
+    /// The primary key, if there is one
+    Default,
+    /// Every column of the old row
+    Full,
+    /// No old-row columns
+    Nothing,
+    /// The columns of the named unique index
+    Index(String),
+}
+
+impl fmt::Display for ReplicaIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaIdentity::Default => write!(f, "DEFAULT"),
+            ReplicaIdentity::Full => write!(f, "FULL"),
+            ReplicaIdentity::Nothing => write!(f, "NOTHING"),
+            ReplicaIdentity::Index(index) => write!(f, "USING INDEX {}", quote_ident(index)),
+        }
+    }
+}
+
+impl ReplicaIdentity {
+    /// Reads `table`'s replica identity from the catalog.
+    pub fn load<C: GenericClient>(table: &Table, client: &mut C) -> Result<Self> {
+        let row = client.query_one(
+            "SELECT c.relreplident::text, i.relname::text
+             FROM pg_class c
+             LEFT JOIN pg_index x ON x.indrelid = c.oid AND x.indisreplident
+             LEFT JOIN pg_class i ON i.oid = x.indexrelid
+             WHERE c.oid = ($1)::text::regclass",
+            &[&table.to_string()],
+        )?;
+        Self::from_code(table, row.get(0), row.get(1))
+    }
+
+    /// The identity for a `relreplident` code, with the name of the identity index if any.
+    fn from_code(table: &Table, code: &str, index: Option<String>) -> Result<Self> {
+        Ok(match (code, index) {
+            ("d", _) => ReplicaIdentity::Default,
+            ("f", _) => ReplicaIdentity::Full,
+            ("n", _) => ReplicaIdentity::Nothing,
+            ("i", Some(index)) => ReplicaIdentity::Index(index),
+            // The index was dropped, which Postgres treats as NOTHING
+            ("i", None) => ReplicaIdentity::Nothing,
+            (other, _) => bail!("Unknown replica identity {:?} on {}", other, table),
+        })
+    }
+
+    /// The `relreplident` code for this identity.
+    fn code(&self) -> &'static str {
+        match self {
+            ReplicaIdentity::Default => "d",
+            ReplicaIdentity::Full => "f",
+            ReplicaIdentity::Nothing => "n",
+            ReplicaIdentity::Index(_) => "i",
+        }
+    }
+
+    /// This identity of `source`, for `target`, a copy made with `LIKE ... INCLUDING ALL` whose
+    /// columns follow `column_map`. The copy's indexes get new names, so an index identity
+    /// names the target's unique index on the mapped columns. When the target has no such
+    /// index, because a column was dropped, the target keeps the default identity.
+    pub fn for_copy<C: GenericClient>(
+        &self,
+        source: &Table,
+        target: &Table,
+        column_map: &ColumnMap,
+        client: &mut C,
+    ) -> Result<Self> {
+        let ReplicaIdentity::Index(index) = self else {
+            return Ok(self.clone());
+        };
+        let columns = client
+            .query(
+                "SELECT a.attname::text
+                 FROM pg_index x
+                 CROSS JOIN LATERAL unnest(x.indkey::int2[]) WITH ORDINALITY AS k(attnum, ord)
+                 JOIN pg_attribute a ON a.attrelid = x.indrelid AND a.attnum = k.attnum
+                 WHERE x.indexrelid = ($1)::text::regclass
+                 ORDER BY k.ord",
+                &[&Table::from_parts(source.schema.as_deref(), index).to_string()],
+            )?
+            .iter()
+            .map(|row| column_map.shadow_col(&row.get::<_, String>(0)))
+            .collect::<Option<Vec<String>>>();
+        let Some(columns) = columns else {
+            return Ok(ReplicaIdentity::Default);
+        };
+        let row = client.query_opt(
+            "SELECT i.relname::text
+             FROM pg_index x
+             JOIN pg_class i ON i.oid = x.indexrelid
+             WHERE x.indrelid = ($1)::text::regclass
+               AND x.indisunique AND x.indimmediate AND x.indpred IS NULL AND x.indexprs IS NULL
+               AND ARRAY(SELECT a.attname::text
+                         FROM unnest(x.indkey::int2[]) WITH ORDINALITY AS k(attnum, ord)
+                         JOIN pg_attribute a ON a.attrelid = x.indrelid AND a.attnum = k.attnum
+                         ORDER BY k.ord) = $2
+             ORDER BY 1
+             LIMIT 1",
+            &[&target.to_string(), &columns],
+        )?;
+        Ok(row.map_or(ReplicaIdentity::Default, |row| {
+            ReplicaIdentity::Index(row.get(0))
+        }))
+    }
+
+    /// Whether `table`'s current identity includes every primary key column, so the old key
+    /// of updated and deleted rows can be found in the WAL.
+    pub fn carries_primary_key<C: GenericClient>(table: &Table, client: &mut C) -> Result<bool> {
+        let row = client.query_one(
+            "SELECT CASE c.relreplident
+                 WHEN 'f' THEN true
+                 WHEN 'd' THEN EXISTS (
+                     SELECT 1 FROM pg_index p WHERE p.indrelid = c.oid AND p.indisprimary)
+                 WHEN 'i' THEN EXISTS (
+                     SELECT 1 FROM pg_index p
+                     JOIN pg_index r ON r.indrelid = p.indrelid AND r.indisreplident
+                     WHERE p.indrelid = c.oid AND p.indisprimary
+                       AND p.indkey::int2[] <@ r.indkey::int2[])
+                 ELSE false
+             END
+             FROM pg_class c WHERE c.oid = ($1)::text::regclass",
+            &[&table.to_string()],
+        )?;
+        Ok(row.get(0))
+    }
+
+    /// The statement giving `table` this identity.
+    pub fn alter_statement(&self, table: &Table) -> String {
+        format!("ALTER TABLE {} REPLICA IDENTITY {}", table, self)
+    }
+}
+
+/// The table recording the original replica identity of each table switched to FULL while it
+/// is published. It outlives the process, so the identity can still be restored, and given to
+/// the new table at cutover, after an interrupted run is resumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SavedIdentities {
+    pub table: Table,
+}
+
+impl SavedIdentities {
+    pub fn new(schema_config: &SchemaConfig) -> Self {
+        SavedIdentities {
+            table: Table::from_parts(
+                Some(&schema_config.state_schema),
+                SAVED_IDENTITIES_TABLE_NAME,
+            ),
+        }
+    }
+
+    pub fn create_statement(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (table_name TEXT PRIMARY KEY, identity TEXT NOT NULL, index_name TEXT)",
+            self.table
+        )
+    }
+
+    /// Creates the table, and its schema if it doesn't exist yet.
+    pub fn create<C: GenericClient>(&self, client: &mut C) -> Result<()> {
+        if let Some(schema) = &self.table.schema {
+            client.batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {}",
+                quote_ident(schema)
+            ))?;
+        }
+        client.batch_execute(&self.create_statement())?;
+        Ok(())
+    }
+
+    /// Records `identity` as `table`'s original. An identity already recorded is kept, since it
+    /// is the one from before the table was first switched.
+    pub fn save<C: GenericClient>(
+        &self,
+        table: &Table,
+        identity: &ReplicaIdentity,
+        client: &mut C,
+    ) -> Result<()> {
+        let index = match identity {
+            ReplicaIdentity::Index(index) => Some(index.as_str()),
+            _ => None,
+        };
+        client.execute(
+            &format!(
+                "INSERT INTO {} (table_name, identity, index_name) VALUES ($1, $2, $3)
+                 ON CONFLICT (table_name) DO NOTHING",
+                self.table
+            ),
+            &[&table.to_string(), &identity.code(), &index],
+        )?;
+        Ok(())
+    }
+
+    /// `table`'s original identity, if it was replaced.
+    pub fn load<C: GenericClient>(
+        &self,
+        table: &Table,
+        client: &mut C,
+    ) -> Result<Option<ReplicaIdentity>> {
+        if !self.table.exists(client)? {
+            return Ok(None);
+        }
+        client
+            .query_opt(
+                &format!(
+                    "SELECT identity, index_name FROM {} WHERE table_name = $1",
+                    self.table
+                ),
+                &[&table.to_string()],
+            )?
+            .map(|row| ReplicaIdentity::from_code(table, row.get(0), row.get(1)))
+            .transpose()
+    }
+
+    /// Puts `table`'s original identity back, if it was replaced, and forgets it.
+    pub fn restore<C: GenericClient>(&self, table: &Table, client: &mut C) -> Result<()> {
+        if let Some(identity) = self.load(table, client)? {
+            client.batch_execute(&identity.alter_statement(table))?;
+            client.execute(
+                &format!("DELETE FROM {} WHERE table_name = $1", self.table),
+                &[&table.to_string()],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alter_statements() {
+        let table = Table::new("public.orders");
+        assert_eq!(
+            ReplicaIdentity::Full.alter_statement(&table),
+            "ALTER TABLE public.orders REPLICA IDENTITY FULL"
+        );
+        assert_eq!(
+            ReplicaIdentity::Index("Orders_code_key".to_string()).alter_statement(&table),
+            "ALTER TABLE public.orders REPLICA IDENTITY USING INDEX \"Orders_code_key\""
+        );
+    }
+
+    #[test]
+    fn test_saved_identities_in_state_schema() {
+        let schema_config = SchemaConfig {
+            state_schema: "tool".to_string(),
+            ..SchemaConfig::default()
+        };
+        assert_eq!(
+            SavedIdentities::new(&schema_config).create_statement(),
+            "CREATE TABLE IF NOT EXISTS tool.replica_identities (table_name TEXT PRIMARY KEY, identity TEXT NOT NULL, index_name TEXT)"
+        );
+        let table = Table::new("public.orders");
+        for identity in [
+            ReplicaIdentity::Default,
+            ReplicaIdentity::Full,
+            ReplicaIdentity::Nothing,
+            ReplicaIdentity::Index("orders_code_key".to_string()),
+        ] {
+            let index = match &identity {
+                ReplicaIdentity::Index(index) => Some(index.clone()),
+                _ => None,
+            };
+            assert_eq!(
+                ReplicaIdentity::from_code(&table, identity.code(), index).unwrap(),
+                identity
+            );
+        }
+    }
+}
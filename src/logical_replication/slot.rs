@@ -1,9 +1,27 @@
 // Slot management for logical replication
 
+use crate::identifier::quote_literal;
+use crate::table::Table;
+
+/// Output format of the wal2json plugin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Wal2JsonFormat {
+    /// One document per transaction, with positional `columnvalues` and `oldkeys`
+    #[default]
+    V1,
+    /// One document per change, with named `columns` and `identity`, so large transactions
+    /// aren't buffered whole
+    V2,
+}
+
 #[derive(Clone)]
 pub struct Slot {
     pub name: String,
     pub plugin: String,
+    /// wal2json output format
+    pub format: Wal2JsonFormat,
+    /// Tables wal2json decodes, passed as `add-tables`. Every table when empty.
+    pub tables: Vec<Table>,
 }
 
 impl Slot {
@@ -11,21 +29,74 @@ impl Slot {
         Slot {
             name,
             plugin: "wal2json".to_string(),
+            format: Wal2JsonFormat::default(),
+            tables: Vec::new(),
         }
     }
 
+    /// Decode with wal2json's `format`.
+    pub fn with_format(mut self, format: Wal2JsonFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Only decode changes to `tables`.
+    pub fn with_tables(mut self, tables: Vec<Table>) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    /// Options for the output plugin, as passed to `pg_logical_slot_get_changes` and
+    /// `START_REPLICATION`. Only wal2json takes any.
+    pub fn plugin_options(&self) -> Vec<(String, String)> {
+        if self.plugin != "wal2json" {
+            return Vec::new();
+        }
+        let version = match self.format {
+            Wal2JsonFormat::V1 => "1",
+            Wal2JsonFormat::V2 => "2",
+        };
+        let mut options = vec![
+            ("format-version".to_string(), version.to_string()),
+            ("include-types".to_string(), "true".to_string()),
+        ];
+        if self.format == Wal2JsonFormat::V2 {
+            options.push(("include-lsn".to_string(), "true".to_string()));
+        }
+        if !self.tables.is_empty() {
+            let tables = self
+                .tables
+                .iter()
+                .map(wal2json_table_name)
+                .collect::<Vec<_>>()
+                .join(",");
+            options.push(("add-tables".to_string(), tables));
+        }
+        options
+    }
+
+    pub fn create_statement(&self) -> String {
+        format!(
+            "SELECT pg_create_logical_replication_slot({}, {})",
+            quote_literal(&self.name),
+            quote_literal(&self.plugin)
+        )
+    }
+
+    pub fn drop_statement(&self) -> String {
+        format!(
+            "SELECT pg_drop_replication_slot({})",
+            quote_literal(&self.name)
+        )
+    }
+
     pub fn create_slot<C: postgres::GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
-        let create_slot_statement = format!(
-            "SELECT pg_create_logical_replication_slot('{}', '{}')",
-            self.name, self.plugin
-        );
-        client.simple_query(&create_slot_statement)?;
+        client.simple_query(&self.create_statement())?;
         Ok(())
     }
 
     pub fn drop_slot<C: postgres::GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
-        let drop_slot_statement = format!("SELECT pg_drop_replication_slot('{}')", self.name);
-        client.simple_query(&drop_slot_statement)?;
+        client.simple_query(&self.drop_statement())?;
         Ok(())
     }
 
@@ -34,25 +105,49 @@ impl Slot {
         client: &mut C,
         upto_n_changes: i64,
     ) -> anyhow::Result<Vec<postgres::Row>> {
-        let get_changes_statement = format!(
-            "SELECT * FROM pg_logical_slot_get_changes('{}', NULL, {})",
-            self.name, upto_n_changes
-        );
-        let rows = client.query(&get_changes_statement, &[])?;
+        let rows = client.query(&self.get_changes_statement(upto_n_changes), &[])?;
         Ok(rows)
     }
 
+    /// Consumes up to `upto_n_changes` changes from transactions committed before `upto_lsn`,
+    /// so a drain ends there even while other tables keep writing.
+    pub fn get_changes_until<C: postgres::GenericClient>(
+        &self,
+        client: &mut C,
+        upto_lsn: postgres::types::PgLsn,
+        upto_n_changes: i64,
+    ) -> anyhow::Result<Vec<postgres::Row>> {
+        let rows = client.query(&self.changes_statement("$1", upto_n_changes), &[&upto_lsn])?;
+        Ok(rows)
+    }
+
+    pub fn get_changes_statement(&self, upto_n_changes: i64) -> String {
+        self.changes_statement("NULL", upto_n_changes)
+    }
+
+    fn changes_statement(&self, upto_lsn: &str, upto_n_changes: i64) -> String {
+        let options: String = self
+            .plugin_options()
+            .iter()
+            .map(|(name, value)| format!(", {}, {}", quote_literal(name), quote_literal(value)))
+            .collect();
+        format!(
+            "SELECT * FROM pg_logical_slot_get_changes({}, {}, {}{})",
+            quote_literal(&self.name),
+            upto_lsn,
+            upto_n_changes,
+            options
+        )
+    }
+
     /// Fetch the confirmed_flush_lsn for this slot from the database.
     pub fn confirmed_flush_lsn(
         &self,
         client: &mut postgres::Client,
     ) -> anyhow::Result<crate::logical_replication::message::Lsn> {
         let row = client.query_one(
-            &format!(
-                "SELECT confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = '{}'",
-                self.name
-            ),
-            &[],
+            "SELECT confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = $1",
+            &[&self.name],
         )?;
         let pg_lsn: postgres::types::PgLsn = row.get(0);
         let lsn_str = pg_lsn.to_string();
@@ -60,3 +155,52 @@ impl Slot {
             .ok_or_else(|| anyhow::anyhow!("Failed to parse confirmed_flush_lsn: {}", lsn_str))
     }
 }
+
+/// A table as wal2json's `add-tables` names it. Its separators and wildcard are escaped with a
+/// backslash, and a table without a schema matches it in any schema.
+fn wal2json_table_name(table: &Table) -> String {
+    let escape = |name: &str| {
+        name.chars()
+            .flat_map(|c| {
+                let escaped = matches!(c, ' ' | '\'' | ',' | '.' | '*' | '\\');
+                escaped
+                    .then_some('\\')
+                    .into_iter()
+                    .chain(std::iter::once(c))
+            })
+            .collect::<String>()
+    };
+    format!(
+        "{}.{}",
+        table.schema.as_deref().map_or("*".to_string(), escape),
+        escape(&table.name)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_changes_passes_wal2json_options() {
+        let slot = Slot::new("ost_slot".to_string())
+            .with_format(Wal2JsonFormat::V2)
+            .with_tables(vec![
+                Table::new("public.orders"),
+                Table::new("billing.\"Line Items\""),
+            ]);
+        assert_eq!(
+            slot.get_changes_statement(100),
+            "SELECT * FROM pg_logical_slot_get_changes('ost_slot', NULL, 100, 'format-version', '2', 'include-types', 'true', 'include-lsn', 'true', 'add-tables', 'public.orders,billing.Line\\ Items')"
+        );
+        assert!(
+            slot.changes_statement("$1", 100)
+                .starts_with("SELECT * FROM pg_logical_slot_get_changes('ost_slot', $1, 100, ")
+        );
+        let test_decoding = Slot {
+            plugin: "test_decoding".to_string(),
+            ..slot
+        };
+        assert!(test_decoding.plugin_options().is_empty());
+    }
+}
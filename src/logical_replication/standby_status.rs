@@ -0,0 +1,189 @@
+// Standby status updates: the positions a replication client reports back to the walsender
+
+use crate::logical_replication::message::{Lsn, ReplicationMessage};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Microseconds from the Unix epoch to the Postgres epoch, 2000-01-01.
+const PG_EPOCH_OFFSET_MICROS: u64 = 946_684_800_000_000;
+
+/// Default longest time between two standby status updates.
+pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks what a stream has received and applied, and when the server should next hear about
+/// it. It holds no connection: the stream feeds it every message and sends the updates it builds.
+#[derive(Debug, Clone)]
+pub struct StandbyStatus {
+    /// End of the last change received
+    pub received_lsn: Lsn,
+    /// Position up to which changes are applied, reported as flushed and applied
+    pub confirmed_lsn: Lsn,
+    /// Longest time between two updates. Keep it well under the server's `wal_sender_timeout`.
+    pub interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl StandbyStatus {
+    pub fn new(start_lsn: Lsn, interval: Duration) -> Self {
+        StandbyStatus {
+            received_lsn: start_lsn,
+            confirmed_lsn: start_lsn,
+            interval,
+            last_sent: None,
+        }
+    }
+
+    /// Whether every change received has been confirmed.
+    pub fn caught_up(&self) -> bool {
+        self.received_lsn <= self.confirmed_lsn
+    }
+
+    /// Records a message from the server and returns whether it asked for an update right away.
+    /// A keepalive confirms its WAL position when no received change is waiting to be applied,
+    /// so a slot keeps advancing while its tables are idle.
+    pub fn observe(&mut self, message: &ReplicationMessage) -> bool {
+        match message {
+            ReplicationMessage::XLogData(xlog) => {
+                self.received_lsn = self.received_lsn.max(xlog.wal_end);
+                false
+            }
+            ReplicationMessage::PrimaryKeepAlive(keepalive) => {
+                if self.caught_up() {
+                    self.confirm(keepalive.wal_end);
+                }
+                keepalive.reply_requested
+            }
+            ReplicationMessage::Unknown(..) => false,
+        }
+    }
+
+    /// Marks the changes up to `lsn` as applied. Positions never move back.
+    pub fn confirm(&mut self, lsn: Lsn) {
+        self.confirmed_lsn = self.confirmed_lsn.max(lsn);
+    }
+
+    /// Whether the interval has passed since the last update.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.until_due(now).is_zero()
+    }
+
+    /// How long until the next periodic update.
+    pub fn until_due(&self, now: Instant) -> Duration {
+        self.last_sent.map_or(Duration::ZERO, |sent| {
+            (sent + self.interval).saturating_duration_since(now)
+        })
+    }
+
+    /// Encodes a standby status update ('r') for the current positions, sent at `now` with the
+    /// wall clock reading `clock`, and starts the interval again. No reply is requested.
+    pub fn update_message(&mut self, now: Instant, clock: SystemTime) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + 8 * 4 + 1);
+        buf.push(b'r');
+        // Written, flushed and applied positions
+        buf.extend_from_slice(&self.received_lsn.max(self.confirmed_lsn).0.to_be_bytes());
+        buf.extend_from_slice(&self.confirmed_lsn.0.to_be_bytes());
+        buf.extend_from_slice(&self.confirmed_lsn.0.to_be_bytes());
+        let micros = clock
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64)
+            .saturating_sub(PG_EPOCH_OFFSET_MICROS);
+        buf.extend_from_slice(&micros.to_be_bytes());
+        buf.push(0);
+        self.last_sent = Some(now);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages recorded from a Postgres 15 walsender decoding with test_decoding: a keepalive,
+    // the BEGIN and COMMIT of one insert, and a keepalive asking for a reply.
+    const KEEPALIVE: [u8; 18] = [
+        0x6b, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a, 0xcb, 0x58, 0x00, 0x03, 0x01, 0x1c, 0x92, 0x2e,
+        0x56, 0x5d, 0x00,
+    ];
+    const BEGIN: [u8; 35] = [
+        0x77, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a, 0xcb, 0x90, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a,
+        0xcb, 0x90, 0x00, 0x03, 0x01, 0x1c, 0x92, 0x2e, 0x59, 0x1e, 0x42, 0x45, 0x47, 0x49, 0x4e,
+        0x20, 0x39, 0x31, 0x37, 0x31,
+    ];
+    const COMMIT: [u8; 36] = [
+        0x77, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a, 0xcd, 0x10, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a,
+        0xcd, 0x10, 0x00, 0x03, 0x01, 0x1c, 0x92, 0x2e, 0x5a, 0x06, 0x43, 0x4f, 0x4d, 0x4d, 0x49,
+        0x54, 0x20, 0x39, 0x31, 0x37, 0x31,
+    ];
+    const KEEPALIVE_REPLY_REQUESTED: [u8; 18] = [
+        0x6b, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x1a, 0xcd, 0x10, 0x00, 0x03, 0x01, 0x1c, 0x92, 0x54,
+        0x8a, 0x13, 0x01,
+    ];
+
+    fn observe(status: &mut StandbyStatus, bytes: &[u8]) -> bool {
+        status.observe(&ReplicationMessage::parse(bytes).expect("recorded message parses"))
+    }
+
+    #[test]
+    fn test_keepalive_confirms_when_caught_up() {
+        let mut status = StandbyStatus::new(Lsn(0), DEFAULT_STATUS_INTERVAL);
+        assert!(!observe(&mut status, &KEEPALIVE));
+        assert_eq!(
+            status.confirmed_lsn,
+            Lsn::from_pg_string("0/A41ACB58").unwrap()
+        );
+        assert!(observe(&mut status, &KEEPALIVE_REPLY_REQUESTED));
+        assert_eq!(
+            status.confirmed_lsn,
+            Lsn::from_pg_string("0/A41ACD10").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_keepalive_waits_for_pending_changes() {
+        let mut status = StandbyStatus::new(Lsn(0), DEFAULT_STATUS_INTERVAL);
+        observe(&mut status, &KEEPALIVE);
+        let before = status.confirmed_lsn;
+        observe(&mut status, &BEGIN);
+        observe(&mut status, &COMMIT);
+        assert_eq!(
+            status.received_lsn,
+            Lsn::from_pg_string("0/A41ACD10").unwrap()
+        );
+        // The transaction isn't applied yet, so the keepalive must not confirm past it
+        assert!(observe(&mut status, &KEEPALIVE_REPLY_REQUESTED));
+        assert_eq!(status.confirmed_lsn, before);
+        status.confirm(status.received_lsn);
+        assert!(status.caught_up());
+        status.confirm(before);
+        assert_eq!(status.confirmed_lsn, status.received_lsn);
+    }
+
+    #[test]
+    fn test_update_message_encoding() {
+        let mut status = StandbyStatus::new(Lsn(0x10), DEFAULT_STATUS_INTERVAL);
+        status.received_lsn = Lsn(0x30);
+        status.confirm(Lsn(0x20));
+        let clock = UNIX_EPOCH + Duration::from_micros(PG_EPOCH_OFFSET_MICROS + 1_500_000);
+        let buf = status.update_message(Instant::now(), clock);
+        let mut expected = vec![b'r'];
+        expected.extend_from_slice(&0x30_u64.to_be_bytes());
+        expected.extend_from_slice(&0x20_u64.to_be_bytes());
+        expected.extend_from_slice(&0x20_u64.to_be_bytes());
+        expected.extend_from_slice(&1_500_000_u64.to_be_bytes());
+        expected.push(0);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_updates_are_due_every_interval() {
+        let mut status = StandbyStatus::new(Lsn(0), Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(status.is_due(start));
+        status.update_message(start, SystemTime::now());
+        assert!(!status.is_due(start + Duration::from_secs(4)));
+        assert_eq!(
+            status.until_due(start + Duration::from_secs(4)),
+            Duration::from_secs(6)
+        );
+        assert!(status.is_due(start + Duration::from_secs(10)));
+    }
+}
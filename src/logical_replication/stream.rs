@@ -1,9 +1,28 @@
 // LogicalReplicationStream: streaming, batching, and LSN tracking
 
+use crate::logical_replication::message::ReplicationMessage;
+use crate::logical_replication::reconnect::{OutageHandler, ReconnectPolicy, ReplicationOutage};
+use crate::logical_replication::standby_status::{DEFAULT_STATUS_INTERVAL, StandbyStatus};
+use std::time::{Duration, Instant, SystemTime};
+
 pub struct LogicalReplicationStream {
     pub conn: libpq::Connection,
+    /// The connection string without the replication parameter, for regular connections
+    pub conninfo: String,
     pub slot_name: String,
+    /// Output plugin options sent with START_REPLICATION
+    pub options: Vec<(String, String)>,
     pub last_lsn: crate::logical_replication::message::Lsn,
+    /// Whether START_REPLICATION is running on the connection
+    pub streaming: bool,
+    /// Positions reported to the server, and when the next report is due
+    pub status: StandbyStatus,
+    /// How to retry when the connection is lost while streaming
+    pub reconnect_policy: ReconnectPolicy,
+    /// Connection losses the stream recovered from, oldest first
+    pub outages: Vec<ReplicationOutage>,
+    /// Told about each outage as the stream recovers from it
+    pub on_outage: Option<OutageHandler>,
 }
 
 impl LogicalReplicationStream {
@@ -12,15 +31,45 @@ impl LogicalReplicationStream {
         slot_name: &str,
         start_lsn: crate::logical_replication::message::Lsn,
     ) -> anyhow::Result<Self> {
-        let conninfo = with_replication_param(conninfo);
-        let conn = libpq::Connection::new(&conninfo)?;
+        let conn = libpq::Connection::new(&with_replication_param(conninfo))?;
         Ok(Self {
             conn,
+            conninfo: conninfo.to_string(),
             slot_name: slot_name.to_string(),
+            options: Vec::new(),
             last_lsn: start_lsn,
+            streaming: false,
+            status: StandbyStatus::new(start_lsn, DEFAULT_STATUS_INTERVAL),
+            reconnect_policy: ReconnectPolicy::default(),
+            outages: Vec::new(),
+            on_outage: None,
         })
     }
 
+    /// Use `reconnect_policy` when the connection is lost while streaming.
+    pub fn with_reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Call `on_outage` each time the stream recovers from a lost connection.
+    pub fn with_outage_handler(mut self, on_outage: OutageHandler) -> Self {
+        self.on_outage = Some(on_outage);
+        self
+    }
+
+    /// Start replication with the output plugin `options`, such as `Slot::plugin_options`.
+    pub fn with_options(mut self, options: Vec<(String, String)>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Send standby status updates at least every `interval`.
+    pub fn with_status_interval(mut self, interval: Duration) -> Self {
+        self.status.interval = interval;
+        self
+    }
+
     /// Format an Lsn as a Postgres LSN string (e.g., "0/0").
     fn lsn_to_pg_string(lsn: crate::logical_replication::message::Lsn) -> String {
         let val = lsn.0;
@@ -30,10 +79,26 @@ impl LogicalReplicationStream {
     /// Start replication and return a stream ready to pull messages.
     pub fn start(&mut self) -> anyhow::Result<()> {
         let lsn_str = Self::lsn_to_pg_string(self.last_lsn);
-        let query = format!(
+        let mut query = format!(
             "START_REPLICATION SLOT {} LOGICAL {}",
-            self.slot_name, lsn_str
+            crate::identifier::quote_ident(&self.slot_name),
+            lsn_str
         );
+        if !self.options.is_empty() {
+            let options = self
+                .options
+                .iter()
+                .map(|(name, value)| {
+                    format!(
+                        "{} {}",
+                        crate::identifier::quote_ident(name),
+                        crate::identifier::quote_literal(value)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            query.push_str(&format!(" ({})", options));
+        }
         let res = self.conn.exec(&query);
         // Use the libpq::Status::CopyBoth enum variant for clarity
         if res.status() != libpq::Status::CopyBoth {
@@ -44,91 +109,292 @@ impl LogicalReplicationStream {
                 msg
             );
         }
+        self.streaming = true;
+        // Changes before the start position are applied already
+        self.status.received_lsn = self.status.received_lsn.max(self.last_lsn);
+        self.status.confirm(self.last_lsn);
         Ok(())
     }
 
-    /// Pull up to `max_messages` replication messages, or until timeout (if provided).
+    /// Pull up to `max_messages` replication messages, or until timeout (if provided). Without
+    /// a timeout it waits until `max_messages` arrive or the stream ends. While waiting on the
+    /// socket it answers keepalives that ask for a reply and sends a status update whenever one
+    /// is due, so an idle stream isn't timed out by the server. If the connection is lost it
+    /// reconnects following `reconnect_policy` and restarts from the confirmed position; changes
+    /// received after that position, including any already returned, are sent again. When the
+    /// server itself ends replication on a live connection, its error is returned instead.
     pub fn next_batch(
         &mut self,
         max_messages: usize,
-        timeout: Option<std::time::Duration>,
-    ) -> anyhow::Result<Vec<crate::logical_replication::message::ReplicationMessage>> {
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<ReplicationMessage>> {
         let mut messages = Vec::new();
-        let start = std::time::Instant::now();
+        let deadline = timeout.map(|t| Instant::now() + t);
         while messages.len() < max_messages {
-            let _ = self.conn.consume_input();
-            match self.conn.copy_data(false) {
+            if let Err(err) = self.conn.consume_input()
+                && !(self.streaming && self.connection_lost())
+            {
+                return Err(err.into());
+            }
+            let lost = match self.conn.copy_data(true) {
                 Ok(msg) => {
-                    if let Some(rep_msg) =
-                        crate::logical_replication::message::ReplicationMessage::parse(&msg)
-                    {
-                        if let crate::logical_replication::message::ReplicationMessage::XLogData(
-                            ref xlog,
-                        ) = rep_msg
-                        {
+                    if let Some(rep_msg) = ReplicationMessage::parse(&msg) {
+                        if let ReplicationMessage::XLogData(ref xlog) = rep_msg {
                             self.last_lsn = xlog.wal_end;
                         }
+                        // A failed send shows up as a lost connection on the next read
+                        if self.status.observe(&rep_msg) {
+                            let _ = self.send_status();
+                        }
                         messages.push(rep_msg);
                     }
+                    None
                 }
+                Err(err) if is_copy_in_progress(&err) => {
+                    if self.connection_lost() {
+                        Some(self.connection_error())
+                    } else {
+                        let now = Instant::now();
+                        if self.status.is_due(now) {
+                            let _ = self.send_status();
+                        }
+                        if deadline.is_some_and(|deadline| now >= deadline) {
+                            break;
+                        }
+                        let mut wait = self.status.until_due(Instant::now());
+                        if let Some(deadline) = deadline {
+                            wait = wait.min(deadline.saturating_duration_since(now));
+                        }
+                        wait_readable(self.conn.socket()?, wait)?;
+                        None
+                    }
+                }
+                Err(_) if self.streaming => Some(self.copy_ended()?),
+                // Stopped, so there is nothing left to read
                 Err(_) => break,
+            };
+            if let Some(cause) = lost {
+                self.reconnect(cause)?;
+                // The server sends these again from the confirmed position
+                messages.retain(|msg| !matches!(msg, ReplicationMessage::XLogData(_)));
             }
-            if let Some(t) = timeout {
-                if start.elapsed() > t {
-                    break;
-                }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
             }
         }
         Ok(messages)
     }
 
-    /// Send a feedback message to Postgres with the confirmed LSN.
+    /// Whether the socket failed, so the connection has to be replaced.
+    fn connection_lost(&self) -> bool {
+        self.conn.status() == libpq::connection::Status::Bad
+    }
+
+    fn connection_error(&self) -> String {
+        self.conn
+            .error_message()
+            .map(str::trim)
+            .filter(|msg| !msg.is_empty())
+            .unwrap_or("the connection was closed")
+            .to_string()
+    }
+
+    /// Finds out why the copy ended, once a read says it isn't in progress any more. When the
+    /// connection was lost, or the server ended the session with a fatal error as it does on
+    /// shutdown or termination, the cause is returned to reconnect with. A CopyDone or error the
+    /// server sent on a live session is returned as an error.
+    fn copy_ended(&mut self) -> anyhow::Result<String> {
+        if self.connection_lost() {
+            return Ok(self.connection_error());
+        }
+        let mut server_error = None;
+        while let Some(result) = self.conn.result() {
+            // Still copying: there is no final result to read
+            if result.status() == libpq::Status::CopyBoth {
+                break;
+            }
+            if let Ok(Some(message)) = result.error_message() {
+                let fatal = matches!(
+                    result.error_field(libpq::result::ErrorField::SeverityNonlocalized),
+                    Ok(Some("FATAL" | "PANIC"))
+                );
+                server_error.get_or_insert((message.trim().to_string(), fatal));
+            }
+        }
+        self.streaming = false;
+        match server_error {
+            Some((message, fatal)) if fatal || self.connection_lost() => Ok(message),
+            None if self.connection_lost() => Ok(self.connection_error()),
+            Some((message, _)) => anyhow::bail!(
+                "The server ended replication from slot {}: {}",
+                self.slot_name,
+                message
+            ),
+            None => anyhow::bail!(
+                "The server ended replication from slot {} without an error",
+                self.slot_name
+            ),
+        }
+    }
+
+    /// Confirm that changes up to `confirmed_lsn` are applied, and tell the server now.
     pub fn send_feedback(
         &mut self,
         confirmed_lsn: crate::logical_replication::message::Lsn,
     ) -> anyhow::Result<()> {
-        // Standby status update message format:
-        // 'r' + 8 bytes wal_write + 8 bytes wal_flush + 8 bytes wal_apply + 8 bytes client time + 1 byte reply requested
-        // We'll set all LSNs to confirmed_lsn, client time to now, reply_requested to 1 for test
-        use std::time::{SystemTime, UNIX_EPOCH};
-        let mut buf = Vec::with_capacity(1 + 8 * 3 + 8 + 1);
-        buf.push(b'r');
-        let lsn = confirmed_lsn.0;
-        for _ in 0..3 {
-            // wal_write, wal_flush, wal_apply
-            buf.extend_from_slice(&lsn.to_be_bytes());
+        self.status.confirm(confirmed_lsn);
+        self.send_status()
+    }
+
+    /// Opens a new connection and restarts replication from the confirmed position, waiting
+    /// longer after each failed attempt. The outage is recorded in `outages` and passed to
+    /// `on_outage`, so a stalled replay doesn't go unnoticed.
+    fn reconnect(&mut self, cause: String) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            std::thread::sleep(self.reconnect_policy.backoff(attempts));
+            match self.restart() {
+                Ok(()) => break,
+                Err(_) if attempts < self.reconnect_policy.max_attempts => {}
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "Replication connection for slot {} lost ({}); gave up after {} attempts",
+                        self.slot_name, cause, attempts
+                    )));
+                }
+            }
         }
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
-        buf.extend_from_slice(&now.to_be_bytes());
-        buf.push(1); // reply_requested = true (for test)
+        let outage = ReplicationOutage {
+            cause,
+            attempts,
+            duration: started.elapsed(),
+            resumed_from: self.status.confirmed_lsn,
+        };
+        if let Some(on_outage) = &self.on_outage {
+            on_outage(&self.slot_name, &outage);
+        }
+        self.outages.push(outage);
+        Ok(())
+    }
+
+    fn restart(&mut self) -> anyhow::Result<()> {
+        self.conn = libpq::Connection::new(&with_replication_param(&self.conninfo))?;
+        self.last_lsn = self.status.confirmed_lsn;
+        self.status.received_lsn = self.status.confirmed_lsn;
+        self.start()
+    }
+
+    /// Restarts replication from the confirmed position, so changes returned since then are
+    /// sent again. For when they couldn't be applied: reading on would skip them. Replication is
+    /// ended first, releasing the slot, then started on a new connection, since the server
+    /// doesn't stream again on a connection whose logical replication was ended.
+    pub fn rewind(&mut self) -> anyhow::Result<()> {
+        if !self.connection_lost() {
+            self.stop()?;
+        }
+        self.restart()
+    }
+
+    /// Send a standby status update with the current positions.
+    fn send_status(&mut self) -> anyhow::Result<()> {
+        let buf = self
+            .status
+            .update_message(Instant::now(), SystemTime::now());
         self.conn.put_copy_data(&buf)?;
+        self.conn.flush()?;
         Ok(())
     }
 
     pub fn last_lsn(&self) -> crate::logical_replication::message::Lsn {
         self.last_lsn
     }
+
+    /// End replication, discarding messages still in flight, so that the slot is released and
+    /// can be dropped.
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        if !self.streaming {
+            return Ok(());
+        }
+        self.conn.put_copy_end(None)?;
+        self.conn.flush()?;
+        // Blocks until the server ends the copy too
+        while self.conn.copy_data(false).is_ok() {}
+        while self.conn.result().is_some() {}
+        self.streaming = false;
+        Ok(())
+    }
+}
+
+/// Prefix of the message marking the end of the changes to replay at cutover.
+pub const REPLAY_COMPLETE_PREFIX: &str = "postgres-ost";
+/// Content of the message marking the end of the changes to replay at cutover.
+pub const REPLAY_COMPLETE_CONTENT: &str = "replay complete";
+
+/// Whether decoded XLogData is the marker written by `emit_replay_complete_message`: a wal2json
+/// message with exactly its prefix and content, as a format 2 `"action":"M"` record or a
+/// `"kind":"message"` entry in a format 1 transaction.
+pub fn is_replay_complete_message(data: &[u8]) -> bool {
+    let Ok(json) = serde_json::from_slice::<serde_json::Value>(data) else {
+        return false;
+    };
+    let is_marker = |message: &serde_json::Value| {
+        message.get("prefix").and_then(|prefix| prefix.as_str()) == Some(REPLAY_COMPLETE_PREFIX)
+            && message.get("content").and_then(|content| content.as_str())
+                == Some(REPLAY_COMPLETE_CONTENT)
+    };
+    match json.get("action").and_then(|action| action.as_str()) {
+        Some(action) => action == "M" && is_marker(&json),
+        None => json
+            .get("change")
+            .and_then(|changes| changes.as_array())
+            .is_some_and(|changes| {
+                changes.iter().any(|change| {
+                    change.get("kind").and_then(|kind| kind.as_str()) == Some("message")
+                        && is_marker(change)
+                })
+            }),
+    }
 }
 
 /// Emit a logical replication message to the stream using pg_logical_emit_message.
-/// This is used to mark a point in the WAL for cutover replay completion.
-pub fn emit_replay_complete_message(client: &mut postgres::Client) -> anyhow::Result<()> {
-    use crate::version::get_pg_version;
-    let v = get_pg_version()
-        .expect("Postgres version must be set before calling emit_replay_complete_message");
-    let sql = if v.version_num < 170000 {
-        // PG16 and below: omit the flush argument
-        "SELECT pg_logical_emit_message(false, 'postgres-ost', convert_to('replay complete', 'UTF8'));"
-    } else {
-        // PG17+: use the flush argument
-        "SELECT pg_logical_emit_message(false, 'postgres-ost', convert_to('replay complete', 'UTF8'), true);"
+/// This is used to mark a point in the WAL for cutover replay completion. The message is
+/// transactional: it is decoded when its transaction commits, after every transaction that
+/// committed before it, and the commit flushes it to the stream. Call it outside an open
+/// transaction, e.g. on its own connection while the cutover transaction holds the lock.
+pub fn emit_replay_complete_message<C: postgres::GenericClient>(
+    client: &mut C,
+) -> anyhow::Result<()> {
+    client.batch_execute(&format!(
+        "SELECT pg_logical_emit_message(true, '{}', convert_to('{}', 'UTF8'));",
+        REPLAY_COMPLETE_PREFIX, REPLAY_COMPLETE_CONTENT
+    ))?;
+    Ok(())
+}
+
+/// Whether a non-blocking read found no complete message yet, rather than the end of the copy.
+fn is_copy_in_progress(err: &libpq::errors::Error) -> bool {
+    matches!(err, libpq::errors::Error::Backend(msg) if msg == "COPY still in progress")
+}
+
+/// Waits up to `timeout` for `socket` to have data to read, with poll(2).
+fn wait_readable(socket: i32, timeout: Duration) -> anyhow::Result<()> {
+    let mut fd = libc::pollfd {
+        fd: socket,
+        events: libc::POLLIN,
+        revents: 0,
     };
-    client.batch_execute(sql).map_err(|e| {
-        panic!(
-            "emit_replay_complete_message: db error: {} (Postgres version_num: {})",
-            e, v.version_num
-        )
-    })
+    // Round up, so a wait shorter than a millisecond doesn't spin
+    let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int;
+    // SAFETY: `fd` is one valid pollfd that outlives the call
+    if unsafe { libc::poll(&mut fd, 1, millis) } < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+    Ok(())
 }
 
 fn with_replication_param(conninfo: &str) -> String {
@@ -154,7 +420,26 @@ fn with_replication_param(conninfo: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::with_replication_param;
+    use super::{is_replay_complete_message, with_replication_param};
+
+    #[test]
+    fn test_is_replay_complete_message() {
+        let v2 = br#"{"action":"M","transactional":true,"prefix":"postgres-ost","content":"replay complete"}"#;
+        assert!(is_replay_complete_message(v2));
+        let v1 = br#"{"change":[{"kind":"message","transactional":true,"prefix":"postgres-ost","content":"replay complete"}]}"#;
+        assert!(is_replay_complete_message(v1));
+        let row = br#"{"action":"I","schema":"public","table":"postgres-ost","columns":[{"name":"note","type":"text","value":"replay complete"}]}"#;
+        assert!(!is_replay_complete_message(row));
+        let change = br#"{"change":[{"kind":"insert","table":"test_table","columnvalues":["postgres-ost","replay complete"]}]}"#;
+        assert!(!is_replay_complete_message(change));
+        let other_prefix = br#"{"action":"M","transactional":true,"prefix":"postgres-ost-app","content":"replay complete"}"#;
+        assert!(!is_replay_complete_message(other_prefix));
+        let other_content = br#"{"action":"M","transactional":true,"prefix":"postgres-ost","content":"replay complete?"}"#;
+        assert!(!is_replay_complete_message(other_content));
+        let test_decoding =
+            b"message: transactional: 1 prefix: postgres-ost, sz: 15 content:replay complete";
+        assert!(!is_replay_complete_message(test_decoding));
+    }
 
     #[test]
     fn test_with_replication_param_kv() {
@@ -1,9 +1,15 @@
 //! Main binary entry point for postgres-ost.
 
-use anyhow::Result;
-use postgres_ost::args::Strategy;
+use anyhow::{Result, anyhow};
+use postgres_ost::Table;
 use postgres_ost::args::{Command, get_args};
-use postgres_ost::migration_runner::{MigrationRunner, ReplayMode};
+use postgres_ost::args::{PlanFormat, SchemaArgs, Strategy};
+use postgres_ost::config::Config;
+use postgres_ost::logical_replication::ReplicationOutage;
+use postgres_ost::migration_queue::MigrationFile;
+use postgres_ost::migration_runner::{CaptureNames, MigrationRunner, ReplayMode};
+use postgres_ost::parse::Parse;
+use postgres_ost::pg_query_parser::PgQueryParser;
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
@@ -12,35 +18,208 @@ use std::sync::{
 fn strategy_to_replay_mode(strategy: Strategy) -> ReplayMode {
     match strategy {
         Strategy::Triggers => ReplayMode::Log,
-        Strategy::Logical => ReplayMode::Logical, // You can add StreamingLogical if you add a CLI option
+        Strategy::Logical => ReplayMode::Logical,
+        Strategy::Streaming => ReplayMode::StreamingLogical,
     }
 }
 
+/// Applies the connection and schema flags on top of the config file and builds a runner with
+/// the settings for the table `sql` changes.
+fn build_runner(
+    mut config: Config,
+    uri: Option<String>,
+    schemas: SchemaArgs,
+    sql: &str,
+) -> Result<MigrationRunner> {
+    if let Some(uri) = uri {
+        config.connection.uri = Some(uri);
+    }
+    schemas.apply_to(&mut config.schemas);
+    let table = PgQueryParser
+        .extract_main_table(sql)
+        .map(|name| Table::new(&name))
+        .ok_or_else(|| anyhow!("No table found in the SQL"))?;
+    Ok(MigrationRunner::from_config(&config, &table)?.with_outage_handler(Arc::new(report_outage)))
+}
+
+/// Reports a replication outage the streaming strategy recovered from on stderr.
+fn report_outage(slot: &str, outage: &ReplicationOutage) {
+    eprintln!("Slot {}: {}", slot, outage);
+}
+
 fn main() -> Result<()> {
     let args = get_args()?;
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let replay_mode = |strategy: Option<Strategy>, config: &Config| {
+        strategy_to_replay_mode(strategy.or(config.strategy).unwrap_or(Strategy::Triggers))
+    };
     match args.command {
         Command::Migrate {
             uri,
             sql,
             execute,
             strategy,
-            ..
+            atomic_cutover,
+            plan,
+            plan_format,
+            copy,
+            schemas,
+            foreign_keys,
+            tuning,
         } => {
-            let runner = MigrationRunner::new(&uri)?;
-            let replay_mode = strategy_to_replay_mode(strategy);
-            runner.run_migrate(&sql, execute, replay_mode)?;
+            let replay_mode = replay_mode(strategy, &config);
+            foreign_keys.apply_to(&mut config.foreign_keys);
+            if let [first, _, ..] = sql.as_slice() {
+                if plan {
+                    anyhow::bail!("--plan takes a single --sql");
+                }
+                if replay_mode == ReplayMode::StreamingLogical {
+                    anyhow::bail!(
+                        "the streaming strategy takes a single --sql; use the logical strategy to migrate several tables"
+                    );
+                }
+                if copy.row_filter.is_some() {
+                    anyhow::bail!(
+                        "--where takes a single --sql; set `where` per table in the config file instead"
+                    );
+                }
+                if !copy.transform.is_empty() {
+                    anyhow::bail!(
+                        "--transform takes a single --sql; set `transform` per table in the config file instead"
+                    );
+                }
+                if !copy.map.is_empty() {
+                    anyhow::bail!(
+                        "--map takes a single --sql; set `map` per table in the config file instead"
+                    );
+                }
+                // The shared replay gets the top-level settings, not the first table's
+                let mut runner = build_runner(config.clone(), uri, schemas, first)?
+                    .with_settings(config.settings());
+                tuning.clone().apply_to(&mut runner.settings);
+                let reports = runner.run_migrate_tables(
+                    &sql,
+                    execute,
+                    atomic_cutover,
+                    replay_mode,
+                    |table| {
+                        let mut settings = config.settings_for(table);
+                        tuning.clone().apply_to(&mut settings);
+                        settings
+                    },
+                )?;
+                if execute {
+                    for (sql, report) in sql.iter().zip(reports) {
+                        print!("{}\n{}", sql, report);
+                    }
+                }
+                return Ok(());
+            }
+            let sql = &sql[0];
+            let mut runner = build_runner(config, uri, schemas, sql)?;
+            tuning.apply_to(&mut runner.settings);
+            copy.apply_to(&mut runner.settings);
+            if plan {
+                let plan = runner.run_plan(sql, &replay_mode, execute)?;
+                match plan_format {
+                    PlanFormat::Text => print!("{}", plan),
+                    PlanFormat::Json => {
+                        println!("{}", serde_json::to_string_pretty(&plan.to_json())?)
+                    }
+                }
+                return Ok(());
+            }
+            let report = runner.run_migrate(sql, execute, replay_mode)?;
+            if execute {
+                print!("{}", report);
+            }
+        }
+        Command::RunDir {
+            dir,
+            uri,
+            execute,
+            strategy,
+            schemas,
+            foreign_keys,
+            tuning,
+        } => {
+            let replay_mode = replay_mode(strategy, &config);
+            let files = MigrationFile::load_dir(&dir)?;
+            if let Some(uri) = uri {
+                config.connection.uri = Some(uri);
+            }
+            schemas.apply_to(&mut config.schemas);
+            foreign_keys.apply_to(&mut config.foreign_keys);
+            let runner = MigrationRunner::new(&config.connection.conninfo()?)?
+                .with_schema_config(config.schemas.clone())
+                .with_foreign_key_options(config.foreign_keys.clone())
+                .with_outage_handler(Arc::new(report_outage));
+            let report = runner.run_queue(&files, execute, replay_mode, |table| {
+                let mut settings = config.settings_for(table);
+                tuning.clone().apply_to(&mut settings);
+                settings
+            })?;
+            print!("{}", report);
+        }
+        Command::Check {
+            uri,
+            sql,
+            strategy,
+            available_disk_bytes,
+            replica_identity_full,
+            copy,
+            schemas,
+            foreign_keys,
+        } => {
+            let replay_mode = replay_mode(strategy, &config);
+            foreign_keys.apply_to(&mut config.foreign_keys);
+            let mut runner = build_runner(config, uri, schemas, &sql)?;
+            if replica_identity_full {
+                runner.settings.replica_identity_full = true;
+            }
+            copy.apply_to(&mut runner.settings);
+            let report = runner.run_check(&sql, &replay_mode, available_disk_bytes)?;
+            print!("{}", report);
+            if !report.passed() {
+                anyhow::bail!("Preflight checks failed");
+            }
         }
         Command::ReplayOnly {
-            uri, sql, strategy, ..
+            uri,
+            sql,
+            strategy,
+            resume_slot,
+            schemas,
         } => {
-            let runner = MigrationRunner::new(&uri)?;
+            let replay_mode = replay_mode(strategy, &config);
+            let runner = build_runner(config, uri, schemas, &sql)?;
             let stop_replay = Arc::new(AtomicBool::new(false));
             let stop_replay_clone = stop_replay.clone();
             ctrlc::set_handler(move || {
                 stop_replay_clone.store(true, Ordering::Relaxed);
             })?;
-            let replay_mode = strategy_to_replay_mode(strategy);
-            let handle = runner.run_replay_only(&sql, replay_mode, stop_replay);
+            let handle = match resume_slot {
+                Some(slot) => {
+                    if replay_mode != ReplayMode::StreamingLogical {
+                        anyhow::bail!("--resume-slot needs --strategy streaming");
+                    }
+                    let capture = CaptureNames::for_slot(&slot)?;
+                    runner.resume_replay_only(&sql, &capture, stop_replay)
+                }
+                None => {
+                    let capture = CaptureNames::generate();
+                    if replay_mode == ReplayMode::StreamingLogical {
+                        println!(
+                            "Streaming through slot {}; pass --resume-slot {} to pick up after an interruption",
+                            capture.slot, capture.slot
+                        );
+                    }
+                    runner.run_replay_only(&sql, replay_mode, &capture, stop_replay)
+                }
+            };
             handle.join().expect("Replay thread panicked")?;
         }
     }
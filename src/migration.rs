@@ -1,7 +1,14 @@
+use crate::ColumnMap;
+use crate::config::MigrationSettings;
+use crate::dependent_view::DependentView;
+use crate::foreign_key::{ForeignKey, ForeignKeyOptions};
+use crate::identifier::quote_ident;
+use crate::logical_replication::SavedIdentities;
 use crate::parse::Parse;
+use crate::schema_config::{MAX_IDENTIFIER_LENGTH, SchemaConfig};
 use crate::table::Table;
-use anyhow::Result;
-use postgres::Client;
+use crate::table_metadata::{MetadataReport, TableMetadata};
+use anyhow::{Context, Result, anyhow, bail};
 use postgres::GenericClient;
 use postgres::types::Type;
 
@@ -20,24 +27,44 @@ pub struct Migration {
     pub log_table: Table,
     pub old_table: Table,
     pub primary_key: PrimaryKeyInfo,
+    pub schema_config: SchemaConfig,
+    /// Foreign keys on other tables referencing the migrated table
+    pub inbound_foreign_keys: Vec<ForeignKey>,
 }
 
 impl Migration {
-    pub fn new(sql: &str, client: &mut Client) -> Self {
+    pub fn new<C: GenericClient>(sql: &str, client: &mut C) -> Result<Self> {
+        Self::new_with_schema_config(sql, SchemaConfig::default(), client)
+    }
+
+    /// Like `new`, but places the tool's tables according to `schema_config`. Fails when the
+    /// SQL names no table, the table doesn't exist, or its primary key isn't supported.
+    pub fn new_with_schema_config<C: GenericClient>(
+        sql: &str,
+        schema_config: SchemaConfig,
+        client: &mut C,
+    ) -> Result<Self> {
+        schema_config.validate()?;
         let parser = crate::pg_query_parser::PgQueryParser;
         let table_name = parser
             .extract_main_table(sql)
-            .expect("Failed to extract main table");
-        let table = Table::new(&table_name);
-        let shadow_table = Table::new(&format!("post_migrations.{}", table_name));
-        let log_table = Table::new(&format!("post_migrations.{}_log", table_name));
-        let old_table = Table::new(&format!("post_migrations.{}_old", table_name));
-        let primary_key = table
-            .get_primary_key_info(client)
-            .expect("Failed to detect primary key");
-        let shadow_table_migrate_sql =
-            parser.migrate_shadow_table_statement(sql, &table_name, &shadow_table.to_string());
-        Migration {
+            .ok_or_else(|| anyhow!("No table found in the migration SQL"))?;
+        let table = Table::new(&table_name)
+            .resolve_schema(client)
+            .with_context(|| format!("Failed to find the table {}", table_name))?;
+        let shadow_table = schema_config.shadow_table(&table);
+        let log_table = schema_config.log_table(&table);
+        let old_table = unused_archive_table(schema_config.archive_table(&table), client)
+            .context("Failed to pick the archive table name")?;
+        let primary_key = table.get_primary_key_info(client)?;
+        let inbound_foreign_keys =
+            ForeignKey::inbound(&table, client).context("Failed to detect inbound foreign keys")?;
+        let shadow_table_migrate_sql = parser.migrate_shadow_table_statement(
+            sql,
+            &table.to_string(),
+            &shadow_table.to_string(),
+        );
+        Ok(Migration {
             sql: sql.to_string(),
             shadow_table_migrate_sql,
             table,
@@ -45,52 +72,356 @@ impl Migration {
             log_table,
             old_table,
             primary_key,
-        }
+            schema_config,
+            inbound_foreign_keys,
+        })
+    }
+
+    /// The same migration built in `shadow_table` instead, for rehearsals that mustn't touch
+    /// the shadow table of a run in progress.
+    pub fn with_shadow_table(mut self, shadow_table: Table) -> Self {
+        self.shadow_table_migrate_sql = crate::pg_query_parser::PgQueryParser
+            .migrate_shadow_table_statement(
+                &self.sql,
+                &self.table.to_string(),
+                &shadow_table.to_string(),
+            );
+        self.shadow_table = shadow_table;
+        self
+    }
+
+    /// Maps the main table's columns onto the migrated shadow table. Renames and drops come
+    /// from the migration SQL, overridden by `settings.column_mappings`. Columns converted with
+    /// `ALTER COLUMN ... TYPE ... USING` are filled from their `USING` expression, then each of
+    /// `settings.transforms` fills its shadow column from its expression. A map dropping the
+    /// primary key is refused.
+    pub fn column_map<C: GenericClient>(
+        &self,
+        settings: &MigrationSettings,
+        client: &mut C,
+    ) -> Result<ColumnMap> {
+        let column_map = ColumnMap::new(
+            &self.table,
+            &self.shadow_table,
+            &self.column_mappings(&settings.column_mappings),
+            client,
+        )?
+        .with_expressions(&self.using_expressions())
+        .with_expressions(&settings.transforms);
+        column_map.shadow_key(&self.primary_key.name)?;
+        Ok(column_map)
     }
 
-    pub fn create_shadow_table(&self, client: &mut Client) -> Result<(), anyhow::Error> {
-        let create_table_statement = format!(
+    /// The renames and drops in the migration SQL, with `overrides` replacing the mapping of
+    /// the main columns they name.
+    pub fn column_mappings(
+        &self,
+        overrides: &[(String, Option<String>)],
+    ) -> Vec<(String, Option<String>)> {
+        let mut mappings = crate::pg_query_parser::PgQueryParser.column_mappings(&self.sql);
+        crate::config::override_pairs(&mut mappings, overrides.iter().cloned());
+        mappings
+    }
+
+    /// The `USING` expressions in the migration SQL, by column.
+    pub fn using_expressions(&self) -> Vec<(String, String)> {
+        crate::pg_query_parser::PgQueryParser.using_expressions(&self.sql)
+    }
+
+    pub fn create_shadow_table_statement(&self) -> String {
+        format!(
             "CREATE TABLE {} (LIKE {} INCLUDING ALL)",
             self.shadow_table, self.table
-        );
-        client.simple_query(&create_table_statement)?;
+        )
+    }
+
+    pub fn create_shadow_table<C: GenericClient>(
+        &self,
+        client: &mut C,
+    ) -> Result<(), anyhow::Error> {
+        client.simple_query(&self.create_shadow_table_statement())?;
+        // LIKE doesn't copy policies. Create them before the migration runs so that Postgres
+        // follows any column renames in their expressions.
+        for statement in
+            TableMetadata::fetch(&self.table, client)?.policy_statements(&self.shadow_table)
+        {
+            client.batch_execute(&statement)?;
+        }
         Ok(())
     }
 
-    pub fn migrate_shadow_table(&self, client: &mut Client) -> Result<(), anyhow::Error> {
+    pub fn migrate_shadow_table<C: GenericClient>(
+        &self,
+        client: &mut C,
+    ) -> Result<(), anyhow::Error> {
         client.batch_execute(&self.shadow_table_migrate_sql)?;
         Ok(())
     }
 
-    pub fn setup_migration(&self, client: &mut Client) -> anyhow::Result<()> {
+    pub fn setup_migration<C: GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
         self.create_post_migrations_schema(client)?;
         self.shadow_table.drop_if_exists(client)?;
         self.create_shadow_table(client)?;
         self.migrate_shadow_table(client)?;
+        self.check_dependent_views(client)?;
         // No replay setup here; caller is responsible for replay setup if needed
         Ok(())
     }
 
+    /// Archives the original table as `old_table` and moves the shadow table into its place.
+    /// Renames happen while each table is in the source schema so that the namespaced names
+    /// used in the tool schemas never clash with other tables. Sequences owned by the original
+    /// table are handed over to the new one, see `release_sequences`.
     pub fn swap_tables<C: GenericClient>(&self, client: &mut C) -> Result<(), anyhow::Error> {
-        let move_old = format!(
-            "ALTER TABLE public.{table} SET SCHEMA post_migrations_old;",
-            table = self.table
-        );
-        client.batch_execute(&move_old)?;
-        // Move the shadow table into public schema
-        let move_shadow = format!(
-            "ALTER TABLE post_migrations.{table} SET SCHEMA public;",
-            table = self.table
-        );
-        client.batch_execute(&move_shadow)?;
+        let rehome = self.release_sequences(client)?;
+        for statement in self.archive_collision_renames(client)? {
+            client.batch_execute(&statement)?;
+        }
+        for statement in self.swap_statements() {
+            client.batch_execute(&statement)?;
+        }
+        for statement in rehome {
+            client.batch_execute(&statement)?;
+        }
+        Ok(())
+    }
+
+    /// The renames and schema moves performed by `swap_tables`, without the sequence handover.
+    pub fn swap_statements(&self) -> Vec<String> {
+        let schema = self.source_schema();
+        let mut statements = Vec::new();
+        let archived = Table::from_parts(Some(schema), &self.old_table.name);
+        if archived.name != self.table.name {
+            statements.push(format!(
+                "ALTER TABLE {table} RENAME TO {name};",
+                table = self.table,
+                name = quote_ident(&archived.name)
+            ));
+        }
+        statements.push(format!(
+            "ALTER TABLE {table} SET SCHEMA {old_schema};",
+            table = archived,
+            old_schema = quote_ident(self.old_table.schema.as_deref().unwrap_or_default())
+        ));
+        // Move the shadow table into the source schema
+        statements.push(format!(
+            "ALTER TABLE {shadow} SET SCHEMA {schema};",
+            shadow = self.shadow_table,
+            schema = quote_ident(schema)
+        ));
+        if self.shadow_table.name != self.table.name {
+            statements.push(format!(
+                "ALTER TABLE {shadow} RENAME TO {name};",
+                shadow = Table::from_parts(Some(schema), &self.shadow_table.name),
+                name = quote_ident(&self.table.name)
+            ));
+        }
+        statements
+    }
+
+    /// Renames the original table's indexes and owned sequences whose names are already taken
+    /// in the archive schema, which happens when an earlier migration of the same table archived
+    /// its copy there. They get the first free numbered suffix.
+    fn archive_collision_renames<C: GenericClient>(&self, client: &mut C) -> Result<Vec<String>> {
+        let archive_schema = self.old_table.schema.as_deref().unwrap_or_default();
+        let rows = client.query(
+            "SELECT c.relname::text, c.relkind = 'S' FROM pg_class c
+             WHERE c.oid IN (SELECT indexrelid FROM pg_index WHERE indrelid = ($1)::text::regclass)
+                OR c.oid IN (SELECT objid FROM pg_depend
+                             WHERE classid = 'pg_class'::regclass AND refclassid = 'pg_class'::regclass
+                               AND refobjid = ($1)::text::regclass AND deptype IN ('a', 'i'))
+                   AND c.relkind = 'S'
+             ORDER BY 1",
+            &[&self.table.to_string()],
+        )?;
+        let mut renames = Vec::new();
+        for row in rows {
+            let name: String = row.get(0);
+            let sequence: bool = row.get(1);
+            let taken = |candidate: &str, client: &mut C| -> Result<bool> {
+                Table::from_parts(Some(archive_schema), candidate).exists(client)
+            };
+            if !taken(&name, client)? {
+                continue;
+            }
+            let mut n = 2;
+            while taken(&format!("{}_{}", name, n), client)? {
+                n += 1;
+            }
+            renames.push(format!(
+                "ALTER {} {} RENAME TO {}",
+                if sequence { "SEQUENCE" } else { "INDEX" },
+                Table::from_parts(Some(self.source_schema()), &name),
+                quote_ident(&format!("{}_{}", name, n))
+            ));
+        }
+        Ok(renames)
+    }
+
+    /// Prepares the original table's owned sequences for the swap, returning the statements that
+    /// attach them to the new table once it's in place.
+    ///
+    /// A serial sequence the shadow table's default still draws from (`LIKE` copies the
+    /// `nextval` default) is detached, so it stays in the source schema instead of moving to
+    /// the archive schema with the old table, and is owned by the new table after the swap.
+    /// Where the shadow table has its own sequence for the column (identity columns, or a
+    /// recreated serial column), that sequence is advanced to the original's position and
+    /// given its name.
+    fn release_sequences<C: GenericClient>(&self, client: &mut C) -> Result<Vec<String>> {
+        let schema = self.source_schema();
+        let shadow_sequences = self.shadow_table.owned_sequences(client)?;
+        let mut rehome = Vec::new();
+        for owned in self.table.owned_sequences(client)? {
+            if !owned.identity
+                && let Some(column) = self
+                    .shadow_table
+                    .columns_using_sequence(&owned.sequence, client)?
+                    .first()
+            {
+                client
+                    .batch_execute(&format!("ALTER SEQUENCE {} OWNED BY NONE", owned.sequence))?;
+                rehome.push(format!(
+                    "ALTER SEQUENCE {} OWNED BY {}.{}",
+                    owned.sequence,
+                    self.table,
+                    quote_ident(column)
+                ));
+            } else if let Some(shadow_owned) = shadow_sequences
+                .iter()
+                .find(|shadow_owned| shadow_owned.column == owned.column)
+            {
+                let row = client.query_one(
+                    &format!("SELECT last_value, is_called FROM {}", owned.sequence),
+                    &[],
+                )?;
+                let last_value: i64 = row.get(0);
+                let is_called: bool = row.get(1);
+                client.execute(
+                    "SELECT setval(($1)::text::regclass, $2, $3)",
+                    &[&shadow_owned.sequence.to_string(), &last_value, &is_called],
+                )?;
+                // The shadow's sequence moves into the source schema along with the table, and
+                // the original's name is free once the old table has moved out
+                if shadow_owned.sequence.name != owned.sequence.name {
+                    rehome.push(format!(
+                        "ALTER SEQUENCE {} RENAME TO {}",
+                        Table::from_parts(Some(schema), &shadow_owned.sequence.name),
+                        quote_ident(&owned.sequence.name)
+                    ));
+                }
+            }
+        }
+        Ok(rehome)
+    }
+
+    /// Copies the original table's owner, grants, row level security settings, policies,
+    /// comment and replica identity onto the shadow table. Run at cutover, under the table lock,
+    /// so late changes are picked up. A table switched to REPLICA IDENTITY FULL for the capture
+    /// passes on the identity saved before the switch.
+    pub fn copy_table_metadata<C: GenericClient>(
+        &self,
+        column_map: &ColumnMap,
+        client: &mut C,
+    ) -> Result<MetadataReport> {
+        let mut metadata = TableMetadata::fetch(&self.table, client)?;
+        if let Some(identity) =
+            SavedIdentities::new(&self.schema_config).load(&self.table, client)?
+        {
+            metadata.replica_identity = identity;
+        }
+        metadata.replica_identity = metadata.replica_identity.for_copy(
+            &self.table,
+            &self.shadow_table,
+            column_map,
+            client,
+        )?;
+        metadata.apply(&self.shadow_table, Some(column_map), client)
+    }
+
+    /// Points the inbound foreign keys at the swapped-in table. Run after `swap_tables` in the
+    /// cutover transaction; the recreated constraints are `NOT VALID` so no rows are scanned
+    /// under the lock.
+    pub fn swap_foreign_keys<C: GenericClient>(
+        &self,
+        options: &ForeignKeyOptions,
+        column_map: &ColumnMap,
+        client: &mut C,
+    ) -> Result<()> {
+        for foreign_key in &self.inbound_foreign_keys {
+            for statement in foreign_key.cutover_statements(options, &self.table, column_map)? {
+                client.batch_execute(&statement)?;
+            }
+        }
         Ok(())
     }
 
-    fn create_post_migrations_schema(&self, client: &mut Client) -> anyhow::Result<()> {
-        client.simple_query("CREATE SCHEMA IF NOT EXISTS post_migrations;")?;
-        client.simple_query("CREATE SCHEMA IF NOT EXISTS post_migrations_old;")?;
+    /// Validates the recreated foreign keys. Run after the cutover has committed, as validation
+    /// scans the referencing tables.
+    pub fn validate_foreign_keys<C: GenericClient>(&self, client: &mut C) -> Result<()> {
+        for foreign_key in &self.inbound_foreign_keys {
+            client.batch_execute(&foreign_key.validate_statement())?;
+        }
         Ok(())
     }
+
+    /// Fails if a view or materialized view depending on the table couldn't be recreated against
+    /// the migrated shadow table.
+    pub fn check_dependent_views<C: GenericClient>(&self, client: &mut C) -> Result<()> {
+        DependentView::check(&self.table, &self.shadow_table, client)
+    }
+
+    /// Captures the views depending on the table. Run in the cutover transaction before
+    /// `swap_tables`, so the definitions still name the original table.
+    pub fn dependent_views<C: GenericClient>(&self, client: &mut C) -> Result<Vec<DependentView>> {
+        DependentView::find(&self.table, client)
+    }
+
+    /// Recreates views captured by `dependent_views` so they read from the swapped-in table.
+    pub fn recreate_dependent_views<C: GenericClient>(
+        &self,
+        views: &[DependentView],
+        client: &mut C,
+    ) -> Result<()> {
+        for view in views {
+            view.recreate(client)?;
+        }
+        Ok(())
+    }
+
+    /// The schema the migrated table lives in.
+    pub fn source_schema(&self) -> &str {
+        self.table.schema.as_deref().unwrap_or("public")
+    }
+
+    pub(crate) fn create_post_migrations_schema<C: GenericClient>(
+        &self,
+        client: &mut C,
+    ) -> anyhow::Result<()> {
+        for statement in self.schema_config.create_statements() {
+            client.simple_query(&statement)?;
+        }
+        Ok(())
+    }
+}
+
+/// `archive`, or when an earlier migration of the same table already archived a copy there,
+/// the first free name with a numbered suffix, so earlier copies are kept.
+fn unused_archive_table<C: GenericClient>(archive: Table, client: &mut C) -> Result<Table> {
+    let mut candidate = archive.clone();
+    for n in 2.. {
+        if !candidate.exists(client)? {
+            break;
+        }
+        candidate = archive.with_suffix(&format!("_{}", n));
+        if candidate.name.len() > MAX_IDENTIFIER_LENGTH {
+            bail!(
+                "No free archive name for {} within {} bytes",
+                archive,
+                MAX_IDENTIFIER_LENGTH
+            );
+        }
+    }
+    Ok(candidate)
 }
 
 // Remove the moved tests from migration.rs
@@ -0,0 +1,191 @@
+// src/migration_queue.rs
+// A directory of numbered SQL files applied one online migration at a time, with the completed
+// ones recorded in a state table so reruns pick up where the last run stopped.
+
+use crate::identifier::quote_literal;
+use crate::parse::Parse;
+use crate::pg_query_parser::PgQueryParser;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use anyhow::{Context, Result, bail};
+use postgres::GenericClient;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Name of the table recording completed migrations, in the state schema.
+pub const STATE_TABLE_NAME: &str = "migration_state";
+
+/// One SQL file of the queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationFile {
+    /// The file name without its extension, recorded once the migration completes
+    pub version: String,
+    pub path: PathBuf,
+    pub sql: String,
+    /// The table the migration changes, as named in the SQL
+    pub table: Table,
+}
+
+impl MigrationFile {
+    /// Reads every `.sql` file in `dir`, ordered by their leading number and then by name, and
+    /// checks that each one names the table it migrates. Nothing runs if any file is invalid.
+    pub fn load_dir(dir: &Path) -> Result<Vec<MigrationFile>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read migrations directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "sql") {
+                paths.push(path);
+            }
+        }
+        paths.sort_by_cached_key(|path| sort_key(&version(path)));
+        let mut files = Vec::new();
+        for path in paths {
+            let sql = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let Some(table) = PgQueryParser.extract_main_table(&sql) else {
+                bail!("{}: no table to migrate found", path.display());
+            };
+            files.push(MigrationFile {
+                version: version(&path),
+                table: Table::new(&table),
+                path,
+                sql,
+            });
+        }
+        Ok(files)
+    }
+}
+
+fn version(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Orders `2_x` before `10_x`; files without a leading number go last.
+fn sort_key(version: &str) -> (u64, String) {
+    let digits: String = version.chars().take_while(|c| c.is_ascii_digit()).collect();
+    (digits.parse().unwrap_or(u64::MAX), version.to_string())
+}
+
+/// The table recording which queue files have completed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateTable {
+    pub table: Table,
+}
+
+impl StateTable {
+    pub fn new(schema_config: &SchemaConfig) -> Self {
+        StateTable {
+            table: Table::from_parts(Some(&schema_config.state_schema), STATE_TABLE_NAME),
+        }
+    }
+
+    pub fn create_statement(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (version TEXT PRIMARY KEY, table_name TEXT NOT NULL, sql TEXT NOT NULL, completed_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+            self.table
+        )
+    }
+
+    /// Creates the tool schemas and the state table if they don't exist yet.
+    pub fn create<C: GenericClient>(
+        &self,
+        schema_config: &SchemaConfig,
+        client: &mut C,
+    ) -> Result<()> {
+        for statement in schema_config.create_statements() {
+            client.batch_execute(&statement)?;
+        }
+        client.batch_execute(&self.create_statement())?;
+        Ok(())
+    }
+
+    /// Versions recorded as completed. Empty when the state table doesn't exist yet.
+    pub fn completed<C: GenericClient>(&self, client: &mut C) -> Result<HashSet<String>> {
+        if !self.table.exists(client)? {
+            return Ok(HashSet::new());
+        }
+        Ok(client
+            .query(&format!("SELECT version FROM {}", self.table), &[])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    /// Statement recording `file` as completed, run in its cutover transaction so the swap and
+    /// the record commit together.
+    pub fn record_statement(&self, file: &MigrationFile) -> String {
+        format!(
+            "INSERT INTO {} (version, table_name, sql) VALUES ({}, {}, {})",
+            self.table,
+            quote_literal(&file.version),
+            quote_literal(&file.table.to_string()),
+            quote_literal(&file.sql)
+        )
+    }
+}
+
+/// What a queue run did, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueReport {
+    pub skipped: Vec<String>,
+    pub applied: Vec<String>,
+    /// Files still to run; all pending files when nothing was executed
+    pub pending: Vec<String>,
+}
+
+impl std::fmt::Display for QueueReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for version in &self.skipped {
+            writeln!(f, "skipped {} (already applied)", version)?;
+        }
+        for version in &self.applied {
+            writeln!(f, "applied {}", version)?;
+        }
+        for version in &self.pending {
+            writeln!(f, "pending {}", version)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_dir_orders_numerically_and_validates() {
+        let dir = std::env::temp_dir().join(format!("ost_queue_{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("10_later.sql"), "ALTER TABLE b ADD COLUMN x INT").unwrap();
+        std::fs::write(dir.join("2_first.sql"), "ALTER TABLE a ADD COLUMN x INT").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a migration").unwrap();
+        let files = MigrationFile::load_dir(&dir).unwrap();
+        let versions: Vec<&str> = files.iter().map(|file| file.version.as_str()).collect();
+        assert_eq!(versions, vec!["2_first", "10_later"]);
+        assert_eq!(files[1].table, Table::new("b"));
+
+        std::fs::write(dir.join("3_broken.sql"), "SELECT 1").unwrap();
+        let err = MigrationFile::load_dir(&dir).unwrap_err();
+        assert!(err.to_string().contains("3_broken.sql"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_statement_quotes_the_file() {
+        let state = StateTable::new(&SchemaConfig::default());
+        let file = MigrationFile {
+            version: "2_note".to_string(),
+            path: PathBuf::from("2_note.sql"),
+            sql: "ALTER TABLE a ADD COLUMN note TEXT DEFAULT 'n/a'".to_string(),
+            table: Table::new("a"),
+        };
+        assert_eq!(
+            state.record_statement(&file),
+            "INSERT INTO post_migrations_state.migration_state (version, table_name, sql) VALUES ('2_note', 'a', 'ALTER TABLE a ADD COLUMN note TEXT DEFAULT ''n/a''')"
+        );
+    }
+}
@@ -2,26 +2,44 @@
 use std::sync::{Arc, atomic::AtomicBool};
 
 // External crate imports
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use r2d2::Pool;
 use r2d2_postgres::{PostgresConnectionManager, postgres::NoTls as R2d2NoTls};
 
 // Internal module imports
 use crate::Replay;
 use crate::backfill::Backfill;
+use crate::check::{CheckOptions, CheckReport};
 use crate::column_map::ColumnMap;
-use crate::logical_replication::{Publication, Slot};
+use crate::config::{Config, MigrationSettings};
+use crate::foreign_key::ForeignKeyOptions;
+use crate::logical_replication::{
+    OutageHandler, Publication, ReplayProgress, Slot, Wal2JsonFormat,
+};
 use crate::migration::Migration;
+use crate::migration_queue::{MigrationFile, QueueReport, StateTable};
 use crate::orchestrator::MigrationOrchestrator;
-use crate::replay::log_table_replay::LogTableReplay;
+use crate::plan::{Plan, PlanOptions};
+use crate::replay::log_table_replay::{LogTableReplay, LogTableReplaySet};
 use crate::replay::logical_replay::LogicalReplay;
+use crate::replay::shared_logical_replay::{ReplayTarget, SharedLogicalReplay};
 use crate::replay::streaming_logical_replay::StreamingLogicalReplay;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use crate::table_metadata::MetadataReport;
 
+#[derive(Clone)]
 pub struct MigrationRunner {
     pub pool: Pool<PostgresConnectionManager<R2d2NoTls>>,
     pub conninfo: String,
+    pub schema_config: SchemaConfig,
+    pub foreign_key_options: ForeignKeyOptions,
+    pub settings: MigrationSettings,
+    /// Told about replication outages the streaming strategy recovered from
+    pub on_outage: Option<OutageHandler>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ReplayMode {
     Log,
     Logical,
@@ -46,78 +64,433 @@ impl MigrationRunner {
         Ok(Self {
             pool,
             conninfo: uri.to_string(),
+            schema_config: SchemaConfig::default(),
+            foreign_key_options: ForeignKeyOptions::default(),
+            settings: MigrationSettings::default(),
+            on_outage: None,
         })
     }
 
     pub fn from_pool(pool: Pool<PostgresConnectionManager<R2d2NoTls>>, conninfo: String) -> Self {
-        Self { pool, conninfo }
+        Self {
+            pool,
+            conninfo,
+            schema_config: SchemaConfig::default(),
+            foreign_key_options: ForeignKeyOptions::default(),
+            settings: MigrationSettings::default(),
+            on_outage: None,
+        }
+    }
+
+    /// Builds a runner from a config file's connection, schema, foreign key and per-table
+    /// settings for `table`, whose schema is resolved through the search path first.
+    pub fn from_config(config: &Config, table: &Table) -> Result<Self> {
+        let runner = Self::new(&config.connection.conninfo()?)?
+            .with_schema_config(config.schemas.clone())
+            .with_foreign_key_options(config.foreign_keys.clone());
+        let table = table.resolve_schema(&mut *runner.pool.get()?)?;
+        Ok(runner.with_settings(config.settings_for(&table)))
+    }
+
+    /// Use `schema_config` for the schemas and names of the tool's tables.
+    pub fn with_schema_config(mut self, schema_config: SchemaConfig) -> Self {
+        self.schema_config = schema_config;
+        self
+    }
+
+    /// Use `foreign_key_options` for foreign keys referencing the migrated table.
+    pub fn with_foreign_key_options(mut self, foreign_key_options: ForeignKeyOptions) -> Self {
+        self.foreign_key_options = foreign_key_options;
+        self
+    }
+
+    /// Use `settings` for batch sizes, throttles and the cutover.
+    pub fn with_settings(mut self, settings: MigrationSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Call `on_outage` when a streaming replay recovers from a lost replication connection.
+    pub fn with_outage_handler(mut self, on_outage: OutageHandler) -> Self {
+        self.on_outage = Some(on_outage);
+        self
+    }
+
+    /// Runs the preflight checks for `sql` without creating anything.
+    pub fn run_check(
+        &self,
+        sql: &str,
+        mode: &ReplayMode,
+        available_disk_bytes: Option<i64>,
+    ) -> Result<CheckReport> {
+        let mut client = self.pool.get()?;
+        let options = CheckOptions {
+            mode,
+            schema_config: &self.schema_config,
+            available_disk_bytes,
+            settings: &self.settings,
+            foreign_key_options: &self.foreign_key_options,
+        };
+        crate::check::run_checks(sql, &options, &mut client)
+    }
+
+    /// Builds the plan for `sql` from the catalogs, without creating anything.
+    pub fn run_plan(&self, sql: &str, mode: &ReplayMode, execute: bool) -> Result<Plan> {
+        let mut client = self.pool.get()?;
+        let options = PlanOptions {
+            mode,
+            execute,
+            schema_config: &self.schema_config,
+            foreign_key_options: &self.foreign_key_options,
+            settings: &self.settings,
+        };
+        Plan::build(sql, &options, &mut *client)
     }
 
     pub fn run_schema_migration(&self, sql: &str) -> Result<(Migration, ColumnMap)> {
         let mut client = self.pool.get()?;
-        let migration = Migration::new(sql, &mut client);
-        migration.setup_migration(&mut client)?;
-        let column_map = ColumnMap::new(&migration.table, &migration.shadow_table, &mut *client);
+        let migration =
+            Migration::new_with_schema_config(sql, self.schema_config.clone(), &mut *client)?;
+        migration.setup_migration(&mut *client)?;
+        let column_map = migration.column_map(&self.settings, &mut *client)?;
+        Ok((migration, column_map))
+    }
+
+    /// The migration for `sql` with its shadow table as a previous run left it.
+    fn load_schema_migration(&self, sql: &str) -> Result<(Migration, ColumnMap)> {
+        let mut client = self.pool.get()?;
+        let migration =
+            Migration::new_with_schema_config(sql, self.schema_config.clone(), &mut *client)?;
+        if !migration.shadow_table.exists(&mut *client)? {
+            bail!(
+                "Shadow table {} doesn't exist; there is no run to resume",
+                migration.shadow_table
+            );
+        }
+        let column_map = migration.column_map(&self.settings, &mut *client)?;
         Ok((migration, column_map))
     }
 
-    pub fn run_migrate(&self, sql: &str, execute: bool, mode: ReplayMode) -> Result<()> {
+    /// Runs a full migration, returning what table metadata was copied onto the new table.
+    pub fn run_migrate(
+        &self,
+        sql: &str,
+        execute: bool,
+        mode: ReplayMode,
+    ) -> Result<MetadataReport> {
         let (migration, column_map) = self.run_schema_migration(sql)?;
-        self.run_replay_setup(&migration, &column_map)?;
-        let orchestrator = MigrationOrchestrator::new(migration.clone(), self.pool.clone());
-        match self.build_replay(&migration, &column_map, mode) {
-            ReplayKind::Logical(replay) => {
-                orchestrator.orchestrate(execute, column_map, replay)?;
+        let orchestrator = MigrationOrchestrator::new(migration.clone(), self.pool.clone())
+            .with_foreign_key_options(self.foreign_key_options.clone())
+            .with_settings(self.settings.clone());
+        match self.build_and_setup_replay(&migration, &column_map, mode)? {
+            ReplayKind::Logical(replay) => orchestrator.orchestrate(execute, column_map, replay),
+            ReplayKind::Log(replay) => orchestrator.orchestrate(execute, column_map, replay),
+            ReplayKind::StreamingLogical(replay) => {
+                orchestrator.orchestrate(execute, column_map, replay)
             }
-            ReplayKind::Log(replay) => {
-                orchestrator.orchestrate(execute, column_map, replay)?;
+        }
+    }
+
+    /// Migrates the tables changed by `sqls` together, one statement per table. Under the logical
+    /// strategy one slot and publication capture every table; with triggers each table gets its
+    /// own log table. Each table is backfilled in parallel and cut over in turn, using the
+    /// settings `settings_for` gives for it, and its metadata report is returned in order.
+    /// With `atomic` the tables are cut over together in one transaction instead. The replay
+    /// polls every table at once, so its interval, and under the logical strategy its batch size,
+    /// come from the runner's own settings rather than any one table's.
+    pub fn run_migrate_tables(
+        &self,
+        sqls: &[String],
+        execute: bool,
+        atomic: bool,
+        mode: ReplayMode,
+        settings_for: impl Fn(&Table) -> MigrationSettings,
+    ) -> Result<Vec<MetadataReport>> {
+        if mode == ReplayMode::StreamingLogical {
+            bail!("The streaming strategy migrates a single table; use the logical strategy");
+        }
+        let migrations = {
+            let mut client = self.pool.get()?;
+            let migrations: Vec<Migration> = sqls
+                .iter()
+                .map(|sql| {
+                    Migration::new_with_schema_config(sql, self.schema_config.clone(), &mut *client)
+                })
+                .collect::<Result<_>>()?;
+            for (index, migration) in migrations.iter().enumerate() {
+                if migrations[..index]
+                    .iter()
+                    .any(|earlier| earlier.table == migration.table)
+                {
+                    bail!(
+                        "{} is changed by more than one statement; combine them into one",
+                        migration.table
+                    );
+                }
+                if let Some(earlier) = migrations[..index]
+                    .iter()
+                    .find(|earlier| earlier.shadow_table == migration.shadow_table)
+                {
+                    bail!(
+                        "{} and {} would share the shadow table {}; use a table name template with {{schema}} or {{namespaced}}",
+                        earlier.table,
+                        migration.table,
+                        migration.shadow_table
+                    );
+                }
             }
-            ReplayKind::StreamingLogical(_) => {
-                panic!(
-                    "StreamingLogicalReplay is not supported in orchestrator context. Use single-threaded context only."
+            migrations
+        };
+        let mut tables = Vec::new();
+        for migration in migrations {
+            let mut client = self.pool.get()?;
+            migration.setup_migration(&mut *client)?;
+            let settings = settings_for(&migration.table);
+            let column_map = migration.column_map(&settings, &mut *client)?;
+            let orchestrator = MigrationOrchestrator::new(migration.clone(), self.pool.clone())
+                .with_foreign_key_options(self.foreign_key_options.clone())
+                .with_settings(settings);
+            tables.push((orchestrator, column_map));
+        }
+        let replay_interval = self.settings.replay_interval;
+        match mode {
+            ReplayMode::Logical => {
+                let capture = CaptureNames::generate();
+                let slot = wal2json_slot(
+                    capture.slot,
+                    tables
+                        .iter()
+                        .map(|(orchestrator, _)| orchestrator.migration.table.clone())
+                        .collect(),
                 );
+                let publication = Publication::for_tables(
+                    capture.publication,
+                    tables
+                        .iter()
+                        .map(|(orchestrator, _)| orchestrator.migration.table.clone())
+                        .collect(),
+                    slot.clone(),
+                )
+                .with_schema_config(&self.schema_config);
+                let publication =
+                    tables
+                        .iter()
+                        .fold(publication, |publication, (orchestrator, _)| {
+                            publication.with_full_identity_for(
+                                &orchestrator.migration.table,
+                                orchestrator.settings.replica_identity_full,
+                            )
+                        });
+                let targets = tables
+                    .iter()
+                    .map(|(orchestrator, column_map)| ReplayTarget {
+                        table: orchestrator.migration.table.clone(),
+                        shadow_table: orchestrator.migration.shadow_table.clone(),
+                        column_map: column_map.clone(),
+                        primary_key: orchestrator.migration.primary_key.clone(),
+                        row_filter: orchestrator.settings.row_filter.clone(),
+                    })
+                    .collect();
+                let replay = SharedLogicalReplay {
+                    slot,
+                    publication,
+                    targets,
+                    batch_size: self.settings.replay_batch_size,
+                };
+                replay.setup(&mut *self.pool.get()?)?;
+                MigrationOrchestrator::orchestrate_tables(
+                    &tables,
+                    execute,
+                    atomic,
+                    replay,
+                    replay_interval,
+                )
+            }
+            ReplayMode::Log => {
+                let replay = LogTableReplaySet {
+                    replays: tables
+                        .iter()
+                        .map(|(orchestrator, column_map)| {
+                            match self.build_replay_with(
+                                &orchestrator.migration,
+                                column_map,
+                                ReplayMode::Log,
+                                &orchestrator.settings,
+                                &CaptureNames::generate(),
+                            )? {
+                                ReplayKind::Log(replay) => Ok(replay),
+                                _ => unreachable!("the log mode builds a log table replay"),
+                            }
+                        })
+                        .collect::<Result<_>>()?,
+                };
+                replay.setup(&mut *self.pool.get()?)?;
+                MigrationOrchestrator::orchestrate_tables(
+                    &tables,
+                    execute,
+                    atomic,
+                    replay,
+                    replay_interval,
+                )
+            }
+            ReplayMode::StreamingLogical => {
+                unreachable!("the streaming strategy is refused before any setup")
             }
         }
-        Ok(())
     }
 
+    /// Applies the queue files not yet recorded in the state table, in order, each as its own
+    /// migration using the settings `settings_for` gives for its table, with the table's schema
+    /// resolved. The first failure stops the queue. Without `execute` nothing runs and the
+    /// pending files are reported.
+    pub fn run_queue(
+        &self,
+        files: &[MigrationFile],
+        execute: bool,
+        mode: ReplayMode,
+        settings_for: impl Fn(&Table) -> MigrationSettings,
+    ) -> Result<QueueReport> {
+        let state = StateTable::new(&self.schema_config);
+        let completed = state.completed(&mut *self.pool.get()?)?;
+        let mut report = QueueReport::default();
+        for file in files {
+            if completed.contains(&file.version) {
+                report.skipped.push(file.version.clone());
+            } else {
+                report.pending.push(file.version.clone());
+            }
+        }
+        if !execute {
+            return Ok(report);
+        }
+        state.create(&self.schema_config, &mut *self.pool.get()?)?;
+        for file in files
+            .iter()
+            .filter(|file| !completed.contains(&file.version))
+        {
+            let failed = || {
+                format!(
+                    "Migration {} failed; the queue stopped after applying {:?}",
+                    file.version, report.applied
+                )
+            };
+            let table = file
+                .table
+                .resolve_schema(&mut *self.pool.get()?)
+                .with_context(failed)?;
+            let mut settings = settings_for(&table);
+            // Recorded under the cutover lock, so a crash can't leave a swapped table unrecorded
+            // and have the next run migrate it again
+            settings
+                .cutover
+                .before_swap
+                .push(state.record_statement(file));
+            self.clone()
+                .with_settings(settings)
+                .run_migrate(&file.sql, true, mode)
+                .with_context(failed)?;
+            report.pending.retain(|version| *version != file.version);
+            report.applied.push(file.version.clone());
+        }
+        Ok(report)
+    }
+
+    /// Sets up the shadow table and replays changes into it until `stop_replay` is set, or until
+    /// a replay fails, whose error the handle returns. The logical modes capture through the
+    /// slot and publication named by `capture`.
     pub fn run_replay_only(
         &self,
         sql: &str,
         mode: ReplayMode,
+        capture: &CaptureNames,
+        stop_replay: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<anyhow::Result<()>> {
+        self.spawn_replay_only(sql, mode, capture.clone(), false, stop_replay)
+    }
+
+    /// Picks up a streaming replay-only run that was interrupted, from the slot and publication
+    /// it left behind. The shadow table is kept and the stream restarts from the saved position.
+    pub fn resume_replay_only(
+        &self,
+        sql: &str,
+        capture: &CaptureNames,
+        stop_replay: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<anyhow::Result<()>> {
+        self.spawn_replay_only(
+            sql,
+            ReplayMode::StreamingLogical,
+            capture.clone(),
+            true,
+            stop_replay,
+        )
+    }
+
+    fn spawn_replay_only(
+        &self,
+        sql: &str,
+        mode: ReplayMode,
+        capture: CaptureNames,
+        resume: bool,
         stop_replay: Arc<AtomicBool>,
     ) -> std::thread::JoinHandle<anyhow::Result<()>> {
-        let (migration, column_map) = self.run_schema_migration(sql).expect("Migration failed");
-        if let ReplayMode::Log = mode {
-            self.run_replay_setup(&migration, &column_map)
-                .expect("Replay setup failed");
+        let prepared = if resume {
+            self.load_schema_migration(sql)
+        } else {
+            self.run_schema_migration(sql)
         }
-        let conninfo = self.conninfo.clone();
-        let pool = self.pool.clone();
-        std::thread::spawn(move || {
-            let mut client = pool.get().expect("Failed to get client");
-            let replay_kind = Self {
-                pool: pool.clone(),
-                conninfo: conninfo.clone(),
+        .and_then(|(migration, column_map)| {
+            if let (ReplayMode::Log, false) = (&mode, resume) {
+                self.run_replay_setup(&migration, &column_map)?;
             }
-            .build_replay(&migration, &column_map, mode);
+            Ok((migration, column_map))
+        });
+        // A failure here is returned through the handle, like the replay's own errors
+        let (migration, column_map) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => return std::thread::spawn(move || Err(err)),
+        };
+        let runner = self.clone();
+        let replay_interval = self.settings.replay_interval;
+        std::thread::spawn(move || {
+            let mut client = runner.pool.get()?;
+            // Log replay was set up above; slots and streams are set up here, where they're used
+            let replay_kind = if resume {
+                ReplayKind::StreamingLogical(runner.resume_streaming_replay(
+                    &migration,
+                    &column_map,
+                    &capture,
+                )?)
+            } else {
+                let replay = runner.build_replay_with(
+                    &migration,
+                    &column_map,
+                    mode,
+                    &runner.settings,
+                    &capture,
+                )?;
+                if !matches!(replay, ReplayKind::Log(_)) {
+                    replay.setup(&mut client)?;
+                }
+                replay
+            };
             match replay_kind {
                 ReplayKind::Logical(replay) => {
                     while !stop_replay.load(std::sync::atomic::Ordering::Relaxed) {
-                        let _ = replay.replay_log(&mut client);
-                        std::thread::sleep(std::time::Duration::from_millis(200));
+                        replay.replay_log(&mut client)?;
+                        std::thread::sleep(replay_interval);
                     }
                 }
                 ReplayKind::Log(replay) => {
                     while !stop_replay.load(std::sync::atomic::Ordering::Relaxed) {
-                        let _ = replay.replay_log(&mut client);
-                        std::thread::sleep(std::time::Duration::from_millis(200));
+                        replay.replay_log(&mut client)?;
+                        std::thread::sleep(replay_interval);
                     }
                 }
-                ReplayKind::StreamingLogical(_) => {
-                    panic!(
-                        "StreamingLogicalReplay is not supported in threaded replay context. Use single-threaded context only."
-                    );
+                ReplayKind::StreamingLogical(replay) => {
+                    while !stop_replay.load(std::sync::atomic::Ordering::Relaxed) {
+                        replay.replay_log(&mut client)?;
+                        std::thread::sleep(replay_interval);
+                    }
                 }
             }
             Ok(())
@@ -126,8 +499,12 @@ impl MigrationRunner {
 
     pub fn run_backfill(&self, migration: &Migration) -> Result<()> {
         let mut client = self.pool.get()?;
-        let column_map = ColumnMap::new(&migration.table, &migration.shadow_table, &mut *client);
-        let backfill = crate::backfill::BatchedBackfill { batch_size: 1000 };
+        let column_map = migration.column_map(&self.settings, &mut *client)?;
+        let backfill = crate::backfill::BatchedBackfill {
+            batch_size: self.settings.backfill_batch_size,
+            throttle: self.settings.backfill_throttle,
+            row_filter: self.settings.row_filter.clone(),
+        };
         backfill.backfill(
             &migration.table,
             &migration.shadow_table,
@@ -139,7 +516,7 @@ impl MigrationRunner {
 
     pub fn run_replay(&self, migration: &Migration, column_map: &ColumnMap) -> Result<()> {
         let mut client = self.pool.get()?;
-        match self.build_replay(migration, column_map, ReplayMode::Log) {
+        match self.build_replay(migration, column_map, ReplayMode::Log)? {
             ReplayKind::Log(replay) => {
                 replay.setup(&mut client)?;
                 replay.replay_log(&mut client)?;
@@ -158,68 +535,98 @@ impl MigrationRunner {
 
     pub fn run_replay_setup(&self, migration: &Migration, column_map: &ColumnMap) -> Result<()> {
         let mut client = self.pool.get()?;
-        let replay = LogTableReplay {
-            log_table: migration.log_table.clone(),
-            shadow_table: migration.shadow_table.clone(),
-            table: migration.table.clone(),
-            column_map: column_map.clone(),
-            primary_key: migration.primary_key.clone(),
-        };
-        replay.setup(&mut client)?;
-        Ok(())
+        self.build_replay(migration, column_map, ReplayMode::Log)?
+            .setup(&mut client)
     }
 
+    /// Builds the replay for `mode` with the runner's settings. Logical replays capture through
+    /// a new slot and publication.
     pub fn build_replay(
         &self,
         migration: &Migration,
         column_map: &ColumnMap,
         mode: ReplayMode,
-    ) -> ReplayKind {
-        match mode {
-            ReplayMode::Logical => {
-                let slot_name = format!("ost_slot_{}", uuid::Uuid::new_v4().simple());
-                let pub_name = format!("ost_pub_{}", uuid::Uuid::new_v4().simple());
-                let slot = Slot::new(slot_name);
-                let publication = Publication::new(pub_name, migration.table.clone(), slot.clone());
-                ReplayKind::Logical(LogicalReplay {
-                    slot,
-                    publication,
-                    table: migration.table.clone(),
-                    shadow_table: migration.shadow_table.clone(),
-                    column_map: column_map.clone(),
-                    primary_key: migration.primary_key.clone(),
-                })
-            }
+    ) -> Result<ReplayKind> {
+        self.build_replay_with(
+            migration,
+            column_map,
+            mode,
+            &self.settings,
+            &CaptureNames::generate(),
+        )
+    }
+
+    /// Builds the replay for `mode` configured by `settings`, capturing through the slot and
+    /// publication named by `capture` in the logical modes. A streaming replay starts from the
+    /// position saved for the slot, if any. Nothing is created on the server.
+    pub fn build_replay_with(
+        &self,
+        migration: &Migration,
+        column_map: &ColumnMap,
+        mode: ReplayMode,
+        settings: &MigrationSettings,
+        capture: &CaptureNames,
+    ) -> Result<ReplayKind> {
+        let slot = wal2json_slot(capture.slot.clone(), vec![migration.table.clone()]);
+        let publication = Publication::new(
+            capture.publication.clone(),
+            migration.table.clone(),
+            slot.clone(),
+        )
+        .with_schema_config(&self.schema_config)
+        .with_full_identity(settings.replica_identity_full);
+        Ok(match mode {
+            ReplayMode::Log => ReplayKind::Log(LogTableReplay {
+                log_table: migration.log_table.clone(),
+                shadow_table: migration.shadow_table.clone(),
+                table: migration.table.clone(),
+                column_map: column_map.clone(),
+                primary_key: migration.primary_key.clone(),
+                batch_size: settings.replay_batch_size,
+                row_filter: settings.row_filter.clone(),
+            }),
+            ReplayMode::Logical => ReplayKind::Logical(LogicalReplay {
+                slot,
+                publication,
+                table: migration.table.clone(),
+                shadow_table: migration.shadow_table.clone(),
+                column_map: column_map.clone(),
+                primary_key: migration.primary_key.clone(),
+                batch_size: settings.replay_batch_size,
+                row_filter: settings.row_filter.clone(),
+            }),
             ReplayMode::StreamingLogical => {
-                let slot_name = format!("ost_slot_{}", uuid::Uuid::new_v4().simple());
-                let pub_name = format!("ost_pub_{}", uuid::Uuid::new_v4().simple());
-                let slot = Slot::new(slot_name.clone());
-                let publication = Publication::new(pub_name, migration.table.clone(), slot.clone());
-                let start_lsn = crate::logical_replication::message::Lsn(0); // Start from 0 or use a real value
+                // Start after the changes already applied through this slot. A new slot has none
+                // saved and starts at its creation point.
+                let progress = ReplayProgress::new(&self.schema_config);
+                let start_lsn = progress
+                    .load(&capture.slot, &mut *self.pool.get()?)?
+                    .unwrap_or(crate::logical_replication::message::Lsn(0));
                 let stream = crate::logical_replication::LogicalReplicationStream::new(
                     &self.conninfo,
-                    &slot_name,
+                    &capture.slot,
                     start_lsn,
-                )
-                .expect("Failed to create LogicalReplicationStream");
+                )?
+                .with_options(slot.plugin_options());
+                let stream = match &self.on_outage {
+                    Some(on_outage) => stream.with_outage_handler(on_outage.clone()),
+                    None => stream,
+                };
                 ReplayKind::StreamingLogical(StreamingLogicalReplay {
-                    stream: std::cell::RefCell::new(stream),
+                    stream: StreamingLogicalReplay::shared_stream(stream),
                     slot,
                     publication,
                     table: migration.table.clone(),
                     shadow_table: migration.shadow_table.clone(),
                     column_map: column_map.clone(),
                     primary_key: migration.primary_key.clone(),
+                    batch_size: settings.replay_batch_size,
+                    progress,
+                    row_filter: settings.row_filter.clone(),
+                    drain_timeout: settings.cutover.drain_timeout(),
                 })
             }
-            ReplayMode::Log => ReplayKind::Log(LogTableReplay {
-                log_table: migration.log_table.clone(),
-                shadow_table: migration.shadow_table.clone(),
-                table: migration.table.clone(),
-                column_map: column_map.clone(),
-                primary_key: migration.primary_key.clone(),
-            }),
-        }
+        })
     }
 
     pub fn build_and_setup_replay(
@@ -228,80 +635,78 @@ impl MigrationRunner {
         column_map: &ColumnMap,
         mode: ReplayMode,
     ) -> Result<ReplayKind> {
-        let mut client = self.pool.get()?;
-        let replay_kind = match mode {
-            ReplayMode::Logical => {
-                let logical_replay = self.build_logical_replay(migration, column_map);
-                logical_replay.setup(&mut client)?;
-                ReplayKind::Logical(logical_replay)
-            }
-            ReplayMode::StreamingLogical => {
-                let streaming_replay = self.build_streaming_logical_replay(migration, column_map);
-                streaming_replay.setup(&mut client)?;
-                ReplayKind::StreamingLogical(streaming_replay)
-            }
-            ReplayMode::Log => {
-                let log_table_replay = self.build_log_table_replay(migration, column_map);
-                log_table_replay.setup(&mut client)?;
-                ReplayKind::Log(log_table_replay)
-            }
-        };
-        Ok(replay_kind)
+        let replay = self.build_replay(migration, column_map, mode)?;
+        replay.setup(&mut *self.pool.get()?)?;
+        Ok(replay)
     }
 
-    fn build_logical_replay(&self, migration: &Migration, column_map: &ColumnMap) -> LogicalReplay {
-        let slot_name = format!("ost_slot_{}", uuid::Uuid::new_v4().simple());
-        let pub_name = format!("ost_pub_{}", uuid::Uuid::new_v4().simple());
-        let slot = Slot::new(slot_name);
-        let publication = Publication::new(pub_name, migration.table.clone(), slot.clone());
-        LogicalReplay {
-            slot,
-            publication,
-            table: migration.table.clone(),
-            shadow_table: migration.shadow_table.clone(),
-            column_map: column_map.clone(),
-            primary_key: migration.primary_key.clone(),
-        }
-    }
-
-    fn build_streaming_logical_replay(
+    /// Reattaches a streaming replay to the slot and publication of an interrupted run. The
+    /// stream restarts from the position saved with the last applied changes, so nothing is
+    /// applied twice or skipped.
+    pub fn resume_streaming_replay(
         &self,
         migration: &Migration,
         column_map: &ColumnMap,
-    ) -> StreamingLogicalReplay {
-        let slot_name = format!("ost_slot_{}", uuid::Uuid::new_v4().simple());
-        let pub_name = format!("ost_pub_{}", uuid::Uuid::new_v4().simple());
-        let slot = Slot::new(slot_name.clone());
-        let publication = Publication::new(pub_name, migration.table.clone(), slot.clone());
-        let start_lsn = crate::logical_replication::message::Lsn(0); // Start from 0 or use a real value
-        let stream = crate::logical_replication::LogicalReplicationStream::new(
-            &self.conninfo,
-            &slot_name,
-            start_lsn,
-        )
-        .expect("Failed to create LogicalReplicationStream");
-        StreamingLogicalReplay {
-            stream: std::cell::RefCell::new(stream),
-            slot,
-            publication,
-            table: migration.table.clone(),
-            shadow_table: migration.shadow_table.clone(),
-            column_map: column_map.clone(),
-            primary_key: migration.primary_key.clone(),
+        capture: &CaptureNames,
+    ) -> Result<StreamingLogicalReplay> {
+        let ReplayKind::StreamingLogical(replay) = self.build_replay_with(
+            migration,
+            column_map,
+            ReplayMode::StreamingLogical,
+            &self.settings,
+            capture,
+        )?
+        else {
+            unreachable!("the streaming mode builds a streaming replay");
+        };
+        replay.resume(&mut *self.pool.get()?)?;
+        Ok(replay)
+    }
+}
+
+impl ReplayKind {
+    /// Creates what the replay captures changes with.
+    pub fn setup(&self, client: &mut postgres::Client) -> Result<()> {
+        match self {
+            ReplayKind::Log(replay) => replay.setup(client),
+            ReplayKind::Logical(replay) => replay.setup(client),
+            ReplayKind::StreamingLogical(replay) => replay.setup(client),
         }
     }
+}
 
-    fn build_log_table_replay(
-        &self,
-        migration: &Migration,
-        column_map: &ColumnMap,
-    ) -> LogTableReplay {
-        LogTableReplay {
-            log_table: migration.log_table.clone(),
-            shadow_table: migration.shadow_table.clone(),
-            table: migration.table.clone(),
-            column_map: column_map.clone(),
-            primary_key: migration.primary_key.clone(),
+/// Names of the slot and publication a logical replay captures changes through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureNames {
+    pub slot: String,
+    pub publication: String,
+}
+
+impl CaptureNames {
+    /// Fresh names for a new run. Both share one id, so the slot names the publication.
+    pub fn generate() -> Self {
+        let id = uuid::Uuid::new_v4().simple();
+        CaptureNames {
+            slot: format!("ost_slot_{}", id),
+            publication: format!("ost_pub_{}", id),
         }
     }
+
+    /// The names a previous run generated, found from its slot.
+    pub fn for_slot(slot: &str) -> Result<Self> {
+        let Some(id) = slot.strip_prefix("ost_slot_") else {
+            bail!("{} isn't a slot created by postgres-ost", slot);
+        };
+        Ok(CaptureNames {
+            slot: slot.to_string(),
+            publication: format!("ost_pub_{}", id),
+        })
+    }
+}
+
+/// A wal2json slot decoding only `tables`, in format 2.
+fn wal2json_slot(name: String, tables: Vec<Table>) -> Slot {
+    Slot::new(name)
+        .with_format(Wal2JsonFormat::V2)
+        .with_tables(tables)
 }
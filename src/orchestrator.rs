@@ -1,35 +1,58 @@
 use crate::Replay;
 use crate::backfill::{Backfill, BatchedBackfill};
-use crate::{ColumnMap, Migration};
+use crate::config::{CutoverSettings, MigrationSettings};
+use crate::foreign_key::ForeignKeyOptions;
+use crate::replay::TableSetReplay;
+use crate::table_metadata::MetadataReport;
+use crate::{ColumnMap, Migration, Table};
+use anyhow::Context;
 use r2d2::Pool;
 use r2d2_postgres::{PostgresConnectionManager, postgres::NoTls as R2d2NoTls};
 
 pub struct MigrationOrchestrator {
     pub migration: crate::Migration,
     pub pool: r2d2::Pool<r2d2_postgres::PostgresConnectionManager<r2d2_postgres::postgres::NoTls>>,
+    pub foreign_key_options: ForeignKeyOptions,
+    pub settings: MigrationSettings,
 }
 
 impl MigrationOrchestrator {
     pub fn new(migration: Migration, pool: Pool<PostgresConnectionManager<R2d2NoTls>>) -> Self {
-        Self { migration, pool }
+        Self {
+            migration,
+            pool,
+            foreign_key_options: ForeignKeyOptions::default(),
+            settings: MigrationSettings::default(),
+        }
+    }
+
+    /// Use `foreign_key_options` for the inbound foreign keys at cutover.
+    pub fn with_foreign_key_options(mut self, foreign_key_options: ForeignKeyOptions) -> Self {
+        self.foreign_key_options = foreign_key_options;
+        self
+    }
+
+    /// Use `settings` for batch sizes, throttles and the cutover.
+    pub fn with_settings(mut self, settings: MigrationSettings) -> Self {
+        self.settings = settings;
+        self
     }
 
     pub fn start_log_replay_thread<R: Replay + Send + Sync + 'static>(
         &self,
         replay: R,
         stop_replay: std::sync::Arc<std::sync::atomic::AtomicBool>,
-    ) -> std::thread::JoinHandle<()> {
-        use std::sync::atomic::Ordering;
-        use std::thread;
-        use std::time::Duration;
-        let mut replay_client = self.pool.get().expect("Failed to get replay client");
-        let stop_replay_clone = stop_replay.clone();
-        thread::spawn(move || {
-            while !stop_replay_clone.load(Ordering::Relaxed) {
-                let _ = replay.replay_log(&mut replay_client).is_err();
-                thread::sleep(Duration::from_millis(200));
-            }
-        })
+    ) -> anyhow::Result<std::thread::JoinHandle<anyhow::Result<()>>> {
+        let replay_client = self
+            .pool
+            .get()
+            .context("Failed to get a connection for the replay")?;
+        Ok(spawn_replay_thread(
+            replay,
+            replay_client,
+            self.settings.replay_interval,
+            stop_replay,
+        ))
     }
 
     pub fn start_backfill_thread(
@@ -37,12 +60,19 @@ impl MigrationOrchestrator {
         column_map: ColumnMap,
         table: crate::table::Table,
         shadow_table: crate::table::Table,
-    ) -> std::thread::JoinHandle<anyhow::Result<()>> {
-        let mut backfill_client = self.pool.get().expect("Failed to get backfill client");
-        let backfill = BatchedBackfill { batch_size: 1000 };
-        std::thread::spawn(move || {
+    ) -> anyhow::Result<std::thread::JoinHandle<anyhow::Result<()>>> {
+        let mut backfill_client = self
+            .pool
+            .get()
+            .context("Failed to get a connection for the backfill")?;
+        let backfill = BatchedBackfill {
+            batch_size: self.settings.backfill_batch_size,
+            throttle: self.settings.backfill_throttle,
+            row_filter: self.settings.row_filter.clone(),
+        };
+        Ok(std::thread::spawn(move || {
             backfill.backfill(&table, &shadow_table, &column_map, &mut backfill_client)
-        })
+        }))
     }
 
     /// Orchestrate the migration, assuming all setup is already done and a concrete Replay is provided.
@@ -51,35 +81,258 @@ impl MigrationOrchestrator {
         execute: bool,
         column_map: ColumnMap,
         replay: T,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<MetadataReport> {
         use std::sync::{
             Arc,
             atomic::{AtomicBool, Ordering},
         };
         // All setup (migration, column_map, replay construction) must be done by the caller
         let stop_replay = Arc::new(AtomicBool::new(false));
-        let replay_handle = self.start_log_replay_thread(replay.clone(), stop_replay.clone());
-        let backfill_handle = self.start_backfill_thread(
-            column_map.clone(),
-            self.migration.table.clone(),
-            self.migration.shadow_table.clone(),
-        );
-        backfill_handle.join().expect("Backfill thread panicked")?;
+        let replay_handle = self.start_log_replay_thread(replay.clone(), stop_replay.clone())?;
+        let backfilled = self
+            .start_backfill_thread(
+                column_map.clone(),
+                self.migration.table.clone(),
+                self.migration.shadow_table.clone(),
+            )
+            .and_then(|handle| handle.join().expect("Backfill thread panicked"));
+        // Stop the replay before returning a failed backfill, so it isn't left running
         stop_replay.store(true, Ordering::Relaxed);
-        replay_handle.join().expect("Replay thread panicked");
-        let mut client = self.pool.get()?;
+        let replayed = replay_handle.join().expect("Replay thread panicked");
+        backfilled?;
+        // A replay that stopped early left the shadow table behind; don't cut it over
+        replayed.context("Replay failed before the cutover")?;
+        self.finish(execute, &column_map, &replay)
+    }
+
+    /// Cuts over once the backfill is done, or only tears the replay down without `execute`.
+    fn finish<T: Replay>(
+        &self,
+        execute: bool,
+        column_map: &ColumnMap,
+        replay: &T,
+    ) -> anyhow::Result<MetadataReport> {
         if execute {
+            self.cutover(column_map, |transaction| {
+                replay.replay_log_until_complete(transaction)?;
+                replay.teardown(transaction)
+            })
+        } else {
+            let mut client = self.pool.get()?;
             let mut transaction = client.transaction()?;
-            self.migration.table.lock_table(&mut transaction)?;
-            replay.replay_log_until_complete(&mut transaction)?;
             replay.teardown(&mut transaction)?;
-            self.migration.swap_tables(&mut transaction)?;
             transaction.commit()?;
-        } else {
+            Ok(MetadataReport::default())
+        }
+    }
+
+    /// Migrates several tables at once over one replay covering all of them. Every table is
+    /// backfilled in its own thread while the replay runs, then the tables are cut over one by
+    /// one, each releasing its capture from the replay, or all in one transaction when `atomic`
+    /// is set. Backfills beyond what the pool can serve alongside the replay wait for earlier
+    /// ones to finish. Each orchestrator's settings drive its table's backfill and cutover; the replay
+    /// runs every `replay_interval`.
+    pub fn orchestrate_tables<T: TableSetReplay + Clone + Send + Sync + 'static>(
+        tables: &[(MigrationOrchestrator, ColumnMap)],
+        execute: bool,
+        atomic: bool,
+        mut replay: T,
+        replay_interval: std::time::Duration,
+    ) -> anyhow::Result<Vec<MetadataReport>> {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        };
+        let Some((first, _column_map)) = tables.first() else {
+            return Ok(Vec::new());
+        };
+        let stop_replay = Arc::new(AtomicBool::new(false));
+        let replay_handle = spawn_replay_thread(
+            replay.clone(),
+            first.pool.get()?,
+            replay_interval,
+            stop_replay.clone(),
+        );
+        // Each backfill holds a connection until it finishes and the replay holds one
+        // throughout, so only as many backfills run at once as leave one connection spare
+        let concurrent = (first.pool.max_size() as usize).saturating_sub(2).max(1);
+        let mut backfilled = Ok(());
+        for wave in tables.chunks(concurrent) {
+            let handles: Vec<_> = wave
+                .iter()
+                .map(|(orchestrator, column_map)| {
+                    orchestrator.start_backfill_thread(
+                        column_map.clone(),
+                        orchestrator.migration.table.clone(),
+                        orchestrator.migration.shadow_table.clone(),
+                    )
+                })
+                .collect();
+            // Wait for every started backfill before stopping the replay, so none is left running
+            for handle in handles {
+                let result =
+                    handle.and_then(|handle| handle.join().expect("Backfill thread panicked"));
+                backfilled = backfilled.and(result);
+            }
+            if backfilled.is_err() {
+                break;
+            }
+        }
+        stop_replay.store(true, Ordering::Relaxed);
+        let replayed = replay_handle.join().expect("Replay thread panicked");
+        backfilled?;
+        replayed.context("Replay failed before the cutover")?;
+        if !execute {
+            let mut client = first.pool.get()?;
             let mut transaction = client.transaction()?;
             replay.teardown(&mut transaction)?;
             transaction.commit()?;
+            return Ok(vec![MetadataReport::default(); tables.len()]);
+        }
+        if atomic {
+            return Self::cutover_together(tables, replay);
+        }
+        let mut reports = Vec::new();
+        for (orchestrator, column_map) in tables {
+            let table = &orchestrator.migration.table;
+            let report = orchestrator
+                .cutover(column_map, |transaction| {
+                    replay.replay_log_until_complete(transaction)?;
+                    replay.release_table(table, transaction)
+                })
+                .with_context(|| {
+                    format!(
+                        "Cutover of {} failed after {} of {} tables were cut over",
+                        table,
+                        reports.len(),
+                        tables.len()
+                    )
+                })?;
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    /// Cuts every table over in one transaction, so they all switch or none do. The tables are
+    /// locked in name order, so concurrent runs can't deadlock, and the strictest timeouts among
+    /// the tables apply to the whole transaction.
+    fn cutover_together<T: TableSetReplay>(
+        tables: &[(MigrationOrchestrator, ColumnMap)],
+        mut replay: T,
+    ) -> anyhow::Result<Vec<MetadataReport>> {
+        let mut client = tables[0].0.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let timeouts = CutoverSettings {
+            lock_timeout: tables
+                .iter()
+                .filter_map(|(orchestrator, _)| orchestrator.settings.cutover.lock_timeout)
+                .min(),
+            statement_timeout: tables
+                .iter()
+                .filter_map(|(orchestrator, _)| orchestrator.settings.cutover.statement_timeout)
+                .min(),
+            ..CutoverSettings::default()
+        };
+        for statement in timeouts.timeout_statements() {
+            transaction.batch_execute(&statement)?;
+        }
+        let mut lock_order: Vec<&Table> = tables
+            .iter()
+            .map(|(orchestrator, _)| &orchestrator.migration.table)
+            .collect();
+        lock_order.sort_by_key(|table| (table.schema.clone(), table.name.clone()));
+        for table in lock_order {
+            table.lock_table(&mut transaction)?;
+        }
+        replay.replay_log_until_complete(&mut transaction)?;
+        let mut reports = Vec::new();
+        for (orchestrator, column_map) in tables {
+            replay.release_table(&orchestrator.migration.table, &mut transaction)?;
+            let report = orchestrator
+                .swap_in(column_map, &mut transaction)
+                .with_context(|| {
+                    format!(
+                        "Cutover of {} failed; no table was cut over",
+                        orchestrator.migration.table
+                    )
+                })?;
+            reports.push(report);
+        }
+        transaction.commit()?;
+        for (orchestrator, _column_map) in tables {
+            orchestrator.after_commit(&mut client)?;
+        }
+        Ok(reports)
+    }
+
+    /// Swaps the shadow table in under the table lock. `finish_replay` runs first under the lock
+    /// to apply the last captured changes and stop capturing.
+    fn cutover(
+        &self,
+        column_map: &ColumnMap,
+        finish_replay: impl FnOnce(&mut postgres::Transaction) -> anyhow::Result<()>,
+    ) -> anyhow::Result<MetadataReport> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        for statement in self.settings.cutover.timeout_statements() {
+            transaction.batch_execute(&statement)?;
+        }
+        self.migration.table.lock_table(&mut transaction)?;
+        finish_replay(&mut transaction)?;
+        let report = self.swap_in(column_map, &mut transaction)?;
+        transaction.commit()?;
+        self.after_commit(&mut client)?;
+        Ok(report)
+    }
+
+    /// The cutover steps after the last changes are replayed, up to the swap and the moved
+    /// foreign keys, run in the locked cutover transaction.
+    fn swap_in(
+        &self,
+        column_map: &ColumnMap,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<MetadataReport> {
+        let report = self
+            .migration
+            .copy_table_metadata(column_map, transaction)?;
+        for statement in &self.settings.cutover.before_swap {
+            transaction.batch_execute(statement)?;
+        }
+        let dependent_views = self.migration.dependent_views(transaction)?;
+        self.migration.swap_tables(transaction)?;
+        self.migration
+            .recreate_dependent_views(&dependent_views, transaction)?;
+        self.migration
+            .swap_foreign_keys(&self.foreign_key_options, column_map, transaction)?;
+        Ok(report)
+    }
+
+    /// Validates the moved foreign keys and runs the after-cutover hooks once the swap has
+    /// committed.
+    fn after_commit(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        if self.foreign_key_options.recreate && self.foreign_key_options.validate {
+            self.migration.validate_foreign_keys(client)?;
+        }
+        for statement in &self.settings.cutover.after_cutover {
+            client.batch_execute(statement)?;
         }
         Ok(())
     }
 }
+
+/// Runs `replay` every `interval` until `stop_replay` is set, or until a replay fails, whose
+/// error the thread returns.
+fn spawn_replay_thread<R: Replay + Send + 'static>(
+    replay: R,
+    mut client: r2d2::PooledConnection<PostgresConnectionManager<R2d2NoTls>>,
+    interval: std::time::Duration,
+    stop_replay: std::sync::Arc<std::sync::atomic::AtomicBool>,
+) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    std::thread::spawn(move || {
+        while !stop_replay.load(std::sync::atomic::Ordering::Relaxed) {
+            replay.replay_log(&mut client)?;
+            std::thread::sleep(interval);
+        }
+        Ok(())
+    })
+}
@@ -103,4 +103,115 @@ mod tests {
         };
         assert_eq!(norm_lines(&rewritten), norm_lines(expected));
     }
+
+    #[test]
+    fn test_extract_main_table_quoted() {
+        let parser = PgQueryParser;
+        assert_eq!(
+            parser.extract_main_table("ALTER TABLE \"MixedCase\" ADD COLUMN foo INT"),
+            Some("\"MixedCase\"".to_string())
+        );
+        assert_eq!(
+            parser.extract_main_table("ALTER TABLE billing.\"order\" ADD COLUMN foo INT"),
+            Some("billing.\"order\"".to_string())
+        );
+        assert_eq!(
+            parser.extract_main_table("DROP TABLE \"Order Items\""),
+            Some("\"Order Items\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_migrate_shadow_table_statement_quoted() {
+        let sql = "ALTER TABLE \"MixedCase\" RENAME COLUMN \"order\" TO \"Position\"";
+        let parser = PgQueryParser;
+        let rewritten = parser.migrate_shadow_table_statement(
+            sql,
+            "\"MixedCase\"",
+            "post_migrations.\"MixedCase\"",
+        );
+        assert_eq!(
+            rewritten,
+            "ALTER TABLE post_migrations.\"MixedCase\" RENAME COLUMN \"order\" TO \"Position\""
+        );
+    }
+
+    #[test]
+    fn test_check_single_table() {
+        let parser = PgQueryParser;
+        assert_eq!(
+            parser
+                .check_single_table("ALTER TABLE test_table ADD COLUMN foo INT")
+                .unwrap(),
+            "test_table"
+        );
+        let partitioned = "DROP TABLE test_table; \
+            CREATE TABLE test_table (id BIGSERIAL PRIMARY KEY) PARTITION BY HASH (id); \
+            CREATE TABLE test_table_p0 PARTITION OF test_table FOR VALUES WITH (MODULUS 2, REMAINDER 0); \
+            CREATE INDEX ON test_table (id);";
+        assert_eq!(
+            parser.check_single_table(partitioned).unwrap(),
+            "test_table"
+        );
+        let error = parser
+            .check_single_table(
+                "ALTER TABLE test_table ADD COLUMN foo INT; ALTER TABLE other ADD COLUMN bar INT",
+            )
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("also changes other"),
+            "{}",
+            error
+        );
+        let error = parser
+            .check_single_table(
+                "ALTER TABLE test_table ADD COLUMN foo INT; UPDATE test_table SET foo = 1",
+            )
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Unsupported statement"),
+            "{}",
+            error
+        );
+        assert!(
+            parser
+                .check_single_table("ALTER TABLE test_table ADD COLUMN")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_using_expressions() {
+        let parser = PgQueryParser;
+        assert_eq!(
+            parser.using_expressions(
+                "ALTER TABLE test_table ALTER COLUMN \"Total\" TYPE numeric USING cents / 100.0, \
+                 ALTER COLUMN target TYPE jsonb USING to_jsonb(target), \
+                 ALTER COLUMN assertable TYPE varchar(64)"
+            ),
+            vec![
+                ("Total".to_string(), "cents / 100.0".to_string()),
+                ("target".to_string(), "to_jsonb(target)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_column_mappings() {
+        let parser = PgQueryParser;
+        assert_eq!(
+            parser.column_mappings(
+                "ALTER TABLE test_table RENAME COLUMN a TO tmp; \
+                 ALTER TABLE test_table RENAME COLUMN b TO a; \
+                 ALTER TABLE test_table RENAME COLUMN tmp TO b; \
+                 ALTER TABLE test_table DROP COLUMN c, ADD COLUMN c text; \
+                 ALTER TABLE test_table RENAME TO other"
+            ),
+            vec![
+                ("a".to_string(), Some("b".to_string())),
+                ("b".to_string(), Some("a".to_string())),
+                ("c".to_string(), None),
+            ]
+        );
+    }
 }
@@ -1,10 +1,176 @@
 // src/pg_query_parser.rs
 
 use crate::Parse;
+use crate::identifier::quote_ident;
+use crate::table::Table;
+use pg_query::protobuf::{AlterTableType, ObjectType, RangeVar};
 use pg_query::{NodeEnum, deparse, parse};
 
 pub struct PgQueryParser;
 
+impl PgQueryParser {
+    /// Checks that every statement parses and only changes the main table, or creates
+    /// partitions or indexes of it. Returns the main table.
+    pub fn check_single_table(&self, sql: &str) -> anyhow::Result<String> {
+        let main = self
+            .extract_main_table(sql)
+            .ok_or_else(|| anyhow::anyhow!("No table found in the migration SQL"))?;
+        let table = Table::new(&main);
+        for stmt_sql in sql.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let result = parse(stmt_sql)
+                .map_err(|e| anyhow::anyhow!("Failed to parse \"{}\": {}", stmt_sql, e))?;
+            for stmt in &result.protobuf.stmts {
+                let targets = match stmt.stmt.as_ref().and_then(|s| s.node.as_ref()) {
+                    Some(NodeEnum::AlterTableStmt(alter_table)) => {
+                        alter_table.relation.iter().collect()
+                    }
+                    Some(NodeEnum::RenameStmt(rename_stmt)) => {
+                        rename_stmt.relation.iter().collect()
+                    }
+                    Some(NodeEnum::IndexStmt(index_stmt)) => index_stmt.relation.iter().collect(),
+                    Some(NodeEnum::CreateStmt(create_stmt)) => {
+                        let partition_of_main = create_stmt.partbound.is_some()
+                            && create_stmt.inh_relations.iter().any(|inh| {
+                                matches!(inh.node.as_ref(), Some(NodeEnum::RangeVar(parent))
+                                    if refers_to(&table, &parent.schemaname, &parent.relname))
+                            });
+                        if partition_of_main {
+                            vec![]
+                        } else {
+                            create_stmt.relation.iter().collect()
+                        }
+                    }
+                    Some(NodeEnum::DropStmt(drop_stmt)) => {
+                        for obj in &drop_stmt.objects {
+                            if let Some(NodeEnum::List(list)) = obj.node.as_ref() {
+                                let parts: Vec<&str> = list
+                                    .items
+                                    .iter()
+                                    .filter_map(|item| match item.node.as_ref() {
+                                        Some(NodeEnum::String(s)) => Some(s.sval.as_str()),
+                                        _ => None,
+                                    })
+                                    .collect();
+                                let (schema, name) = match parts.as_slice() {
+                                    [name] => ("", *name),
+                                    [schema, name] => (*schema, *name),
+                                    _ => ("", ""),
+                                };
+                                if !refers_to(&table, schema, name) {
+                                    anyhow::bail!(
+                                        "The migration must only change {}, but also drops {}",
+                                        main,
+                                        quote_qualified(&parts)
+                                    );
+                                }
+                            }
+                        }
+                        vec![]
+                    }
+                    _ => anyhow::bail!("Unsupported statement in the migration: {}", stmt_sql),
+                };
+                for relation in targets {
+                    if !refers_to(&table, &relation.schemaname, &relation.relname) {
+                        anyhow::bail!(
+                            "The migration must only change {}, but also changes {}",
+                            main,
+                            range_var_name(relation)
+                        );
+                    }
+                }
+            }
+        }
+        Ok(main)
+    }
+
+    /// The `USING` expressions of `ALTER COLUMN ... TYPE` in the migration, by column. They
+    /// convert the main table's values when copying them into the shadow table.
+    pub fn using_expressions(&self, sql: &str) -> Vec<(String, String)> {
+        let mut expressions = Vec::new();
+        for stmt_sql in sql.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let Ok(result) = parse(stmt_sql) else {
+                continue;
+            };
+            for stmt in &result.protobuf.stmts {
+                let Some(NodeEnum::AlterTableStmt(alter_table)) =
+                    stmt.stmt.as_ref().and_then(|s| s.node.as_ref())
+                else {
+                    continue;
+                };
+                for cmd in &alter_table.cmds {
+                    if let Some(NodeEnum::AlterTableCmd(cmd)) = cmd.node.as_ref()
+                        && cmd.subtype == AlterTableType::AtAlterColumnType as i32
+                        && let Some(NodeEnum::ColumnDef(column_def)) =
+                            cmd.def.as_ref().and_then(|def| def.node.as_ref())
+                        && let Some(using) = &column_def.raw_default
+                        && let Some(expression) = deparse_expression(using)
+                    {
+                        expressions.push((cmd.name.clone(), expression));
+                    }
+                }
+            }
+        }
+        expressions
+    }
+
+    /// Where the migration's `RENAME COLUMN` and `DROP COLUMN` statements send each main table
+    /// column: its shadow column name, or `None` when it is dropped. Renames are followed in
+    /// order, so chains and swaps through a temporary name resolve to the final name.
+    pub fn column_mappings(&self, sql: &str) -> Vec<(String, Option<String>)> {
+        let mut mappings: Vec<(String, Option<String>)> = Vec::new();
+        let mut apply = |from: &str, to: Option<&str>| match mappings
+            .iter_mut()
+            .find(|(_, target)| target.as_deref() == Some(from))
+        {
+            Some((_, target)) => *target = to.map(str::to_string),
+            None => mappings.push((from.to_string(), to.map(str::to_string))),
+        };
+        for stmt_sql in sql.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let Ok(result) = parse(stmt_sql) else {
+                continue;
+            };
+            for stmt in &result.protobuf.stmts {
+                match stmt.stmt.as_ref().and_then(|s| s.node.as_ref()) {
+                    Some(NodeEnum::RenameStmt(rename_stmt))
+                        if rename_stmt.rename_type == ObjectType::ObjectColumn as i32 =>
+                    {
+                        apply(&rename_stmt.subname, Some(&rename_stmt.newname));
+                    }
+                    Some(NodeEnum::AlterTableStmt(alter_table)) => {
+                        for cmd in &alter_table.cmds {
+                            if let Some(NodeEnum::AlterTableCmd(cmd)) = cmd.node.as_ref()
+                                && cmd.subtype == AlterTableType::AtDropColumn as i32
+                            {
+                                apply(&cmd.name, None);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        mappings
+    }
+}
+
+/// Deparses an expression node by putting it in `SELECT` and taking the statement apart again.
+fn deparse_expression(expression: &pg_query::protobuf::Node) -> Option<String> {
+    let mut select = parse("SELECT NULL").ok()?;
+    let stmt = select.protobuf.stmts.first_mut()?.stmt.as_mut()?;
+    let Some(NodeEnum::SelectStmt(select_stmt)) = stmt.node.as_mut() else {
+        return None;
+    };
+    let Some(NodeEnum::ResTarget(target)) = select_stmt.target_list.first_mut()?.node.as_mut()
+    else {
+        return None;
+    };
+    target.val = Some(Box::new(expression.clone()));
+    deparse(&select.protobuf)
+        .ok()?
+        .strip_prefix("SELECT ")
+        .map(str::to_string)
+}
+
 impl Parse for PgQueryParser {
     fn extract_tables(&self, sql: &str) -> Vec<String> {
         match pg_query::parse(sql) {
@@ -13,18 +179,19 @@ impl Parse for PgQueryParser {
                     result.tables.into_iter().map(|(name, _ctx)| name).collect();
                 if tables.is_empty() {
                     // Fallback: try to extract table name from protobuf for RENAME COLUMN, etc.
-                    use pg_query::NodeEnum;
                     for stmt in &result.protobuf.stmts {
-                        if let Some(node) = stmt.stmt.as_ref().map(|s| &s.node) {
-                            if let Some(NodeEnum::AlterTableStmt(alter_table)) = node.as_ref() {
+                        match stmt.stmt.as_ref().and_then(|s| s.node.as_ref()) {
+                            Some(NodeEnum::AlterTableStmt(alter_table)) => {
                                 if let Some(relation) = &alter_table.relation {
                                     tables.push(relation.relname.clone());
                                 }
-                            } else if let Some(NodeEnum::RenameStmt(rename_stmt)) = node.as_ref() {
+                            }
+                            Some(NodeEnum::RenameStmt(rename_stmt)) => {
                                 if let Some(relation) = &rename_stmt.relation {
                                     tables.push(relation.relname.clone());
                                 }
                             }
+                            _ => {}
                         }
                     }
                 }
@@ -47,13 +214,9 @@ impl Parse for PgQueryParser {
             .filter(|s| !s.is_empty())
             .collect();
         let mut rewritten_stmts = Vec::new();
-        // Parse schema and table from shadow_table_name
-        let (shadow_schema, shadow_table) =
-            if let Some((schema, table)) = shadow_table_name.split_once('.') {
-                (Some(schema.to_string()), table.to_string())
-            } else {
-                (None, shadow_table_name.to_string())
-            };
+        // Both names are SQL names, possibly quoted; compare and rewrite using the unquoted parts
+        let table = Table::new(table_name);
+        let shadow = Table::new(shadow_table_name);
         for stmt in stmts {
             let rewritten = match parse(stmt) {
                 Ok(mut result) => {
@@ -63,87 +226,33 @@ impl Parse for PgQueryParser {
                             match node {
                                 Some(NodeEnum::AlterTableStmt(alter_table)) => {
                                     if let Some(relation) = &mut alter_table.relation {
-                                        if relation.relname == table_name {
-                                            relation.relname = shadow_table.clone();
-                                            if let Some(schema) = &shadow_schema {
-                                                relation.schemaname = schema.clone();
-                                            }
-                                            changed = true;
-                                        }
+                                        changed |= rewrite_range_var(relation, &table, &shadow);
                                     }
                                 }
                                 Some(NodeEnum::DropStmt(drop_stmt)) => {
                                     for obj in &mut drop_stmt.objects {
                                         if let Some(NodeEnum::List(list)) = obj.node.as_mut() {
-                                            let len = list.items.len();
-                                            if len > 0 {
-                                                // Find the last String node (should be the table name)
-                                                if let Some(NodeEnum::String(s)) =
-                                                    list.items[len - 1].node.as_mut()
-                                                {
-                                                    if s.sval == table_name {
-                                                        s.sval = shadow_table.clone();
-                                                        changed = true;
-                                                        if let Some(schema) = &shadow_schema {
-                                                            // If schema is present, set or insert as the second-to-last String node
-                                                            if len > 1 {
-                                                                if let Some(NodeEnum::String(
-                                                                    schema_node,
-                                                                )) = list.items[len - 2]
-                                                                    .node
-                                                                    .as_mut()
-                                                                {
-                                                                    schema_node.sval =
-                                                                        schema.clone();
-                                                                }
-                                                            } else {
-                                                                // Insert schema node before table node
-                                                                list.items.insert(0, pg_query::protobuf::Node {
-                                                                    node: Some(NodeEnum::String(pg_query::protobuf::String {
-                                                                        sval: schema.clone(),
-                                                                    })),
-                                                                });
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
+                                            changed |=
+                                                rewrite_name_list(&mut list.items, &table, &shadow);
                                         }
                                     }
                                 }
                                 Some(NodeEnum::RenameStmt(rename_stmt)) => {
                                     if let Some(relation) = &mut rename_stmt.relation {
-                                        if relation.relname == table_name {
-                                            relation.relname = shadow_table.clone();
-                                            if let Some(schema) = &shadow_schema {
-                                                relation.schemaname = schema.clone();
-                                            }
-                                            changed = true;
-                                        }
+                                        changed |= rewrite_range_var(relation, &table, &shadow);
                                     }
                                 }
                                 Some(NodeEnum::CreateStmt(create_stmt)) => {
                                     if let Some(relation) = &mut create_stmt.relation {
-                                        if relation.relname == table_name {
-                                            relation.relname = shadow_table.clone();
-                                            if let Some(schema) = &shadow_schema {
-                                                relation.schemaname = schema.clone();
-                                            }
-                                            changed = true;
-                                        }
+                                        changed |= rewrite_range_var(relation, &table, &shadow);
                                     }
                                     // Also rewrite PARTITION OF references (inh_relations)
                                     for inh in &mut create_stmt.inh_relations {
                                         if let Some(NodeEnum::RangeVar(range_var)) =
                                             inh.node.as_mut()
                                         {
-                                            if range_var.relname == table_name {
-                                                range_var.relname = shadow_table.clone();
-                                                if let Some(schema) = &shadow_schema {
-                                                    range_var.schemaname = schema.clone();
-                                                }
-                                                changed = true;
-                                            }
+                                            changed |=
+                                                rewrite_range_var(range_var, &table, &shadow);
                                         }
                                     }
                                 }
@@ -164,50 +273,138 @@ impl Parse for PgQueryParser {
         rewritten_stmts.join("; ")
     }
 
+    /// Returns the table the migration targets as a SQL name, quoted where needed.
     fn extract_main_table(&self, sql: &str) -> Option<String> {
         for stmt_sql in sql.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
             if let Ok(result) = pg_query::parse(stmt_sql) {
-                // Try to get the first table from the parsed tables list
-                if let Some((name, _)) = result.tables.into_iter().next() {
-                    return Some(name);
-                }
-                // Fallback: look for first DDL table in protobuf
-                if let Some(stmt) = result.protobuf.stmts.first() {
-                    if let Some(node) = stmt.stmt.as_ref().map(|s| &s.node) {
-                        use pg_query::NodeEnum;
-                        match node {
-                            Some(NodeEnum::AlterTableStmt(alter_table)) => {
-                                if let Some(relation) = &alter_table.relation {
-                                    return Some(relation.relname.clone());
-                                }
+                // Look for the first DDL table in protobuf, keeping schema and case intact
+                if let Some(stmt) = result.protobuf.stmts.first()
+                    && let Some(node) = stmt.stmt.as_ref().map(|s| &s.node)
+                {
+                    match node {
+                        Some(NodeEnum::AlterTableStmt(alter_table)) => {
+                            if let Some(relation) = &alter_table.relation {
+                                return Some(range_var_name(relation));
                             }
-                            Some(NodeEnum::DropStmt(drop_stmt)) => {
-                                for obj in &drop_stmt.objects {
-                                    if let Some(NodeEnum::List(list)) = obj.node.as_ref() {
-                                        for item in &list.items {
-                                            if let Some(NodeEnum::String(s)) = item.node.as_ref() {
-                                                return Some(s.sval.clone());
-                                            }
-                                        }
+                        }
+                        Some(NodeEnum::DropStmt(drop_stmt)) => {
+                            for obj in &drop_stmt.objects {
+                                if let Some(NodeEnum::List(list)) = obj.node.as_ref() {
+                                    let parts: Vec<&str> = list
+                                        .items
+                                        .iter()
+                                        .filter_map(|item| match item.node.as_ref() {
+                                            Some(NodeEnum::String(s)) => Some(s.sval.as_str()),
+                                            _ => None,
+                                        })
+                                        .collect();
+                                    if !parts.is_empty() {
+                                        return Some(quote_qualified(&parts));
                                     }
                                 }
                             }
-                            Some(NodeEnum::RenameStmt(rename_stmt)) => {
-                                if let Some(relation) = &rename_stmt.relation {
-                                    return Some(relation.relname.clone());
-                                }
+                        }
+                        Some(NodeEnum::RenameStmt(rename_stmt)) => {
+                            if let Some(relation) = &rename_stmt.relation {
+                                return Some(range_var_name(relation));
                             }
-                            Some(NodeEnum::CreateStmt(create_stmt)) => {
-                                if let Some(relation) = &create_stmt.relation {
-                                    return Some(relation.relname.clone());
-                                }
+                        }
+                        Some(NodeEnum::CreateStmt(create_stmt)) => {
+                            if let Some(relation) = &create_stmt.relation {
+                                return Some(range_var_name(relation));
                             }
-                            _ => {}
                         }
+                        _ => {}
                     }
                 }
+                // Fallback: the first table from the parsed tables list
+                if let Some((name, _)) = result.tables.into_iter().next() {
+                    return Some(name);
+                }
             }
         }
         None
     }
 }
+
+/// Formats a `RangeVar` as a quoted, optionally schema qualified, SQL name.
+fn range_var_name(relation: &RangeVar) -> String {
+    if relation.schemaname.is_empty() {
+        quote_ident(&relation.relname)
+    } else {
+        quote_qualified(&[&relation.schemaname, &relation.relname])
+    }
+}
+
+fn quote_qualified(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .map(|part| quote_ident(part))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Points `relation` at the shadow table if it refers to the main table.
+fn rewrite_range_var(relation: &mut RangeVar, table: &Table, shadow: &Table) -> bool {
+    if !refers_to(table, &relation.schemaname, &relation.relname) {
+        return false;
+    }
+    relation.relname = shadow.name.clone();
+    if let Some(schema) = &shadow.schema {
+        relation.schemaname = schema.clone();
+    }
+    true
+}
+
+/// Whether a (possibly unqualified) name from the migration SQL refers to `table`. An
+/// unqualified name is taken to mean the main table, as is any name when `table` itself
+/// has no schema.
+fn refers_to(table: &Table, schemaname: &str, relname: &str) -> bool {
+    relname == table.name
+        && (schemaname.is_empty() || table.schema.as_deref().is_none_or(|s| s == schemaname))
+}
+
+/// Rewrites a qualified name list (as used by DROP) if it refers to the main table.
+fn rewrite_name_list(
+    items: &mut Vec<pg_query::protobuf::Node>,
+    table: &Table,
+    shadow: &Table,
+) -> bool {
+    let len = items.len();
+    if len == 0 {
+        return false;
+    }
+    let schema_name = match len {
+        1 => String::new(),
+        _ => match items[len - 2].node.as_ref() {
+            Some(NodeEnum::String(s)) => s.sval.clone(),
+            _ => String::new(),
+        },
+    };
+    // The last String node is the table name
+    match items[len - 1].node.as_mut() {
+        Some(NodeEnum::String(s)) if refers_to(table, &schema_name, &s.sval) => {
+            s.sval = shadow.name.clone()
+        }
+        _ => return false,
+    }
+    if let Some(schema) = &shadow.schema {
+        // If schema is present, set or insert as the second-to-last String node
+        if len > 1 {
+            if let Some(NodeEnum::String(schema_node)) = items[len - 2].node.as_mut() {
+                schema_node.sval = schema.clone();
+            }
+        } else {
+            // Insert schema node before table node
+            items.insert(
+                0,
+                pg_query::protobuf::Node {
+                    node: Some(NodeEnum::String(pg_query::protobuf::String {
+                        sval: schema.clone(),
+                    })),
+                },
+            );
+        }
+    }
+    true
+}
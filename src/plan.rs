@@ -0,0 +1,463 @@
+// src/plan.rs
+// The ordered script `postgres-ost migrate --plan` prints instead of running a migration. Built
+// from read-only catalog queries; nothing is created.
+
+use crate::ColumnMap;
+use crate::backfill::BatchedBackfill;
+use crate::config::MigrationSettings;
+use crate::dependent_view::DependentView;
+use crate::foreign_key::ForeignKeyOptions;
+use crate::identifier::quote_ident;
+use crate::logical_replication::{Publication, ReplicaIdentity, Slot};
+use crate::migration::Migration;
+use crate::migration_runner::ReplayMode;
+use crate::pg_query_parser::PgQueryParser;
+use crate::replay::log_table_replay::LogTableReplay;
+use crate::schema_config::SchemaConfig;
+use crate::table::Table;
+use crate::table_metadata::TableMetadata;
+use anyhow::Result;
+use postgres::GenericClient;
+use std::fmt;
+
+/// One step of the plan: what happens, and the SQL it runs. Steps whose SQL is only known once
+/// the shadow table exists, or that loop until done, are described without statements.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanStep {
+    pub phase: String,
+    pub description: String,
+    pub statements: Vec<String>,
+}
+
+/// The steps of a migration, in the order they run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub table: String,
+    pub steps: Vec<PlanStep>,
+}
+
+/// Settings the plan depends on.
+pub struct PlanOptions<'a> {
+    pub mode: &'a ReplayMode,
+    pub execute: bool,
+    pub schema_config: &'a SchemaConfig,
+    pub foreign_key_options: &'a ForeignKeyOptions,
+    pub settings: &'a MigrationSettings,
+}
+
+impl Plan {
+    fn push(&mut self, phase: &str, description: impl Into<String>, statements: Vec<String>) {
+        self.steps.push(PlanStep {
+            phase: phase.to_string(),
+            description: description.into(),
+            statements,
+        });
+    }
+
+    /// Builds the plan for `sql`. The shadow table doesn't exist yet, so columns are mapped from
+    /// the renames and drops in the SQL and the `column_mappings` setting, as `migrate` maps
+    /// them, and every other column is assumed to keep its name.
+    pub fn build<C: GenericClient>(
+        sql: &str,
+        options: &PlanOptions,
+        client: &mut C,
+    ) -> Result<Plan> {
+        let main = PgQueryParser.check_single_table(sql)?;
+        let table = Table::new(&main).resolve_schema(client)?;
+        let migration =
+            Migration::new_with_schema_config(sql, options.schema_config.clone(), client)?;
+        let metadata = TableMetadata::fetch(&table, client)?;
+        let column_map = ColumnMap::identity(table.get_columns(client)?)
+            .with_mappings(&migration.column_mappings(&options.settings.column_mappings))
+            .with_expressions(&migration.using_expressions())
+            .with_expressions(&options.settings.transforms);
+        let mut plan = Plan {
+            table: table.to_string(),
+            steps: Vec::new(),
+        };
+
+        plan.push(
+            "setup",
+            "Create the tool schemas",
+            options.schema_config.create_statements(),
+        );
+        plan.push(
+            "setup",
+            "Drop a shadow table left over from an earlier run",
+            vec![migration.shadow_table.drop_if_exists_statement()],
+        );
+        let mut create_shadow = vec![migration.create_shadow_table_statement()];
+        create_shadow.extend(metadata.policy_statements(&migration.shadow_table));
+        plan.push(
+            "setup",
+            "Create the shadow table like the original, with its policies",
+            create_shadow,
+        );
+        plan.push(
+            "setup",
+            "Apply the migration to the shadow table",
+            vec![migration.shadow_table_migrate_sql.clone()],
+        );
+        let views = DependentView::find(&table, client)?;
+        if !views.is_empty() {
+            plan.push(
+                "setup",
+                format!(
+                    "Check that dependent views still apply to the shadow table: {}",
+                    view_names(&views)
+                ),
+                Vec::new(),
+            );
+        }
+
+        let (capture_setup, capture_teardown, replay) = match options.mode {
+            ReplayMode::Log => {
+                let replay = LogTableReplay {
+                    log_table: migration.log_table.clone(),
+                    shadow_table: migration.shadow_table.clone(),
+                    table: migration.table.clone(),
+                    column_map: column_map.clone(),
+                    primary_key: migration.primary_key.clone(),
+                    batch_size: options.settings.replay_batch_size,
+                    row_filter: options.settings.row_filter.clone(),
+                };
+                (
+                    replay.setup_statements(),
+                    replay.teardown_statements(),
+                    format!("from {}", migration.log_table),
+                )
+            }
+            ReplayMode::Logical | ReplayMode::StreamingLogical => {
+                // Slot and publication names are generated for each run
+                let slot = Slot::new("ost_slot_<generated>".to_string());
+                let publication = Publication::new(
+                    "ost_pub_<generated>".to_string(),
+                    migration.table.clone(),
+                    slot.clone(),
+                )
+                .with_schema_config(options.schema_config)
+                .with_full_identity(options.settings.replica_identity_full);
+                let mut setup = publication.create_statements();
+                setup.push(slot.create_statement());
+                (
+                    setup,
+                    vec![slot.drop_statement(), publication.drop_statement()],
+                    format!("from replication slot {}", slot.name),
+                )
+            }
+        };
+        plan.push("capture", "Start capturing changes", capture_setup);
+
+        let settings = options.settings;
+        let backfill = BatchedBackfill {
+            batch_size: settings.backfill_batch_size,
+            throttle: settings.backfill_throttle,
+            row_filter: settings.row_filter.clone(),
+        };
+        let pk = &migration.primary_key.name;
+        plan.push(
+            "backfill",
+            format!(
+                "Copy rows{} in batches of {} by {}, resuming past the last copied key, until none are left{}",
+                backfill
+                    .row_filter
+                    .as_ref()
+                    .map_or(String::new(), |row_filter| format!(" matching {}", row_filter)),
+                backfill.batch_size,
+                pk,
+                if backfill.throttle.is_zero() {
+                    String::new()
+                } else {
+                    format!(", pausing {}ms between batches", backfill.throttle.as_millis())
+                }
+            ),
+            vec![
+                backfill.batch_statement(
+                    &migration.table,
+                    &migration.shadow_table,
+                    &column_map,
+                    &migration.primary_key,
+                    false,
+                )?,
+                backfill.batch_statement(
+                    &migration.table,
+                    &migration.shadow_table,
+                    &column_map,
+                    &migration.primary_key,
+                    true,
+                )?,
+            ],
+        );
+        plan.push(
+            "backfill",
+            format!(
+                "Meanwhile, replay captured changes {} in batches of {} every {}ms",
+                replay,
+                settings.replay_batch_size,
+                settings.replay_interval.as_millis()
+            ),
+            Vec::new(),
+        );
+
+        if !options.execute {
+            plan.push(
+                "teardown",
+                "Stop capturing changes; the shadow table is left in place",
+                capture_teardown,
+            );
+            return Ok(plan);
+        }
+
+        let mut lock = vec!["BEGIN".to_string()];
+        lock.extend(settings.cutover.timeout_statements());
+        lock.push(migration.table.lock_statement());
+        plan.push("cutover", "Lock the table", lock);
+        plan.push(
+            "cutover",
+            format!("Replay the remaining changes {}", replay),
+            Vec::new(),
+        );
+        plan.push("cutover", "Stop capturing changes", capture_teardown);
+        // The shadow table is created by the current user, with the policies copied at setup.
+        // `LIKE` leaves it the default replica identity, and its copy of an identity index is
+        // assumed to keep the index's name.
+        let shadow_metadata = TableMetadata {
+            owner: client.query_one("SELECT current_user::text", &[])?.get(0),
+            grants: Vec::new(),
+            row_security: false,
+            force_row_security: false,
+            policies: metadata.policies.clone(),
+            comment: None,
+            replica_identity: ReplicaIdentity::Default,
+        };
+        plan.push(
+            "cutover",
+            "Copy the owner, grants, row level security, comment and replica identity onto the shadow table",
+            metadata
+                .apply_statements(&migration.shadow_table, &shadow_metadata, Some(&column_map))
+                .into_iter()
+                .filter_map(|(_description, sql)| sql)
+                .collect(),
+        );
+
+        if !settings.cutover.before_swap.is_empty() {
+            plan.push(
+                "cutover",
+                "Run the before-swap hooks",
+                settings.cutover.before_swap.clone(),
+            );
+        }
+
+        let sequences = table.owned_sequences(client)?;
+        let serial: Vec<_> = sequences.iter().filter(|owned| !owned.identity).collect();
+        if !serial.is_empty() {
+            plan.push(
+                "cutover",
+                "Detach serial sequences so they stay in place when the old table is archived",
+                serial
+                    .iter()
+                    .map(|owned| format!("ALTER SEQUENCE {} OWNED BY NONE", owned.sequence))
+                    .collect(),
+            );
+        }
+        plan.push(
+            "cutover",
+            format!(
+                "Archive the original table to {} and move the shadow table into its place",
+                migration.old_table
+            ),
+            migration.swap_statements(),
+        );
+        if !serial.is_empty() {
+            plan.push(
+                "cutover",
+                "Attach the serial sequences to the new table",
+                serial
+                    .iter()
+                    .map(|owned| {
+                        format!(
+                            "ALTER SEQUENCE {} OWNED BY {}.{}",
+                            owned.sequence,
+                            migration.table,
+                            quote_ident(&owned.column)
+                        )
+                    })
+                    .collect(),
+            );
+        }
+        for owned in sequences.iter().filter(|owned| owned.identity) {
+            plan.push(
+                "cutover",
+                format!(
+                    "Advance the new table's identity sequence for {} to {}'s position and rename it to {}",
+                    owned.column, owned.sequence, owned.sequence.name
+                ),
+                Vec::new(),
+            );
+        }
+
+        if !views.is_empty() {
+            let mut statements = Vec::new();
+            for view in &views {
+                statements.extend(view.recreate_statements());
+            }
+            plan.push(
+                "cutover",
+                format!(
+                    "Recreate dependent views against the new table: {}",
+                    view_names(&views)
+                ),
+                statements,
+            );
+        }
+
+        let foreign_keys = &migration.inbound_foreign_keys;
+        if !foreign_keys.is_empty() && options.foreign_key_options.recreate {
+            let mut statements = Vec::new();
+            for foreign_key in foreign_keys {
+                statements.extend(foreign_key.cutover_statements(
+                    options.foreign_key_options,
+                    &migration.table,
+                    &column_map,
+                )?);
+            }
+            plan.push(
+                "cutover",
+                "Point inbound foreign keys at the new table",
+                statements,
+            );
+        }
+        plan.push("cutover", "Release the lock", vec!["COMMIT".to_string()]);
+
+        if !foreign_keys.is_empty()
+            && options.foreign_key_options.recreate
+            && options.foreign_key_options.validate
+        {
+            plan.push(
+                "validate",
+                "Validate the recreated foreign keys outside the lock",
+                foreign_keys
+                    .iter()
+                    .map(|foreign_key| foreign_key.validate_statement())
+                    .collect(),
+            );
+        }
+        if !settings.cutover.after_cutover.is_empty() {
+            plan.push(
+                "after cutover",
+                "Run the after-cutover hooks",
+                settings.cutover.after_cutover.clone(),
+            );
+        }
+        Ok(plan)
+    }
+
+    /// The plan as JSON: the table and a list of steps with their phase, description and SQL.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "table": self.table,
+            "steps": self
+                .steps
+                .iter()
+                .map(|step| {
+                    serde_json::json!({
+                        "phase": step.phase,
+                        "description": step.description,
+                        "statements": step.statements,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Renders the plan as an annotated SQL script.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "-- Migration plan for {}", self.table)?;
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f)?;
+            writeln!(f, "-- {}. [{}] {}", i + 1, step.phase, step.description)?;
+            for statement in &step.statements {
+                let statement = dedent(statement);
+                if statement.ends_with(';') {
+                    writeln!(f, "{}", statement)?;
+                } else {
+                    writeln!(f, "{};", statement)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Strips the indentation statements built from raw string literals carry, so the script reads
+/// as if written by hand.
+fn dedent(statement: &str) -> String {
+    let lines: Vec<&str> = statement
+        .trim()
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| if i == 0 { line } else { &line[indent..] })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn view_names(views: &[DependentView]) -> String {
+    views
+        .iter()
+        .map(|view| view.view.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_annotates_steps() {
+        let plan = Plan {
+            table: "public.test_table".to_string(),
+            steps: vec![
+                PlanStep {
+                    phase: "cutover".to_string(),
+                    description: "Lock the table".to_string(),
+                    statements: vec![
+                        "BEGIN".to_string(),
+                        "LOCK TABLE public.test_table IN ACCESS EXCLUSIVE MODE;".to_string(),
+                    ],
+                },
+                PlanStep {
+                    phase: "cutover".to_string(),
+                    description: "Replay the remaining changes".to_string(),
+                    statements: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            plan.to_string(),
+            "-- Migration plan for public.test_table\n\
+             \n\
+             -- 1. [cutover] Lock the table\n\
+             BEGIN;\n\
+             LOCK TABLE public.test_table IN ACCESS EXCLUSIVE MODE;\n\
+             \n\
+             -- 2. [cutover] Replay the remaining changes\n"
+        );
+        assert_eq!(
+            plan.to_json()["steps"][1]["statements"],
+            serde_json::json!([])
+        );
+    }
+}
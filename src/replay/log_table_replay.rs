@@ -1,6 +1,7 @@
 // log_table_replay.rs
 // Contains LogTableReplay and related logic.
 
+use crate::identifier::{quote_ident, quote_ident_list};
 use crate::{ColumnMap, PrimaryKeyInfo, Replay, Table};
 use anyhow::Result;
 use postgres::types::Type;
@@ -12,9 +13,82 @@ pub struct LogTableReplay {
     pub table: Table,
     pub column_map: ColumnMap,
     pub primary_key: PrimaryKeyInfo,
+    /// Log rows applied per batch
+    pub batch_size: usize,
+    /// Only keep rows matching this condition in the shadow table
+    pub row_filter: Option<String>,
 }
 
 impl LogTableReplay {
+    /// Name of the trigger capturing `operation` (insert, update or delete) on the main table.
+    fn trigger_name(&self, operation: &str) -> String {
+        quote_ident(&format!("{}_{}_trigger", self.table.name, operation))
+    }
+
+    /// The trigger function for `operation`, living next to the log table.
+    fn trigger_function(&self, operation: &str) -> Table {
+        self.log_table
+            .with_suffix(&format!("_{}_trigger_fn", operation))
+    }
+
+    /// Statements creating the log table and the triggers that fill it.
+    pub fn setup_statements(&self) -> Vec<String> {
+        let mut statements = vec![format!(
+            "CREATE TABLE IF NOT EXISTS {} (post_migration_log_id BIGSERIAL PRIMARY KEY, operation TEXT, timestamp TIMESTAMPTZ DEFAULT NOW(), LIKE {})",
+            self.log_table, self.table
+        )];
+        let pk_col = &quote_ident(&self.primary_key.name);
+        for (operation, row) in [("insert", "NEW"), ("delete", "OLD"), ("update", "NEW")] {
+            statements.push(format!(
+                r#"
+            CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $$
+            BEGIN
+                INSERT INTO {log_table} (operation, {pk_col}) VALUES ('{op}', {row}.{pk_col});
+                RETURN {row};
+            END;
+            $$ LANGUAGE plpgsql;
+            
+            DROP TRIGGER IF EXISTS {trigger} ON {table};
+            CREATE TRIGGER {trigger}
+                AFTER {op} ON {table}
+                FOR EACH ROW EXECUTE FUNCTION {function}();
+            "#,
+                function = self.trigger_function(operation),
+                trigger = self.trigger_name(operation),
+                log_table = self.log_table,
+                table = self.table,
+                pk_col = pk_col,
+                op = operation.to_uppercase(),
+                row = row
+            ));
+        }
+        statements
+    }
+
+    /// Statements dropping the triggers, their functions and the log table.
+    pub fn teardown_statements(&self) -> Vec<String> {
+        vec![
+            format!(
+                r#"
+            DROP TRIGGER IF EXISTS {insert_trigger} ON {table};
+            DROP TRIGGER IF EXISTS {delete_trigger} ON {table};
+            DROP TRIGGER IF EXISTS {update_trigger} ON {table};
+            DROP FUNCTION IF EXISTS {insert_function}();
+            DROP FUNCTION IF EXISTS {delete_function}();
+            DROP FUNCTION IF EXISTS {update_function}();
+            "#,
+                table = self.table,
+                insert_trigger = self.trigger_name("insert"),
+                delete_trigger = self.trigger_name("delete"),
+                update_trigger = self.trigger_name("update"),
+                insert_function = self.trigger_function("insert"),
+                delete_function = self.trigger_function("delete"),
+                update_function = self.trigger_function("update"),
+            ),
+            format!("DROP TABLE IF EXISTS {};", self.log_table),
+        ]
+    }
+
     /// Fetches and deletes a batch of N rows from the log table, ordered by post_migration_log_id, returning the deleted rows.
     pub fn fetch_batch(
         &self,
@@ -33,43 +107,55 @@ impl LogTableReplay {
 
     /// Converts a batch of log table rows to SQL statements to replay the changes.
     /// Handles DELETE and INSERT. For INSERT, uses a mapping of main to shadow columns, supporting dropped and renamed columns.
-    pub fn batch2sql(&self, rows: &[postgres::Row], column_map: &ColumnMap) -> Vec<String> {
+    pub fn batch2sql(&self, rows: &[postgres::Row], column_map: &ColumnMap) -> Result<Vec<String>> {
         let mut statements = Vec::new();
-        let shadow_cols = column_map.shadow_cols();
-        let main_cols = column_map.main_cols();
-        let insert_cols_csv = shadow_cols.join(", ");
-        let select_cols_csv = main_cols.join(", ");
-        let pk_col = &self.primary_key.name;
+        let assignments = column_map.assignments();
+        let insert_cols_csv = quote_ident_list(&column_map.insert_cols());
+        let select_cols_csv = column_map.select_list();
+        let pk_col = &quote_ident(&self.primary_key.name);
+        let shadow_pk = column_map.shadow_key(&self.primary_key.name)?;
+        let shadow_pk_col = &quote_ident(&shadow_pk);
         let pk_type = &self.primary_key.ty;
+        let row_filter = self
+            .row_filter
+            .as_ref()
+            .map_or(String::new(), |row_filter| format!(" AND ({})", row_filter));
         for row in rows {
             let operation: String = row.get("operation");
-            let pk_val = PrimaryKey::from_row(row, pk_col, pk_type);
+            let pk_val = PrimaryKey::from_row(row, &self.primary_key.name, pk_type)?;
             let pk_sql = pk_val.to_sql();
             if operation == "DELETE" {
                 let stmt = format!(
                     "DELETE FROM {} WHERE {} = {}",
-                    self.shadow_table, pk_col, pk_sql
+                    self.shadow_table, shadow_pk_col, pk_sql
                 );
                 statements.push(stmt);
             } else if operation == "INSERT" {
                 let stmt = format!(
-                    "INSERT INTO {shadow} ({cols}) SELECT {selectCols} FROM {main} WHERE {pk_col} = {pk_val}",
+                    "INSERT INTO {shadow} ({cols}) OVERRIDING SYSTEM VALUE SELECT {selectCols} FROM {main} WHERE {pk_col} = {pk_val}{row_filter}",
                     shadow = self.shadow_table,
                     main = self.table,
                     cols = insert_cols_csv,
                     selectCols = select_cols_csv,
                     pk_col = pk_col,
-                    pk_val = pk_sql
+                    pk_val = pk_sql,
+                    row_filter = row_filter
                 );
                 statements.push(stmt);
             } else if operation == "UPDATE" {
-                let set_clause = shadow_cols
+                let set_clause = assignments
                     .iter()
-                    .zip(main_cols.iter())
-                    .map(|(shadow_col, main_col)| {
+                    // The key is matched by the WHERE clause, and can't be assigned when it's
+                    // a GENERATED ALWAYS identity column
+                    .filter(|(shadow_col, _value)| *shadow_col != shadow_pk)
+                    .map(|(shadow_col, value)| {
                         format!(
                             "{} = (SELECT {} FROM {} WHERE {} = {})",
-                            shadow_col, main_col, self.table, pk_col, pk_sql
+                            quote_ident(shadow_col),
+                            value,
+                            self.table,
+                            pk_col,
+                            pk_sql
                         )
                     })
                     .collect::<Vec<_>>()
@@ -78,13 +164,23 @@ impl LogTableReplay {
                     "UPDATE {shadow} SET {set_clause} WHERE {pk_col} = {pk_val}",
                     shadow = self.shadow_table,
                     set_clause = set_clause,
-                    pk_col = pk_col,
+                    pk_col = shadow_pk_col,
                     pk_val = pk_sql
                 );
                 statements.push(stmt);
+                if let Some(row_filter) = &self.row_filter {
+                    statements.extend(crate::replay::row_filter_statements(
+                        &self.table,
+                        &self.shadow_table,
+                        column_map,
+                        &self.primary_key.name,
+                        &pk_sql,
+                        row_filter,
+                    )?);
+                }
             }
         }
-        statements
+        Ok(statements)
     }
 }
 
@@ -95,12 +191,12 @@ pub enum PrimaryKey {
 }
 
 impl PrimaryKey {
-    pub fn from_row(row: &postgres::Row, pk_col: &str, pk_type: &Type) -> Self {
-        match *pk_type {
-            Type::INT4 => PrimaryKey::I32(row.get::<_, i32>(pk_col)),
-            Type::INT8 => PrimaryKey::I64(row.get::<_, i64>(pk_col)),
-            _ => panic!("Unsupported primary key type: {:?}", pk_type),
-        }
+    pub fn from_row(row: &postgres::Row, pk_col: &str, pk_type: &Type) -> Result<Self> {
+        Ok(match *pk_type {
+            Type::INT4 => PrimaryKey::I32(row.try_get::<_, i32>(pk_col)?),
+            Type::INT8 => PrimaryKey::I64(row.try_get::<_, i64>(pk_col)?),
+            _ => anyhow::bail!("Unsupported primary key type: {}", pk_type),
+        })
     }
     pub fn to_sql(&self) -> String {
         match self {
@@ -108,13 +204,20 @@ impl PrimaryKey {
             PrimaryKey::I64(v) => v.to_string(),
         }
     }
+    /// The key as a query parameter of the column's own type.
+    pub fn as_param(&self) -> &(dyn postgres::types::ToSql + Sync) {
+        match self {
+            PrimaryKey::I32(v) => v,
+            PrimaryKey::I64(v) => v,
+        }
+    }
 }
 
 impl Replay for LogTableReplay {
     fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
         let mut txn = client.transaction()?;
-        let rows = self.fetch_batch(&mut txn, 100)?;
-        let statements = self.batch2sql(&rows, &self.column_map);
+        let rows = self.fetch_batch(&mut txn, self.batch_size)?;
+        let statements = self.batch2sql(&rows, &self.column_map)?;
         for stmt in statements {
             txn.batch_execute(&stmt)?;
         }
@@ -122,96 +225,15 @@ impl Replay for LogTableReplay {
         Ok(())
     }
     fn setup(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
-        // Create log table
-        let create_log_statement = format!(
-            "CREATE TABLE IF NOT EXISTS {} (post_migration_log_id BIGSERIAL PRIMARY KEY, operation TEXT, timestamp TIMESTAMPTZ DEFAULT NOW(), LIKE {})",
-            self.log_table, self.table
-        );
-        client.simple_query(&create_log_statement)?;
-
-        let pk_col = &self.primary_key.name;
-        // Insert trigger
-        let insert_trigger = format!(
-            r#"
-            CREATE OR REPLACE FUNCTION {log_table}_insert_trigger_fn() RETURNS trigger AS $$
-            BEGIN
-                INSERT INTO {log_table} (operation, {pk_col}) VALUES ('INSERT', NEW.{pk_col});
-                RETURN NEW;
-            END;
-            $$ LANGUAGE plpgsql;
-            
-            DROP TRIGGER IF EXISTS {table}_insert_trigger ON {table};
-            CREATE TRIGGER {table}_insert_trigger
-                AFTER INSERT ON {table}
-                FOR EACH ROW EXECUTE FUNCTION {log_table}_insert_trigger_fn();
-            "#,
-            log_table = self.log_table,
-            table = self.table,
-            pk_col = pk_col
-        );
-        client.batch_execute(&insert_trigger)?;
-
-        // Delete trigger
-        let delete_trigger = format!(
-            r#"
-            CREATE OR REPLACE FUNCTION {log_table}_delete_trigger_fn() RETURNS trigger AS $$
-            BEGIN
-                INSERT INTO {log_table} (operation, {pk_col}) VALUES ('DELETE', OLD.{pk_col});
-                RETURN OLD;
-            END;
-            $$ LANGUAGE plpgsql;
-            
-            DROP TRIGGER IF EXISTS {table}_delete_trigger ON {table};
-            CREATE TRIGGER {table}_delete_trigger
-                AFTER DELETE ON {table}
-                FOR EACH ROW EXECUTE FUNCTION {log_table}_delete_trigger_fn();
-            "#,
-            log_table = self.log_table,
-            table = self.table,
-            pk_col = pk_col
-        );
-        client.batch_execute(&delete_trigger)?;
-
-        // Update trigger
-        let update_trigger = format!(
-            r#"
-            CREATE OR REPLACE FUNCTION {log_table}_update_trigger_fn() RETURNS trigger AS $$
-            BEGIN
-                INSERT INTO {log_table} (operation, {pk_col}) VALUES ('UPDATE', NEW.{pk_col});
-                RETURN NEW;
-            END;
-            $$ LANGUAGE plpgsql;
-            
-            DROP TRIGGER IF EXISTS {table}_update_trigger ON {table};
-            CREATE TRIGGER {table}_update_trigger
-                AFTER UPDATE ON {table}
-                FOR EACH ROW EXECUTE FUNCTION {log_table}_update_trigger_fn();
-            "#,
-            log_table = self.log_table,
-            table = self.table,
-            pk_col = pk_col
-        );
-        client.batch_execute(&update_trigger)?;
-
+        for statement in self.setup_statements() {
+            client.batch_execute(&statement)?;
+        }
         Ok(())
     }
     fn teardown(&self, transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
-        let drop_triggers_and_functions = format!(
-            r#"
-            DROP TRIGGER IF EXISTS {table}_insert_trigger ON {table};
-            DROP TRIGGER IF EXISTS {table}_delete_trigger ON {table};
-            DROP TRIGGER IF EXISTS {table}_update_trigger ON {table};
-            DROP FUNCTION IF EXISTS {log_table}_insert_trigger_fn();
-            DROP FUNCTION IF EXISTS {log_table}_delete_trigger_fn();
-            DROP FUNCTION IF EXISTS {log_table}_update_trigger_fn();
-            "#,
-            table = self.table,
-            log_table = self.log_table
-        );
-        transaction.batch_execute(&drop_triggers_and_functions)?;
-        // Drop log table
-        let drop_log_table = format!("DROP TABLE IF EXISTS {};", self.log_table);
-        transaction.batch_execute(&drop_log_table)?;
+        for statement in self.teardown_statements() {
+            transaction.batch_execute(&statement)?;
+        }
         Ok(())
     }
     fn replay_log_until_complete(
@@ -219,17 +241,11 @@ impl Replay for LogTableReplay {
         transaction: &mut postgres::Transaction,
     ) -> anyhow::Result<()> {
         loop {
-            let query = format!(
-                "DELETE FROM {} WHERE post_migration_log_id IN (\
-                    SELECT post_migration_log_id FROM {} ORDER BY post_migration_log_id ASC LIMIT $1\
-                ) RETURNING *",
-                self.log_table, self.log_table
-            );
-            let rows = transaction.query(&query, &[&100_i64])?;
+            let rows = self.fetch_batch(transaction, self.batch_size)?;
             if rows.is_empty() {
                 break;
             }
-            let statements = self.batch2sql(&rows, &self.column_map);
+            let statements = self.batch2sql(&rows, &self.column_map)?;
             for stmt in statements {
                 transaction.batch_execute(&stmt)?;
             }
@@ -237,3 +253,56 @@ impl Replay for LogTableReplay {
         Ok(())
     }
 }
+
+/// Log table replays for several tables migrated together, each with its own log table.
+#[derive(Clone)]
+pub struct LogTableReplaySet {
+    pub replays: Vec<LogTableReplay>,
+}
+
+impl Replay for LogTableReplaySet {
+    fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        for replay in &self.replays {
+            replay.replay_log(client)?;
+        }
+        Ok(())
+    }
+    fn setup(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        for replay in &self.replays {
+            replay.setup(client)?;
+        }
+        Ok(())
+    }
+    fn teardown(&self, transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
+        for replay in &self.replays {
+            replay.teardown(transaction)?;
+        }
+        Ok(())
+    }
+    fn replay_log_until_complete(
+        &self,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()> {
+        for replay in &self.replays {
+            replay.replay_log_until_complete(transaction)?;
+        }
+        Ok(())
+    }
+}
+
+impl crate::replay::TableSetReplay for LogTableReplaySet {
+    fn release_table(
+        &mut self,
+        table: &Table,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()> {
+        if let Some(index) = self
+            .replays
+            .iter()
+            .position(|replay| replay.table == *table)
+        {
+            self.replays.remove(index).teardown(transaction)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 // logical_replay.rs
 // Contains LogicalReplay and related logic.
 
+use crate::identifier::{quote_ident, quote_ident_list};
+use crate::replay::wal2json::{self, ChangeKind, ColumnValue};
 use crate::{ColumnMap, PrimaryKeyInfo, Replay};
 
 #[derive(Clone)]
@@ -11,26 +13,28 @@ pub struct LogicalReplay {
     pub shadow_table: crate::table::Table,
     pub column_map: crate::ColumnMap,
     pub primary_key: crate::PrimaryKeyInfo,
+    /// Changes consumed from the slot per batch
+    pub batch_size: usize,
+    /// Only keep rows matching this condition in the shadow table
+    pub row_filter: Option<String>,
 }
 
 impl Replay for LogicalReplay {
     fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
         // Consume changes from the slot
-        let rows = self.slot.get_changes(client, 100)?;
-        let batch: Vec<serde_json::Value> = rows
+        let rows = self.slot.get_changes(client, self.batch_size as i64)?;
+        let batch = rows
             .iter()
-            .filter_map(|row| {
-                let data: String = row.get("data");
-                serde_json::from_str(&data).ok()
-            })
-            .collect();
+            .map(|row| wal2json::document(row.get::<_, String>("data").as_bytes()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
         let statements = wal2json2sql(
             &batch,
             &self.column_map,
             &self.table,
             &self.shadow_table,
             &self.primary_key,
-        );
+            self.row_filter.as_deref(),
+        )?;
         for stmt in statements {
             client.batch_execute(&stmt)?;
         }
@@ -51,14 +55,11 @@ impl Replay for LogicalReplay {
         transaction: &mut postgres::Transaction,
     ) -> anyhow::Result<()> {
         loop {
-            let rows = self.slot.get_changes(transaction, 100)?;
-            let batch: Vec<serde_json::Value> = rows
+            let rows = self.slot.get_changes(transaction, self.batch_size as i64)?;
+            let batch = rows
                 .iter()
-                .filter_map(|row| {
-                    let data: String = row.get("data");
-                    serde_json::from_str(&data).ok()
-                })
-                .collect();
+                .map(|row| wal2json::document(row.get::<_, String>("data").as_bytes()))
+                .collect::<anyhow::Result<Vec<_>>>()?;
             if batch.is_empty() {
                 break;
             }
@@ -68,7 +69,8 @@ impl Replay for LogicalReplay {
                 &self.table,
                 &self.shadow_table,
                 &self.primary_key,
-            );
+                self.row_filter.as_deref(),
+            )?;
             for stmt in statements {
                 transaction.batch_execute(&stmt)?;
             }
@@ -77,99 +79,427 @@ impl Replay for LogicalReplay {
     }
 }
 
-/// Converts a batch of wal2json rows to SQL statements to replay the changes.
+/// Converts a batch of wal2json rows to SQL statements to replay the changes. Only changes to
+/// `main_table` are converted, so one slot can feed several shadow tables. Rows in format 1 hold
+/// a transaction's changes in `change`; rows in format 2 are a single change with an `action`.
+/// With a `row_filter`, only rows matching it are kept in the shadow table; it's evaluated
+/// against the main table's row, in the main table's column names.
 pub fn wal2json2sql(
     batch: &[serde_json::Value],
     column_map: &ColumnMap,
     main_table: &crate::table::Table,
     shadow_table: &crate::table::Table,
     primary_key: &PrimaryKeyInfo,
-) -> Vec<String> {
+    row_filter: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
     let mut statements = Vec::new();
-    let shadow_cols = column_map.shadow_cols();
-    let main_cols = column_map.main_cols();
-    let insert_cols_csv = shadow_cols.join(", ");
-    let select_cols_csv = main_cols.join(", ");
-    let pk_col = &primary_key.name;
-    let pk_type = &primary_key.ty;
+    let assignments = column_map.assignments();
+    let insert_cols_csv = quote_ident_list(&column_map.insert_cols());
+    let select_cols_csv = column_map.select_list();
+    let pk_col = &quote_ident(&primary_key.name);
+    let shadow_pk = column_map.shadow_key(&primary_key.name)?;
+    let shadow_pk_col = &quote_ident(&shadow_pk);
+    let filter_condition =
+        row_filter.map_or(String::new(), |row_filter| format!(" AND ({})", row_filter));
+    let statement = |kind: ChangeKind, pk_sql: &str| match kind {
+        ChangeKind::Delete => format!(
+            "DELETE FROM {} WHERE {} = {}",
+            shadow_table, shadow_pk_col, pk_sql
+        ),
+        ChangeKind::Insert => format!(
+            "INSERT INTO {shadow} ({cols}) OVERRIDING SYSTEM VALUE SELECT {selectCols} FROM {main} WHERE {pk_col} = {pk_val}{row_filter}",
+            shadow = shadow_table,
+            main = main_table,
+            cols = insert_cols_csv,
+            selectCols = select_cols_csv,
+            pk_col = pk_col,
+            pk_val = pk_sql,
+            row_filter = filter_condition
+        ),
+        ChangeKind::Update => {
+            let set_clause = assignments
+                .iter()
+                // The key is matched by the WHERE clause, and can't be assigned
+                // when it's a GENERATED ALWAYS identity column
+                .filter(|(shadow_col, _value)| *shadow_col != shadow_pk)
+                .map(|(shadow_col, value)| {
+                    format!(
+                        "{} = (SELECT {} FROM {} WHERE {} = {})",
+                        quote_ident(shadow_col),
+                        value,
+                        main_table,
+                        pk_col,
+                        pk_sql
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "UPDATE {shadow} SET {set_clause} WHERE {pk_col} = {pk_val}",
+                shadow = shadow_table,
+                set_clause = set_clause,
+                pk_col = shadow_pk_col,
+                pk_val = pk_sql
+            )
+        }
+    };
+    // An update moving the row to a new key leaves nothing to copy from at the old key, so
+    // the old row is deleted and the new one upserted
+    let upsert = |pk_sql: &str| {
+        let set_clause = assignments
+            .iter()
+            .filter(|(shadow_col, _value)| *shadow_col != shadow_pk)
+            .map(|(shadow_col, _value)| {
+                format!("{col} = EXCLUDED.{col}", col = quote_ident(shadow_col))
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{} ON CONFLICT ({}) {}",
+            statement(ChangeKind::Insert, pk_sql),
+            shadow_pk_col,
+            if set_clause.is_empty() {
+                "DO NOTHING".to_string()
+            } else {
+                format!("DO UPDATE SET {}", set_clause.join(", "))
+            }
+        )
+    };
     for json in batch {
-        // Parse wal2json JSON and extract operation, pk, etc.
-        if let Some(changes) = json.get("change").and_then(|c| c.as_array()) {
-            for change in changes {
-                let kind = change.get("kind").and_then(|k| k.as_str()).unwrap_or("");
-                let pk_sql = if kind == "delete" {
-                    // For DELETE, get PK from oldkeys.keyvalues
-                    change
-                        .get("oldkeys")
-                        .and_then(|ok| ok.get("keyvalues"))
-                        .and_then(|v| v.as_array())
-                        .and_then(|arr| arr.first())
-                        .map(|pk_val| {
-                            if pk_type == &postgres::types::Type::INT4 {
-                                pk_val.as_i64().unwrap_or(0).to_string()
-                            } else {
-                                pk_val.to_string()
-                            }
-                        })
-                        .unwrap_or("NULL".to_string())
-                } else {
-                    // For insert/update, get PK from columnvalues
-                    change
-                        .get("columnvalues")
-                        .and_then(|v| v.as_array())
-                        .and_then(|arr| arr.first())
-                        .map(|pk_val| {
-                            if pk_type == &postgres::types::Type::INT4 {
-                                pk_val.as_i64().unwrap_or(0).to_string()
-                            } else {
-                                pk_val.to_string()
-                            }
-                        })
-                        .unwrap_or("NULL".to_string())
-                };
-                match kind {
-                    "delete" => {
-                        let stmt =
-                            format!("DELETE FROM {} WHERE {} = {}", shadow_table, pk_col, pk_sql);
-                        statements.push(stmt);
-                    }
-                    "insert" => {
-                        let stmt = format!(
-                            "INSERT INTO {shadow} ({cols}) SELECT {selectCols} FROM {main} WHERE {pk_col} = {pk_val}",
-                            shadow = shadow_table,
-                            main = main_table,
-                            cols = insert_cols_csv,
-                            selectCols = select_cols_csv,
-                            pk_col = pk_col,
-                            pk_val = pk_sql
-                        );
-                        statements.push(stmt);
-                    }
-                    "update" => {
-                        let set_clause = shadow_cols
-                            .iter()
-                            .zip(main_cols.iter())
-                            .map(|(shadow_col, main_col)| {
-                                format!(
-                                    "{} = (SELECT {} FROM {} WHERE {} = {})",
-                                    shadow_col, main_col, main_table, pk_col, pk_sql
-                                )
-                            })
-                            .collect::<Vec<_>>()
-                            .join(", ");
-                        let stmt = format!(
-                            "UPDATE {shadow} SET {set_clause} WHERE {pk_col} = {pk_val}",
-                            shadow = shadow_table,
-                            set_clause = set_clause,
-                            pk_col = pk_col,
-                            pk_val = pk_sql
-                        );
-                        statements.push(stmt);
-                    }
-                    _ => {}
-                }
+        for change in wal2json::changes(json)?
+            .iter()
+            .filter(|change| change.is_to(main_table))
+        {
+            if let Some((old_key, new_key)) = change.changed_value(&primary_key.name) {
+                statements.push(statement(ChangeKind::Delete, &old_key.to_sql()));
+                statements.push(upsert(&new_key.to_sql()));
+                continue;
+            }
+            // Matching `NULL` would drop the change without a trace
+            let Some(pk_sql) = change.key_value(&primary_key.name).map(ColumnValue::to_sql) else {
+                anyhow::bail!(
+                    "{:?} on {} carries no value for the key {}",
+                    change.kind,
+                    main_table,
+                    primary_key.name
+                );
+            };
+            statements.push(statement(change.kind, &pk_sql));
+            if let (ChangeKind::Update, Some(row_filter)) = (change.kind, row_filter) {
+                statements.extend(crate::replay::row_filter_statements(
+                    main_table,
+                    shadow_table,
+                    column_map,
+                    &primary_key.name,
+                    &pk_sql,
+                    row_filter,
+                )?);
             }
         }
     }
-    statements
+    Ok(statements)
+}
+
+/// Whether decoded data from the stream ends a transaction, so that its position is safe to
+/// confirm. Only format 2 spreads a transaction over several messages; anything else, including
+/// output from other plugins, stands alone.
+pub fn ends_transaction(data: &[u8]) -> bool {
+    let Ok(json) = serde_json::from_slice::<serde_json::Value>(data) else {
+        return true;
+    };
+    match json.get("action").and_then(|a| a.as_str()) {
+        Some("C") | None => true,
+        Some("M") => json.get("transactional").and_then(|t| t.as_bool()) == Some(false),
+        Some(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+
+    #[test]
+    fn test_wal2json2sql_only_replays_changes_to_the_main_table() {
+        let batch = vec![serde_json::json!({
+            "change": [
+                {"kind": "insert", "schema": "public", "table": "orders",
+                 "columnnames": ["id"], "columnvalues": [1]},
+                {"kind": "insert", "schema": "public", "table": "users",
+                 "columnnames": ["id"], "columnvalues": [2]},
+                {"kind": "delete", "schema": "post_migrations", "table": "orders",
+                 "oldkeys": {"keynames": ["id"], "keyvalues": [3]}},
+                {"kind": "delete", "schema": "public", "table": "orders",
+                 "oldkeys": {"keynames": ["id"], "keyvalues": [4]}}
+            ]
+        })];
+        let column_map = ColumnMap::identity(vec!["id".to_string()]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.orders"),
+            &Table::new("post_migrations.orders"),
+            &primary_key,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.orders (id) OVERRIDING SYSTEM VALUE SELECT id FROM public.orders WHERE id = 1",
+                "DELETE FROM post_migrations.orders WHERE id = 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wal2json2sql_refuses_a_change_without_its_key() {
+        let batch = vec![serde_json::json!({
+            "action": "D", "schema": "public", "table": "orders",
+            "identity": [{"name": "note", "type": "text", "value": "gone"}]
+        })];
+        let column_map = ColumnMap::identity(vec!["id".to_string(), "note".to_string()]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let err = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.orders"),
+            &Table::new("post_migrations.orders"),
+            &primary_key,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Delete on public.orders carries no value for the key id"
+        );
+    }
+
+    #[test]
+    fn test_wal2json2sql_format_2_finds_the_key_by_name() {
+        let batch = vec![
+            serde_json::json!({"action": "B"}),
+            serde_json::json!({"action": "I", "schema": "public", "table": "orders",
+            "columns": [
+                {"name": "note", "type": "text", "value": "first"},
+                {"name": "id", "type": "bigint", "value": 1}
+            ]}),
+            serde_json::json!({"action": "U", "schema": "public", "table": "orders",
+            "columns": [
+                {"name": "note", "type": "text", "value": "moved"},
+                {"name": "id", "type": "bigint", "value": 3}
+            ],
+            "identity": [
+                {"name": "note", "type": "text", "value": "first"},
+                {"name": "id", "type": "bigint", "value": 2}
+            ]}),
+            serde_json::json!({"action": "D", "schema": "public", "table": "orders",
+                "identity": [{"name": "id", "type": "bigint", "value": 4}]}),
+            serde_json::json!({"action": "I", "schema": "public", "table": "users",
+                "columns": [{"name": "id", "type": "bigint", "value": 5}]}),
+            serde_json::json!({"action": "C"}),
+        ];
+        let column_map = ColumnMap::identity(vec!["note".to_string(), "id".to_string()]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.orders"),
+            &Table::new("post_migrations.orders"),
+            &primary_key,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.orders (note, id) OVERRIDING SYSTEM VALUE SELECT note, id FROM public.orders WHERE id = 1",
+                "DELETE FROM post_migrations.orders WHERE id = 2",
+                "INSERT INTO post_migrations.orders (note, id) OVERRIDING SYSTEM VALUE SELECT note, id FROM public.orders WHERE id = 3 ON CONFLICT (id) DO UPDATE SET note = EXCLUDED.note",
+                "DELETE FROM post_migrations.orders WHERE id = 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ends_transaction() {
+        assert!(ends_transaction(br#"{"action":"C"}"#));
+        assert!(!ends_transaction(br#"{"action":"I","table":"orders"}"#));
+        assert!(ends_transaction(
+            br#"{"action":"M","transactional":false,"prefix":"p","content":"c"}"#
+        ));
+        assert!(!ends_transaction(
+            br#"{"action":"M","transactional":true,"prefix":"p","content":"c"}"#
+        ));
+        assert!(ends_transaction(br#"{"change":[]}"#));
+        assert!(ends_transaction(b"COMMIT 9171"));
+    }
+
+    #[test]
+    fn test_wal2json2sql_key_is_not_the_first_column() {
+        let batch = vec![serde_json::json!({
+            "change": [
+                {"kind": "insert", "schema": "public", "table": "events",
+                 "columnnames": ["tenant_id", "payload", "event_id"],
+                 "columntypes": ["integer", "text", "bigint"],
+                 "columnvalues": [9, "created", 100]},
+                {"kind": "delete", "schema": "public", "table": "events",
+                 "oldkeys": {"keynames": ["tenant_id", "event_id"],
+                             "keytypes": ["integer", "bigint"], "keyvalues": [9, 101]}}
+            ]
+        })];
+        let column_map = ColumnMap::identity(vec![
+            "tenant_id".to_string(),
+            "payload".to_string(),
+            "event_id".to_string(),
+        ]);
+        let primary_key = PrimaryKeyInfo {
+            name: "event_id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.events"),
+            &Table::new("post_migrations.events"),
+            &primary_key,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.events (tenant_id, payload, event_id) OVERRIDING SYSTEM VALUE SELECT tenant_id, payload, event_id FROM public.events WHERE event_id = 100",
+                "DELETE FROM post_migrations.events WHERE event_id = 101",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wal2json2sql_renamed_key() {
+        let batch = vec![
+            serde_json::json!({"action": "I", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 1}]}),
+            serde_json::json!({"action": "U", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 2}]}),
+            serde_json::json!({"action": "D", "schema": "public", "table": "events",
+                "identity": [{"name": "id", "type": "bigint", "value": 3}]}),
+        ];
+        let column_map = ColumnMap::identity(vec!["id".to_string(), "note".to_string()])
+            .with_mappings(&[("id".to_string(), Some("event_id".to_string()))]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.events"),
+            &Table::new("post_migrations.events"),
+            &primary_key,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.events (event_id, note) OVERRIDING SYSTEM VALUE SELECT id, note FROM public.events WHERE id = 1",
+                "UPDATE post_migrations.events SET note = (SELECT note FROM public.events WHERE id = 2) WHERE event_id = 2",
+                "DELETE FROM post_migrations.events WHERE event_id = 3",
+            ]
+        );
+
+        let dropped = ColumnMap::identity(vec!["id".to_string(), "note".to_string()])
+            .with_mappings(&[("id".to_string(), None)]);
+        assert!(
+            wal2json2sql(
+                &batch,
+                &dropped,
+                &Table::new("public.events"),
+                &Table::new("post_migrations.events"),
+                &primary_key,
+                None,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_wal2json2sql_row_filter() {
+        let batch = vec![
+            serde_json::json!({"action": "I", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 1}]}),
+            serde_json::json!({"action": "U", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 2}]}),
+        ];
+        let column_map = ColumnMap::identity(vec!["id".to_string(), "note".to_string()]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.events"),
+            &Table::new("post_migrations.events"),
+            &primary_key,
+            Some("created_at > now() - interval '90 days'"),
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.events (id, note) OVERRIDING SYSTEM VALUE SELECT id, note FROM public.events WHERE id = 1 AND (created_at > now() - interval '90 days')",
+                "UPDATE post_migrations.events SET note = (SELECT note FROM public.events WHERE id = 2) WHERE id = 2",
+                "DELETE FROM post_migrations.events WHERE id = 2 AND NOT EXISTS (SELECT 1 FROM public.events WHERE id = 2 AND (created_at > now() - interval '90 days'))",
+                "INSERT INTO post_migrations.events (id, note) OVERRIDING SYSTEM VALUE SELECT id, note FROM public.events WHERE id = 2 AND (created_at > now() - interval '90 days') AND NOT EXISTS (SELECT 1 FROM post_migrations.events WHERE id = 2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wal2json2sql_row_filter_reads_the_main_table() {
+        let batch = vec![
+            serde_json::json!({"action": "I", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 1}]}),
+            serde_json::json!({"action": "U", "schema": "public", "table": "events",
+                "columns": [{"name": "id", "type": "bigint", "value": 2}]}),
+        ];
+        let column_map = ColumnMap::identity(vec!["id".to_string(), "created_at".to_string()])
+            .with_mappings(&[
+                ("id".to_string(), Some("event_id".to_string())),
+                ("created_at".to_string(), Some("created".to_string())),
+            ]);
+        let primary_key = PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        };
+        let statements = wal2json2sql(
+            &batch,
+            &column_map,
+            &Table::new("public.events"),
+            &Table::new("post_migrations.events"),
+            &primary_key,
+            Some("created_at > '2020-01-01'"),
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO post_migrations.events (event_id, created) OVERRIDING SYSTEM VALUE SELECT id, created_at FROM public.events WHERE id = 1 AND (created_at > '2020-01-01')",
+                "UPDATE post_migrations.events SET created = (SELECT created_at FROM public.events WHERE id = 2) WHERE event_id = 2",
+                "DELETE FROM post_migrations.events WHERE event_id = 2 AND NOT EXISTS (SELECT 1 FROM public.events WHERE id = 2 AND (created_at > '2020-01-01'))",
+                "INSERT INTO post_migrations.events (event_id, created) OVERRIDING SYSTEM VALUE SELECT id, created_at FROM public.events WHERE id = 2 AND (created_at > '2020-01-01') AND NOT EXISTS (SELECT 1 FROM post_migrations.events WHERE event_id = 2)",
+            ]
+        );
+    }
 }
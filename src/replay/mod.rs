@@ -1,6 +1,56 @@
 pub mod log_table_replay;
 pub mod logical_replay;
+pub mod shared_logical_replay;
 pub mod streaming_logical_replay;
+pub mod wal2json;
+
+/// Changes applied per replay batch unless configured otherwise.
+pub const DEFAULT_REPLAY_BATCH_SIZE: usize = 100;
+/// Pause between replay batches while the backfill runs, unless configured otherwise.
+pub const DEFAULT_REPLAY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+/// How long a streaming cutover waits for the replay complete marker when no cutover timeout is
+/// configured.
+pub const DEFAULT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Statements run after a replayed update of the row keyed `pk_sql`, keeping the shadow table
+/// to the rows matching `row_filter`: the row is removed when it no longer matches, and copied
+/// in when it has come to match. `pk_col` is the main table's key, found in the shadow table
+/// through `column_map`. `row_filter` names the main table's columns, so it's only evaluated
+/// against the main table's row.
+pub fn row_filter_statements(
+    main_table: &crate::table::Table,
+    shadow_table: &crate::table::Table,
+    column_map: &crate::ColumnMap,
+    pk_col: &str,
+    pk_sql: &str,
+    row_filter: &str,
+) -> anyhow::Result<Vec<String>> {
+    use crate::identifier::{quote_ident, quote_ident_list};
+    let shadow_pk_col = quote_ident(&column_map.shadow_key(pk_col)?);
+    let pk_col = quote_ident(pk_col);
+    Ok(vec![
+        format!(
+            "DELETE FROM {shadow} WHERE {shadow_pk_col} = {pk_val} AND NOT EXISTS (SELECT 1 FROM {main} WHERE {pk_col} = {pk_val} AND ({row_filter}))",
+            shadow = shadow_table,
+            main = main_table,
+            shadow_pk_col = shadow_pk_col,
+            pk_col = pk_col,
+            pk_val = pk_sql,
+            row_filter = row_filter
+        ),
+        format!(
+            "INSERT INTO {shadow} ({cols}) OVERRIDING SYSTEM VALUE SELECT {select_cols} FROM {main} WHERE {pk_col} = {pk_val} AND ({row_filter}) AND NOT EXISTS (SELECT 1 FROM {shadow} WHERE {shadow_pk_col} = {pk_val})",
+            shadow = shadow_table,
+            main = main_table,
+            cols = quote_ident_list(&column_map.insert_cols()),
+            select_cols = column_map.select_list(),
+            shadow_pk_col = shadow_pk_col,
+            pk_col = pk_col,
+            pk_val = pk_sql,
+            row_filter = row_filter
+        ),
+    ])
+}
 
 pub trait Replay {
     fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()>;
@@ -11,3 +61,14 @@ pub trait Replay {
         transaction: &mut postgres::Transaction,
     ) -> anyhow::Result<()>;
 }
+
+/// Replay capturing changes for several tables migrated together. Each table is released from
+/// capture at its own cutover, once its remaining changes have been replayed.
+pub trait TableSetReplay: Replay {
+    /// Stops capturing `table`, tearing down the capture altogether after the last table.
+    fn release_table(
+        &mut self,
+        table: &crate::table::Table,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()>;
+}
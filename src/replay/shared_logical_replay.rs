@@ -0,0 +1,111 @@
+// shared_logical_replay.rs
+// One slot and publication feeding the shadow tables of several migrations.
+
+use crate::logical_replication::{Publication, Slot};
+use crate::replay::TableSetReplay;
+use crate::replay::logical_replay::wal2json2sql;
+use crate::{ColumnMap, PrimaryKeyInfo, Replay, Table};
+
+/// A table whose changes are replayed into its shadow table.
+#[derive(Clone)]
+pub struct ReplayTarget {
+    pub table: Table,
+    pub shadow_table: Table,
+    pub column_map: ColumnMap,
+    pub primary_key: PrimaryKeyInfo,
+    /// Only keep rows matching this condition in the shadow table
+    pub row_filter: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct SharedLogicalReplay {
+    pub slot: Slot,
+    pub publication: Publication,
+    /// Tables still being captured
+    pub targets: Vec<ReplayTarget>,
+    /// Changes consumed from the slot per batch
+    pub batch_size: usize,
+}
+
+impl SharedLogicalReplay {
+    /// Consumes a batch from the slot, only from transactions committed before `upto_lsn` when
+    /// given, and replays each table's changes into its shadow table. Returns whether the slot
+    /// had any changes.
+    fn replay_batch<C: postgres::GenericClient>(
+        &self,
+        client: &mut C,
+        upto_lsn: Option<postgres::types::PgLsn>,
+    ) -> anyhow::Result<bool> {
+        let rows = match upto_lsn {
+            Some(upto_lsn) => {
+                self.slot
+                    .get_changes_until(client, upto_lsn, self.batch_size as i64)?
+            }
+            None => self.slot.get_changes(client, self.batch_size as i64)?,
+        };
+        let batch = rows
+            .iter()
+            .map(|row| crate::replay::wal2json::document(row.get::<_, String>("data").as_bytes()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for target in &self.targets {
+            let statements = wal2json2sql(
+                &batch,
+                &target.column_map,
+                &target.table,
+                &target.shadow_table,
+                &target.primary_key,
+                target.row_filter.as_deref(),
+            )?;
+            for stmt in statements {
+                client.batch_execute(&stmt)?;
+            }
+        }
+        Ok(!batch.is_empty())
+    }
+}
+
+impl Replay for SharedLogicalReplay {
+    fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        self.replay_batch(client, None)?;
+        Ok(())
+    }
+    fn setup(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        self.publication.create(client)?;
+        self.slot.create_slot(client)?;
+        Ok(())
+    }
+    fn teardown(&self, transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
+        self.slot.drop_slot(transaction)?;
+        self.publication.drop(transaction)?;
+        Ok(())
+    }
+    fn replay_log_until_complete(
+        &self,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()> {
+        // The caller holds the lock on the table being cut over, so everything it committed is
+        // before the current position. Stopping there keeps writes to the other, unlocked
+        // tables from holding the lock open.
+        let upto_lsn: postgres::types::PgLsn = transaction
+            .query_one("SELECT pg_current_wal_lsn()", &[])?
+            .get(0);
+        while self.replay_batch(transaction, Some(upto_lsn))? {}
+        Ok(())
+    }
+}
+
+impl TableSetReplay for SharedLogicalReplay {
+    fn release_table(
+        &mut self,
+        table: &Table,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()> {
+        self.targets.retain(|target| target.table != *table);
+        self.slot.tables.retain(|decoded| decoded != table);
+        if self.targets.is_empty() {
+            self.teardown(transaction)
+        } else {
+            self.publication.drop_table(table, transaction)
+        }
+    }
+}
@@ -2,73 +2,254 @@
 // Implements StreamingLogicalReplay using LogicalReplicationStream.
 
 use crate::logical_replication::LogicalReplicationStream;
-use crate::replay::logical_replay;
+use crate::logical_replication::stream::{
+    emit_replay_complete_message, is_replay_complete_message,
+};
+use crate::logical_replication::{ReplayProgress, ReplicationMessage};
+use crate::replay::{logical_replay, wal2json};
 use crate::{ColumnMap, PrimaryKeyInfo, Replay, Table};
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+/// Replays changes read from a replication connection. Clones share the connection, so the
+/// replay thread and the cutover take turns with the one stream.
+#[derive(Clone)]
 pub struct StreamingLogicalReplay {
-    pub stream: RefCell<LogicalReplicationStream>,
+    pub stream: Arc<Mutex<LogicalReplicationStream>>,
     pub slot: crate::logical_replication::Slot,
     pub publication: crate::logical_replication::Publication,
     pub table: Table,
     pub shadow_table: Table,
     pub column_map: ColumnMap,
     pub primary_key: PrimaryKeyInfo,
+    /// Messages read from the stream per batch
+    pub batch_size: usize,
+    /// Where the applied position is saved, with the shadow table changes
+    pub progress: ReplayProgress,
+    /// Only keep rows matching this condition in the shadow table
+    pub row_filter: Option<String>,
+    /// How long the cutover waits for the replay complete marker while holding the lock
+    pub drain_timeout: std::time::Duration,
 }
 
-impl Replay for StreamingLogicalReplay {
-    fn setup(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
-        // Create publication if needed
-        self.publication.create(client)?;
-        // Create slot if needed
-        self.slot.create_slot(client)?;
-        // Start the logical replication stream
-        self.stream.borrow_mut().start()?;
-        Ok(())
+impl StreamingLogicalReplay {
+    /// Wraps `stream` so it can be shared between the replay thread and the cutover.
+    pub fn shared_stream(stream: LogicalReplicationStream) -> Arc<Mutex<LogicalReplicationStream>> {
+        Arc::new(Mutex::new(stream))
     }
 
-    fn teardown(&self, _transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
-        // TODO: implement teardown logic
-        Ok(())
+    fn stream(&self) -> anyhow::Result<MutexGuard<'_, LogicalReplicationStream>> {
+        self.stream
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Replication stream poisoned by a panicked replay"))
     }
 
-    fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
-        let mut stream = self.stream.borrow_mut();
-        let messages = stream.next_batch(100, Some(std::time::Duration::from_millis(500)))?;
+    /// Reads the next batch from `stream`, applies it and saves how far it goes, then confirms
+    /// that position.
+    fn apply_batch(
+        &self,
+        stream: &mut LogicalReplicationStream,
+        client: &mut postgres::Client,
+    ) -> anyhow::Result<()> {
+        let mut outages = stream.outages.len();
+        let mut messages =
+            stream.next_batch(self.batch_size, Some(std::time::Duration::from_millis(500)))?;
+        // With wal2json format 2 a batch can end inside a transaction. Read on to its commit, so
+        // the saved position is always between transactions.
+        while messages
+            .iter()
+            .rev()
+            .find_map(|msg| match msg {
+                ReplicationMessage::XLogData(xlog) => Some(xlog),
+                _ => None,
+            })
+            .is_some_and(|xlog| !logical_replay::ends_transaction(&xlog.data))
+        {
+            let more = stream.next_batch(1, None)?;
+            if more.is_empty() {
+                break;
+            }
+            if stream.outages.len() > outages {
+                // Everything since the confirmed position is sent again
+                outages = stream.outages.len();
+                messages = more;
+            } else {
+                messages.extend(more);
+            }
+        }
 
         // Collect wal2json JSON values from XLogData messages
         let mut batch = Vec::new();
         for msg in &messages {
-            if let crate::logical_replication::message::ReplicationMessage::XLogData(xlog) = msg {
-                if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&xlog.data) {
-                    batch.push(json);
-                }
+            if let ReplicationMessage::XLogData(xlog) = msg {
+                batch.push(wal2json::document(&xlog.data)?);
             }
         }
 
-        // Generate SQL statements and execute them
+        // Apply the changes and save how far they go in one transaction
         let statements = logical_replay::wal2json2sql(
             &batch,
             &self.column_map,
             &self.table,
             &self.shadow_table,
             &self.primary_key,
-        );
+            self.row_filter.as_deref(),
+        )?;
+        let lsn = stream.last_lsn();
+        let mut transaction = client.transaction()?;
         for stmt in statements {
-            client.batch_execute(&stmt)?;
+            transaction.batch_execute(&stmt)?;
         }
+        if messages
+            .iter()
+            .any(|msg| matches!(msg, ReplicationMessage::XLogData(_)))
+        {
+            self.progress.save(&self.slot.name, lsn, &mut transaction)?;
+        }
+        transaction.commit()?;
 
-        // Advance the slot's confirmed_flush_lsn to the stream's last_lsn
-        let lsn = stream.last_lsn();
+        // Only confirm the position once the changes up to it are committed
         stream.send_feedback(lsn)?;
         Ok(())
     }
 
-    fn replay_log_until_complete(
+    /// Applies the stream up to the marker written by `emit_replay_complete_message`, within
+    /// `transaction`, giving up after `drain_timeout`.
+    fn drain_to_marker(
         &self,
-        _transaction: &mut postgres::Transaction,
+        stream: &mut LogicalReplicationStream,
+        transaction: &mut postgres::Transaction,
     ) -> anyhow::Result<()> {
-        // TODO: implement streaming replay until complete
+        let mut marker_client = postgres::Client::connect(&stream.conninfo, postgres::NoTls)?;
+        emit_replay_complete_message(&mut marker_client)?;
+        // Nothing drained here is confirmed until the cutover commits, so after a reconnect the
+        // server sends it all again: start over from a savepoint
+        let mut outages = stream.outages.len();
+        let mut savepoint = transaction.savepoint("replay_drain")?;
+        let deadline = std::time::Instant::now() + self.drain_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                anyhow::bail!(
+                    "Timed out after {:?} waiting for the replay complete marker",
+                    self.drain_timeout
+                );
+            }
+            // One message at a time, so reading never waits for WAL past the marker
+            let messages = stream.next_batch(1, Some(remaining))?;
+            if stream.outages.len() > outages {
+                savepoint.rollback()?;
+                savepoint = transaction.savepoint("replay_drain")?;
+                outages = stream.outages.len();
+            }
+            if messages.is_empty() {
+                if std::time::Instant::now() >= deadline {
+                    anyhow::bail!(
+                        "Timed out after {:?} waiting for the replay complete marker",
+                        self.drain_timeout
+                    );
+                }
+                anyhow::bail!(
+                    "Replication stream ended before the replay complete marker: {}",
+                    stream.conn.error_message().unwrap_or("no error reported")
+                );
+            }
+            let mut batch = Vec::new();
+            let mut complete = false;
+            for msg in &messages {
+                if let ReplicationMessage::XLogData(xlog) = msg {
+                    if is_replay_complete_message(&xlog.data) {
+                        complete = true;
+                    } else {
+                        batch.push(wal2json::document(&xlog.data)?);
+                    }
+                }
+            }
+            let statements = logical_replay::wal2json2sql(
+                &batch,
+                &self.column_map,
+                &self.table,
+                &self.shadow_table,
+                &self.primary_key,
+                self.row_filter.as_deref(),
+            )?;
+            for stmt in statements {
+                savepoint.batch_execute(&stmt)?;
+            }
+            if complete {
+                break;
+            }
+        }
+        // The drained position is left unconfirmed: if the cutover rolls back, the slot still
+        // holds these changes, and once it commits teardown drops the slot
+        savepoint.commit()?;
         Ok(())
     }
+
+    /// Restarts streaming from an existing slot after the process was interrupted, from the
+    /// last position saved in `progress`. Changes before it are already in the shadow table.
+    pub fn resume(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        self.progress.create(client)?;
+        let mut stream = self.stream()?;
+        if let Some(lsn) = self.progress.load(&self.slot.name, client)? {
+            stream.last_lsn = lsn;
+        }
+        stream.start()
+    }
+}
+
+impl Replay for StreamingLogicalReplay {
+    fn setup(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        // Create publication if needed
+        self.publication.create(client)?;
+        // Create slot if needed
+        self.slot.create_slot(client)?;
+        self.progress.create(client)?;
+        // Start the logical replication stream
+        self.stream()?.start()?;
+        Ok(())
+    }
+
+    fn teardown(&self, transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
+        // The slot can only be dropped once the stream has released it
+        self.stream()?.stop()?;
+        self.slot.drop_slot(transaction)?;
+        self.publication.drop(transaction)?;
+        self.progress.clear(&self.slot.name, transaction)?;
+        Ok(())
+    }
+
+    /// Applies the next batch. If it fails, the stream is rewound to the confirmed position
+    /// before the error is returned, so the next batch reads the same changes again instead of
+    /// skipping them.
+    fn replay_log(&self, client: &mut postgres::Client) -> anyhow::Result<()> {
+        let mut stream = self.stream()?;
+        self.apply_batch(&mut stream, client)
+            .map_err(|err| rewound(&mut stream, err))
+    }
+
+    /// Marks the end of the WAL to replay with `emit_replay_complete_message`, once the caller
+    /// holds the table lock, and applies the stream up to that marker. The marker is committed
+    /// from its own connection: it can't be decoded until its transaction commits. Reading stops
+    /// with an error after `drain_timeout`, so the cutover rolls back and releases the lock.
+    fn replay_log_until_complete(
+        &self,
+        transaction: &mut postgres::Transaction,
+    ) -> anyhow::Result<()> {
+        let mut stream = self.stream()?;
+        // The cutover rolls back what was drained, so it has to be read again
+        self.drain_to_marker(&mut stream, transaction)
+            .map_err(|err| rewound(&mut stream, err))
+    }
+}
+
+/// Rewinds `stream` to its confirmed position after `err`, the changes read since then not
+/// being applied. A failed rewind is added to `err`.
+fn rewound(stream: &mut LogicalReplicationStream, err: anyhow::Error) -> anyhow::Error {
+    match stream.rewind() {
+        Ok(()) => err,
+        Err(rewind_err) => err.context(format!(
+            "Restarting replication from the confirmed position failed too: {}",
+            rewind_err
+        )),
+    }
 }
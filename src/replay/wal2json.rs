@@ -0,0 +1,293 @@
+// wal2json.rs
+// Typed row changes decoded from wal2json output, in format 1 or 2.
+
+use crate::identifier::quote_literal;
+use crate::table::Table;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A column's value in a change, with its type when wal2json includes types.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnValue {
+    pub ty: Option<String>,
+    pub value: serde_json::Value,
+}
+
+impl ColumnValue {
+    /// The value as an SQL literal. Text is cast to the column's type when it is known.
+    pub fn to_sql(&self) -> String {
+        match &self.value {
+            serde_json::Value::Null => "NULL".to_string(),
+            serde_json::Value::Bool(b) => b.to_string(),
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::String(s) => match &self.ty {
+                Some(ty) => format!("{}::{}", quote_literal(s), ty),
+                None => quote_literal(s),
+            },
+            other => quote_literal(&other.to_string()),
+        }
+    }
+}
+
+/// A row change with its columns by name, so nothing depends on the column order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub schema: Option<String>,
+    pub table: String,
+    /// The new row, empty for deletes
+    pub columns: HashMap<String, ColumnValue>,
+    /// The old row's replica identity columns. Updates only carry them when the identity
+    /// changed or is the full row.
+    pub identity: HashMap<String, ColumnValue>,
+}
+
+impl Change {
+    /// Whether the change is to `table`. The schema is only compared when `table` has one.
+    pub fn is_to(&self, table: &Table) -> bool {
+        self.table == table.name
+            && table
+                .schema
+                .as_deref()
+                .is_none_or(|schema| self.schema.as_deref() == Some(schema))
+    }
+
+    /// The value of `column` identifying the changed row: the old value for updates and
+    /// deletes when wal2json sent it, the new value otherwise.
+    pub fn key_value(&self, column: &str) -> Option<&ColumnValue> {
+        match self.kind {
+            ChangeKind::Insert => self.columns.get(column),
+            ChangeKind::Update => self
+                .identity
+                .get(column)
+                .or_else(|| self.columns.get(column)),
+            ChangeKind::Delete => self.identity.get(column),
+        }
+    }
+
+    /// The old and new values of `column` when an update changed it. wal2json only sends the
+    /// old value of identity columns, and only when they changed or the identity is FULL.
+    pub fn changed_value(&self, column: &str) -> Option<(&ColumnValue, &ColumnValue)> {
+        if self.kind != ChangeKind::Update {
+            return None;
+        }
+        let old = self.identity.get(column)?;
+        let new = self.columns.get(column)?;
+        (old.value != new.value).then_some((old, new))
+    }
+}
+
+/// Parses one document of wal2json output. Output that isn't JSON fails rather than being
+/// skipped, since the changes in it would be lost.
+pub fn document(data: &[u8]) -> Result<serde_json::Value> {
+    serde_json::from_slice(data).with_context(|| {
+        format!(
+            "Couldn't parse wal2json output: {}",
+            String::from_utf8_lossy(data)
+        )
+    })
+}
+
+/// The row changes in one row of wal2json output: every change of a transaction in format 1,
+/// or the single change in format 2. Anything else, such as transaction boundaries and
+/// messages, has none. A document of neither format fails.
+pub fn changes(json: &serde_json::Value) -> Result<Vec<Change>> {
+    if json.get("change").is_some() {
+        let transaction = serde_json::from_value::<V1Transaction>(json.clone())
+            .with_context(|| format!("Couldn't read wal2json format 1 output: {}", json))?;
+        Ok(transaction
+            .change
+            .into_iter()
+            .filter_map(V1Change::into_change)
+            .collect())
+    } else {
+        let change = serde_json::from_value::<V2Change>(json.clone())
+            .with_context(|| format!("Couldn't read wal2json format 2 output: {}", json))?;
+        Ok(change.into_change().into_iter().collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct V1Transaction {
+    #[serde(default)]
+    change: Vec<V1Change>,
+}
+
+#[derive(Deserialize)]
+struct V1Change {
+    kind: String,
+    schema: Option<String>,
+    table: Option<String>,
+    #[serde(default)]
+    columnnames: Vec<String>,
+    #[serde(default)]
+    columntypes: Vec<String>,
+    #[serde(default)]
+    columnvalues: Vec<serde_json::Value>,
+    oldkeys: Option<V1OldKeys>,
+}
+
+#[derive(Deserialize)]
+struct V1OldKeys {
+    #[serde(default)]
+    keynames: Vec<String>,
+    #[serde(default)]
+    keytypes: Vec<String>,
+    #[serde(default)]
+    keyvalues: Vec<serde_json::Value>,
+}
+
+/// Pairs names with values, and types when present, into a map.
+fn by_name(
+    names: Vec<String>,
+    types: Vec<String>,
+    values: Vec<serde_json::Value>,
+) -> HashMap<String, ColumnValue> {
+    let mut types = types.into_iter();
+    names
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let ty = types.next();
+            (name, ColumnValue { ty, value })
+        })
+        .collect()
+}
+
+impl V1Change {
+    fn into_change(self) -> Option<Change> {
+        let kind = match self.kind.as_str() {
+            "insert" => ChangeKind::Insert,
+            "update" => ChangeKind::Update,
+            "delete" => ChangeKind::Delete,
+            _ => return None,
+        };
+        let identity = self
+            .oldkeys
+            .map(|keys| by_name(keys.keynames, keys.keytypes, keys.keyvalues))
+            .unwrap_or_default();
+        Some(Change {
+            kind,
+            schema: self.schema,
+            table: self.table?,
+            columns: by_name(self.columnnames, self.columntypes, self.columnvalues),
+            identity,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct V2Change {
+    action: String,
+    schema: Option<String>,
+    table: Option<String>,
+    #[serde(default)]
+    columns: Vec<V2Column>,
+    #[serde(default)]
+    identity: Vec<V2Column>,
+}
+
+#[derive(Deserialize)]
+struct V2Column {
+    name: String,
+    #[serde(rename = "type")]
+    ty: Option<String>,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+impl V2Change {
+    fn into_change(self) -> Option<Change> {
+        let kind = match self.action.as_str() {
+            "I" => ChangeKind::Insert,
+            "U" => ChangeKind::Update,
+            "D" => ChangeKind::Delete,
+            _ => return None,
+        };
+        let by_name = |columns: Vec<V2Column>| {
+            columns
+                .into_iter()
+                .map(|column| {
+                    let value = ColumnValue {
+                        ty: column.ty,
+                        value: column.value,
+                    };
+                    (column.name, value)
+                })
+                .collect()
+        };
+        Some(Change {
+            kind,
+            schema: self.schema,
+            table: self.table?,
+            columns: by_name(self.columns),
+            identity: by_name(self.identity),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_1_columns_by_name() {
+        let json = serde_json::json!({"change": [
+            {"kind": "update", "schema": "public", "table": "orders",
+             "columnnames": ["note", "tenant", "id"],
+             "columntypes": ["text", "integer", "bigint"],
+             "columnvalues": ["hi", 7, 42],
+             "oldkeys": {"keynames": ["tenant", "id"], "keytypes": ["integer", "bigint"],
+                         "keyvalues": [7, 41]}},
+            {"kind": "message", "prefix": "postgres-ost", "content": "replay complete"}
+        ]});
+        let changes = changes(&json).unwrap();
+        assert_eq!(changes.len(), 1);
+        let change = &changes[0];
+        assert_eq!(change.kind, ChangeKind::Update);
+        assert!(change.is_to(&Table::new("public.orders")));
+        assert_eq!(change.key_value("id").unwrap().to_sql(), "41");
+        assert_eq!(change.key_value("tenant").unwrap().to_sql(), "7");
+        assert_eq!(change.columns["note"].to_sql(), "'hi'::text");
+        let (old, new) = change.changed_value("id").unwrap();
+        assert_eq!(
+            (old.to_sql(), new.to_sql()),
+            ("41".to_string(), "42".to_string())
+        );
+        assert!(change.changed_value("tenant").is_none());
+        assert!(change.changed_value("note").is_none());
+    }
+
+    #[test]
+    fn test_format_2_delete_uses_identity() {
+        let json = serde_json::json!({"action": "D", "schema": "public", "table": "orders",
+            "identity": [{"name": "code", "type": "uuid",
+                          "value": "6f1c7a36-5d0c-4c8e-9a43-1d5f3f1f2b10"}]});
+        let changes = changes(&json).unwrap();
+        assert_eq!(
+            changes[0].key_value("code").unwrap().to_sql(),
+            "'6f1c7a36-5d0c-4c8e-9a43-1d5f3f1f2b10'::uuid"
+        );
+        assert!(changes[0].key_value("missing").is_none());
+        assert!(
+            super::changes(&serde_json::json!({"action": "C"}))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_unreadable_output_fails() {
+        assert!(document(b"{\"action\": \"I\"").is_err());
+        assert!(changes(&serde_json::json!({"change": [{"table": "orders"}]})).is_err());
+        assert!(changes(&serde_json::json!({"table": "orders"})).is_err());
+    }
+}
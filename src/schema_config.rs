@@ -0,0 +1,300 @@
+// src/schema_config.rs
+// Where the tool keeps its own objects, how they're named and who may access them.
+
+use crate::identifier::quote_ident;
+use crate::table::Table;
+use anyhow::{Result, bail};
+
+pub const DEFAULT_SHADOW_SCHEMA: &str = "post_migrations";
+pub const DEFAULT_LOG_SCHEMA: &str = "post_migrations_log";
+pub const DEFAULT_ARCHIVE_SCHEMA: &str = "post_migrations_old";
+pub const DEFAULT_STATE_SCHEMA: &str = "post_migrations_state";
+pub const DEFAULT_TABLE_NAME_TEMPLATE: &str = "{namespaced}";
+
+/// Longest identifier Postgres keeps (NAMEDATALEN - 1); longer names are silently truncated.
+pub const MAX_IDENTIFIER_LENGTH: usize = 63;
+/// Room left after a tool table name for the suffixes derived from it, the longest being the
+/// log table's trigger functions (`_log_insert_trigger_fn`).
+const DERIVED_SUFFIX_LENGTH: usize = "_log_insert_trigger_fn".len();
+
+/// Schema names, naming template and access control for the tool's shadow, log and archived
+/// tables and its bookkeeping tables.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaConfig {
+    /// Schema holding the shadow table while it is being built.
+    pub shadow_schema: String,
+    /// Schema holding the trigger log table and its trigger functions.
+    pub log_schema: String,
+    /// Schema the original table is moved to after the swap.
+    pub archive_schema: String,
+    /// Schema holding the tool's bookkeeping tables, such as saved replay positions. No tool
+    /// table is placed here, so no migrated table can take their names.
+    pub state_schema: String,
+    /// Template for the names of tool tables. `{schema}` and `{table}` expand to the source
+    /// table's schema and name, `{namespaced}` to the table name prefixed with its schema and a
+    /// `.` unless it lives in `public`. Names that contain a `.` themselves, or that would leave
+    /// no room for the tool's suffixes within 63 bytes, are shortened and given a hash of the
+    /// source table's schema and name. With `{namespaced}` two tables never share a tool table;
+    /// a template without the schema, such as `{table}`, gives same-named tables in different
+    /// schemas the same one.
+    pub table_name_template: String,
+    /// Role that should own the tool schemas.
+    pub owner: Option<String>,
+    /// Roles granted `USAGE` on the tool schemas. When set, `PUBLIC` access is revoked.
+    pub usage_grants: Vec<String>,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig {
+            shadow_schema: DEFAULT_SHADOW_SCHEMA.to_string(),
+            log_schema: DEFAULT_LOG_SCHEMA.to_string(),
+            archive_schema: DEFAULT_ARCHIVE_SCHEMA.to_string(),
+            state_schema: DEFAULT_STATE_SCHEMA.to_string(),
+            table_name_template: DEFAULT_TABLE_NAME_TEMPLATE.to_string(),
+            owner: None,
+            usage_grants: Vec::new(),
+        }
+    }
+}
+
+impl SchemaConfig {
+    /// Refuses layouts where tool tables could collide. Each kind of tool table needs a schema
+    /// of its own: a shadow and an archived copy of one table have the same name, a log table
+    /// shares the name of the other tables of a table called `orders_log`, and a table called
+    /// `replay_progress` would take the name of a bookkeeping table.
+    pub fn validate(&self) -> Result<()> {
+        let schemas = [
+            ("shadow", &self.shadow_schema),
+            ("log", &self.log_schema),
+            ("archive", &self.archive_schema),
+            ("state", &self.state_schema),
+        ];
+        for (index, (kind, schema)) in schemas.iter().enumerate() {
+            for (other_kind, other_schema) in &schemas[index + 1..] {
+                if schema == other_schema {
+                    bail!(
+                        "The {} and {} schemas are both {}; each needs its own schema, or their tables could take each other's names",
+                        kind,
+                        other_kind,
+                        schema
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Name used for the tool's copies of `table` in the tool schemas.
+    pub fn table_name(&self, table: &Table) -> String {
+        let schema = table.schema.as_deref().unwrap_or("public");
+        let namespaced = match schema {
+            "public" => table.name.clone(),
+            schema => format!("{}.{}", schema, table.name),
+        };
+        let name = self
+            .table_name_template
+            .replace("{schema}", schema)
+            .replace("{table}", &table.name)
+            .replace("{namespaced}", &namespaced);
+        // A `.` inside either part makes the split ambiguous: `billing`.`invoices` and a public
+        // table named `billing.invoices` would otherwise expand alike
+        let ambiguous = schema.contains('.') || table.name.contains('.');
+        let limit = MAX_IDENTIFIER_LENGTH - DERIVED_SUFFIX_LENGTH;
+        if !ambiguous && name.len() <= limit {
+            return name;
+        }
+        let hash = format!("_{:08x}", fnv1a(&format!("{}\0{}", schema, table.name)));
+        let mut end = name.len().min(limit - hash.len());
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{}", &name[..end], hash)
+    }
+
+    pub fn shadow_table(&self, table: &Table) -> Table {
+        Table::from_parts(Some(&self.shadow_schema), &self.table_name(table))
+    }
+
+    pub fn log_table(&self, table: &Table) -> Table {
+        Table::from_parts(Some(&self.log_schema), &self.table_name(table)).with_suffix("_log")
+    }
+
+    pub fn archive_table(&self, table: &Table) -> Table {
+        Table::from_parts(Some(&self.archive_schema), &self.table_name(table))
+    }
+
+    /// The distinct schemas the tool needs.
+    pub fn schemas(&self) -> Vec<&str> {
+        let mut schemas = vec![
+            self.shadow_schema.as_str(),
+            self.log_schema.as_str(),
+            self.archive_schema.as_str(),
+            self.state_schema.as_str(),
+        ];
+        schemas.sort();
+        schemas.dedup();
+        schemas
+    }
+
+    /// Statements creating the tool schemas and applying the configured owner and grants.
+    pub fn create_statements(&self) -> Vec<String> {
+        let mut statements = Vec::new();
+        for schema in self.schemas() {
+            let schema = quote_ident(schema);
+            statements.push(format!("CREATE SCHEMA IF NOT EXISTS {};", schema));
+            if let Some(owner) = &self.owner {
+                statements.push(format!(
+                    "ALTER SCHEMA {} OWNER TO {};",
+                    schema,
+                    quote_ident(owner)
+                ));
+            }
+            if !self.usage_grants.is_empty() {
+                statements.push(format!("REVOKE ALL ON SCHEMA {} FROM PUBLIC;", schema));
+                for role in &self.usage_grants {
+                    statements.push(format!(
+                        "GRANT USAGE ON SCHEMA {} TO {};",
+                        schema,
+                        quote_ident(role)
+                    ));
+                }
+            }
+        }
+        statements
+    }
+}
+
+/// 32-bit FNV-1a, stable across builds so a resumed run derives the same names.
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_names() {
+        let config = SchemaConfig::default();
+        let table = Table::from_parts(Some("public"), "test_table");
+        assert_eq!(
+            config.shadow_table(&table).to_string(),
+            "post_migrations.test_table"
+        );
+        assert_eq!(
+            config.log_table(&table).to_string(),
+            "post_migrations_log.test_table_log"
+        );
+        assert_eq!(
+            config.archive_table(&table).to_string(),
+            "post_migrations_old.test_table"
+        );
+        let table = Table::from_parts(Some("billing"), "invoices");
+        assert_eq!(
+            config.shadow_table(&table).to_string(),
+            "post_migrations.\"billing.invoices\""
+        );
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_ambiguous_and_long_names_are_hashed() {
+        let config = SchemaConfig::default();
+        let qualified = config.table_name(&Table::from_parts(Some("billing"), "invoices"));
+        let dotted = config.table_name(&Table::from_parts(Some("public"), "billing.invoices"));
+        assert_eq!(qualified, "billing.invoices");
+        assert_ne!(dotted, qualified);
+        assert!(dotted.starts_with("billing.invoices_"));
+
+        let long = "a".repeat(60);
+        let first = config.table_name(&Table::from_parts(Some("public"), &long));
+        let second = config.table_name(&Table::from_parts(Some("public"), &format!("{}b", long)));
+        assert_ne!(first, second);
+        assert_eq!(first.len(), MAX_IDENTIFIER_LENGTH - DERIVED_SUFFIX_LENGTH);
+        let log = config.log_table(&Table::from_parts(Some("public"), &long));
+        assert!(format!("{}_insert_trigger_fn", log.name).len() <= MAX_IDENTIFIER_LENGTH);
+        // Stable, so a resumed run finds the same tables
+        assert_eq!(
+            first,
+            config.table_name(&Table::from_parts(Some("public"), &long))
+        );
+    }
+
+    #[test]
+    fn test_tool_schemas_must_be_distinct() {
+        let config = SchemaConfig {
+            log_schema: DEFAULT_SHADOW_SCHEMA.to_string(),
+            ..SchemaConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = SchemaConfig {
+            archive_schema: DEFAULT_SHADOW_SCHEMA.to_string(),
+            ..SchemaConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = SchemaConfig {
+            archive_schema: DEFAULT_LOG_SCHEMA.to_string(),
+            ..SchemaConfig::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "The log and archive schemas are both post_migrations_log; each needs its own schema, or their tables could take each other's names"
+        );
+        let config = SchemaConfig {
+            state_schema: DEFAULT_SHADOW_SCHEMA.to_string(),
+            ..SchemaConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_custom_schemas_and_template() {
+        let config = SchemaConfig {
+            shadow_schema: "_ost".to_string(),
+            log_schema: "_ost_log".to_string(),
+            archive_schema: "_ost_archive".to_string(),
+            state_schema: "_ost_state".to_string(),
+            table_name_template: "{schema}__{table}".to_string(),
+            owner: Some("ost_owner".to_string()),
+            usage_grants: vec!["dba".to_string()],
+        };
+        let table = Table::from_parts(Some("billing"), "invoices");
+        assert_eq!(
+            config.shadow_table(&table).to_string(),
+            "_ost.billing__invoices"
+        );
+        assert_eq!(
+            config.log_table(&table).to_string(),
+            "_ost_log.billing__invoices_log"
+        );
+        assert_eq!(
+            config.archive_table(&table).to_string(),
+            "_ost_archive.billing__invoices"
+        );
+        assert_eq!(
+            config.create_statements(),
+            vec![
+                "CREATE SCHEMA IF NOT EXISTS _ost;",
+                "ALTER SCHEMA _ost OWNER TO ost_owner;",
+                "REVOKE ALL ON SCHEMA _ost FROM PUBLIC;",
+                "GRANT USAGE ON SCHEMA _ost TO dba;",
+                "CREATE SCHEMA IF NOT EXISTS _ost_archive;",
+                "ALTER SCHEMA _ost_archive OWNER TO ost_owner;",
+                "REVOKE ALL ON SCHEMA _ost_archive FROM PUBLIC;",
+                "GRANT USAGE ON SCHEMA _ost_archive TO dba;",
+                "CREATE SCHEMA IF NOT EXISTS _ost_log;",
+                "ALTER SCHEMA _ost_log OWNER TO ost_owner;",
+                "REVOKE ALL ON SCHEMA _ost_log FROM PUBLIC;",
+                "GRANT USAGE ON SCHEMA _ost_log TO dba;",
+                "CREATE SCHEMA IF NOT EXISTS _ost_state;",
+                "ALTER SCHEMA _ost_state OWNER TO ost_owner;",
+                "REVOKE ALL ON SCHEMA _ost_state FROM PUBLIC;",
+                "GRANT USAGE ON SCHEMA _ost_state TO dba;",
+            ]
+        );
+    }
+}
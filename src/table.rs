@@ -1,41 +1,51 @@
 // src/table.rs
 // Extracted Table struct and related impls from migration.rs
 
+use crate::identifier::{parse_qualified_name, quote_ident};
 use anyhow::Result;
-use postgres::Client;
 use postgres::GenericClient;
 use postgres::types::Type;
 use std::fmt;
 use std::str::FromStr;
 
+/// A sequence owned by one of a table's columns, through `OWNED BY` (serial columns) or as the
+/// column's identity sequence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedSequence {
+    pub sequence: Table,
+    pub column: String,
+    pub identity: bool,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Table {
     pub schema: Option<String>,
     pub name: String,
 }
 
+/// Parses a SQL table name, e.g. `my_schema."MixedCase"`. Unquoted parts are folded to lower case.
 impl FromStr for Table {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some((schema, name)) = s.split_once('.') {
-            Ok(Table {
-                schema: Some(schema.to_string()),
-                name: name.to_string(),
-            })
-        } else {
-            Ok(Table {
-                schema: None,
-                name: s.to_string(),
-            })
+        let mut parts = parse_qualified_name(s);
+        let name = parts.pop().ok_or(())?;
+        match parts.len() {
+            0 => Ok(Table { schema: None, name }),
+            1 => Ok(Table {
+                schema: parts.pop(),
+                name,
+            }),
+            _ => Err(()),
         }
     }
 }
 
+/// Formats the table as a quoted SQL name, ready to be spliced into a statement.
 impl fmt::Display for Table {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.schema {
-            Some(schema) => write!(f, "{}.{}", schema, self.name),
-            None => write!(f, "{}", self.name),
+            Some(schema) => write!(f, "{}.{}", quote_ident(schema), quote_ident(&self.name)),
+            None => write!(f, "{}", quote_ident(&self.name)),
         }
     }
 }
@@ -45,46 +55,187 @@ impl Table {
         full_name.parse().unwrap()
     }
 
-    pub fn get_primary_key_info(&self, client: &mut Client) -> Result<crate::PrimaryKeyInfo> {
-        let full_table = self.to_string();
+    /// Builds a table from unquoted schema and table names.
+    pub fn from_parts(schema: Option<&str>, name: &str) -> Self {
+        Table {
+            schema: schema.map(str::to_string),
+            name: name.to_string(),
+        }
+    }
+
+    /// Resolves the table's schema through the search path, returning a schema qualified table.
+    pub fn resolve_schema<C: GenericClient>(&self, client: &mut C) -> Result<Table> {
         let row = client.query_one(
+            "SELECT n.nspname::text
+             FROM pg_class c
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE c.oid = ($1)::text::regclass",
+            &[&self.to_string()],
+        )?;
+        Ok(Table {
+            schema: Some(row.get(0)),
+            name: self.name.clone(),
+        })
+    }
+
+    /// Returns a table in the same schema whose name is this table's name plus `suffix`,
+    /// e.g. for naming functions and triggers that belong to this table.
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        Table {
+            schema: self.schema.clone(),
+            name: format!("{}{}", self.name, suffix),
+        }
+    }
+
+    /// The table's single column primary key. Tables without one, or with a composite key, are
+    /// refused: the backfill pages through the key and the replays match rows by it.
+    pub fn get_primary_key_info<C: GenericClient>(
+        &self,
+        client: &mut C,
+    ) -> Result<crate::PrimaryKeyInfo> {
+        let full_table = self.to_string();
+        let rows = client.query(
             "SELECT a.attname, a.atttypid::regtype::text
              FROM pg_index i
              JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
              WHERE i.indrelid = ($1)::text::regclass AND i.indisprimary
-             LIMIT 1",
+             ORDER BY array_position(i.indkey::int2[], a.attnum)",
             &[&full_table],
         )?;
+        let row = match rows.as_slice() {
+            [row] => row,
+            [] => anyhow::bail!("{} has no primary key", self),
+            _ => anyhow::bail!(
+                "{} has a composite primary key ({}), which isn't supported",
+                self,
+                rows.iter()
+                    .map(|row| quote_ident(row.get(0)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
         let name: String = row.get(0);
         let type_name: String = row.get(1);
         let ty = match type_name.as_str() {
             "integer" => Type::INT4,
             "bigint" => Type::INT8,
-            _ => panic!("Unsupported PK type: {}", type_name),
+            _ => anyhow::bail!("Unsupported PK type: {}", type_name),
         };
         Ok(crate::PrimaryKeyInfo { name, ty })
     }
 
-    pub fn get_columns<C: GenericClient>(&self, client: &mut C) -> Vec<String> {
+    pub fn get_columns<C: GenericClient>(&self, client: &mut C) -> Result<Vec<String>> {
         let rows = client.query(
             "SELECT column_name FROM information_schema.columns WHERE table_schema = $1 AND table_name = $2 ORDER BY ordinal_position",
             &[&self.schema.as_deref().unwrap_or("public"), &self.name],
-        ).unwrap();
-        rows.iter()
+        )?;
+        Ok(rows
+            .iter()
             .map(|row| row.get::<_, String>("column_name"))
-            .collect()
+            .collect())
     }
 
-    pub fn drop_if_exists(&self, client: &mut Client) -> anyhow::Result<()> {
-        let sql = format!("DROP TABLE IF EXISTS {}", self);
-        client.simple_query(&sql)?;
+    /// Returns the sequences owned by this table's columns, in column order.
+    pub fn owned_sequences<C: GenericClient>(&self, client: &mut C) -> Result<Vec<OwnedSequence>> {
+        let rows = client.query(
+            "SELECT n.nspname::text, s.relname::text, a.attname::text, d.deptype = 'i'
+             FROM pg_depend d
+             JOIN pg_class s ON s.oid = d.objid AND s.relkind = 'S'
+             JOIN pg_namespace n ON n.oid = s.relnamespace
+             JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid
+             WHERE d.classid = 'pg_class'::regclass AND d.refclassid = 'pg_class'::regclass
+               AND d.refobjid = ($1)::text::regclass AND d.deptype IN ('a', 'i')
+             ORDER BY a.attnum",
+            &[&self.to_string()],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let schema: String = row.get(0);
+                let name: String = row.get(1);
+                OwnedSequence {
+                    sequence: Table::from_parts(Some(&schema), &name),
+                    column: row.get(2),
+                    identity: row.get(3),
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the columns of this table whose defaults draw from `sequence`.
+    pub fn columns_using_sequence<C: GenericClient>(
+        &self,
+        sequence: &Table,
+        client: &mut C,
+    ) -> Result<Vec<String>> {
+        let rows = client.query(
+            "SELECT a.attname::text
+             FROM pg_depend d
+             JOIN pg_attrdef ad ON ad.oid = d.objid
+             JOIN pg_attribute a ON a.attrelid = ad.adrelid AND a.attnum = ad.adnum
+             WHERE d.classid = 'pg_attrdef'::regclass AND d.refclassid = 'pg_class'::regclass
+               AND d.refobjid = ($1)::text::regclass AND ad.adrelid = ($2)::text::regclass
+             ORDER BY a.attnum",
+            &[&sequence.to_string(), &self.to_string()],
+        )?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub fn exists<C: GenericClient>(&self, client: &mut C) -> Result<bool> {
+        Ok(client
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[&self.to_string()])?
+            .get(0))
+    }
+
+    pub fn drop_if_exists_statement(&self) -> String {
+        format!("DROP TABLE IF EXISTS {}", self)
+    }
+
+    pub fn drop_if_exists<C: GenericClient>(&self, client: &mut C) -> anyhow::Result<()> {
+        client.simple_query(&self.drop_if_exists_statement())?;
         Ok(())
     }
 
+    pub fn lock_statement(&self) -> String {
+        format!("LOCK TABLE {} IN ACCESS EXCLUSIVE MODE", self)
+    }
+
     /// Locks the table in ACCESS EXCLUSIVE mode for the duration of the transaction.
     pub fn lock_table(&self, transaction: &mut postgres::Transaction) -> anyhow::Result<()> {
-        let sql = format!("LOCK TABLE {} IN ACCESS EXCLUSIVE MODE", self.name);
-        transaction.batch_execute(&sql)?;
+        transaction.batch_execute(&self.lock_statement())?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_quotes_when_needed() {
+        assert_eq!(Table::new("test_table").to_string(), "test_table");
+        assert_eq!(
+            Table::from_parts(Some("post_migrations"), "test_table").to_string(),
+            "post_migrations.test_table"
+        );
+        assert_eq!(
+            Table::from_parts(Some("Billing"), "order").to_string(),
+            "\"Billing\".\"order\""
+        );
+        assert_eq!(
+            Table::from_parts(None, "my.table").to_string(),
+            "\"my.table\""
+        );
+    }
+
+    #[test]
+    fn test_parse_roundtrip() {
+        let table = Table::from_parts(Some("My Schema"), "MixedCase");
+        assert_eq!(table.to_string().parse::<Table>(), Ok(table));
+        assert_eq!(
+            "Public.Test_Table".parse::<Table>(),
+            Ok(Table::from_parts(Some("public"), "test_table"))
+        );
+        assert!("a.b.c".parse::<Table>().is_err());
+    }
+}
@@ -0,0 +1,316 @@
+// src/table_metadata.rs
+// Table properties that `CREATE TABLE ... (LIKE ... INCLUDING ALL)` doesn't copy:
+// owner, privileges, row level security and policies, the table comment and the replica
+// identity.
+
+use crate::ColumnMap;
+use crate::identifier::{quote_ident, quote_literal};
+use crate::logical_replication::ReplicaIdentity;
+use crate::table::Table;
+use anyhow::Result;
+use postgres::GenericClient;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grant {
+    /// Role name, or `PUBLIC`
+    pub grantee: String,
+    pub privilege: String,
+    /// Set for column privileges
+    pub column: Option<String>,
+    pub grantable: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub name: String,
+    pub permissive: bool,
+    pub roles: Vec<String>,
+    pub command: String,
+    pub using: Option<String>,
+    pub with_check: Option<String>,
+}
+
+/// Owner, privileges, policies, comment and replica identity of a table, as read from the
+/// catalogs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableMetadata {
+    pub owner: String,
+    pub grants: Vec<Grant>,
+    pub row_security: bool,
+    pub force_row_security: bool,
+    pub policies: Vec<Policy>,
+    pub comment: Option<String>,
+    pub replica_identity: ReplicaIdentity,
+}
+
+/// What was copied onto a table, one line per item.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataReport(pub Vec<String>);
+
+impl fmt::Display for MetadataReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(
+                f,
+                "No grants, owner, policies, comments or replica identity to copy"
+            );
+        }
+        writeln!(f, "Copied table metadata:")?;
+        for item in &self.0 {
+            writeln!(f, "  - {}", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl TableMetadata {
+    /// Reads the table's owner, ACL, row level security settings, policies, comment and replica
+    /// identity.
+    pub fn fetch<C: GenericClient>(table: &Table, client: &mut C) -> Result<Self> {
+        let full_table = table.to_string();
+        let row = client.query_one(
+            "SELECT pg_get_userbyid(c.relowner)::text, c.relrowsecurity, c.relforcerowsecurity,
+                    obj_description(c.oid, 'pg_class')
+             FROM pg_class c
+             WHERE c.oid = ($1)::text::regclass",
+            &[&full_table],
+        )?;
+        let owner: String = row.get(0);
+        let row_security: bool = row.get(1);
+        let force_row_security: bool = row.get(2);
+        let comment: Option<String> = row.get(3);
+
+        // Table and column privileges, without the owner's implicit ones
+        let grants = client
+            .query(
+                "SELECT CASE WHEN x.grantee = 0 THEN 'PUBLIC' ELSE pg_get_userbyid(x.grantee)::text END,
+                        x.privilege_type, NULL::text, x.is_grantable
+                 FROM pg_class c, aclexplode(c.relacl) x
+                 WHERE c.oid = ($1)::text::regclass AND x.grantee <> c.relowner
+                 UNION ALL
+                 SELECT CASE WHEN x.grantee = 0 THEN 'PUBLIC' ELSE pg_get_userbyid(x.grantee)::text END,
+                        x.privilege_type, a.attname::text, x.is_grantable
+                 FROM pg_attribute a
+                 JOIN pg_class c ON c.oid = a.attrelid, aclexplode(a.attacl) x
+                 WHERE a.attrelid = ($1)::text::regclass AND a.attnum > 0 AND NOT a.attisdropped
+                   AND x.grantee <> c.relowner",
+                &[&full_table],
+            )?
+            .iter()
+            .map(|row| Grant {
+                grantee: row.get(0),
+                privilege: row.get(1),
+                column: row.get(2),
+                grantable: row.get(3),
+            })
+            .collect();
+
+        let policies = client
+            .query(
+                "SELECT p.polname::text, p.polpermissive,
+                        CASE WHEN p.polroles = '{0}' THEN ARRAY['PUBLIC']
+                             ELSE ARRAY(SELECT rolname::text FROM pg_roles WHERE oid = ANY(p.polroles) ORDER BY rolname)
+                        END,
+                        CASE p.polcmd WHEN 'r' THEN 'SELECT' WHEN 'a' THEN 'INSERT' WHEN 'w' THEN 'UPDATE'
+                                      WHEN 'd' THEN 'DELETE' ELSE 'ALL' END,
+                        pg_get_expr(p.polqual, p.polrelid),
+                        pg_get_expr(p.polwithcheck, p.polrelid)
+                 FROM pg_policy p
+                 WHERE p.polrelid = ($1)::text::regclass
+                 ORDER BY p.polname",
+                &[&full_table],
+            )?
+            .iter()
+            .map(|row| Policy {
+                name: row.get(0),
+                permissive: row.get(1),
+                roles: row.get(2),
+                command: row.get(3),
+                using: row.get(4),
+                with_check: row.get(5),
+            })
+            .collect();
+
+        Ok(TableMetadata {
+            owner,
+            grants,
+            row_security,
+            force_row_security,
+            policies,
+            comment,
+            replica_identity: ReplicaIdentity::load(table, client)?,
+        })
+    }
+
+    /// `CREATE POLICY` statements for this table's policies on `target`. Run before the migration
+    /// changes the shadow table so that renamed columns are followed in the policy expressions.
+    pub fn policy_statements(&self, target: &Table) -> Vec<String> {
+        self.policies
+            .iter()
+            .map(|policy| policy.create_statement(target))
+            .collect()
+    }
+
+    /// Statements recreating this metadata on `target`, whose current metadata is `existing`,
+    /// each paired with a description for the report. Policies `target` already has are reported
+    /// without a statement, and ones it has that the source no longer does are dropped. Column
+    /// privileges follow `column_map` when given; ones on dropped columns are skipped.
+    pub fn apply_statements(
+        &self,
+        target: &Table,
+        existing: &TableMetadata,
+        column_map: Option<&ColumnMap>,
+    ) -> Vec<(String, Option<String>)> {
+        let mut statements = Vec::new();
+        if self.owner != existing.owner {
+            statements.push((
+                format!("owner {}", self.owner),
+                Some(format!(
+                    "ALTER TABLE {} OWNER TO {}",
+                    target,
+                    quote_role(&self.owner)
+                )),
+            ));
+        }
+        for grant in &self.grants {
+            let with_grant_option = if grant.grantable {
+                " WITH GRANT OPTION"
+            } else {
+                ""
+            };
+            let (privilege, description) = match &grant.column {
+                None => (
+                    grant.privilege.clone(),
+                    format!("grant {} to {}", grant.privilege, grant.grantee),
+                ),
+                Some(column) => {
+                    match column_map.map_or(Some(column.clone()), |map| map.shadow_col(column)) {
+                        Some(shadow_col) => (
+                            format!("{} ({})", grant.privilege, quote_ident(&shadow_col)),
+                            format!(
+                                "grant {} ({}) to {}",
+                                grant.privilege, shadow_col, grant.grantee
+                            ),
+                        ),
+                        None => continue,
+                    }
+                }
+            };
+            statements.push((
+                description,
+                Some(format!(
+                    "GRANT {} ON {} TO {}{}",
+                    privilege,
+                    target,
+                    quote_role(&grant.grantee),
+                    with_grant_option
+                )),
+            ));
+        }
+        for policy in &self.policies {
+            let sql = if existing.policies.iter().any(|p| p.name == policy.name) {
+                None
+            } else {
+                Some(policy.create_statement(target))
+            };
+            statements.push((format!("policy {}", policy.name), sql));
+        }
+        for policy in &existing.policies {
+            if !self.policies.iter().any(|p| p.name == policy.name) {
+                statements.push((
+                    format!("dropped policy {}", policy.name),
+                    Some(format!(
+                        "DROP POLICY {} ON {}",
+                        quote_ident(&policy.name),
+                        target
+                    )),
+                ));
+            }
+        }
+        if self.row_security && !existing.row_security {
+            statements.push((
+                "row level security enabled".to_string(),
+                Some(format!("ALTER TABLE {} ENABLE ROW LEVEL SECURITY", target)),
+            ));
+        }
+        if self.force_row_security && !existing.force_row_security {
+            statements.push((
+                "row level security forced".to_string(),
+                Some(format!("ALTER TABLE {} FORCE ROW LEVEL SECURITY", target)),
+            ));
+        }
+        if let Some(comment) = &self.comment {
+            statements.push((
+                "table comment".to_string(),
+                Some(format!(
+                    "COMMENT ON TABLE {} IS {}",
+                    target,
+                    quote_literal(comment)
+                )),
+            ));
+        }
+        if self.replica_identity != existing.replica_identity {
+            statements.push((
+                format!("replica identity {}", self.replica_identity),
+                Some(self.replica_identity.alter_statement(target)),
+            ));
+        }
+        statements
+    }
+
+    /// Recreates this metadata on `target` and reports what was copied.
+    pub fn apply<C: GenericClient>(
+        &self,
+        target: &Table,
+        column_map: Option<&ColumnMap>,
+        client: &mut C,
+    ) -> Result<MetadataReport> {
+        let existing = TableMetadata::fetch(target, client)?;
+        let mut report = MetadataReport::default();
+        for (description, sql) in self.apply_statements(target, &existing, column_map) {
+            if let Some(sql) = sql {
+                client.batch_execute(&sql)?;
+            }
+            report.0.push(description);
+        }
+        Ok(report)
+    }
+}
+
+impl Policy {
+    pub fn create_statement(&self, target: &Table) -> String {
+        let roles = self
+            .roles
+            .iter()
+            .map(|role| quote_role(role))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = format!(
+            "CREATE POLICY {} ON {} AS {} FOR {} TO {}",
+            quote_ident(&self.name),
+            target,
+            if self.permissive {
+                "PERMISSIVE"
+            } else {
+                "RESTRICTIVE"
+            },
+            self.command,
+            roles
+        );
+        if let Some(using) = &self.using {
+            sql.push_str(&format!(" USING ({})", using));
+        }
+        if let Some(with_check) = &self.with_check {
+            sql.push_str(&format!(" WITH CHECK ({})", with_check));
+        }
+        sql
+    }
+}
+
+fn quote_role(role: &str) -> String {
+    match role {
+        "PUBLIC" => role.to_string(),
+        role => quote_ident(role),
+    }
+}
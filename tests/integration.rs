@@ -28,6 +28,7 @@ mod integration {
         let handle = runner.run_replay_only(
             "ALTER TABLE test_table ADD COLUMN bar TEXT",
             postgres_ost::migration_runner::ReplayMode::Log,
+            &postgres_ost::migration_runner::CaptureNames::generate(),
             stop_replay.clone(),
         );
         std::thread::sleep(Duration::from_secs(2));
@@ -50,6 +51,99 @@ mod integration {
         );
     }
 
+    #[test]
+    fn test_replay_only_resumes_streaming_from_its_slot() {
+        use postgres_ost::migration_runner::{CaptureNames, MigrationRunner, ReplayMode};
+        use std::sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        };
+        use std::time::Duration;
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut client = pool.get().unwrap();
+        let runner = MigrationRunner::from_pool(pool.clone(), test_db.test_db_url.clone());
+        let sql = "ALTER TABLE test_table ADD COLUMN bar TEXT";
+        let capture = CaptureNames::generate();
+        assert_eq!(CaptureNames::for_slot(&capture.slot).unwrap(), capture);
+
+        let stop_replay = Arc::new(AtomicBool::new(false));
+        let handle = runner.run_replay_only(
+            sql,
+            ReplayMode::StreamingLogical,
+            &capture,
+            stop_replay.clone(),
+        );
+        std::thread::sleep(Duration::from_secs(2));
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('first run')")
+            .unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        stop_replay.store(true, Ordering::Relaxed);
+        handle.join().expect("Replay thread panicked").unwrap();
+
+        // Written while nothing replays; the slot keeps it for the resumed run
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('while stopped')")
+            .unwrap();
+        let stop_replay = Arc::new(AtomicBool::new(false));
+        let handle = runner.resume_replay_only(sql, &capture, stop_replay.clone());
+        std::thread::sleep(Duration::from_secs(3));
+        stop_replay.store(true, Ordering::Relaxed);
+        handle.join().expect("Replay thread panicked").unwrap();
+
+        let rows: Vec<String> = client
+            .query(
+                "SELECT assertable FROM post_migrations.test_table ORDER BY id",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(rows, vec!["first run", "while stopped"]);
+        client
+            .execute("SELECT pg_drop_replication_slot($1)", &[&capture.slot])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_streaming_replay_starts_from_saved_progress() {
+        use postgres_ost::logical_replication::ReplayProgress;
+        use postgres_ost::logical_replication::message::Lsn;
+        use postgres_ost::migration_runner::{
+            CaptureNames, MigrationRunner, ReplayKind, ReplayMode,
+        };
+        let test_db = setup_test_db();
+        let runner = MigrationRunner::from_pool(test_db.pool.clone(), test_db.test_db_url.clone());
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN bar TEXT")
+            .unwrap();
+        let capture = CaptureNames::generate();
+        let start_lsn = |runner: &MigrationRunner| match runner
+            .build_replay_with(
+                &migration,
+                &column_map,
+                ReplayMode::StreamingLogical,
+                &runner.settings,
+                &capture,
+            )
+            .unwrap()
+        {
+            ReplayKind::StreamingLogical(replay) => replay.stream.lock().unwrap().last_lsn(),
+            _ => unreachable!("the streaming mode builds a streaming replay"),
+        };
+        assert_eq!(start_lsn(&runner), Lsn(0));
+
+        let mut client = test_db.get_client();
+        let progress = ReplayProgress::new(&Default::default());
+        progress.create(&mut *client).unwrap();
+        progress
+            .save(&capture.slot, Lsn(0x16B3748), &mut *client)
+            .unwrap();
+        assert_eq!(start_lsn(&runner), Lsn(0x16B3748));
+    }
+
     // Helper to run the concurrent DML/backfill/replay test for any ALTER TABLE statement, always using 'assertable' as the expected column
     fn run_concurrent_change_test(alter_table_sql: &str) {
         let test_db = setup_test_db();
@@ -128,6 +222,332 @@ mod integration {
         run_concurrent_change_test("ALTER TABLE test_table RENAME COLUMN target TO something_else");
     }
 
+    #[test]
+    fn test_row_filter_purges_rows_during_backfill_and_replay() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.row_filter = Some("target = 'keep'".to_string());
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('backfilled', 'keep'), ('purged', 'drop'),
+                    ('leaves', 'keep'), ('joins', 'drop')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN bar TEXT")
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('inserted', 'keep'), ('skipped', 'drop');
+                 UPDATE test_table SET target = 'drop' WHERE assertable = 'leaves';
+                 UPDATE test_table SET target = 'keep' WHERE assertable = 'joins';",
+            )
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+
+        let kept: Vec<String> = client
+            .query(
+                "SELECT assertable FROM post_migrations.test_table ORDER BY assertable",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(kept, vec!["backfilled", "inserted", "joins"]);
+    }
+
+    #[test]
+    fn test_row_filter_backfill_skips_rows_the_replay_copied_ahead_of_it() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.row_filter = Some("target = 'keep'".to_string());
+        runner.settings.backfill_batch_size = 1;
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('first', 'keep'), ('ahead', 'drop'), ('last', 'keep')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN bar TEXT")
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+
+        // The update brings a row the backfill hasn't reached into the filter, so the replay
+        // copies it first; its batch of one then copies nothing
+        client
+            .simple_query("UPDATE test_table SET target = 'keep' WHERE assertable = 'ahead'")
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        let kept: Vec<String> = client
+            .query(
+                "SELECT assertable FROM post_migrations.test_table ORDER BY id",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(kept, vec!["first", "ahead", "last"]);
+    }
+
+    #[test]
+    fn test_row_filter_reads_the_main_table_when_its_column_is_renamed() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.row_filter = Some("target = 'keep'".to_string());
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('backfilled', 'keep'), ('purged', 'drop'), ('leaves', 'keep')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table RENAME COLUMN target TO destination")
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('inserted', 'keep'), ('skipped', 'drop');
+                 UPDATE test_table SET target = 'drop' WHERE assertable = 'leaves';",
+            )
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+
+        let kept: Vec<(String, String)> = client
+            .query(
+                "SELECT assertable, destination FROM post_migrations.test_table \
+                 ORDER BY assertable",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                ("backfilled".to_string(), "keep".to_string()),
+                ("inserted".to_string(), "keep".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_renamed_primary_key_addresses_the_shadow_during_backfill_and_replay() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.row_filter = Some("target = 'keep'".to_string());
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('backfilled', 'keep'), ('updated', 'keep'),
+                    ('deleted', 'keep'), ('leaves', 'keep'), ('joins', 'drop')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table RENAME COLUMN id TO order_id")
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES ('inserted', 'keep');
+                 UPDATE test_table SET assertable = 'changed' WHERE assertable = 'updated';
+                 DELETE FROM test_table WHERE assertable = 'deleted';
+                 UPDATE test_table SET target = 'drop' WHERE assertable = 'leaves';
+                 UPDATE test_table SET target = 'keep' WHERE assertable = 'joins';",
+            )
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+
+        let main: Vec<(i64, String)> = client
+            .query(
+                "SELECT id::bigint, assertable FROM test_table WHERE target = 'keep' ORDER BY id",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        let shadow: Vec<(i64, String)> = client
+            .query(
+                "SELECT order_id::bigint, assertable FROM post_migrations.test_table \
+                 ORDER BY order_id",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        assert_eq!(
+            shadow.iter().map(|(_, a)| a.as_str()).collect::<Vec<_>>(),
+            vec!["backfilled", "changed", "joins", "inserted"]
+        );
+        assert_eq!(shadow, main);
+
+        let Err(err) = runner.run_schema_migration("ALTER TABLE test_table DROP COLUMN id") else {
+            panic!("dropping the primary key should be refused");
+        };
+        assert!(
+            err.to_string().contains("primary key id is dropped"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_using_and_transforms_fill_shadow_columns_during_backfill_and_replay() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.transforms = vec![("shout".to_string(), "upper(assertable)".to_string())];
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES
+                    ('backfilled', 'a'), ('updated', 'b')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration(
+                "ALTER TABLE test_table ALTER COLUMN target TYPE jsonb USING to_jsonb(target), \
+                 ADD COLUMN shout TEXT",
+            )
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES ('inserted', 'c');
+                 UPDATE test_table SET target = 'd' WHERE assertable = 'updated';",
+            )
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+
+        let rows: Vec<(String, String, String)> = client
+            .query(
+                "SELECT assertable, target::text, shout FROM post_migrations.test_table \
+                 ORDER BY assertable",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+        let expected = [
+            ("backfilled", "\"a\"", "BACKFILLED"),
+            ("inserted", "\"c\"", "INSERTED"),
+            ("updated", "\"d\"", "UPDATED"),
+        ];
+        assert_eq!(
+            rows,
+            expected
+                .iter()
+                .map(|(a, t, s)| (a.to_string(), t.to_string(), s.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_renames_come_from_the_sql_and_ambiguity_is_refused() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("INSERT INTO test_table (assertable, target) VALUES ('a', 'b')")
+            .unwrap();
+
+        // Two renames are both followed rather than treated as drops
+        let (migration, column_map) = runner
+            .run_schema_migration(
+                "ALTER TABLE test_table RENAME COLUMN assertable TO label; \
+                 ALTER TABLE test_table RENAME COLUMN target TO destination",
+            )
+            .unwrap();
+        assert_eq!(
+            column_map.shadow_col("assertable"),
+            Some("label".to_string())
+        );
+        assert_eq!(
+            column_map.shadow_col("target"),
+            Some("destination".to_string())
+        );
+        runner.run_backfill(&migration).unwrap();
+        let row = client
+            .query_one(
+                "SELECT label, destination FROM post_migrations.test_table",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "a");
+        assert_eq!(row.get::<_, String>(1), "b");
+
+        // A recreated table losing one column and gaining another is refused until mapped
+        let recreate = "DROP TABLE test_table; \
+            CREATE TABLE test_table (id BIGSERIAL PRIMARY KEY, assertable TEXT, destination TEXT)";
+        let Err(err) = runner.run_schema_migration(recreate) else {
+            panic!("the ambiguous migration should be refused");
+        };
+        assert!(
+            err.to_string().contains("--map"),
+            "unexpected error: {}",
+            err
+        );
+
+        runner.settings.column_mappings = vec![("target".to_string(), None)];
+        let (_, column_map) = runner.run_schema_migration(recreate).unwrap();
+        assert_eq!(column_map.shadow_col("target"), None);
+
+        runner.settings.column_mappings =
+            vec![("target".to_string(), Some("destination".to_string()))];
+        let (_, column_map) = runner.run_schema_migration(recreate).unwrap();
+        assert_eq!(
+            column_map.shadow_col("target"),
+            Some("destination".to_string())
+        );
+    }
+
     #[test]
     fn test_migration_with_simple_add_column() {
         let test_db = setup_test_db();
@@ -139,7 +559,7 @@ mod integration {
         let migration_sql = "ALTER TABLE test_table ADD COLUMN foo TEXT;";
         let (migration, column_map) = runner.run_schema_migration(migration_sql).unwrap();
         runner.run_replay_setup(&migration, &column_map).unwrap();
-        assert_eq!(migration.table.to_string(), "test_table");
+        assert_eq!(migration.table.to_string(), "public.test_table");
         assert_eq!(
             migration.shadow_table.to_string(),
             "post_migrations.test_table"
@@ -171,7 +591,7 @@ mod integration {
             CREATE TABLE test_table_p1 PARTITION OF test_table FOR VALUES WITH (MODULUS 2, REMAINDER 1);";
         let (migration, column_map) = runner.run_schema_migration(migration_sql).unwrap();
         runner.run_replay_setup(&migration, &column_map).unwrap();
-        assert_eq!(migration.table.to_string(), "test_table");
+        assert_eq!(migration.table.to_string(), "public.test_table");
         assert_eq!(
             migration.shadow_table.to_string(),
             "post_migrations.test_table"
@@ -367,7 +787,8 @@ mod integration {
                 for _i in 0..50 {
                     streaming_replay
                         .stream
-                        .borrow()
+                        .lock()
+                        .unwrap()
                         .conn
                         .consume_input()
                         .expect("consume input");
@@ -376,7 +797,8 @@ mod integration {
                         .expect("replay_log failed");
                     streaming_replay
                         .stream
-                        .borrow()
+                        .lock()
+                        .unwrap()
                         .conn
                         .flush()
                         .expect("flush feedback");
@@ -429,4 +851,1598 @@ mod integration {
             _ => panic!("Expected StreamingLogicalReplay kind"),
         }
     }
+
+    #[test]
+    fn test_quoted_and_mixed_case_names_with_concurrent_changes() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "CREATE TABLE \"Order Items\" (\"Id\" BIGSERIAL PRIMARY KEY, \"order\" TEXT, \"Target\" TEXT)",
+            )
+            .unwrap();
+        client.simple_query("INSERT INTO \"Order Items\" (\"order\", \"Target\") VALUES ('expect_backfilled', 't')").unwrap();
+        client.simple_query("INSERT INTO \"Order Items\" (\"order\", \"Target\") VALUES ('expect_row_deleted', 't')").unwrap();
+        client.simple_query("INSERT INTO \"Order Items\" (\"order\", \"Target\") VALUES ('expect_row_to_update', 't')").unwrap();
+
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE \"Order Items\" ADD COLUMN \"Select\" TEXT")
+            .unwrap();
+        assert_eq!(migration.table.to_string(), "public.\"Order Items\"");
+        assert_eq!(
+            migration.shadow_table.to_string(),
+            "post_migrations.\"Order Items\""
+        );
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+
+        client.simple_query("INSERT INTO \"Order Items\" (\"order\", \"Target\") VALUES ('expect_row_inserted', 't')").unwrap();
+        client.simple_query("UPDATE \"Order Items\" SET \"order\" = 'expect_row_updated' WHERE \"order\" = 'expect_row_to_update'").unwrap();
+        client
+            .simple_query("DELETE FROM \"Order Items\" WHERE \"order\" = 'expect_row_deleted'")
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+
+        let rows = client
+            .query(
+                "SELECT \"order\" FROM post_migrations.\"Order Items\" ORDER BY \"Id\"",
+                &[],
+            )
+            .unwrap();
+        let vals: Vec<String> = rows.iter().map(|row| row.get("order")).collect();
+        assert_eq!(
+            vals,
+            vec![
+                "expect_backfilled",
+                "expect_row_updated",
+                "expect_row_inserted"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batched_backfill_with_integer_key() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.backfill_batch_size = 2;
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "CREATE TABLE int_keyed (id INTEGER PRIMARY KEY, note TEXT);
+                 INSERT INTO int_keyed SELECT n, 'row ' || n FROM generate_series(1, 5) AS n",
+            )
+            .unwrap();
+        let (migration, _column_map) = runner
+            .run_schema_migration("ALTER TABLE int_keyed ADD COLUMN extra TEXT")
+            .unwrap();
+        runner.run_backfill(&migration).unwrap();
+        let ids: Vec<i32> = client
+            .query("SELECT id FROM post_migrations.int_keyed ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_widening_a_serial_key_to_bigint() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.backfill_batch_size = 2;
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "CREATE TABLE ib (id SERIAL PRIMARY KEY, note TEXT);
+                 INSERT INTO ib (note) SELECT 'row ' || n FROM generate_series(1, 5) AS n",
+            )
+            .unwrap();
+        runner
+            .run_migrate(
+                "ALTER TABLE ib ALTER COLUMN id TYPE bigint",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let ids: Vec<i64> = client
+            .query("SELECT id FROM ib ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+        let next: i64 = client
+            .query_one("INSERT INTO ib (note) VALUES ('after') RETURNING id", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(next, 6);
+    }
+
+    #[test]
+    fn test_composite_primary_key_is_refused() {
+        let test_db = setup_test_db();
+        let mut client = test_db.pool.get().unwrap();
+        client
+            .simple_query(
+                "CREATE TABLE composite_keyed (tenant_id INTEGER, id BIGINT, note TEXT, PRIMARY KEY (tenant_id, id))",
+            )
+            .unwrap();
+        let Err(err) =
+            postgres_ost::Table::new("composite_keyed").get_primary_key_info(&mut *client)
+        else {
+            panic!("a composite key was accepted");
+        };
+        assert!(
+            err.to_string()
+                .contains("composite primary key (tenant_id, id)"),
+            "{}",
+            err
+        );
+        let primary_key = postgres_ost::Table::new("test_table")
+            .get_primary_key_info(&mut *client)
+            .unwrap();
+        assert_eq!(primary_key.name, "id");
+    }
+
+    #[test]
+    fn test_simple_backfill_applies_the_row_filter() {
+        use postgres_ost::backfill::{Backfill, SimpleBackfill};
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query(
+                "INSERT INTO test_table (assertable, target) VALUES ('kept', 'keep'), ('purged', 'drop')",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN bar TEXT")
+            .unwrap();
+        SimpleBackfill {
+            row_filter: Some("target = 'keep'".to_string()),
+        }
+        .backfill(
+            &migration.table,
+            &migration.shadow_table,
+            &column_map,
+            &mut client,
+        )
+        .unwrap();
+        let rows: Vec<String> = client
+            .query("SELECT assertable FROM post_migrations.test_table", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(rows, vec!["kept"]);
+    }
+
+    #[test]
+    fn test_full_migration_execute_with_mixed_case_table() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("CREATE TABLE \"MixedCase\" (id BIGSERIAL PRIMARY KEY, \"user\" TEXT)")
+            .unwrap();
+        client
+            .simple_query("INSERT INTO \"MixedCase\" (\"user\") VALUES ('before_swap')")
+            .unwrap();
+        runner
+            .run_migrate(
+                "ALTER TABLE \"MixedCase\" ADD COLUMN \"Swapped\" INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let row = client
+            .query_one(
+                "SELECT \"Swapped\" FROM \"MixedCase\" WHERE \"user\" = 'before_swap'",
+                &[],
+            )
+            .unwrap();
+        let swapped: i32 = row.get("Swapped");
+        assert_eq!(swapped, 42);
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM post_migrations_old.\"MixedCase\"",
+                &[],
+            )
+            .unwrap();
+        let count: i64 = row.get(0);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_full_migration_execute_in_non_public_schema() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE SCHEMA billing;
+                 CREATE TABLE billing.test_table (id BIGSERIAL PRIMARY KEY, assertable TEXT);
+                 INSERT INTO billing.test_table (assertable) VALUES ('billing_row');
+                 INSERT INTO test_table (assertable) VALUES ('public_row');",
+            )
+            .unwrap();
+
+        // Set up the public table's migration first so both shadows exist side by side
+        let (public_migration, _) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN other TEXT")
+            .unwrap();
+        let migration_sql = "ALTER TABLE billing.test_table ADD COLUMN swapped INTEGER DEFAULT 42";
+        let (migration, _) = runner.run_schema_migration(migration_sql).unwrap();
+        assert_eq!(migration.table.to_string(), "billing.test_table");
+        assert_eq!(
+            migration.shadow_table.to_string(),
+            "post_migrations.\"billing.test_table\""
+        );
+        assert_ne!(migration.shadow_table, public_migration.shadow_table);
+        assert_ne!(migration.log_table, public_migration.log_table);
+        assert_ne!(migration.old_table, public_migration.old_table);
+
+        runner
+            .run_migrate(
+                migration_sql,
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+
+        // The new table is back in its own schema under its original name
+        let row = client
+            .query_one(
+                "SELECT swapped FROM billing.test_table WHERE assertable = 'billing_row'",
+                &[],
+            )
+            .unwrap();
+        let swapped: i32 = row.get("swapped");
+        assert_eq!(swapped, 42);
+        // The public table is untouched
+        let row = client
+            .query_one("SELECT assertable FROM public.test_table", &[])
+            .unwrap();
+        let assertable: String = row.get("assertable");
+        assert_eq!(assertable, "public_row");
+        // The old table is archived under its namespaced name
+        let row = client
+            .query_one(
+                "SELECT assertable FROM post_migrations_old.\"billing.test_table\"",
+                &[],
+            )
+            .unwrap();
+        let assertable: String = row.get("assertable");
+        assert_eq!(assertable, "billing_row");
+    }
+
+    #[test]
+    fn test_full_migration_execute_with_custom_schema_config() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let schema_config = postgres_ost::SchemaConfig {
+            shadow_schema: "_ost".to_string(),
+            log_schema: "_ost_log".to_string(),
+            archive_schema: "_ost_archive".to_string(),
+            state_schema: "_ost_state".to_string(),
+            table_name_template: "ost_{table}".to_string(),
+            owner: Some("post_test".to_string()),
+            usage_grants: vec!["pg_monitor".to_string()],
+        };
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        )
+        .with_schema_config(schema_config);
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('before_swap')")
+            .unwrap();
+        let migration_sql = "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42";
+        let (migration, _) = runner.run_schema_migration(migration_sql).unwrap();
+        assert_eq!(migration.shadow_table.to_string(), "_ost.ost_test_table");
+        assert_eq!(
+            migration.log_table.to_string(),
+            "_ost_log.ost_test_table_log"
+        );
+        assert_eq!(
+            migration.old_table.to_string(),
+            "_ost_archive.ost_test_table"
+        );
+
+        runner
+            .run_migrate(
+                migration_sql,
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let row = client
+            .query_one("SELECT swapped FROM test_table", &[])
+            .unwrap();
+        let swapped: i32 = row.get("swapped");
+        assert_eq!(swapped, 42);
+        let row = client
+            .query_one("SELECT assertable FROM _ost_archive.ost_test_table", &[])
+            .unwrap();
+        let assertable: String = row.get("assertable");
+        assert_eq!(assertable, "before_swap");
+        // Only the granted role may use the tool schemas
+        let row = client
+            .query_one(
+                "SELECT has_schema_privilege('pg_monitor', '_ost', 'USAGE'),
+                        has_schema_privilege('public', '_ost_archive', 'USAGE')",
+                &[],
+            )
+            .unwrap();
+        let granted: bool = row.get(0);
+        let public: bool = row.get(1);
+        assert!(granted, "pg_monitor should have USAGE on _ost");
+        assert!(!public, "PUBLIC should not have USAGE on _ost_archive");
+    }
+
+    #[test]
+    fn test_replica_identity_is_saved_restored_and_passed_to_the_new_table() {
+        use postgres_ost::logical_replication::{
+            Publication, ReplicaIdentity, SavedIdentities, Slot,
+        };
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "ALTER TABLE test_table ALTER COLUMN target SET NOT NULL;
+                 CREATE UNIQUE INDEX test_table_by_target ON test_table (target);
+                 ALTER TABLE test_table REPLICA IDENTITY USING INDEX test_table_by_target;",
+            )
+            .unwrap();
+        let table = postgres_ost::Table::new("public.test_table");
+        let original = ReplicaIdentity::Index("test_table_by_target".to_string());
+        let publication = || {
+            Publication::new(
+                format!("{}_pub", test_db.dbname),
+                table.clone(),
+                Slot::new(format!("{}_slot", test_db.dbname)),
+            )
+            .with_full_identity(true)
+        };
+        publication().create(&mut *client).unwrap();
+        assert_eq!(
+            ReplicaIdentity::load(&table, &mut *client).unwrap(),
+            ReplicaIdentity::Full
+        );
+        let saved = SavedIdentities::new(&Default::default());
+        assert_eq!(
+            saved.load(&table, &mut *client).unwrap(),
+            Some(original.clone())
+        );
+
+        // A later run, such as a resumed one, restores the identity from the saved copy
+        publication().drop(&mut *client).unwrap();
+        assert_eq!(
+            ReplicaIdentity::load(&table, &mut *client).unwrap(),
+            original
+        );
+        assert_eq!(saved.load(&table, &mut *client).unwrap(), None);
+
+        let report = runner
+            .run_migrate(
+                "ALTER TABLE test_table RENAME COLUMN target TO renamed",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        assert!(
+            report
+                .0
+                .iter()
+                .any(|item| item.starts_with("replica identity USING INDEX")),
+            "{:?}",
+            report.0
+        );
+        let columns: Vec<String> = client
+            .query_one(
+                "SELECT ARRAY(SELECT a.attname::text FROM pg_attribute a
+                              WHERE a.attrelid = x.indrelid AND a.attnum = ANY(x.indkey))
+                 FROM pg_index x
+                 WHERE x.indrelid = 'public.test_table'::regclass AND x.indisreplident",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(columns, vec!["renamed"]);
+    }
+
+    #[test]
+    fn test_full_migration_execute_copies_table_metadata() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        // Predefined roles, so nothing leaks outside the test database
+        client
+            .batch_execute(
+                "INSERT INTO test_table (assertable) VALUES ('before_swap');
+                 ALTER TABLE test_table OWNER TO pg_monitor;
+                 GRANT SELECT, INSERT ON test_table TO pg_read_all_stats;
+                 GRANT UPDATE (assertable) ON test_table TO pg_signal_backend WITH GRANT OPTION;
+                 ALTER TABLE test_table ENABLE ROW LEVEL SECURITY;
+                 CREATE POLICY visible_rows ON test_table FOR SELECT TO pg_read_all_stats
+                     USING (assertable <> 'hidden');
+                 COMMENT ON TABLE test_table IS 'it''s the test table';",
+            )
+            .unwrap();
+
+        let report = runner
+            .run_migrate(
+                "ALTER TABLE test_table RENAME COLUMN assertable TO renamed",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        assert_eq!(
+            report.0,
+            vec![
+                "owner pg_monitor",
+                "grant INSERT to pg_read_all_stats",
+                "grant SELECT to pg_read_all_stats",
+                "grant UPDATE (renamed) to pg_signal_backend",
+                "policy visible_rows",
+                "row level security enabled",
+                "table comment",
+            ]
+        );
+
+        let row = client
+            .query_one(
+                "SELECT pg_get_userbyid(relowner)::text, relrowsecurity,
+                        obj_description(oid, 'pg_class'),
+                        has_table_privilege('pg_read_all_stats', oid, 'SELECT'),
+                        has_column_privilege('pg_signal_backend', oid, 'renamed', 'UPDATE WITH GRANT OPTION')
+                 FROM pg_class WHERE oid = 'public.test_table'::regclass",
+                &[],
+            )
+            .unwrap();
+        let owner: String = row.get(0);
+        let row_security: bool = row.get(1);
+        let comment: Option<String> = row.get(2);
+        let can_select: bool = row.get(3);
+        let can_update: bool = row.get(4);
+        assert_eq!(owner, "pg_monitor");
+        assert!(row_security);
+        assert_eq!(comment.as_deref(), Some("it's the test table"));
+        assert!(can_select);
+        assert!(can_update);
+        let row = client
+            .query_one(
+                "SELECT cmd, roles::text[], qual FROM pg_policies
+                 WHERE schemaname = 'public' AND tablename = 'test_table' AND policyname = 'visible_rows'",
+                &[],
+            )
+            .unwrap();
+        let cmd: String = row.get(0);
+        let roles: Vec<String> = row.get(1);
+        let qual: String = row.get(2);
+        assert_eq!(cmd, "SELECT");
+        assert_eq!(roles, vec!["pg_read_all_stats"]);
+        assert_eq!(qual, "(renamed <> 'hidden'::text)");
+    }
+
+    #[test]
+    fn test_full_migration_execute_moves_inbound_foreign_keys() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO test_table (assertable) VALUES ('parent');
+                 CREATE TABLE child (id BIGSERIAL PRIMARY KEY,
+                     parent_id BIGINT CONSTRAINT child_parent_fk REFERENCES test_table (id) ON DELETE CASCADE);
+                 INSERT INTO child (parent_id) SELECT id FROM test_table;",
+            )
+            .unwrap();
+        let migration_sql = "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42";
+        let (migration, _) = runner.run_schema_migration(migration_sql).unwrap();
+        assert_eq!(migration.inbound_foreign_keys.len(), 1);
+        assert_eq!(migration.inbound_foreign_keys[0].name, "child_parent_fk");
+        assert_eq!(
+            migration.inbound_foreign_keys[0].options,
+            "ON UPDATE NO ACTION ON DELETE CASCADE"
+        );
+
+        runner
+            .run_migrate(
+                migration_sql,
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let rows = client
+            .query(
+                "SELECT conname::text, confrelid::regclass::text, convalidated FROM pg_constraint
+                 WHERE contype = 'f' AND conrelid = 'child'::regclass",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(rows.len(), 1, "the old foreign key should be dropped");
+        let name: String = rows[0].get(0);
+        let referenced: String = rows[0].get(1);
+        let validated: bool = rows[0].get(2);
+        assert_eq!(name, "child_parent_fk");
+        assert_eq!(referenced, "test_table");
+        assert!(validated);
+        // The cascade now follows deletes from the new table
+        client.simple_query("DELETE FROM test_table").unwrap();
+        let row = client.query_one("SELECT count(*) FROM child", &[]).unwrap();
+        let count: i64 = row.get(0);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_full_migration_execute_keeps_old_foreign_keys_unvalidated() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        )
+        .with_foreign_key_options(postgres_ost::foreign_key::ForeignKeyOptions {
+            recreate: true,
+            validate: false,
+            drop_old: false,
+        });
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE child (id BIGSERIAL PRIMARY KEY,
+                     parent_id BIGINT CONSTRAINT child_parent_fk REFERENCES test_table (id));",
+            )
+            .unwrap();
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let rows = client
+            .query(
+                "SELECT conname::text, confrelid::regclass::text, convalidated FROM pg_constraint
+                 WHERE contype = 'f' AND conrelid = 'child'::regclass ORDER BY conname",
+                &[],
+            )
+            .unwrap();
+        let constraints: Vec<(String, String, bool)> = rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+        assert_eq!(
+            constraints,
+            vec![
+                (
+                    "child_parent_fk".to_string(),
+                    "test_table".to_string(),
+                    false
+                ),
+                (
+                    "child_parent_fk_old".to_string(),
+                    "post_migrations_old.test_table".to_string(),
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_foreign_keys_that_are_not_recreated_are_kept() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        )
+        .with_foreign_key_options(postgres_ost::foreign_key::ForeignKeyOptions {
+            recreate: false,
+            validate: true,
+            drop_old: true,
+        });
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE child (id BIGSERIAL PRIMARY KEY,
+                     parent_id BIGINT CONSTRAINT child_parent_fk REFERENCES test_table (id));",
+            )
+            .unwrap();
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let rows = client
+            .query(
+                "SELECT conname::text, confrelid::regclass::text FROM pg_constraint
+                 WHERE contype = 'f' AND conrelid = 'child'::regclass",
+                &[],
+            )
+            .unwrap();
+        let constraints: Vec<(String, String)> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        assert_eq!(
+            constraints,
+            vec![(
+                "child_parent_fk".to_string(),
+                "post_migrations_old.test_table".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_full_migration_execute_recreates_dependent_views() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO test_table (assertable) VALUES ('before_swap');
+                 CREATE VIEW test_view WITH (security_barrier) AS
+                     SELECT id, assertable FROM test_table WHERE assertable IS NOT NULL;
+                 CREATE VIEW test_view_of_view AS SELECT assertable FROM test_view;
+                 CREATE MATERIALIZED VIEW test_matview AS SELECT id, assertable FROM test_table;
+                 CREATE UNIQUE INDEX test_matview_id ON test_matview (id);
+                 GRANT SELECT ON test_matview TO pg_read_all_stats;",
+            )
+            .unwrap();
+
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('after_swap')")
+            .unwrap();
+        let rows = client
+            .query(
+                "SELECT assertable FROM test_view_of_view ORDER BY assertable",
+                &[],
+            )
+            .unwrap();
+        let values: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        assert_eq!(values, vec!["after_swap", "before_swap"]);
+        let row = client
+            .query_one(
+                "SELECT reloptions::text[] FROM pg_class WHERE oid = 'test_view'::regclass",
+                &[],
+            )
+            .unwrap();
+        let options: Vec<String> = row.get(0);
+        assert_eq!(options, vec!["security_barrier=true"]);
+
+        // The materialized view now reads from the new table and keeps its index and grants
+        client
+            .simple_query("REFRESH MATERIALIZED VIEW CONCURRENTLY test_matview")
+            .unwrap();
+        let row = client
+            .query_one("SELECT count(*) FROM test_matview", &[])
+            .unwrap();
+        let count: i64 = row.get(0);
+        assert_eq!(count, 2);
+        let row = client
+            .query_one(
+                "SELECT has_table_privilege('pg_read_all_stats', 'test_matview', 'SELECT')",
+                &[],
+            )
+            .unwrap();
+        let granted: bool = row.get(0);
+        assert!(granted);
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_depend d JOIN pg_rewrite r ON r.oid = d.objid
+                 WHERE d.refobjid = 'post_migrations_old.test_table'::regclass
+                   AND d.classid = 'pg_rewrite'::regclass",
+                &[],
+            )
+            .unwrap();
+        let old_dependents: i64 = row.get(0);
+        assert_eq!(old_dependents, 0, "no views should read the archived table");
+    }
+
+    #[test]
+    fn test_dependent_view_preflight_fails_when_definition_no_longer_applies() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("CREATE VIEW test_view AS SELECT id, target FROM test_table")
+            .unwrap();
+        let error = runner
+            .run_schema_migration("ALTER TABLE test_table DROP COLUMN target")
+            .err()
+            .expect("preflight should fail");
+        assert!(
+            error
+                .to_string()
+                .contains("View public.test_view can't be recreated"),
+            "unexpected error: {}",
+            error
+        );
+
+        let error = runner
+            .run_schema_migration("ALTER TABLE test_table ALTER COLUMN target TYPE VARCHAR(10)")
+            .err()
+            .expect("preflight should fail");
+        assert!(
+            error.to_string().contains("its columns would change"),
+            "unexpected error: {}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_full_migration_execute_rehomes_serial_sequence() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('one'), ('two')")
+            .unwrap();
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+        let row = client
+            .query_one("SELECT pg_get_serial_sequence('test_table', 'id')", &[])
+            .unwrap();
+        let sequence: Option<String> = row.get(0);
+        assert_eq!(sequence.as_deref(), Some("public.test_table_id_seq"));
+        // Dropping the archived table must not take the sequence with it
+        client
+            .simple_query("DROP TABLE post_migrations_old.test_table")
+            .unwrap();
+        let row = client
+            .query_one(
+                "INSERT INTO test_table (assertable) VALUES ('three') RETURNING id",
+                &[],
+            )
+            .unwrap();
+        let id: i64 = row.get(0);
+        assert_eq!(id, 3);
+    }
+
+    #[test]
+    fn test_identity_column_sequence_follows_new_table() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "DROP TABLE test_table;
+                 CREATE TABLE test_table (id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY, assertable TEXT, target TEXT);
+                 INSERT INTO test_table (assertable) VALUES ('expect_backfilled'), ('expect_row_to_update'), ('expect_row_deleted');",
+            )
+            .unwrap();
+        let (migration, column_map) = runner
+            .run_schema_migration("ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42")
+            .unwrap();
+        runner.run_replay_setup(&migration, &column_map).unwrap();
+        runner.run_backfill(&migration).unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO test_table (assertable) VALUES ('expect_row_inserted');
+                 UPDATE test_table SET assertable = 'expect_row_updated' WHERE assertable = 'expect_row_to_update';
+                 DELETE FROM test_table WHERE assertable = 'expect_row_deleted';",
+            )
+            .unwrap();
+        runner.run_replay(&migration, &column_map).unwrap();
+        let mut transaction = client.transaction().unwrap();
+        migration.table.lock_table(&mut transaction).unwrap();
+        migration.swap_tables(&mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        let rows = client
+            .query("SELECT id, assertable FROM test_table ORDER BY id", &[])
+            .unwrap();
+        let values: Vec<(i64, String)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        assert_eq!(
+            values,
+            vec![
+                (1, "expect_backfilled".to_string()),
+                (2, "expect_row_updated".to_string()),
+                (4, "expect_row_inserted".to_string()),
+            ]
+        );
+        let row = client
+            .query_one(
+                "SELECT pg_get_serial_sequence('test_table', 'id'), attidentity::text
+                 FROM pg_attribute WHERE attrelid = 'test_table'::regclass AND attname = 'id'",
+                &[],
+            )
+            .unwrap();
+        let sequence: Option<String> = row.get(0);
+        let identity: String = row.get(1);
+        assert_eq!(sequence.as_deref(), Some("public.test_table_id_seq"));
+        assert_eq!(identity, "a");
+        client
+            .simple_query("DROP TABLE post_migrations_old.test_table")
+            .unwrap();
+        let row = client
+            .query_one(
+                "INSERT INTO test_table (assertable) VALUES ('after_swap') RETURNING id",
+                &[],
+            )
+            .unwrap();
+        let id: i64 = row.get(0);
+        assert_eq!(id, 5);
+    }
+
+    #[test]
+    fn test_check_reports_without_creating_anything() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE VIEW test_view AS SELECT id, target FROM test_table;
+                 CREATE FUNCTION noop() RETURNS trigger LANGUAGE plpgsql AS 'BEGIN RETURN NEW; END';
+                 CREATE TRIGGER test_trigger BEFORE INSERT ON test_table FOR EACH ROW EXECUTE FUNCTION noop();",
+            )
+            .unwrap();
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let report = runner
+            .run_check(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                &mode,
+                Some(1 << 40),
+            )
+            .unwrap();
+        assert!(report.passed(), "{}", report);
+        let status = |name: &str| report.get(name).map(|result| result.status);
+        use postgres_ost::check::CheckStatus::*;
+        assert_eq!(status("primary key"), Some(Pass));
+        assert_eq!(status("disk space"), Some(Pass));
+        assert_eq!(status("privileges"), Some(Pass));
+        assert_eq!(status("triggers"), Some(Warn));
+        assert_eq!(status("migration"), Some(Pass));
+        assert_eq!(status("dependent views"), Some(Pass));
+        assert_eq!(status("wal_level"), None);
+
+        let report = runner
+            .run_check("ALTER TABLE test_table DROP COLUMN target", &mode, Some(0))
+            .unwrap();
+        assert!(!report.passed());
+        assert_eq!(
+            report.get("disk space").map(|result| result.status),
+            Some(Fail)
+        );
+        assert_eq!(
+            report.get("migration").map(|result| result.status),
+            Some(Warn)
+        );
+        assert_eq!(
+            report.get("dependent views").map(|result| result.status),
+            Some(Fail)
+        );
+
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_class WHERE relnamespace = 'post_migrations'::regnamespace",
+                &[],
+            )
+            .unwrap();
+        let relations: i64 = row.get(0);
+        assert_eq!(relations, 0, "check must not create anything");
+    }
+
+    #[test]
+    fn test_check_reports_what_happens_to_foreign_keys() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE child (id BIGSERIAL PRIMARY KEY,
+                     parent_id BIGINT CONSTRAINT child_parent_fk REFERENCES test_table (id));",
+            )
+            .unwrap();
+        let sql = "ALTER TABLE test_table ADD COLUMN swapped INTEGER";
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let message = |runner: &postgres_ost::migration_runner::MigrationRunner| {
+            let report = runner.run_check(sql, &mode, None).unwrap();
+            report.get("foreign keys").unwrap().message.clone()
+        };
+        assert_eq!(
+            message(&runner),
+            "recreated NOT VALID at cutover and validated afterwards; the old constraints are dropped: child_parent_fk on public.child"
+        );
+        let runner =
+            runner.with_foreign_key_options(postgres_ost::foreign_key::ForeignKeyOptions {
+                recreate: true,
+                validate: false,
+                drop_old: false,
+            });
+        assert_eq!(
+            message(&runner),
+            "recreated NOT VALID at cutover and left unvalidated; the old constraints are kept with an _old suffix: child_parent_fk on public.child"
+        );
+        let runner =
+            runner.with_foreign_key_options(postgres_ost::foreign_key::ForeignKeyOptions {
+                recreate: false,
+                validate: true,
+                drop_old: true,
+            });
+        assert!(message(&runner).starts_with("not recreated: "));
+    }
+
+    #[test]
+    fn test_check_rehearses_the_row_filter_and_transforms() {
+        use postgres_ost::check::CheckStatus::*;
+        let test_db = setup_test_db();
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            test_db.pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let sql = "ALTER TABLE test_table ADD COLUMN label TEXT";
+        let report = runner.run_check(sql, &mode, None).unwrap();
+        assert!(report.get("copy").is_none());
+
+        runner.settings.row_filter = Some("target = 'keep'".to_string());
+        runner.settings.transforms = vec![("label".to_string(), "upper(target)".to_string())];
+        let report = runner.run_check(sql, &mode, None).unwrap();
+        assert_eq!(report.get("copy").map(|result| result.status), Some(Pass));
+
+        runner.settings.row_filter = Some("missing_column = 1".to_string());
+        let report = runner.run_check(sql, &mode, None).unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.get("copy").map(|result| result.status), Some(Fail));
+    }
+
+    #[test]
+    fn test_check_fails_without_primary_key_or_on_other_tables() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("CREATE TABLE no_pk (id BIGINT, val TEXT)")
+            .unwrap();
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let report = runner
+            .run_check("ALTER TABLE no_pk ADD COLUMN foo INT", &mode, None)
+            .unwrap();
+        assert!(!report.passed());
+        assert_eq!(
+            report.get("primary key").map(|result| result.status),
+            Some(postgres_ost::check::CheckStatus::Fail)
+        );
+        assert!(report.get("migration").is_none());
+
+        let report = runner
+            .run_check(
+                "ALTER TABLE test_table ADD COLUMN foo INT; ALTER TABLE no_pk ADD COLUMN foo INT",
+                &mode,
+                None,
+            )
+            .unwrap();
+        assert!(!report.passed());
+        assert_eq!(
+            report.get("sql").map(|result| result.status),
+            Some(postgres_ost::check::CheckStatus::Fail)
+        );
+    }
+
+    #[test]
+    fn test_plan_lists_steps_in_order_without_creating_anything() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE VIEW test_view AS SELECT id, target FROM test_table;
+                 CREATE TABLE child (id BIGSERIAL PRIMARY KEY, parent_id BIGINT REFERENCES test_table (id));",
+            )
+            .unwrap();
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let sql = "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42";
+        let plan = runner.run_plan(sql, &mode, true).unwrap();
+        let phases: Vec<&str> = plan.steps.iter().map(|step| step.phase.as_str()).collect();
+        let mut deduped = phases.clone();
+        deduped.dedup();
+        assert_eq!(
+            deduped,
+            vec!["setup", "capture", "backfill", "cutover", "validate"]
+        );
+        let script = plan.to_string();
+        let position = |needle: &str| {
+            script
+                .find(needle)
+                .unwrap_or_else(|| panic!("{} not in plan:\n{}", needle, script))
+        };
+        assert!(
+            position("CREATE TABLE post_migrations.test_table (LIKE public.test_table")
+                < position("ADD COLUMN swapped int DEFAULT 42")
+        );
+        assert!(position("ADD COLUMN swapped int DEFAULT 42") < position("CREATE TRIGGER"));
+        assert!(position("CREATE TRIGGER") < position("LIMIT 1000"));
+        assert!(position("LIMIT 1000") < position("LOCK TABLE public.test_table"));
+        assert!(
+            position("LOCK TABLE public.test_table")
+                < position("DROP TABLE IF EXISTS post_migrations_log.test_table_log")
+        );
+        assert!(
+            position("ALTER SEQUENCE public.test_table_id_seq OWNED BY NONE")
+                < position("SET SCHEMA post_migrations_old")
+        );
+        assert!(position("CREATE OR REPLACE VIEW public.test_view") < position("COMMIT"));
+        assert!(
+            position("FOREIGN KEY (parent_id) REFERENCES public.test_table (id)")
+                < position("COMMIT")
+        );
+        assert!(position("COMMIT") < position("VALIDATE CONSTRAINT"));
+
+        let json = plan.to_json();
+        assert_eq!(json["table"], "public.test_table");
+        assert_eq!(json["steps"][0]["phase"], "setup");
+
+        let plan = runner.run_plan(sql, &mode, false).unwrap();
+        let last = plan.steps.last().unwrap();
+        assert_eq!(last.phase, "teardown");
+        assert!(plan.steps.iter().all(|step| step.phase != "cutover"));
+
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_class WHERE relnamespace = 'post_migrations'::regnamespace",
+                &[],
+            )
+            .unwrap();
+        let relations: i64 = row.get(0);
+        assert_eq!(relations, 0, "plan must not create anything");
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_trigger WHERE tgrelid = 'test_table'::regclass AND NOT tgisinternal",
+                &[],
+            )
+            .unwrap();
+        let triggers: i64 = row.get(0);
+        assert_eq!(triggers, 0);
+    }
+
+    #[test]
+    fn test_plan_maps_columns_like_migrate() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner.settings.column_mappings = vec![("target".to_string(), None)];
+        let mode = postgres_ost::migration_runner::ReplayMode::Log;
+        let sql = "ALTER TABLE test_table RENAME COLUMN assertable TO renamed";
+        let plan = runner.run_plan(sql, &mode, false).unwrap();
+        let backfill = plan
+            .steps
+            .iter()
+            .find(|step| step.phase == "backfill")
+            .unwrap();
+        assert!(
+            backfill.statements[0].starts_with(
+                "INSERT INTO post_migrations.test_table (id, renamed) OVERRIDING SYSTEM VALUE SELECT id, assertable FROM public.test_table"
+            ),
+            "{}",
+            backfill.statements[0]
+        );
+    }
+
+    #[test]
+    fn test_schema_qualified_table_settings_only_apply_in_their_schema() {
+        use postgres_ost::migration_queue::MigrationFile;
+        use postgres_ost::migration_runner::{MigrationRunner, ReplayMode};
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE SCHEMA billing;
+                 CREATE TABLE public.t (id BIGSERIAL PRIMARY KEY, note TEXT);
+                 CREATE TABLE billing.t (id BIGSERIAL PRIMARY KEY, note TEXT);
+                 INSERT INTO public.t (note) SELECT 'row ' || i FROM generate_series(1, 10) i;
+                 INSERT INTO billing.t (note) SELECT 'row ' || i FROM generate_series(1, 10) i;",
+            )
+            .unwrap();
+        let mut config = postgres_ost::config::Config::from_toml(
+            r#"
+[tables."billing.t"]
+where = "id <= 3"
+"#,
+        )
+        .unwrap();
+        config.connection.uri = Some(test_db.test_db_url.clone());
+        let count = |client: &mut postgres::Client, table: &str| -> i64 {
+            client
+                .query_one(&format!("SELECT count(*) FROM {}", table), &[])
+                .unwrap()
+                .get(0)
+        };
+
+        // `t` resolves to public.t through the search path, so billing's filter doesn't apply
+        let runner = MigrationRunner::from_config(&config, &postgres_ost::Table::new("t")).unwrap();
+        assert_eq!(runner.settings.row_filter, None);
+        runner
+            .run_migrate("ALTER TABLE t ADD COLUMN first INT", true, ReplayMode::Log)
+            .unwrap();
+        assert_eq!(count(&mut client, "public.t"), 10);
+
+        let dir =
+            std::env::temp_dir().join(format!("ost_run_dir_{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(
+            dir.join("1_add_second.sql"),
+            "ALTER TABLE t ADD COLUMN second INT",
+        )
+        .unwrap();
+        let files = MigrationFile::load_dir(&dir).unwrap();
+        runner
+            .run_queue(&files, true, ReplayMode::Log, |table| {
+                config.settings_for(table)
+            })
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(count(&mut client, "public.t"), 10);
+
+        let runner =
+            MigrationRunner::from_config(&config, &postgres_ost::Table::new("billing.t")).unwrap();
+        assert_eq!(runner.settings.row_filter.as_deref(), Some("id <= 3"));
+        runner
+            .run_migrate(
+                "ALTER TABLE billing.t ADD COLUMN first INT",
+                true,
+                ReplayMode::Log,
+            )
+            .unwrap();
+        assert_eq!(count(&mut client, "billing.t"), 3);
+    }
+
+    #[test]
+    fn test_config_settings_drive_backfill_and_cutover_hooks() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE cutover_audit (step TEXT, columns BIGINT);
+                 INSERT INTO test_table (assertable) SELECT 'row ' || i FROM generate_series(1, 25) i;",
+            )
+            .unwrap();
+        let config = postgres_ost::config::Config::from_toml(
+            r#"
+[backfill]
+batch_size = 1000
+throttle_ms = 1
+
+[cutover]
+lock_timeout_ms = 5000
+before_swap = ["INSERT INTO cutover_audit SELECT 'before', count(*) FROM information_schema.columns WHERE table_schema = 'public' AND table_name = 'test_table'"]
+after_cutover = ["INSERT INTO cutover_audit SELECT 'after', count(*) FROM information_schema.columns WHERE table_schema = 'public' AND table_name = 'test_table'"]
+
+[tables.test_table.backfill]
+batch_size = 10
+"#,
+        )
+        .unwrap();
+        let settings = config.settings_for(&postgres_ost::Table::new("test_table"));
+        assert_eq!(settings.backfill_batch_size, 10);
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        )
+        .with_settings(settings);
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::Log,
+            )
+            .unwrap();
+
+        let count: i64 = client
+            .query_one("SELECT count(*) FROM test_table WHERE swapped = 42", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 25, "batches of 10 must copy every row");
+        let audit: Vec<(String, i64)> = client
+            .query(
+                "SELECT step, columns FROM cutover_audit ORDER BY step DESC",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        // Before the swap the original three columns are in place; afterwards the new table's four
+        assert_eq!(
+            audit,
+            vec![("before".to_string(), 3), ("after".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_run_queue_applies_pending_files_in_order_and_stops_on_failure() {
+        use postgres_ost::migration_queue::MigrationFile;
+        use postgres_ost::migration_runner::ReplayMode;
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let mut client = pool.get().unwrap();
+        client
+            .simple_query("INSERT INTO test_table (assertable) VALUES ('kept')")
+            .unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("ost_run_dir_{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(
+            dir.join("1_add_first.sql"),
+            "ALTER TABLE test_table ADD COLUMN first INT DEFAULT 1",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("2_add_second.sql"),
+            "ALTER TABLE test_table ADD COLUMN second INT DEFAULT 2",
+        )
+        .unwrap();
+        let settings = |_: &postgres_ost::Table| postgres_ost::config::MigrationSettings::default();
+
+        let files = MigrationFile::load_dir(&dir).unwrap();
+        let report = runner
+            .run_queue(&files, false, ReplayMode::Log, settings)
+            .unwrap();
+        assert_eq!(report.pending, vec!["1_add_first", "2_add_second"]);
+        assert!(report.applied.is_empty());
+
+        let report = runner
+            .run_queue(&files, true, ReplayMode::Log, settings)
+            .unwrap();
+        assert_eq!(report.applied, vec!["1_add_first", "2_add_second"]);
+        let row = client
+            .query_one(
+                "SELECT first, second FROM test_table WHERE assertable = 'kept'",
+                &[],
+            )
+            .unwrap();
+        assert_eq!((row.get::<_, i32>(0), row.get::<_, i32>(1)), (1, 2));
+
+        // The second file fails on the shadow table, so the third never runs
+        std::fs::write(
+            dir.join("3_duplicate.sql"),
+            "ALTER TABLE test_table ADD COLUMN first INT",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("4_add_third.sql"),
+            "ALTER TABLE test_table ADD COLUMN third INT",
+        )
+        .unwrap();
+        let files = MigrationFile::load_dir(&dir).unwrap();
+        let err = runner
+            .run_queue(&files, true, ReplayMode::Log, settings)
+            .unwrap_err();
+        assert!(err.to_string().contains("3_duplicate"), "{}", err);
+        let versions: Vec<String> = client
+            .query(
+                "SELECT version FROM post_migrations_state.migration_state ORDER BY version",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(versions, vec!["1_add_first", "2_add_second"]);
+        let third: i64 = client
+            .query_one(
+                "SELECT count(*) FROM information_schema.columns WHERE table_name = 'test_table' AND column_name = 'third'",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(third, 0);
+
+        std::fs::remove_file(dir.join("3_duplicate.sql")).unwrap();
+        let files = MigrationFile::load_dir(&dir).unwrap();
+        let report = runner
+            .run_queue(&files, true, ReplayMode::Log, settings)
+            .unwrap();
+        assert_eq!(report.skipped, vec!["1_add_first", "2_add_second"]);
+        assert_eq!(report.applied, vec!["4_add_third"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn run_multi_table_migration_test(mode: postgres_ost::migration_runner::ReplayMode) {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE other_table (id BIGSERIAL PRIMARY KEY, note TEXT);
+                 INSERT INTO test_table (assertable) SELECT 'row ' || i FROM generate_series(1, 20) i;
+                 INSERT INTO other_table (note) SELECT 'note ' || i FROM generate_series(1, 10) i;",
+            )
+            .unwrap();
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let sqls = vec![
+            "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42".to_string(),
+            "ALTER TABLE other_table ADD COLUMN flag BOOLEAN DEFAULT true".to_string(),
+        ];
+        let reports = runner
+            .run_migrate_tables(&sqls, true, false, mode, |table| {
+                let mut settings = postgres_ost::config::MigrationSettings::default();
+                // Written while other_table is still being captured, so its cutover replays it
+                if table.name == "test_table" {
+                    settings.cutover.before_swap = vec![
+                        "INSERT INTO other_table (note) VALUES ('during first cutover')"
+                            .to_string(),
+                    ];
+                }
+                settings
+            })
+            .unwrap();
+        assert_eq!(reports.len(), 2);
+
+        let mut client = pool.get().unwrap();
+        let count: i64 = client
+            .query_one("SELECT count(*) FROM test_table WHERE swapped = 42", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 20);
+        let notes: Vec<String> = client
+            .query("SELECT note FROM other_table WHERE flag ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(notes.len(), 11);
+        assert_eq!(notes.last().unwrap(), "during first cutover");
+        for archived in [
+            "post_migrations_old.test_table",
+            "post_migrations_old.other_table",
+        ] {
+            let exists: bool = client
+                .query_one("SELECT to_regclass($1) IS NOT NULL", &[&archived])
+                .unwrap()
+                .get(0);
+            assert!(exists, "{} should be archived", archived);
+        }
+        let leftovers: i64 = client
+            .query_one(
+                "SELECT (SELECT count(*) FROM pg_replication_slots WHERE slot_name LIKE 'ost_slot_%')
+                      + (SELECT count(*) FROM pg_publication WHERE pubname LIKE 'ost_pub_%')
+                      + (SELECT count(*) FROM pg_trigger WHERE tgname LIKE '%_trigger' AND NOT tgisinternal)",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(
+            leftovers, 0,
+            "capture must be torn down after the last cutover"
+        );
+        drop(client);
+
+        let err = runner
+            .run_migrate_tables(
+                &[
+                    "ALTER TABLE test_table ADD COLUMN a INT".to_string(),
+                    "ALTER TABLE public.test_table ADD COLUMN b INT".to_string(),
+                ],
+                true,
+                false,
+                mode,
+                |_table| postgres_ost::config::MigrationSettings::default(),
+            )
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("more than one statement"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_multi_table_migration_with_triggers() {
+        run_multi_table_migration_test(postgres_ost::migration_runner::ReplayMode::Log);
+    }
+
+    #[test]
+    fn test_multi_table_migration_shares_one_slot() {
+        run_multi_table_migration_test(postgres_ost::migration_runner::ReplayMode::Logical);
+    }
+
+    #[test]
+    fn test_atomic_cutover_swaps_all_tables_or_none() {
+        use postgres_ost::config::MigrationSettings;
+        use postgres_ost::migration_runner::ReplayMode;
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE other_table (id BIGSERIAL PRIMARY KEY, note TEXT);
+                 INSERT INTO test_table (assertable) VALUES ('first');
+                 INSERT INTO other_table (note) VALUES ('second');",
+            )
+            .unwrap();
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        let sqls = vec![
+            "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42".to_string(),
+            "ALTER TABLE other_table ADD COLUMN flag BOOLEAN DEFAULT true".to_string(),
+        ];
+        let column_count = |table: &str| -> i64 {
+            pool.get()
+                .unwrap()
+                .query_one(
+                    "SELECT count(*) FROM information_schema.columns WHERE table_schema = 'public' AND table_name = $1",
+                    &[&table],
+                )
+                .unwrap()
+                .get(0)
+        };
+
+        // The second table's swap fails, so the first one must not switch either
+        let err = runner
+            .run_migrate_tables(&sqls, true, true, ReplayMode::Log, |table| {
+                let mut settings = MigrationSettings::default();
+                if table.name == "other_table" {
+                    settings.cutover.before_swap = vec!["SELECT 1 / 0".to_string()];
+                }
+                settings
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("no table was cut over"), "{}", err);
+        assert_eq!(column_count("test_table"), 3);
+        assert_eq!(column_count("other_table"), 2);
+
+        // Both swaps share one transaction, so other_table's hook already sees the new test_table
+        runner
+            .run_migrate_tables(&sqls, true, true, ReplayMode::Log, |table| {
+                let mut settings = MigrationSettings::default();
+                if table.name == "other_table" {
+                    settings.cutover.before_swap = vec![
+                        "DO $$ BEGIN PERFORM swapped FROM public.test_table; END $$".to_string(),
+                    ];
+                }
+                settings
+            })
+            .unwrap();
+        assert_eq!(column_count("test_table"), 4);
+        assert_eq!(column_count("other_table"), 3);
+        let triggers: i64 = pool
+            .get()
+            .unwrap()
+            .query_one(
+                "SELECT count(*) FROM pg_trigger WHERE tgname LIKE '%_trigger' AND NOT tgisinternal",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(triggers, 0);
+    }
+
+    #[test]
+    fn test_streaming_migrate_execute_drains_and_tears_down() {
+        let test_db = setup_test_db();
+        let pool = &test_db.pool;
+        pool.get()
+            .unwrap()
+            .simple_query("INSERT INTO test_table (assertable, target) VALUES ('before', 't1')")
+            .unwrap();
+        let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+            pool.clone(),
+            test_db.test_db_url.clone(),
+        );
+        runner
+            .run_migrate(
+                "ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42",
+                true,
+                postgres_ost::migration_runner::ReplayMode::StreamingLogical,
+            )
+            .unwrap();
+        let mut client = pool.get().unwrap();
+        let swapped: i32 = client
+            .query_one(
+                "SELECT swapped FROM test_table WHERE assertable = 'before'",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(swapped, 42);
+        let leftovers: i64 = client
+            .query_one(
+                "SELECT (SELECT count(*) FROM pg_replication_slots WHERE slot_name LIKE 'ost_slot_%')
+                      + (SELECT count(*) FROM pg_publication WHERE pubname LIKE 'ost_pub_%')",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(leftovers, 0);
+    }
 }
@@ -1,5 +1,5 @@
 mod common;
-use postgres_ost::logical_replication::{Publication, Slot};
+use postgres_ost::logical_replication::{Publication, ReplicaIdentity, Slot};
 use postgres_ost::table::Table;
 use uuid::Uuid;
 
@@ -72,3 +72,48 @@ fn test_publication_and_logical_replication() {
     publication.drop(client).ok();
     slot.drop_slot(client).ok();
 }
+
+#[test]
+fn test_publication_keeps_or_restores_replica_identity() {
+    let test_db = common::setup_test_db();
+    let mut pooled = test_db.pool.get().unwrap();
+    let client: &mut postgres::Client = &mut pooled;
+    let table = Table::new("public.test_table");
+    let slot = Slot::new(unique_slot_name());
+
+    // The default identity carries the primary key, so it is left as it is
+    let publication = Publication::new(unique_pub_name(), table.clone(), slot.clone());
+    publication.create(client).expect("create publication");
+    assert_eq!(
+        ReplicaIdentity::load(&table, client).unwrap(),
+        ReplicaIdentity::Default
+    );
+    publication.drop(client).unwrap();
+
+    // An index without the key is refused unless FULL is requested
+    client
+        .simple_query(
+            "CREATE UNIQUE INDEX test_table_target_key ON test_table (target);
+             ALTER TABLE test_table ALTER COLUMN target SET NOT NULL;
+             ALTER TABLE test_table REPLICA IDENTITY USING INDEX test_table_target_key",
+        )
+        .unwrap();
+    let original = ReplicaIdentity::Index("test_table_target_key".to_string());
+    let publication = Publication::new(unique_pub_name(), table.clone(), slot.clone());
+    let err = publication.create(client).unwrap_err();
+    assert!(
+        err.to_string().contains("--replica-identity-full"),
+        "unexpected error: {}",
+        err
+    );
+
+    let publication = publication.with_full_identity(true);
+    publication.create(client).expect("create publication");
+    assert_eq!(
+        ReplicaIdentity::load(&table, client).unwrap(),
+        ReplicaIdentity::Full
+    );
+    // Any clone can tear down
+    publication.clone().drop(client).unwrap();
+    assert_eq!(ReplicaIdentity::load(&table, client).unwrap(), original);
+}
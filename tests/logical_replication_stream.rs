@@ -4,6 +4,7 @@ use postgres_ost::logical_replication::LogicalReplicationStream;
 use postgres_ost::logical_replication::ReplicationMessage;
 use postgres_ost::logical_replication::Slot;
 use postgres_ost::logical_replication::stream::emit_replay_complete_message;
+use std::time::Duration;
 
 // Integration test for using libpq to access logical replication XLogData
 // Requires: a running Postgres instance, logical replication enabled, and a logical slot created
@@ -15,8 +16,8 @@ fn setup_slot_and_stream() -> (common::TestDb, LogicalReplicationStream) {
     let mut pooled = test_db.pool.get().unwrap();
     let client: &mut postgres::Client = &mut pooled;
     let slot = Slot {
-        name: dbname.to_string(),
         plugin: "test_decoding".to_string(),
+        ..Slot::new(dbname.to_string())
     };
     let _ = slot.drop_slot(client);
     slot.create_slot(client).unwrap();
@@ -98,7 +99,7 @@ fn test_send_feedback() {
     let mut feedback_lsn = None;
     for rep_msg in messages {
         if let ReplicationMessage::XLogData(xlog) = rep_msg {
-            // Send feedback for the received LSN, requesting a reply
+            // Send feedback for the received LSN
             stream.send_feedback(xlog.wal_end).expect("send_feedback");
             stream.conn.flush().expect("flush after feedback");
             feedback_lsn = Some(xlog.wal_end);
@@ -110,29 +111,30 @@ fn test_send_feedback() {
         "Did not find XLogData to send feedback for"
     );
 
-    // After sending feedback, expect a PrimaryKeepAlive with reply_requested = true and wal_end >= feedback_lsn
+    // The server then asks for a reply once it hasn't heard from us for a while, with wal_end >= feedback_lsn
     use std::{thread, time::Duration, time::Instant};
     let start = Instant::now();
     let mut got_reply = false;
     if let Some(lsn) = feedback_lsn {
-        for _ in 0..50 {
+        // Keepalives for WAL written by other tests can arrive first, so wait by time
+        while start.elapsed() < Duration::from_secs(10) {
             stream.conn.consume_input().expect("consume_input");
             let responses = stream
                 .next_batch(1, Some(Duration::from_millis(10)))
                 .expect("next_batch after feedback");
             for rep_msg in &responses {
-                if let ReplicationMessage::PrimaryKeepAlive(pk) = rep_msg {
-                    if pk.reply_requested {
-                        assert!(
-                            pk.wal_end >= lsn,
-                            "PrimaryKeepAlive.wal_end ({:?}) < feedback_lsn ({:?})",
-                            pk.wal_end,
-                            lsn
-                        );
-                        got_reply = true;
-                        println!("Got reply after {:?}", start.elapsed());
-                        break;
-                    }
+                if let ReplicationMessage::PrimaryKeepAlive(pk) = rep_msg
+                    && pk.reply_requested
+                {
+                    assert!(
+                        pk.wal_end >= lsn,
+                        "PrimaryKeepAlive.wal_end ({:?}) < feedback_lsn ({:?})",
+                        pk.wal_end,
+                        lsn
+                    );
+                    got_reply = true;
+                    println!("Got reply after {:?}", start.elapsed());
+                    break;
                 }
             }
             if got_reply {
@@ -147,6 +149,119 @@ fn test_send_feedback() {
     );
 }
 
+#[test]
+fn test_idle_stream_outlives_wal_sender_timeout() {
+    let (test_db, mut stream) = setup_slot_and_stream();
+    let mut client = test_db.get_client();
+    let timeout_ms: i32 = client
+        .query_one(
+            "SELECT setting::int FROM pg_settings WHERE name = 'wal_sender_timeout'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    stream.start().expect("stream start");
+    // Nothing is written, so only keepalives and status updates cross the connection
+    let idle = std::time::Duration::from_millis(timeout_ms as u64 + 1000);
+    let start = std::time::Instant::now();
+    while start.elapsed() < idle {
+        stream
+            .next_batch(usize::MAX, Some(idle - start.elapsed()))
+            .expect("next_batch while idle");
+    }
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('still', 'here')")
+        .unwrap();
+    assert!(
+        wait_for_xlog_data_containing(&mut stream, 10, 50, &["still"]),
+        "the stream should still be open after idling past wal_sender_timeout"
+    );
+}
+
+#[test]
+fn test_stream_reconnects_after_losing_its_connection() {
+    use postgres_ost::logical_replication::ReconnectPolicy;
+    let (test_db, stream) = setup_slot_and_stream();
+    let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reported_clone = reported.clone();
+    let mut stream = stream
+        .with_reconnect_policy(ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: 20,
+        })
+        .with_outage_handler(std::sync::Arc::new(move |slot: &str, outage| {
+            reported_clone
+                .lock()
+                .unwrap()
+                .push((slot.to_string(), outage.clone()));
+        }));
+    let mut client = test_db.get_client();
+    stream.start().expect("stream start");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('before', 'outage')")
+        .unwrap();
+    assert!(wait_for_xlog_data_containing(
+        &mut stream,
+        10,
+        50,
+        &["before"]
+    ));
+    let confirmed = stream.last_lsn();
+    stream.send_feedback(confirmed).expect("send_feedback");
+
+    // Kill the walsender, as a failover or network drop would
+    client
+        .simple_query(&format!(
+            "SELECT pg_terminate_backend(active_pid) FROM pg_replication_slots WHERE slot_name = '{}'",
+            test_db.dbname
+        ))
+        .unwrap();
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('after', 'outage')")
+        .unwrap();
+    assert!(
+        wait_for_xlog_data_containing(&mut stream, 10, 50, &["after"]),
+        "changes made during the outage should arrive once reconnected"
+    );
+    assert_eq!(stream.outages.len(), 1);
+    // Keepalives may have confirmed further while the table was idle
+    assert!(stream.outages[0].resumed_from >= confirmed);
+    assert_eq!(
+        *reported.lock().unwrap(),
+        vec![(test_db.dbname.clone(), stream.outages[0].clone())]
+    );
+}
+
+#[test]
+fn test_stream_passes_plugin_options() {
+    let (test_db, stream) = setup_slot_and_stream();
+    let mut stream = stream.with_options(vec![("include-xids".to_string(), "false".to_string())]);
+    let mut client = test_db.get_client();
+    stream.start().expect("stream start with options");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('with', 'options')")
+        .unwrap();
+    // Without xids test_decoding writes a bare BEGIN
+    let mut begins = Vec::new();
+    for _ in 0..50 {
+        for msg in stream
+            .next_batch(10, Some(Duration::from_millis(20)))
+            .unwrap()
+        {
+            if let ReplicationMessage::XLogData(xlog) = msg
+                && xlog.data.starts_with(b"BEGIN")
+            {
+                begins.push(String::from_utf8_lossy(&xlog.data).to_string());
+            }
+        }
+        if !begins.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(begins, vec!["BEGIN".to_string()]);
+}
+
 #[test]
 fn test_emit_replay_complete_message() {
     let (test_db, mut stream) = setup_slot_and_stream();
@@ -161,7 +276,7 @@ fn test_emit_replay_complete_message() {
         .unwrap();
 
     // Emit the replay complete message
-    emit_replay_complete_message(&mut client).expect("emit_replay_complete_message");
+    emit_replay_complete_message(&mut *client).expect("emit_replay_complete_message");
 
     // Use helper to look for the replay complete marker
     let found_marker = wait_for_xlog_data_containing(&mut stream, 10, 50, &["replay complete"]);
@@ -170,3 +285,331 @@ fn test_emit_replay_complete_message() {
         "Did not find replay complete marker in WAL stream"
     );
 }
+
+#[test]
+fn test_stop_releases_slot() {
+    let (test_db, mut stream) = setup_slot_and_stream();
+    let mut client = test_db.get_client();
+    stream.start().expect("stream start");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('stop', 'me')")
+        .unwrap();
+    stream.stop().expect("stream stop");
+    assert!(!stream.streaming);
+    // A second stop is a no-op
+    stream.stop().expect("second stop");
+    let slot = Slot {
+        plugin: "test_decoding".to_string(),
+        ..Slot::new(test_db.dbname.clone())
+    };
+    slot.drop_slot(&mut *client)
+        .expect("slot should be released once the stream stops");
+}
+
+#[test]
+fn test_rewind_sends_unconfirmed_changes_again() {
+    let (test_db, mut stream) = setup_slot_and_stream();
+    let mut client = test_db.get_client();
+    stream.start().expect("stream start");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('rewound', 'once')")
+        .unwrap();
+    assert!(wait_for_xlog_data_containing(
+        &mut stream,
+        5,
+        50,
+        &["rewound", "once"]
+    ));
+
+    stream.rewind().expect("rewind");
+    assert!(
+        wait_for_xlog_data_containing(&mut stream, 5, 50, &["rewound", "once"]),
+        "the unconfirmed change should be sent again"
+    );
+}
+
+#[test]
+fn test_streaming_replay_reads_a_failed_batch_again() {
+    use postgres_ost::Replay;
+    use postgres_ost::logical_replication::Publication;
+    let test_db = common::setup_test_db();
+    let mut client = test_db.get_client();
+    postgres_ost::version::detect_and_set_pg_version(&mut client).expect("set pg version");
+    client
+        .simple_query("CREATE TABLE post_migrations.test_table (LIKE test_table INCLUDING ALL)")
+        .unwrap();
+    let slot = Slot::new(format!("{}_retry", test_db.dbname))
+        .with_format(postgres_ost::logical_replication::Wal2JsonFormat::V2);
+    let table = postgres_ost::Table::new("public.test_table");
+    let replay = postgres_ost::StreamingLogicalReplay {
+        stream: postgres_ost::StreamingLogicalReplay::shared_stream(
+            LogicalReplicationStream::new(
+                &test_db.test_db_url,
+                &slot.name,
+                postgres_ost::logical_replication::message::Lsn(0),
+            )
+            .expect("stream new")
+            .with_options(slot.plugin_options()),
+        ),
+        publication: Publication::new(
+            format!("{}_pub", test_db.dbname),
+            table.clone(),
+            slot.clone(),
+        ),
+        slot: slot.clone(),
+        table: table.clone(),
+        shadow_table: postgres_ost::Table::new("post_migrations.test_table"),
+        column_map: postgres_ost::ColumnMap::identity(vec![
+            "id".to_string(),
+            "assertable".to_string(),
+            "target".to_string(),
+        ]),
+        primary_key: postgres_ost::PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        },
+        batch_size: 10,
+        progress: postgres_ost::logical_replication::ReplayProgress::new(&Default::default()),
+        row_filter: None,
+        drain_timeout: postgres_ost::replay::DEFAULT_DRAIN_TIMEOUT,
+    };
+    replay.setup(&mut client).expect("setup");
+    // Applying fails while the shadow table is missing
+    client
+        .batch_execute(
+            "ALTER TABLE post_migrations.test_table RENAME TO test_table_away;
+             INSERT INTO test_table (assertable, target) VALUES ('retried', 'x');",
+        )
+        .unwrap();
+    let failed = (0..20).any(|_| replay.replay_log(&mut client).is_err());
+    assert!(failed, "the batch should fail to apply");
+
+    client
+        .simple_query("ALTER TABLE post_migrations.test_table_away RENAME TO test_table")
+        .unwrap();
+    let applied = (0..20).any(|_| {
+        replay.replay_log(&mut client).expect("replay");
+        client
+            .query_one(
+                "SELECT count(*) FROM post_migrations.test_table WHERE assertable = 'retried'",
+                &[],
+            )
+            .unwrap()
+            .get::<_, i64>(0)
+            == 1
+    });
+    assert!(applied, "the failed batch should be read and applied again");
+}
+
+#[test]
+fn test_streaming_replay_drains_to_marker_and_tears_down() {
+    use postgres_ost::Replay;
+    use postgres_ost::logical_replication::Publication;
+    let test_db = common::setup_test_db();
+    let mut client = test_db.get_client();
+    postgres_ost::version::detect_and_set_pg_version(&mut client).expect("set pg version");
+    // The drain looks for the marker as a wal2json message
+    let slot = Slot::new(format!("{}_drain", test_db.dbname))
+        .with_format(postgres_ost::logical_replication::Wal2JsonFormat::V2);
+    let table = postgres_ost::Table::new("public.test_table");
+    let conninfo = format!(
+        "host=localhost user=post_test dbname={} password=postgres",
+        test_db.dbname
+    );
+    let replay = postgres_ost::StreamingLogicalReplay {
+        stream: postgres_ost::StreamingLogicalReplay::shared_stream(
+            LogicalReplicationStream::new(
+                &conninfo,
+                &slot.name,
+                postgres_ost::logical_replication::message::Lsn(0),
+            )
+            .expect("stream new")
+            .with_options(slot.plugin_options()),
+        ),
+        publication: Publication::new(
+            format!("{}_pub", test_db.dbname),
+            table.clone(),
+            slot.clone(),
+        ),
+        slot: slot.clone(),
+        table: table.clone(),
+        shadow_table: postgres_ost::Table::new("post_migrations.test_table"),
+        column_map: postgres_ost::ColumnMap::identity(vec!["id".to_string()]),
+        primary_key: postgres_ost::PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        },
+        batch_size: 10,
+        progress: postgres_ost::logical_replication::ReplayProgress::new(&Default::default()),
+        row_filter: None,
+        drain_timeout: postgres_ost::replay::DEFAULT_DRAIN_TIMEOUT,
+    };
+    replay.setup(&mut client).expect("setup");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('drain', 'me')")
+        .unwrap();
+
+    let mut transaction = client.transaction().unwrap();
+    replay
+        .replay_log_until_complete(&mut transaction)
+        .expect("drain until the marker");
+    replay.teardown(&mut transaction).expect("teardown");
+    transaction.commit().unwrap();
+    let remaining: i64 = client
+        .query_one(
+            "SELECT count(*) FROM pg_replication_slots WHERE slot_name = $1",
+            &[&slot.name],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(remaining, 0, "teardown should drop the slot");
+}
+
+#[test]
+fn test_streaming_replay_runs_in_the_orchestrator() {
+    use postgres_ost::Replay;
+    use postgres_ost::logical_replication::Publication;
+    let test_db = common::setup_test_db();
+    let runner = postgres_ost::migration_runner::MigrationRunner::from_pool(
+        test_db.pool.clone(),
+        test_db.test_db_url.clone(),
+    );
+    test_db
+        .get_client()
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('before', 't1')")
+        .unwrap();
+    let (migration, column_map) = runner
+        .run_schema_migration("ALTER TABLE test_table ADD COLUMN swapped INTEGER DEFAULT 42")
+        .unwrap();
+    // The replay thread and the cutover share the stream
+    let slot = Slot::new(format!("{}_orchestrated", test_db.dbname))
+        .with_format(postgres_ost::logical_replication::Wal2JsonFormat::V2);
+    let replay = postgres_ost::StreamingLogicalReplay {
+        stream: postgres_ost::StreamingLogicalReplay::shared_stream(
+            LogicalReplicationStream::new(
+                &test_db.test_db_url,
+                &slot.name,
+                postgres_ost::logical_replication::message::Lsn(0),
+            )
+            .expect("stream new")
+            .with_options(slot.plugin_options()),
+        ),
+        publication: Publication::new(
+            format!("{}_pub", test_db.dbname),
+            migration.table.clone(),
+            slot.clone(),
+        ),
+        slot: slot.clone(),
+        table: migration.table.clone(),
+        shadow_table: migration.shadow_table.clone(),
+        column_map: column_map.clone(),
+        primary_key: migration.primary_key.clone(),
+        batch_size: 10,
+        progress: postgres_ost::logical_replication::ReplayProgress::new(&Default::default()),
+        row_filter: None,
+        drain_timeout: postgres_ost::replay::DEFAULT_DRAIN_TIMEOUT,
+    };
+    replay.setup(&mut test_db.get_client()).expect("setup");
+    postgres_ost::MigrationOrchestrator::new(migration, test_db.pool.clone())
+        .orchestrate(true, column_map, replay)
+        .expect("orchestrate");
+
+    let mut client = test_db.get_client();
+    let swapped: i32 = client
+        .query_one(
+            "SELECT swapped FROM test_table WHERE assertable = 'before'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(swapped, 42);
+    let remaining: i64 = client
+        .query_one(
+            "SELECT count(*) FROM pg_replication_slots WHERE slot_name = $1",
+            &[&slot.name],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(remaining, 0);
+}
+
+#[test]
+fn test_streaming_replay_saves_progress_and_resumes_from_it() {
+    use postgres_ost::Replay;
+    use postgres_ost::logical_replication::message::Lsn;
+    use postgres_ost::logical_replication::{Publication, ReplayProgress};
+    let test_db = common::setup_test_db();
+    let mut client = test_db.get_client();
+    let slot = Slot {
+        plugin: "test_decoding".to_string(),
+        ..Slot::new(format!("{}_resume", test_db.dbname))
+    };
+    let table = postgres_ost::Table::new("public.test_table");
+    let progress = ReplayProgress::new(&Default::default());
+    let replay_from = |start_lsn| postgres_ost::StreamingLogicalReplay {
+        stream: postgres_ost::StreamingLogicalReplay::shared_stream(
+            LogicalReplicationStream::new(&test_db.test_db_url, &slot.name, start_lsn)
+                .expect("stream new"),
+        ),
+        publication: Publication::new(
+            format!("{}_pub", test_db.dbname),
+            table.clone(),
+            slot.clone(),
+        ),
+        slot: slot.clone(),
+        table: table.clone(),
+        shadow_table: postgres_ost::Table::new("post_migrations.test_table"),
+        column_map: postgres_ost::ColumnMap::identity(vec!["id".to_string()]),
+        primary_key: postgres_ost::PrimaryKeyInfo {
+            name: "id".to_string(),
+            ty: postgres::types::Type::INT8,
+        },
+        batch_size: 10,
+        progress: progress.clone(),
+        row_filter: None,
+        drain_timeout: postgres_ost::replay::DEFAULT_DRAIN_TIMEOUT,
+    };
+    let replay_until_saved = |replay: &postgres_ost::StreamingLogicalReplay,
+                              client: &mut postgres::Client,
+                              after: Option<Lsn>| {
+        for _ in 0..20 {
+            replay.replay_log(client).expect("replay_log");
+            let saved = progress.load(&slot.name, client).unwrap();
+            if let Some(lsn) = saved
+                && saved != after
+            {
+                return lsn;
+            }
+        }
+        panic!("no progress saved after {:?}", after);
+    };
+
+    let replay = replay_from(Lsn(0));
+    replay.setup(&mut client).expect("setup");
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('first', 'run')")
+        .unwrap();
+    let saved = replay_until_saved(&replay, &mut client, None);
+    assert_eq!(saved, replay.stream.lock().unwrap().last_lsn());
+    // Interrupt the run without tearing anything down
+    replay.stream.lock().unwrap().stop().expect("stop");
+    drop(replay);
+
+    let resumed = replay_from(Lsn(0));
+    resumed.resume(&mut client).expect("resume");
+    assert_eq!(resumed.stream.lock().unwrap().last_lsn(), saved);
+    client
+        .simple_query("INSERT INTO test_table (assertable, target) VALUES ('second', 'run')")
+        .unwrap();
+    let advanced = replay_until_saved(&resumed, &mut client, Some(saved));
+    assert!(
+        advanced.0 > saved.0,
+        "progress should move past {:?}",
+        saved
+    );
+
+    let mut transaction = client.transaction().unwrap();
+    resumed.teardown(&mut transaction).expect("teardown");
+    transaction.commit().unwrap();
+    assert_eq!(progress.load(&slot.name, &mut *client).unwrap(), None);
+}